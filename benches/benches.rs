@@ -42,10 +42,24 @@ fn benchmark_conf() -> *mut WeldConf {
     conf
 }
 
+/// Returns a configuration which uses several threads and does not vectorize loops.
+fn scalar_benchmark_conf() -> *mut WeldConf {
+    let conf = benchmark_conf();
+    let passes = weld::conf::default_passes_except(&["vectorize"]);
+    let key = CString::new("weld.optimization.passes").unwrap().into_raw() as *const c_char;
+    let value = CString::new(passes).unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+    conf
+}
+
 /// Returns a compiled, runnable Weld module.
 unsafe fn compile_program(code: &str) -> Result<*mut WeldModule, ()> {
+    compile_program_with_conf(code, benchmark_conf())
+}
+
+/// Returns a Weld module compiled with the given configuration.
+unsafe fn compile_program_with_conf(code: &str, conf: *mut WeldConf) -> Result<*mut WeldModule, ()> {
     let code = CString::new(code).unwrap();
-    let conf = benchmark_conf();
 
     let err = weld_error_new();
     let module = weld_module_compile(code.into_raw() as *const c_char, conf, err);
@@ -259,6 +273,110 @@ fn bench_tpch_q1(bench: &mut Bencher) {
                });
 }
 
+/// Runs a q1-style aggregation, without q1's filter on the ship date, so that its loop can be
+/// vectorized: group keys and aggregates are computed in SIMD lanes and merged into a vecmerger.
+fn run_tpch_q1_aggregation(bench: &mut Bencher, conf: *mut WeldConf) {
+    let code = include_str!("benchmarks/tpch/q1_aggregation.weld");
+
+    #[allow(dead_code)]
+    struct Args {
+        l_returnflag: WeldVec<i8>,
+        l_linestatus: WeldVec<i8>,
+        l_quantity: WeldVec<f32>,
+        l_ep: WeldVec<f32>,
+        l_discount: WeldVec<f32>,
+        l_tax: WeldVec<f32>,
+    }
+
+    #[derive(Clone)]
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ReturnValue {
+        l_returnflag: i8,
+        l_linestatus: i8,
+        sum_qty: f32,
+        sum_disc_price: f32,
+        sum_charge: f32,
+        avg_qty: f32,
+        avg_disc_price: f32,
+        avg_disc: f32,
+        count_order: i32,
+    }
+
+    // 100MB of data per column.
+    let data_size: usize = 2 << 26;
+    let size: usize = data_size / std::mem::size_of::<i32>();
+
+    // Spread the rows over all six groups.
+    let l_returnflag: Vec<i8> = (0..size).map(|i| (i % 3) as i8).collect();
+    let l_linestatus: Vec<i8> = (0..size).map(|i| (i % 2) as i8).collect();
+    let l_quantity: Vec<f32> = vec![20.0; size];
+    let l_ep: Vec<f32> = vec![100.0; size];
+    let l_discount: Vec<f32> = vec![0.5; size];
+    let l_tax: Vec<f32> = vec![0.5; size];
+
+    let ref args = Args {
+        l_returnflag: WeldVec {
+            data: l_returnflag.as_ptr() as *const i8,
+            len: size as i64,
+        },
+        l_linestatus: WeldVec {
+            data: l_linestatus.as_ptr() as *const i8,
+            len: size as i64,
+        },
+        l_quantity: WeldVec {
+            data: l_quantity.as_ptr() as *const f32,
+            len: size as i64,
+        },
+        l_ep: WeldVec {
+            data: l_ep.as_ptr() as *const f32,
+            len: size as i64,
+        },
+        l_discount: WeldVec {
+            data: l_discount.as_ptr() as *const f32,
+            len: size as i64,
+        },
+        l_tax: WeldVec {
+            data: l_tax.as_ptr() as *const f32,
+            len: size as i64,
+        },
+    };
+
+    let module = unsafe { compile_program_with_conf(code, conf).unwrap() };
+
+    // Run once to check correctness/warm up.
+    let ret_value = unsafe { run_module(module, args).unwrap() };
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<ReturnValue> };
+    let result = unsafe { (*data).clone() };
+
+    assert_eq!(result.len, 6);
+    let mut counts = [0; 6];
+    for i in 0..size {
+        counts[(l_returnflag[i] * 2 + l_linestatus[i]) as usize] += 1;
+    }
+    for i in 0..(result.len as isize) {
+        let group = unsafe { &*result.data.offset(i) };
+        assert_eq!(group.count_order, counts[i as usize]);
+        assert_eq!(group.avg_qty, 20.0);
+    }
+    unsafe { weld_value_free(ret_value) };
+
+    bench.iter(|| match unsafe { run_module(module, args) } {
+                   Ok(v) => unsafe { weld_value_free(v) },
+                   Err(e) => unsafe { weld_error_free(e) },
+               });
+
+    unsafe { weld_module_free(module) };
+}
+
+fn bench_tpch_q1_aggregation(bench: &mut Bencher) {
+    run_tpch_q1_aggregation(bench, benchmark_conf());
+}
+
+fn bench_tpch_q1_aggregation_scalar(bench: &mut Bencher) {
+    run_tpch_q1_aggregation(bench, scalar_benchmark_conf());
+}
+
 fn bench_tpch_q6(bench: &mut Bencher) {
     let code = include_str!("benchmarks/tpch/q6.weld");
 
@@ -355,6 +473,8 @@ fn main() {
         vec![("bench_vector_sum", bench_vector_sum),
              ("bench_map_reduce", bench_map_reduce),
              ("bench_tpch_q1", bench_tpch_q1),
+             ("bench_tpch_q1_aggregation", bench_tpch_q1_aggregation),
+             ("bench_tpch_q1_aggregation_scalar", bench_tpch_q1_aggregation_scalar),
              ("bench_tpch_q6", bench_tpch_q6)];
 
     let ref mut wtr = csv::Writer::from_file("bench.csv").unwrap();
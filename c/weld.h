@@ -84,6 +84,19 @@ weld_value_free(weld_value_t);
 extern "C" weld_module_t 
 weld_module_compile(const char *code, weld_conf_t, weld_error_t);
 
+/** Loads a module written to a shared library when it was compiled.
+ *
+ * Loads a shared library written by `weld_module_compile` with the
+ * `weld.compile.outputFile` configuration, without compiling the program
+ * again. Loaded modules report no optimization pass statistics.
+ *
+ * @param path the path of the shared library.
+ * @param err a Weld error for loading the module.
+ * @return a runnable module.
+ */
+extern "C" weld_module_t 
+weld_module_load(const char *path, weld_error_t);
+
 /** Runs a module using the given argument.
  *
  * Multi-argument Weld functions take a Weld value encapsulating
@@ -101,6 +114,18 @@ weld_module_compile(const char *code, weld_conf_t, weld_error_t);
 extern "C" weld_value_t 
 weld_module_run(weld_module_t, weld_conf_t, weld_value_t, weld_error_t);
 
+/** Returns statistics about the optimization passes run while compiling a module.
+ *
+ * The result has one line per pass, in the order in which the passes ran,
+ * giving the pass name, the number of times its transforms ran, its wall
+ * time, and the number of expression nodes before and after it.
+ *
+ * @param module the module.
+ * @return a string owned by the module, valid until the module is freed.
+ */
+extern "C" const char *
+weld_module_pass_stats(weld_module_t);
+
 /** Garbage collects a module.
  *
  * @param module the module to garbage collect.
@@ -1,4 +1,5 @@
 use std::env;
+use std::process::Command;
 
 fn main() {
     let target = env::var("TARGET").unwrap();
@@ -8,4 +9,38 @@ fn main() {
             println!("cargo:rustc-link-lib={}", lib);
         }
     }
+
+    // Compile the C++ helpers in src/host.cpp, which call parts of LLVM not exposed by its C API.
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let cxxflags = match Command::new("llvm-config").arg("--cxxflags").output() {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Ok(output) => {
+            panic!("`llvm-config --cxxflags` failed: {}",
+                   String::from_utf8_lossy(&output.stderr))
+        }
+        Err(e) => panic!("could not run llvm-config, which must be on the PATH: {}", e),
+    };
+    let status = Command::new("c++")
+        .args(&cxxflags.split_whitespace().collect::<Vec<_>>())
+        .args(&["-fPIC", "-c", "src/host.cpp", "-o"])
+        .arg(&format!("{}/host.o", out_dir))
+        .status()
+        .unwrap_or_else(|e| panic!("could not run the C++ compiler c++: {}", e));
+    if !status.success() {
+        panic!("c++ failed to compile src/host.cpp");
+    }
+    let status = Command::new("ar")
+        .arg("crs")
+        .arg(&format!("{}/libeasyllhost.a", out_dir))
+        .arg(&format!("{}/host.o", out_dir))
+        .status()
+        .unwrap_or_else(|e| panic!("could not run the archiver ar: {}", e));
+    if !status.success() {
+        panic!("ar failed to archive src/host.cpp");
+    }
+    println!("cargo:rustc-link-search=native={}", out_dir);
+    println!("cargo:rustc-link-lib=static=easyllhost");
+    println!("cargo:rerun-if-changed=src/host.cpp");
 }
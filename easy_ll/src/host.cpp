@@ -0,0 +1,29 @@
+// Reports features of the host CPU that LLVM's C API does not expose.
+
+#include <llvm/ADT/StringMap.h>
+#include <llvm/Support/Host.h>
+
+#include <string>
+
+// Returns the width in bits of the widest SIMD registers the host CPU supports, as reported by
+// LLVM's host feature detection, or 128 if LLVM cannot detect the host's features.
+extern "C" unsigned easy_ll_host_vector_bits() {
+    llvm::StringMap<bool> features;
+    if (!llvm::sys::getHostCPUFeatures(features)) {
+        return 128;
+    }
+    if (features.lookup("avx512f")) {
+        return 512;
+    }
+    if (features.lookup("avx")) {
+        return 256;
+    }
+    return 128;
+}
+
+// Returns the name of the host CPU as LLVM knows it (e.g. "haswell"), for generating object code
+// that uses all of the host's features. The string is valid for the lifetime of the process.
+extern "C" const char *easy_ll_host_cpu_name() {
+    static const std::string name = llvm::sys::getHostCPUName().str();
+    return name.c_str();
+}
@@ -15,6 +15,7 @@ use llvm::support::LLVMLoadLibraryPermanently;
 use llvm::prelude::{LLVMContextRef, LLVMModuleRef, LLVMMemoryBufferRef};
 use llvm::execution_engine::{LLVMExecutionEngineRef, LLVMMCJITCompilerOptions};
 use llvm::analysis::LLVMVerifierFailureAction;
+use llvm::target_machine as tm;
 use llvm::transforms::pass_manager_builder as pmb;
 
 #[cfg(test)]
@@ -96,6 +97,17 @@ impl Drop for CompiledModule {
     }
 }
 
+extern "C" {
+    fn easy_ll_host_vector_bits() -> u32;
+    fn easy_ll_host_cpu_name() -> *const c_char;
+}
+
+/// Returns the width in bits of the widest SIMD registers supported by the host CPU, based on the
+/// CPU features LLVM detects (e.g. 256 on a machine with AVX).
+pub fn host_vector_bits() -> u32 {
+    unsafe { easy_ll_host_vector_bits() }
+}
+
 /// Loads a dynamic library by name. It is safe to call this function multiple times. The library
 /// must be on the search path or in one of the build directories for the module.
 pub fn load_library(libname: &str) -> Result<(), LlvmError> {
@@ -142,18 +154,7 @@ pub fn compile_module(code: &str, bc_file: Option<&[u8]>) -> Result<CompiledModu
             run_function: None,
         };
 
-        // Parse the IR to get an LLVMModuleRef
-        let module = parse_module_str(context, code)?;
-
-        if let Some(s) = bc_file {
-            let bc_module = parse_module_bytes(context, s)?;
-            llvm::linker::LLVMLinkModules2(module, bc_module);
-        }
-
-        // Validate and optimize the module
-        verify_module(module)?;
-        check_run_function(module)?;
-        optimize_module(module)?;
+        let module = prepare_module(context, code, bc_file)?;
 
         // Create an execution engine for the module and find its run function
         let engine = create_exec_engine(module)?;
@@ -165,6 +166,51 @@ pub fn compile_module(code: &str, bc_file: Option<&[u8]>) -> Result<CompiledModu
     }
 }
 
+/// Compile a string of LLVM IR into a native object file for the host at `path`, optimized the
+/// same way as by `compile_module`. The object file is position-independent, so it can be linked
+/// into a shared library, and defines the module's `run` function; functions the module calls but
+/// does not define are left for the linker or loader to resolve.
+pub fn compile_module_to_object(code: &str,
+                                bc_file: Option<&[u8]>,
+                                path: &str)
+                                -> Result<(), LlvmError> {
+    unsafe {
+        ONCE.call_once(|| initialize());
+        if INITIALIZE_FAILED {
+            return Err(LlvmError::new("LLVM initialization failed"));
+        }
+
+        let context = llvm::core::LLVMContextCreate();
+        if context.is_null() {
+            return Err(LlvmError::new("LLVMContextCreate returned null"));
+        }
+        let result = prepare_module(context, code, bc_file)
+            .and_then(|module| emit_object_file(module, path));
+        llvm::core::LLVMContextDispose(context);
+        result
+    }
+}
+
+/// Parse a module and link in the bitcode in `bc_file`, then validate and optimize the result.
+unsafe fn prepare_module(context: LLVMContextRef,
+                         code: &str,
+                         bc_file: Option<&[u8]>)
+                         -> Result<LLVMModuleRef, LlvmError> {
+    // Parse the IR to get an LLVMModuleRef
+    let module = parse_module_str(context, code)?;
+
+    if let Some(s) = bc_file {
+        let bc_module = parse_module_bytes(context, s)?;
+        llvm::linker::LLVMLinkModules2(module, bc_module);
+    }
+
+    // Validate and optimize the module
+    verify_module(module)?;
+    check_run_function(module)?;
+    optimize_module(module)?;
+    Ok(module)
+}
+
 /// Initialize LLVM or save an error message in `INITIALIZE_FAILED` if this does not work.
 /// We call this function only once in cases some steps are expensive.
 fn initialize() {
@@ -335,6 +381,50 @@ unsafe fn create_exec_engine(module: LLVMModuleRef) -> Result<LLVMExecutionEngin
     Ok(engine)
 }
 
+/// Generate native code for a module and write it to an object file, targeting the host CPU.
+unsafe fn emit_object_file(module: LLVMModuleRef, path: &str) -> Result<(), LlvmError> {
+    let triple = tm::LLVMGetDefaultTargetTriple();
+    let mut target = 0 as tm::LLVMTargetRef;
+    let mut error_str = 0 as *mut c_char;
+    if tm::LLVMGetTargetFromTriple(triple, &mut target, &mut error_str) != 0 {
+        let msg = format!("Finding target failed: {}",
+                          CStr::from_ptr(error_str).to_str().unwrap());
+        llvm::core::LLVMDisposeMessage(error_str);
+        llvm::core::LLVMDisposeMessage(triple);
+        return Err(LlvmError(msg));
+    }
+
+    let features = CString::new("")?;
+    let machine = tm::LLVMCreateTargetMachine(target,
+                                              triple,
+                                              easy_ll_host_cpu_name(),
+                                              features.as_ptr(),
+                                              tm::LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                                              tm::LLVMRelocMode::LLVMRelocPIC,
+                                              tm::LLVMCodeModel::LLVMCodeModelDefault);
+    llvm::core::LLVMSetTarget(module, triple);
+    llvm::core::LLVMDisposeMessage(triple);
+    if machine.is_null() {
+        return Err(LlvmError::new("LLVMCreateTargetMachine returned null"));
+    }
+
+    let c_path = CString::new(path)?;
+    let result_code = tm::LLVMTargetMachineEmitToFile(machine,
+                                                      module,
+                                                      c_path.as_ptr() as *mut c_char,
+                                                      tm::LLVMCodeGenFileType::LLVMObjectFile,
+                                                      &mut error_str);
+    tm::LLVMDisposeTargetMachine(machine);
+    if result_code != 0 {
+        let msg = format!("Writing object file {} failed: {}",
+                          path,
+                          CStr::from_ptr(error_str).to_str().unwrap());
+        llvm::core::LLVMDisposeMessage(error_str);
+        return Err(LlvmError(msg));
+    }
+    Ok(())
+}
+
 /// Get a pointer to a named function in an execution engine.
 unsafe fn find_function(engine: LLVMExecutionEngineRef, name: &str) -> Result<I64Func, LlvmError> {
     let c_name = CString::new(name).unwrap();
@@ -1,6 +1,9 @@
+use std::env;
 use std::error::Error;
+use std::fs;
+use std::process;
 
-use super::compile_module;
+use super::{compile_module, compile_module_to_object, host_vector_bits};
 
 #[test]
 fn basic_use() {
@@ -52,3 +55,35 @@ fn wrong_function_type() {
     assert!(!module.is_ok());
     assert!(module.unwrap_err().description().contains("wrong type"));
 }
+
+#[test]
+fn object_file() {
+    let path = env::temp_dir().join(format!("easy_ll_object_file_test-{}.o", process::id()));
+    let path = path.to_str().unwrap();
+    let result = compile_module_to_object("
+       define i64 @run(i64 %arg) {
+           %1 = add i64 %arg, 1
+           ret i64 %1
+       }
+    ",
+                                          None,
+                                          path);
+    assert!(result.is_ok());
+    assert!(fs::metadata(path).unwrap().len() > 0);
+    fs::remove_file(path).unwrap();
+
+    let result = compile_module_to_object("
+       define i64 @ZZZZZZZ(i64 %arg) {
+           ret i64 0
+       }
+    ",
+                                          None,
+                                          path);
+    assert!(result.unwrap_err().description().contains("run function"));
+}
+
+#[test]
+fn host_vector_width() {
+    let bits = host_vector_bits();
+    assert!(bits >= 128 && bits.is_power_of_two());
+}
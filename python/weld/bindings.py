@@ -63,6 +63,13 @@ class WeldModule(c_void_p):
         ret = weld_module_run(self.module, conf.conf, arg.val, err.error)
         return WeldValue(ret, assign=True)
 
+    def pass_stats(self):
+        weld_module_pass_stats = weld.weld_module_pass_stats
+        weld_module_pass_stats.argtypes = [c_weld_module]
+        weld_module_pass_stats.restype = c_char_p
+        val = weld_module_pass_stats(self.module)
+        return copy.copy(val)
+
     def __del__(self):
         weld_module_free = weld.weld_module_free
         weld_module_free.argtypes = [c_weld_module]
@@ -1,4 +1,11 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::process::Command;
+use std::slice;
 
 extern crate weld;
 extern crate weld_common;
@@ -10,9 +17,16 @@ use weld::WeldConf;
 use weld::WeldValue;
 use weld::WeldError;
 use weld::{weld_value_new, weld_value_data, weld_value_free};
-use weld::{weld_module_compile, weld_module_run, weld_module_free};
+use weld::{weld_module_compile, weld_module_run, weld_module_pass_stats, weld_module_free};
+use weld::weld_module_load;
 use weld::{weld_error_new, weld_error_code, weld_error_message, weld_error_free};
 use weld::{weld_conf_new, weld_conf_set, weld_conf_free};
+use weld::ast::ExprKind::Literal;
+use weld::ast::LiteralKind::I32Literal;
+use weld::passes::{register_transform, unregister_pass, PassOrder};
+use weld::parser::parse_program;
+use weld::conf::ParsedConf;
+use weld::interpreter::interpret_program;
 
 use std::f64::consts::PI;
 use std::ffi::{CStr, CString};
@@ -42,12 +56,20 @@ struct Pair<K, V> {
     ele2: V,
 }
 
+/// Enables type checking and the other invariant checks after every optimization pass.
+fn verify_passes(conf: *mut WeldConf) {
+    let key = CString::new("weld.compile.verifyPasses").unwrap().into_raw() as *const c_char;
+    let value = CString::new("true").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+}
+
 /// Returns a default configuration which uses a single thread.
 fn default_conf() -> *mut WeldConf {
     let conf = weld_conf_new();
     let key = CString::new("weld.threads").unwrap().into_raw() as *const c_char;
     let value = CString::new("1").unwrap().into_raw() as *const c_char;
     unsafe { weld_conf_set(conf, key, value) };
+    verify_passes(conf);
     conf
 }
 
@@ -57,6 +79,16 @@ fn many_threads_conf() -> *mut WeldConf {
     let key = CString::new("weld.threads").unwrap().into_raw() as *const c_char;
     let value = CString::new("4").unwrap().into_raw() as *const c_char;
     unsafe { weld_conf_set(conf, key, value) };
+    verify_passes(conf);
+    conf
+}
+
+/// Returns a configuration which uses a single thread and compiles programs to C.
+fn c_backend_conf() -> *mut WeldConf {
+    let conf = default_conf();
+    let key = CString::new("weld.compile.backend").unwrap().into_raw() as *const c_char;
+    let value = CString::new("c").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
     conf
 }
 
@@ -116,6 +148,16 @@ fn compile_and_run<T>(code: &str, conf: *mut WeldConf, ptr: &T) -> *mut WeldValu
     }
 }
 
+/// Returns the expression `code` compiles to after the default optimization passes.
+fn optimized_code(code: &str) -> String {
+    let program = parse_program(code).unwrap();
+    let mut expr = weld::interpreter::typed_program(&program).unwrap();
+    for pass in weld::conf::default_optimization_passes() {
+        pass.transform(&mut expr, 100).unwrap();
+    }
+    weld::pretty_print::print_expr(&expr)
+}
+
 fn basic_program() {
     let code = "|| 40 + 2";
     let conf = default_conf();
@@ -130,6 +172,85 @@ fn basic_program() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn registered_optimization_pass() {
+    // A pass that replaces the literal 40 with 41.
+    register_transform("replace-forty", PassOrder::Manual, true, |e| {
+        e.transform(&mut |ref mut e| {
+            if let Literal(I32Literal(40)) = e.kind {
+                e.kind = Literal(I32Literal(41));
+            }
+            None
+        })
+    }).unwrap();
+
+    let code = "|| 40 + 2";
+    let conf = default_conf();
+    let key = CString::new("weld.optimization.passes").unwrap().into_raw() as *const c_char;
+    let value = CString::new("inline-let,replace-forty").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+
+    let ref input_data = 0;
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { *data };
+    assert_eq!(result, 43);
+
+    unsafe { weld_value_free(ret_value) };
+    unregister_pass("replace-forty");
+}
+
+fn optimization_pass_stats() {
+    let code = CString::new("|| let x = 40; x + 2").unwrap();
+    let conf = default_conf();
+    let key = CString::new("weld.optimization.passes").unwrap().into_raw() as *const c_char;
+    let value = CString::new("inline-let,fold-constants").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+
+    let err = weld_error_new();
+    let module = unsafe { weld_module_compile(code.into_raw() as *const c_char, conf, err) };
+    assert_eq!(unsafe { weld_error_code(err) }, WeldRuntimeErrno::Success);
+
+    let stats = unsafe { CStr::from_ptr(weld_module_pass_stats(module)) };
+    let stats: Vec<&str> = stats.to_str().unwrap().lines().collect();
+    assert_eq!(stats.len(), 2);
+    assert!(stats[0].starts_with("inline-let: "));
+    assert!(stats[1].starts_with("fold-constants: "));
+
+    unsafe { weld_module_free(module) };
+    unsafe { weld_error_free(err) };
+    unsafe { weld_conf_free(conf) };
+}
+
+fn nonconverging_pass_error_test() {
+    // A pass that changes the program every time it runs.
+    register_transform("increment-literals", PassOrder::Manual, true, |e| {
+        e.transform(&mut |ref mut e| {
+            if let Literal(I32Literal(v)) = e.kind {
+                e.kind = Literal(I32Literal(v + 1));
+            }
+            None
+        })
+    }).unwrap();
+
+    let code = "|| 40 + 2";
+    let conf = default_conf();
+    let key = CString::new("weld.optimization.passes").unwrap().into_raw() as *const c_char;
+    let value = CString::new("increment-literals").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+    let key = CString::new("weld.optimization.maxIterations").unwrap().into_raw() as *const c_char;
+    let value = CString::new("10").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+
+    let ref input_data = 0;
+
+    let err_value = compile_and_run_error(code, conf, input_data);
+    assert_eq!(unsafe { weld_error_code(err_value) },
+               WeldRuntimeErrno::CompileError);
+    unsafe { weld_error_free(err_value) };
+    unregister_pass("increment-literals");
+}
+
 fn float_literals() {
     let values = vec![0.0, PI, -PI, 1.2e20, -1.2e-20];
     for v in values {
@@ -655,6 +776,30 @@ fn complex_parallel_for_appender_loop() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn conf_simd_width_vectorizable_loop() {
+    // With a 64-bit width, each SIMD value holds a single i64 and a comparison gives a <1 x i1>.
+    let code = "|x:vec[i64]| result(for(x, merger[i64,+], |b,i,e| merge(b, select(e > 500L, e, 0L))))";
+    let input_vec: Vec<i64> = (0..1001).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i64,
+        len: input_vec.len() as i64,
+    };
+    let output: i64 = (501..1001).sum();
+
+    for width in ["64", "128", "512"].iter() {
+        let conf = default_conf();
+        let key = CString::new("weld.compile.simdWidth").unwrap().into_raw() as *const c_char;
+        let value = CString::new(*width).unwrap().into_raw() as *const c_char;
+        unsafe { weld_conf_set(conf, key, value) };
+
+        let ret_value = compile_and_run(code, conf, input_data);
+        let data = unsafe { weld_value_data(ret_value) as *const i64 };
+        let result = unsafe { (*data).clone() };
+        assert_eq!(result, output);
+        unsafe { weld_value_free(ret_value) };
+    }
+}
+
 fn simple_for_vectorizable_loop() {
     #[allow(dead_code)]
     struct Args {
@@ -663,7 +808,11 @@ fn simple_for_vectorizable_loop() {
 
     let code = "|x:vec[i32]| result(for(simditer(x), merger[i32,+], |b,i,e:simd[i32]| let a = broadcast(1); let a2 = a +\
                     broadcast(1); merge(b, e+a2)))";
+    // The loop has no fringe, so use a SIMD width whose vectors evenly divide the input.
     let conf = default_conf();
+    let key = CString::new("weld.compile.simdWidth").unwrap().into_raw() as *const c_char;
+    let value = CString::new("128").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
 
     let size = 1000;
     let input_vec = vec![1 as i32; size as usize];
@@ -735,7 +884,11 @@ fn for_predicated_vectorizable_loop() {
 			  ))
 	))
 	";
+    // The loop has no fringe, so use a SIMD width whose vectors evenly divide the input.
     let conf = default_conf();
+    let key = CString::new("weld.compile.simdWidth").unwrap().into_raw() as *const c_char;
+    let value = CString::new("128").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
 
     let size = 1000;
     let input_vec = vec![1 as i32; size as usize];
@@ -754,6 +907,201 @@ fn for_predicated_vectorizable_loop() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn grain_sized_vectorizable_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+    }
+
+    // The grain size is not a multiple of the vector size, so the SIMD vectors have to be
+    // split among threads whole for each element to be summed exactly once.
+    let code = "|x:vec[i32]|
+	let b1 = @(grain_size:7) for(simditer(x), merger[i32,+], |b,i,e:simd[i32]| merge(b, e));
+	result(for(fringeiter(x), b1, |b,i,e| merge(b, e)))";
+    let conf = many_threads_conf();
+
+    let size: i32 = 10002;
+    let input_vec: Vec<i32> = (0..size).collect();
+    let ref input_data = Args {
+        x: WeldVec {
+            data: input_vec.as_ptr() as *const i32,
+            len: input_vec.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    let output = size * (size - 1) / 2;
+    assert_eq!(result, output);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn strided_vectorizable_loop() {
+    // The loop is vectorized with a gather, and its last 333 % 4 iterations run in the fringe loop.
+    let code = "|x:vec[i32]| result(for(iter(x, 1L, 1000L, 3L), merger[i32,+], |b,i,e| \
+                merge(b, e * 2)))";
+    let conf = default_conf();
+
+    let input_vec: Vec<i32> = (0..1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    let output: i32 = (1..1000).filter(|e| e % 3 == 1).map(|e| e * 2).sum();
+    assert_eq!(result, output);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn strided_zip_vectorizable_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+        y: WeldVec<i32>,
+    }
+
+    let code = "|x:vec[i32], y:vec[i32]| result(for(zip(iter(x, 0L, 2000L, 2L), y), \
+                merger[i32,+], |b,i,e| merge(b, e.$0 * e.$1)))";
+    let conf = default_conf();
+
+    let x: Vec<i32> = (0..2000).collect();
+    let y = vec![3; 1000];
+    let ref input_data = Args {
+        x: WeldVec {
+            data: x.as_ptr() as *const i32,
+            len: x.len() as i64,
+        },
+        y: WeldVec {
+            data: y.as_ptr() as *const i32,
+            len: y.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    let output: i32 = (0..2000).filter(|e| e % 2 == 0).map(|e| e * 3).sum();
+    assert_eq!(result, output);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn predicated_scalar_merger_loop() {
+    // Uses the loop index, so the loop cannot be vectorized.
+    let code = "|x:vec[i32]| result(for(x, merger[i64,+], |b,i,e| @(predicate:true) if(e>5, \
+                merge(b,i), b)))";
+    let conf = default_conf();
+
+    let input_vec = [1, 7, 3, 9, 6, 2];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i64 };
+    let result = unsafe { (*data).clone() };
+    let output = 1 + 3 + 4;
+    assert_eq!(result, output);
+
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn loop_invariant_length_merger_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+        y: WeldVec<i32>,
+    }
+
+    // len(y) is hoisted out of the loop, which lets the loop be vectorized.
+    let code = "|x:vec[i32], y:vec[i32]| result(for(x, merger[i32,+], |b,i,e| \
+                merge(b, e*i32(len(y)))))";
+    assert!(optimized_code(code).contains("simditer("));
+    let conf = default_conf();
+
+    let input_vec = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    let other_vec = vec![0, 0, 0];
+    let ref input_data = Args {
+        x: WeldVec {
+            data: input_vec.as_ptr(),
+            len: input_vec.len() as i64,
+        },
+        y: WeldVec {
+            data: other_vec.as_ptr(),
+            len: other_vec.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    let output = 55 * 3;
+    assert_eq!(result, output);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn loop_invariant_lookup_merger_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+        y: WeldVec<i32>,
+    }
+
+    // The lookup is hoisted out of a version of the loop that runs when x is not empty, which
+    // lets that version be vectorized; y may be empty when x is.
+    let code = "|x:vec[i32], y:vec[i32]| result(for(x, merger[i32,+], |b,i,e| \
+                merge(b, e*lookup(y, 0L))))";
+    assert!(optimized_code(code).contains("simditer("));
+
+    let input_vec = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    let other_vec = vec![3];
+    let empty_vec: Vec<i32> = vec![];
+    let cases = vec![(&input_vec, &other_vec, 55 * 3), (&empty_vec, &empty_vec, 0)];
+    for (x, y, output) in cases {
+        let ref input_data = Args {
+            x: WeldVec {
+                data: x.as_ptr(),
+                len: x.len() as i64,
+            },
+            y: WeldVec {
+                data: y.as_ptr(),
+                len: y.len() as i64,
+            },
+        };
+
+        let conf = default_conf();
+        let ret_value = compile_and_run(code, conf, input_data);
+        let data = unsafe { weld_value_data(ret_value) as *const i32 };
+        let result = unsafe { (*data).clone() };
+        assert_eq!(result, output);
+        unsafe { weld_value_free(ret_value) };
+    }
+}
+
+fn dead_struct_fields_loop() {
+    // Only the last field of the intermediate vector is read, so the other two are removed.
+    let code = "|x:vec[i32]| let v = result(for(x, appender[{i32,i64,i32}], |b,i,e| \
+                merge(b, {e, i, e*2}))); result(for(v, merger[i32,+], |b,i,e| merge(b, e.$2)))";
+    let conf = default_conf();
+
+    let input_vec = [1, 2, 3, 4, 5];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    assert_eq!(result, 30);
+
+    unsafe { weld_value_free(ret_value) };
+}
+
 fn simple_for_merger_loop() {
     #[allow(dead_code)]
     struct Args {
@@ -1229,103 +1577,270 @@ fn simple_parallel_for_dictmerger_loop() {
     unsafe { weld_value_free(ret_value) };
 }
 
-fn simple_dict_lookup() {
+fn vectorized_dictmerger_loop() {
+    #[derive(Clone)]
+    #[allow(dead_code)]
+    struct Entry {
+        key1: i32,
+        key2: i32,
+        value: i32,
+    }
     #[allow(dead_code)]
     struct Args {
         x: WeldVec<i32>,
         y: WeldVec<i32>,
     }
 
-    let code = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), dictmerger[i32,i32,+], \
-                |b,i,e| merge(b, e))); lookup(a, 1)";
-    let conf = default_conf();
+    // Keys and values are computed in SIMD lanes and merged one lane at a time.
+    let code = "|x:vec[i32], y:vec[i32]| tovec(result(for(zip(x,y), dictmerger[{i32,i32},i32,+], \
+                |b,i,e| merge(b, {{e.$0 / 100, e.$0 / 500}, e.$1 * 2}))))";
+    let conf = many_threads_conf();
 
-    let keys = [1, 2, 2, 1, 3];
-    let vals = [2, 3, 4, 2, 1];
+    let keys: Vec<i32> = (0..1003).collect();
+    let vals: Vec<i32> = vec![1; keys.len()];
     let ref input_data = Args {
         x: WeldVec {
-            data: &keys as *const i32,
+            data: keys.as_ptr() as *const i32,
             len: keys.len() as i64,
         },
         y: WeldVec {
-            data: &vals as *const i32,
+            data: vals.as_ptr() as *const i32,
             len: vals.len() as i64,
         },
     };
 
     let ret_value = compile_and_run(code, conf, input_data);
-    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<Entry> };
     let result = unsafe { (*data).clone() };
 
-    let output = 4;
-    assert_eq!(output, result);
+    let mut expected = HashMap::new();
+    for k in keys.iter() {
+        *expected.entry((k / 100, k / 500)).or_insert(0) += 2;
+    }
+    assert_eq!(result.len, expected.len() as i64);
+    for i in 0..(result.len as isize) {
+        let entry = unsafe { (*result.data.offset(i)).clone() };
+        assert_eq!(expected.get(&(entry.key1, entry.key2)), Some(&entry.value));
+    }
     unsafe { weld_value_free(ret_value) };
 }
 
-fn simple_dict_exists() {
+fn vectorized_vecmerger_loop() {
     #[allow(dead_code)]
     struct Args {
-        x: WeldVec<i32>,
-        y: WeldVec<i32>,
+        x: WeldVec<i8>,
+        y: WeldVec<f64>,
+        z: WeldVec<f64>,
     }
 
-    let keys = [1, 2, 2, 1, 3];
-    let vals = [2, 3, 4, 2, 1];
-
-    let code_true = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), dictmerger[i32,i32,+], \
-                |b,i,e| merge(b, e))); keyexists(a, 1)";
-    let code_false = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), \
-                      dictmerger[i32,i32,+], |b,i,e| merge(b, e))); keyexists(a, 4)";
-    let conf = default_conf();
+    // A histogram whose bucket indices are computed and cast in SIMD lanes.
+    let code = "|x:vec[i8], y:vec[f64], z:vec[f64]| result(for(zip(x,y), vecmerger[f64,+](z), \
+                |b,i,e| merge(b, {i64(e.$0), e.$1 * 2.0})))";
+    let conf = many_threads_conf();
 
+    let buckets: Vec<i8> = (0..1003).map(|i| (i % 10) as i8).collect();
+    let vals: Vec<f64> = (0..1003).map(|i| i as f64).collect();
+    let init: Vec<f64> = vec![1.0; 10];
     let ref input_data = Args {
         x: WeldVec {
-            data: &keys as *const i32,
-            len: keys.len() as i64,
+            data: buckets.as_ptr() as *const i8,
+            len: buckets.len() as i64,
         },
         y: WeldVec {
-            data: &vals as *const i32,
+            data: vals.as_ptr() as *const f64,
             len: vals.len() as i64,
         },
+        z: WeldVec {
+            data: init.as_ptr() as *const f64,
+            len: init.len() as i64,
+        },
     };
 
-    let ret_value = compile_and_run(code_true, conf, input_data.clone());
-    let data = unsafe { weld_value_data(ret_value) as *const bool };
-    let result = unsafe { (*data).clone() };
-
-    let output = true;
-    assert_eq!(output, result);
-
-    let conf2 = default_conf();
-    let ret_value2 = compile_and_run(code_false, conf2, input_data.clone());
-    let data = unsafe { weld_value_data(ret_value2) as *const bool };
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<f64> };
     let result = unsafe { (*data).clone() };
 
-    let output = false;
-    assert_eq!(output, result);
+    let mut expected = init.clone();
+    for (b, v) in buckets.iter().zip(vals.iter()) {
+        expected[*b as usize] += v * 2.0;
+    }
+    assert_eq!(result.len, expected.len() as i64);
+    for i in 0..(result.len as isize) {
+        assert_eq!(unsafe { *result.data.offset(i) }, expected[i as usize]);
+    }
     unsafe { weld_value_free(ret_value) };
 }
 
-fn simple_length() {
-    let code = "|x:vec[i32]| len(x)";
-    let conf = default_conf();
+fn vectorized_struct_appender_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<f64>,
+        y: WeldVec<f64>,
+    }
 
-    let input_vec = [2, 3, 4, 2, 1];
-    let ref input_data = WeldVec {
-        data: &input_vec as *const i32,
-        len: input_vec.len() as i64,
+    // Each lane appends a struct, and lanes must be appended in order.
+    let code = "|x:vec[f64], y:vec[f64]| result(for(zip(x,y), appender[{f64,f64}], \
+                |b,i,e| let s = {e.$0 + e.$1, e.$0 * e.$1}; merge(b, {s.$1, s.$0})))";
+    let conf = many_threads_conf();
+
+    let xs: Vec<f64> = (0..1003).map(|i| i as f64).collect();
+    let ys: Vec<f64> = (0..1003).map(|i| (i % 7) as f64).collect();
+    let ref input_data = Args {
+        x: WeldVec {
+            data: xs.as_ptr() as *const f64,
+            len: xs.len() as i64,
+        },
+        y: WeldVec {
+            data: ys.as_ptr() as *const f64,
+            len: ys.len() as i64,
+        },
     };
 
     let ret_value = compile_and_run(code, conf, input_data);
-    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<Pair<f64, f64>> };
     let result = unsafe { (*data).clone() };
 
-    let output = 5;
-    assert_eq!(output, result);
+    assert_eq!(result.len, xs.len() as i64);
+    for i in 0..(result.len as isize) {
+        let (x, y) = (xs[i as usize], ys[i as usize]);
+        assert_eq!(unsafe { (*result.data.offset(i)).ele1 }, x * y);
+        assert_eq!(unsafe { (*result.data.offset(i)).ele2 }, x + y);
+    }
     unsafe { weld_value_free(ret_value) };
 }
 
-fn filter_length() {
+fn vectorized_nested_loop() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i64>,
+        y: WeldVec<i64>,
+    }
+
+    // The inner loop depends on the outer element, so it stays scalar while the outer loop is
+    // vectorized.
+    let code = "|x:vec[i64], y:vec[i64]| result(for(x, merger[i64,+], |b,i,e| \
+                merge(b, e + result(for(y, merger[i64,+], |b2,i2,f| merge(b2, f * e))))))";
+    let conf = many_threads_conf();
+
+    let xs: Vec<i64> = (0..1003).map(|i| (i % 13) - 6).collect();
+    let ys: Vec<i64> = vec![-3, 1, 4, -1, 5];
+    let ref input_data = Args {
+        x: WeldVec {
+            data: xs.as_ptr() as *const i64,
+            len: xs.len() as i64,
+        },
+        y: WeldVec {
+            data: ys.as_ptr() as *const i64,
+            len: ys.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i64 };
+    let result = unsafe { *data };
+
+    let expected: i64 = xs.iter().map(|x| x + ys.iter().map(|y| y * x).sum::<i64>()).sum();
+    assert_eq!(result, expected);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn simple_dict_lookup() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+        y: WeldVec<i32>,
+    }
+
+    let code = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), dictmerger[i32,i32,+], \
+                |b,i,e| merge(b, e))); lookup(a, 1)";
+    let conf = default_conf();
+
+    let keys = [1, 2, 2, 1, 3];
+    let vals = [2, 3, 4, 2, 1];
+    let ref input_data = Args {
+        x: WeldVec {
+            data: &keys as *const i32,
+            len: keys.len() as i64,
+        },
+        y: WeldVec {
+            data: &vals as *const i32,
+            len: vals.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+
+    let output = 4;
+    assert_eq!(output, result);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn simple_dict_exists() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<i32>,
+        y: WeldVec<i32>,
+    }
+
+    let keys = [1, 2, 2, 1, 3];
+    let vals = [2, 3, 4, 2, 1];
+
+    let code_true = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), dictmerger[i32,i32,+], \
+                |b,i,e| merge(b, e))); keyexists(a, 1)";
+    let code_false = "|x:vec[i32], y:vec[i32]| let a = result(for(zip(x,y), \
+                      dictmerger[i32,i32,+], |b,i,e| merge(b, e))); keyexists(a, 4)";
+    let conf = default_conf();
+
+    let ref input_data = Args {
+        x: WeldVec {
+            data: &keys as *const i32,
+            len: keys.len() as i64,
+        },
+        y: WeldVec {
+            data: &vals as *const i32,
+            len: vals.len() as i64,
+        },
+    };
+
+    let ret_value = compile_and_run(code_true, conf, input_data.clone());
+    let data = unsafe { weld_value_data(ret_value) as *const bool };
+    let result = unsafe { (*data).clone() };
+
+    let output = true;
+    assert_eq!(output, result);
+
+    let conf2 = default_conf();
+    let ret_value2 = compile_and_run(code_false, conf2, input_data.clone());
+    let data = unsafe { weld_value_data(ret_value2) as *const bool };
+    let result = unsafe { (*data).clone() };
+
+    let output = false;
+    assert_eq!(output, result);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn simple_length() {
+    let code = "|x:vec[i32]| len(x)";
+    let conf = default_conf();
+
+    let input_vec = [2, 3, 4, 2, 1];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+
+    let output = 5;
+    assert_eq!(output, result);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn filter_length() {
     let code = "|x:vec[i32]| len(filter(x, |i| i < 4 && i > 1))";
     let conf = default_conf();
 
@@ -1344,6 +1859,91 @@ fn filter_length() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn map_filter_map_chain() {
+    let code = "|x:vec[i32]| map(filter(map(x, |a| a*2), |b| b>5), |c| c+1)";
+    let conf = default_conf();
+
+    let input_vec = [1, 2, 3, 4, 5, 6];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<i32> };
+    let result = unsafe { (*data).clone() };
+    let output = [7, 9, 11, 13];
+    assert_eq!(result.len as usize, output.len());
+    for i in 0..(result.len as isize) {
+        assert_eq!(unsafe { *result.data.offset(i) }, output[i as usize])
+    }
+
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn filter_map_merger_chain() {
+    let code = "|x:vec[i32]| result(for(filter(map(x, |a| a*3), |b| b>4), merger[i64,+], \
+                |s,i,e| merge(s, i64(e))))";
+    let conf = default_conf();
+
+    let input_vec = [1, 2, 3, 4, 5, 6];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i64 };
+    let result = unsafe { (*data).clone() };
+    assert_eq!(result, 6 + 9 + 12 + 15 + 18);
+
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn filter_indexed_consumer() {
+    // The consumer's index counts filtered elements, so these loops are not fused.
+    let code = "|x:vec[i32]| result(for(filter(x, |a| a>2), appender[i64], |b,i,e| merge(b, i)))";
+    let conf = default_conf();
+
+    let input_vec = [1, 5, 2, 7, 3];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<i64> };
+    let result = unsafe { (*data).clone() };
+    let output = [0, 1, 2];
+    assert_eq!(result.len as usize, output.len());
+    for i in 0..(result.len as isize) {
+        assert_eq!(unsafe { *result.data.offset(i) }, output[i as usize])
+    }
+
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn independent_reductions() {
+    // Both reductions scan x and use the index, so they are computed by a single scalar loop.
+    let code = "|x:vec[i32]| let s = result(for(x, merger[i32,+], |b,i,e| merge(b, e * i32(i)))); \
+                let p = result(for(x, merger[i32,*], |b,i,e| merge(b, e + i32(i)))); {s, p}";
+    let conf = default_conf();
+
+    let input_vec = [1, 5, 2, 7, 3];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const Pair<i32, i32> };
+    let result = unsafe { (*data).clone() };
+    assert_eq!(result.ele1, 42);
+    assert_eq!(result.ele2, 1680);
+
+    unsafe { weld_value_free(ret_value) };
+}
+
 fn flat_map_length() {
     let code = "|x:vec[i32]| len(flatten(map(x, |i:i32| x)))";
     let conf = default_conf();
@@ -1455,6 +2055,63 @@ fn map_exp() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn vectorized_unary_ops() {
+    #[allow(dead_code)]
+    struct Args {
+        x: WeldVec<f64>,
+        y: WeldVec<f32>,
+    }
+
+    let xs: Vec<f64> = (0..1003).map(|i| (i as f64 - 501.0) / 125.0).collect();
+    let ys: Vec<f32> = xs.iter().map(|x| *x as f32).collect();
+    let ref input_data = Args {
+        x: WeldVec {
+            data: xs.as_ptr() as *const f64,
+            len: xs.len() as i64,
+        },
+        y: WeldVec {
+            data: ys.as_ptr() as *const f32,
+            len: ys.len() as i64,
+        },
+    };
+
+    // Runs a program with and without the vectorizer.
+    let run = |code: &str| {
+        let scalar_conf = default_conf();
+        let passes = weld::conf::default_passes_except(&["vectorize"]);
+        let key = CString::new("weld.optimization.passes").unwrap().into_raw() as *const c_char;
+        let value = CString::new(passes).unwrap().into_raw() as *const c_char;
+        unsafe { weld_conf_set(scalar_conf, key, value) };
+
+        let mut results = vec![];
+        for conf in vec![many_threads_conf(), scalar_conf] {
+            let ret_value = compile_and_run(code, conf, input_data);
+            let data = unsafe { weld_value_data(ret_value) as *const WeldVec<f64> };
+            let result = unsafe { (*data).clone() };
+            assert_eq!(result.len, xs.len() as i64);
+            results.push((0..(result.len as isize))
+                .map(|i| unsafe { *result.data.offset(i) })
+                .collect::<Vec<_>>());
+            unsafe { weld_value_free(ret_value) };
+        }
+        (results[0].clone(), results[1].clone())
+    };
+
+    // exp, log, sqrt and erf compute the same results in SIMD lanes as on scalars.
+    let ops = vec!["exp(e.$0)",
+                   "log(e.$0 * e.$0 + 0.5)",
+                   "sqrt(e.$0 * e.$0)",
+                   "erf(e.$0)",
+                   "f64(erf(e.$1))"];
+    for op in ops {
+        let code = format!("|x:vec[f64], y:vec[f32]| result(for(zip(x,y), appender[f64], \
+                            |b,i,e| merge(b, {})))",
+                           op);
+        let (vectorized, scalar) = run(&code);
+        assert_eq!(vectorized, scalar, "{}", op);
+    }
+}
+
 fn if_for_loop() {
     let code = "|x:vec[i32], a:i32| if(a > 5, map(x, |e| e+1), map(x, |e| e+2))";
     let conf = default_conf();
@@ -1577,6 +2234,106 @@ fn serial_parlib_test() {
     unsafe { weld_value_free(ret_value) };
 }
 
+fn annotated_grain_size_parallel_loop() {
+    let code = "|x:vec[i32]| result(@(grain_size:16) for(x, appender[i64], |b,i,e| merge(b, i)))";
+    let conf = many_threads_conf();
+
+    let size = 10000;
+    let input_vec: Vec<i32> = vec![0; size as usize];
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: size,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const WeldVec<i64> };
+    let result = unsafe { (*data).clone() };
+
+    assert_eq!(result.len, size);
+    for i in 0..(result.len as isize) {
+        assert_eq!(unsafe { *result.data.offset(i) }, i as i64)
+    }
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn conf_grain_size_parallel_loop() {
+    let code = "|x:vec[i32]| result(for(x, merger[i32,+], |b,i,e| merge(b, e)))";
+    let conf = many_threads_conf();
+    let key = CString::new("weld.threads.grainSize").unwrap().into_raw() as *const c_char;
+    let value = CString::new("64").unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+
+    let size: i32 = 10000;
+    let input_vec: Vec<i32> = vec![1; size as usize];
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: size as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+
+    assert_eq!(result, size as i32);
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn tiled_matrix_vector_product() {
+    #[allow(dead_code)]
+    struct Args {
+        m: WeldVec<f64>,
+        x: WeldVec<f64>,
+        y: WeldVec<f64>,
+    }
+
+    // Computes y + m * x, where m is a row-major matrix with len(y) rows and len(x) columns.
+    let untiled = "|m:vec[f64], x:vec[f64], y:vec[f64]| result(for(y, vecmerger[f64,+](y), \
+                   |b,i,r| for(x, b, |b2,j,e| merge(b2, {i, lookup(m, i * len(x) + j) * e}))))";
+    let tiled = "|m:vec[f64], x:vec[f64], y:vec[f64]| result(@(tile_size:4) for(y, \
+                 vecmerger[f64,+](y), |b,i,r| for(x, b, |b2,j,e| merge(b2, {i, lookup(m, i * \
+                 len(x) + j) * e}))))";
+
+    let rows = 7;
+    let cols = 10;
+    let m: Vec<f64> = (0..rows * cols).map(|i| (i % 13) as f64 * 0.5).collect();
+    let x: Vec<f64> = (0..cols).map(|i| i as f64 + 1.0).collect();
+    let y: Vec<f64> = (0..rows).map(|i| i as f64).collect();
+    let ref input_data = Args {
+        m: WeldVec {
+            data: m.as_ptr() as *const f64,
+            len: m.len() as i64,
+        },
+        x: WeldVec {
+            data: x.as_ptr() as *const f64,
+            len: x.len() as i64,
+        },
+        y: WeldVec {
+            data: y.as_ptr() as *const f64,
+            len: y.len() as i64,
+        },
+    };
+
+    let run = |code: &str| -> Vec<f64> {
+        let ret_value = compile_and_run(code, default_conf(), input_data);
+        let data = unsafe { weld_value_data(ret_value) as *const WeldVec<f64> };
+        let result = unsafe { (*data).clone() };
+        let output = (0..result.len as isize)
+            .map(|i| unsafe { *result.data.offset(i) })
+            .collect::<Vec<_>>();
+        unsafe { weld_value_free(ret_value) };
+        output
+    };
+
+    let expected = run(untiled);
+    let output = run(tiled);
+    assert_eq!(expected.len(), rows);
+    assert_eq!(output, expected);
+    for i in 0..rows {
+        let dot: f64 = (0..cols).map(|j| m[i * cols + j] * x[j]).sum();
+        assert!(approx_equal(output[i], y[i] + dot, 5));
+    }
+}
+
 fn iters_outofbounds_error_test() {
     let code = "|x:vec[i32]| result(for(iter(x,0L,20000L,1L), appender, |b,i,e| merge(b,e+1)))";
     let conf = many_threads_conf();
@@ -1593,6 +2350,440 @@ fn iters_outofbounds_error_test() {
     unsafe { weld_error_free(err_value) };
 }
 
+fn zip_with_mapped_vector() {
+    // Both sides of the zip have the length of x, so the loop runs without a bounds check.
+    let code = "|x:vec[i32]| result(for(zip(x, map(x, |e| e * 2)), merger[i32,+], |b,i,e| \
+                merge(b, e.$0 + e.$1)))";
+    let conf = default_conf();
+
+    let input_vec = [1, 5, 2, 7, 3];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let ret_value = compile_and_run(code, conf, input_data);
+    let data = unsafe { weld_value_data(ret_value) as *const i32 };
+    let result = unsafe { (*data).clone() };
+    assert_eq!(result, 54);
+
+    unsafe { weld_value_free(ret_value) };
+}
+
+fn mismatched_zip_sizes_error_test() {
+    let code = "|| result(for(zip([1,2,3], [1,2]), merger[i32,+], |b,i,e| merge(b, e.$0 + e.$1)))";
+    let conf = default_conf();
+    let ref input_data = 0;
+
+    let err_value = compile_and_run_error(code, conf, input_data);
+    assert_eq!(unsafe { weld_error_code(err_value) },
+               WeldRuntimeErrno::CompileError);
+    unsafe { weld_error_free(err_value) };
+}
+
+/// Copies the elements of a Weld vector.
+unsafe fn vec_elements<T: Clone>(vec: &WeldVec<T>) -> Vec<T> {
+    slice::from_raw_parts(vec.data, vec.len as usize).to_vec()
+}
+
+/// Runs `code` on the same input with the LLVM and C backends, using `read` to copy each result
+/// out of its value, and checks that both give the same result.
+fn check_c_backend<T, R, F>(code: &str, ptr: &T, read: F) -> R
+    where R: PartialEq + Debug,
+          F: Fn(*const c_void) -> R
+{
+    let llvm_value = compile_and_run(code, default_conf(), ptr);
+    let c_value = compile_and_run(code, c_backend_conf(), ptr);
+    let expected = read(unsafe { weld_value_data(llvm_value) });
+    let result = read(unsafe { weld_value_data(c_value) });
+    assert_eq!(result, expected, "backends disagree on {}", code);
+    unsafe {
+        weld_value_free(llvm_value);
+        weld_value_free(c_value);
+    }
+    result
+}
+
+fn c_backend_scalar_programs() {
+    #[allow(dead_code)]
+    struct Args {
+        x: i64,
+        y: f64,
+    }
+    let ref input_data = Args { x: 7, y: 2.5 };
+    let read = |p: *const c_void| unsafe { *(p as *const i64) };
+
+    let codes = ["|x:i64, y:f64| x * 3L - x / 2L",
+                 "|x:i64, y:f64| if(x > 5L && y < 10.0, i64(y * 2.0), -x)",
+                 "|x:i64, y:f64| i64(i32(x) * 7) + i64(i8(x * 100L)) + i64(f32(y) / 2.0f)",
+                 "|x:i64, y:f64| let v = [x, x + 1L, x + 2L]; lookup(v, 1L) + len(v)",
+                 "|x:i64, y:f64| i64(sqrt(y) * 1000.0) + i64(exp(y) + log(y) + erf(y))",
+                 "|x:i64, y:f64| i64((x ^ 3L) | (x & 12L)) + i64(y != y) + i64([1,2] < [1,3])"];
+    assert_eq!(check_c_backend(codes[0], input_data, &read), 18);
+    for code in codes[1..].iter() {
+        check_c_backend(code, input_data, &read);
+    }
+}
+
+fn c_backend_loops() {
+    let input_vec: Vec<i32> = (0..1003).map(|i| (i * 7919) % 1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+    let read_vec = |p: *const c_void| unsafe { vec_elements(&*(p as *const WeldVec<i32>)) };
+    let read_i64 = |p: *const c_void| unsafe { *(p as *const i64) };
+
+    // Vectorized, strided and filtered loops over appenders.
+    let result = check_c_backend("|x:vec[i32]| map(x, |e| e * 2 + 1)", input_data, &read_vec);
+    assert_eq!(result.len(), input_vec.len());
+    assert_eq!(result[1], input_vec[1] * 2 + 1);
+    check_c_backend("|x:vec[i32]| result(for(iter(x, 1L, 1001L, 3L), appender[i32], |b,i,e| \
+                     merge(b, e)))",
+                    input_data,
+                    &read_vec);
+    check_c_backend("|x:vec[i32]| filter(x, |e| e > 500)", input_data, &read_vec);
+    check_c_backend("|x:vec[i32]| result(for(x, vecmerger[i32,+](x), |b,i,e| merge(b, {i / 2L, e})))",
+                    input_data,
+                    &read_vec);
+
+    // Mergers, including a nested loop.
+    let sum = check_c_backend("|x:vec[i32]| i64(result(for(x, merger[i32,+], |b,i,e| merge(b, e))))",
+                              input_data,
+                              &read_i64);
+    assert_eq!(sum, input_vec.iter().map(|&e| e as i64).sum::<i64>());
+    check_c_backend("|x:vec[i32]| result(for(x, merger[i64,+], |b,i,e| for(x, b, |b2,j,f| \
+                     merge(b2, i64(e * f) + j))))",
+                    input_data,
+                    &read_i64);
+
+    // Struct results.
+    let read_pair = |p: *const c_void| unsafe {
+        let pair = &*(p as *const Pair<i32, i64>);
+        (pair.ele1, pair.ele2)
+    };
+    check_c_backend("|x:vec[i32]| {result(for(x, merger[i32,*], |b,i,e| merge(b, e / 400 + 1))), \
+                     len(x)}",
+                    input_data,
+                    &read_pair);
+}
+
+fn c_backend_dictionaries() {
+    let input_vec: Vec<i32> = (0..1003).map(|i| (i * 7919) % 1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    // Both backends hash keys the same way, so they return the entries in the same order.
+    let read_entries = |p: *const c_void| unsafe {
+        let entries = vec_elements(&*(p as *const WeldVec<Pair<i32, i64>>));
+        entries.iter().map(|e| (e.ele1, e.ele2)).collect::<Vec<_>>()
+    };
+    let entries = check_c_backend("|x:vec[i32]| tovec(result(for(x, dictmerger[i32,i64,+], \
+                                   |b,i,e| merge(b, {e / 10, i64(e)}))))",
+                                  input_data,
+                                  &read_entries);
+    assert_eq!(entries.len(), 100);
+
+    let read_groups = |p: *const c_void| unsafe {
+        let groups = vec_elements(&*(p as *const WeldVec<Pair<i32, WeldVec<i32>>>));
+        groups.iter().map(|g| (g.ele1, vec_elements(&g.ele2))).collect::<Vec<_>>()
+    };
+    check_c_backend("|x:vec[i32]| tovec(result(for(x, groupmerger[i32,i32], |b,i,e| \
+                     merge(b, {e / 100, e}))))",
+                    input_data,
+                    &read_groups);
+
+    let read_i32 = |p: *const c_void| unsafe { *(p as *const i32) };
+    check_c_backend("|x:vec[i32]| let d = result(for(x, dictmerger[i32,i32,+], |b,i,e| \
+                     merge(b, {e / 10, 1}))); lookup(d, 5) + (if(keyexists(d, 1000), 1, 0))",
+                    input_data,
+                    &read_i32);
+}
+
+fn c_backend_iters_outofbounds_error_test() {
+    let code = "|x:vec[i32]| result(for(iter(x,0L,20000L,1L), appender, |b,i,e| merge(b,e+1)))";
+    let conf = c_backend_conf();
+
+    let input_vec = [4; 1000 as usize];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let err_value = compile_and_run_error(code, conf, input_data);
+    assert_eq!(unsafe { weld_error_code(err_value) },
+               WeldRuntimeErrno::BadIteratorLength);
+    unsafe { weld_error_free(err_value) };
+}
+
+/// Runs a program with the interpreter and compiled, and checks that they return the same result.
+fn check_interpreter<T, R, F>(code: &str, ptr: &T, read: F) -> R
+    where R: PartialEq + Debug,
+          F: Fn(*const c_void) -> R
+{
+    let program = parse_program(code).unwrap();
+    let conf = ParsedConf::default();
+    let memory = unsafe {
+        interpret_program(&program, &conf, ptr as *const T as *const c_void).unwrap()
+    };
+    let compiled_value = compile_and_run(code, default_conf(), ptr);
+    let expected = read(unsafe { weld_value_data(compiled_value) });
+    let result = read(memory.data());
+    assert_eq!(result, expected, "interpreter disagrees with compiled code on {}", code);
+    unsafe { weld_value_free(compiled_value) };
+    result
+}
+
+fn interpreter_scalar_programs() {
+    #[allow(dead_code)]
+    struct Args {
+        x: i64,
+        y: f64,
+    }
+    let ref input_data = Args { x: 7, y: 2.5 };
+    let read = |p: *const c_void| unsafe { *(p as *const i64) };
+
+    let codes = ["|x:i64, y:f64| x * 3L - x / 2L",
+                 "|x:i64, y:f64| if(x > 5L && y < 10.0, i64(y * 2.0), -x)",
+                 "|x:i64, y:f64| i64(i32(x) * 7) + i64(i8(x * 100L)) + i64(f32(y) / 2.0f)",
+                 "|x:i64, y:f64| let v = [x, x + 1L, x + 2L]; lookup(v, 1L) + len(v)",
+                 "|x:i64, y:f64| i64(sqrt(y) * 1000.0) + i64(exp(y) + log(y) + erf(y))",
+                 "|x:i64, y:f64| i64((x ^ 3L) | (x & 12L)) + i64(y != y) + i64([1,2] < [1,3])",
+                 "|x:i64, y:f64| i64(bool(x)) + i64(bool(x + 1L)) + i64(i8(x * 40L))"];
+    assert_eq!(check_interpreter(codes[0], input_data, &read), 18);
+    for code in codes[1..].iter() {
+        check_interpreter(code, input_data, &read);
+    }
+}
+
+fn interpreter_loops() {
+    let input_vec: Vec<i32> = (0..1003).map(|i| (i * 7919) % 1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+    let read_vec = |p: *const c_void| unsafe { vec_elements(&*(p as *const WeldVec<i32>)) };
+    let read_i64 = |p: *const c_void| unsafe { *(p as *const i64) };
+
+    let result = check_interpreter("|x:vec[i32]| map(x, |e| e * 2 + 1)", input_data, &read_vec);
+    assert_eq!(result[1], input_vec[1] * 2 + 1);
+    check_interpreter("|x:vec[i32]| result(for(iter(x, 1L, 1001L, 3L), appender[i32], |b,i,e| \
+                       merge(b, e)))",
+                      input_data,
+                      &read_vec);
+    check_interpreter("|x:vec[i32]| filter(x, |e| e > 500)", input_data, &read_vec);
+    check_interpreter("|x:vec[i32]| result(for(x, vecmerger[i32,+](x), |b,i,e| \
+                       merge(b, {i / 2L, e})))",
+                      input_data,
+                      &read_vec);
+    check_interpreter("|x:vec[i32]| result(for(zip(x, x), appender[i32], |b,i,e| \
+                       merge(b, e.$0 - e.$1 / 2)))",
+                      input_data,
+                      &read_vec);
+    check_interpreter("|x:vec[i32]| let b1 = for(simditer(x), merger[i32,+], |b,i,e:simd[i32]| \
+                       merge(b, e + broadcast(1))); \
+                       i64(result(for(fringeiter(x), b1, |b,i,e| merge(b, e + 1))))",
+                      input_data,
+                      &read_i64);
+    check_interpreter("|x:vec[i32]| result(for(x, merger[i64,+], |b,i,e| for(x, b, |b2,j,f| \
+                       merge(b2, i64(e * f) + j))))",
+                      input_data,
+                      &read_i64);
+
+    let read_pair = |p: *const c_void| unsafe {
+        let pair = &*(p as *const Pair<i32, i64>);
+        (pair.ele1, pair.ele2)
+    };
+    check_interpreter("|x:vec[i32]| {result(for(x, merger[i32,*], |b,i,e| merge(b, e / 400 + 1))), \
+                       len(x)}",
+                      input_data,
+                      &read_pair);
+}
+
+fn interpreter_dictionaries() {
+    let input_vec: Vec<i32> = (0..1003).map(|i| (i * 7919) % 1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    // The interpreter lays out dictionaries like the runtime, so entries come out in the same order.
+    let read_entries = |p: *const c_void| unsafe {
+        let entries = vec_elements(&*(p as *const WeldVec<Pair<i32, i64>>));
+        entries.iter().map(|e| (e.ele1, e.ele2)).collect::<Vec<_>>()
+    };
+    let entries = check_interpreter("|x:vec[i32]| tovec(result(for(x, dictmerger[i32,i64,+], \
+                                     |b,i,e| merge(b, {e / 10, i64(e)}))))",
+                                    input_data,
+                                    &read_entries);
+    assert_eq!(entries.len(), 100);
+
+    // The runtime may not keep the values of a group in the order they were merged.
+    let read_groups = |p: *const c_void| unsafe {
+        let groups = vec_elements(&*(p as *const WeldVec<Pair<i32, WeldVec<i32>>>));
+        groups.iter()
+            .map(|g| {
+                let mut values = vec_elements(&g.ele2);
+                values.sort();
+                (g.ele1, values)
+            })
+            .collect::<Vec<_>>()
+    };
+    check_interpreter("|x:vec[i32]| tovec(result(for(x, groupmerger[i32,i32], |b,i,e| \
+                       merge(b, {e / 100, e}))))",
+                      input_data,
+                      &read_groups);
+
+    let read_i32 = |p: *const c_void| unsafe { *(p as *const i32) };
+    check_interpreter("|x:vec[i32]| let d = result(for(x, dictmerger[i32,i32,+], |b,i,e| \
+                       merge(b, {e / 10, 1}))); lookup(d, 5) + (if(keyexists(d, 1000), 1, 0))",
+                      input_data,
+                      &read_i32);
+}
+
+fn interpreter_iters_outofbounds_error_test() {
+    let code = "|x:vec[i32]| result(for(iter(x,0L,20000L,1L), appender, |b,i,e| merge(b,e+1)))";
+    let input_vec = [4; 1000 as usize];
+    let ref input_data = WeldVec {
+        data: &input_vec as *const i32,
+        len: input_vec.len() as i64,
+    };
+
+    let program = parse_program(code).unwrap();
+    let conf = ParsedConf::default();
+    let result =
+        unsafe { interpret_program(&program, &conf, input_data as *const _ as *const c_void) };
+    assert!(result.is_err());
+}
+
+/// Sets the file a configuration writes compiled programs to.
+fn set_output_file(conf: *mut WeldConf, path: &Path) {
+    let key = CString::new("weld.compile.outputFile").unwrap().into_raw() as *const c_char;
+    let value = CString::new(path.to_str().unwrap()).unwrap().into_raw() as *const c_char;
+    unsafe { weld_conf_set(conf, key, value) };
+}
+
+/// Loads a module from a shared library and runs it on an input data pointer, panicking if either
+/// fails. The returned value must be freed by the caller.
+unsafe fn load_and_run<T>(path: &Path, ptr: &T) -> *mut WeldValue {
+    let path = CString::new(path.to_str().unwrap()).unwrap();
+    let err = weld_error_new();
+    let module = weld_module_load(path.as_ptr(), err);
+    assert_eq!(weld_error_code(err), WeldRuntimeErrno::Success);
+
+    let conf = default_conf();
+    let input_value = weld_value_new(ptr as *const _ as *const c_void);
+    let ret_value = weld_module_run(module, conf, input_value, err);
+    assert_eq!(weld_error_code(err), WeldRuntimeErrno::Success);
+
+    weld_module_free(module);
+    weld_value_free(input_value);
+    weld_conf_free(conf);
+    weld_error_free(err);
+    ret_value
+}
+
+fn output_shared_library() {
+    let code = "|x:vec[i32]| result(for(x, merger[i64,+], |b,i,e| merge(b, i64(e) * i)))";
+    let input_vec: Vec<i32> = (0..1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+    let expected = input_vec.iter().enumerate().map(|(i, &e)| e as i64 * i as i64).sum::<i64>();
+
+    for (i, conf) in vec![default_conf(), c_backend_conf()].into_iter().enumerate() {
+        let path = env::temp_dir().join(format!("weld-output-test-{}-{}.so", process::id(), i));
+        set_output_file(conf, &path);
+        let ret_value = compile_and_run(code, conf, input_data);
+        assert_eq!(unsafe { *(weld_value_data(ret_value) as *const i64) }, expected);
+        unsafe { weld_value_free(ret_value) };
+
+        let ret_value = unsafe { load_and_run(&path, input_data) };
+        assert_eq!(unsafe { *(weld_value_data(ret_value) as *const i64) }, expected);
+        unsafe { weld_value_free(ret_value) };
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+fn output_object_file() {
+    let code = "|x:vec[i32]| map(x, |e| e * 3)";
+    let input_vec: Vec<i32> = (0..1000).collect();
+    let ref input_data = WeldVec {
+        data: input_vec.as_ptr() as *const i32,
+        len: input_vec.len() as i64,
+    };
+    let expected: Vec<i32> = input_vec.iter().map(|e| e * 3).collect();
+
+    let object = env::temp_dir().join(format!("weld-output-test-{}.o", process::id()));
+    let conf = default_conf();
+    set_output_file(conf, &object);
+    let ret_value = compile_and_run(code, conf, input_data);
+    let result = unsafe { vec_elements(&*(weld_value_data(ret_value) as *const WeldVec<i32>)) };
+    assert_eq!(result, expected);
+    unsafe { weld_value_free(ret_value) };
+
+    // Link the object file into a shared library as an application would, leaving its references
+    // to the runtime to be resolved when it is loaded.
+    let library = object.with_extension("so");
+    let mut command = Command::new("cc");
+    command.arg("-shared");
+    if cfg!(target_os = "macos") {
+        command.args(&["-undefined", "dynamic_lookup"]);
+    }
+    let status = command.arg("-o").arg(&library).arg(&object).status().unwrap();
+    assert!(status.success());
+
+    let ret_value = unsafe { load_and_run(&library, input_data) };
+    let result = unsafe { vec_elements(&*(weld_value_data(ret_value) as *const WeldVec<i32>)) };
+    assert_eq!(result, expected);
+    unsafe { weld_value_free(ret_value) };
+    fs::remove_file(&object).unwrap();
+    fs::remove_file(&library).unwrap();
+}
+
+fn output_shared_library_reused_path() {
+    let ref input_data = 0;
+    let path = env::temp_dir().join(format!("weld-output-test-{}-reused.so", process::id()));
+
+    // Keep a module compiled to the path loaded while another program is compiled to it.
+    let code = CString::new("|| 1").unwrap();
+    let conf = default_conf();
+    set_output_file(conf, &path);
+    let err = weld_error_new();
+    let first_module = unsafe { weld_module_compile(code.as_ptr(), conf, err) };
+    assert_eq!(unsafe { weld_error_code(err) }, WeldRuntimeErrno::Success);
+    unsafe {
+        weld_error_free(err);
+        weld_conf_free(conf);
+    }
+
+    for conf in vec![default_conf(), c_backend_conf()] {
+        set_output_file(conf, &path);
+        let ret_value = compile_and_run("|| 2", conf, input_data);
+        assert_eq!(unsafe { *(weld_value_data(ret_value) as *const i32) }, 2);
+        unsafe { weld_value_free(ret_value) };
+
+        let ret_value = unsafe { load_and_run(&path, input_data) };
+        assert_eq!(unsafe { *(weld_value_data(ret_value) as *const i32) }, 2);
+        unsafe { weld_value_free(ret_value) };
+    }
+
+    unsafe { weld_module_free(first_module) };
+    fs::remove_file(&path).unwrap();
+}
+
+fn module_load_error_test() {
+    let path = CString::new("/nonexistent/weld-module.so").unwrap();
+    let err = weld_error_new();
+    let module = unsafe { weld_module_load(path.as_ptr(), err) };
+    assert!(module.is_null());
+    assert_eq!(unsafe { weld_error_code(err) }, WeldRuntimeErrno::CompileError);
+    unsafe { weld_error_free(err) };
+}
+
 fn outofmemory_error_test() {
     let code = "|x:vec[i32]| result(for(x, vecmerger[i32,+](x), |b,i,e| merge(b,{i,e+1})))";
     let conf = default_conf();
@@ -1618,6 +2809,9 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     let tests: Vec<(&str, fn())> =
         vec![("basic_program", basic_program),
+             ("registered_optimization_pass", registered_optimization_pass),
+             ("optimization_pass_stats", optimization_pass_stats),
+             ("nonconverging_pass_error_test", nonconverging_pass_error_test),
              ("float_literals", float_literals),
              ("negation", negation),
              ("negation_double", negation_double),
@@ -1645,12 +2839,21 @@ fn main() {
              ("simple_erf", simple_erf),
              ("simple_sqrt", simple_sqrt),
              ("map_exp", map_exp),
+             ("vectorized_unary_ops", vectorized_unary_ops),
              ("simple_for_appender_loop", simple_for_appender_loop),
              ("simple_parallel_for_appender_loop", simple_parallel_for_appender_loop),
              ("complex_parallel_for_appender_loop", complex_parallel_for_appender_loop),
              ("simple_for_vectorizable_loop", simple_for_vectorizable_loop),
+             ("conf_simd_width_vectorizable_loop", conf_simd_width_vectorizable_loop),
              ("fringed_for_vectorizable_loop", fringed_for_vectorizable_loop),
              ("for_predicated_vectorizable_loop", for_predicated_vectorizable_loop),
+             ("grain_sized_vectorizable_loop", grain_sized_vectorizable_loop),
+             ("strided_vectorizable_loop", strided_vectorizable_loop),
+             ("strided_zip_vectorizable_loop", strided_zip_vectorizable_loop),
+             ("predicated_scalar_merger_loop", predicated_scalar_merger_loop),
+             ("loop_invariant_length_merger_loop", loop_invariant_length_merger_loop),
+             ("loop_invariant_lookup_merger_loop", loop_invariant_lookup_merger_loop),
+             ("dead_struct_fields_loop", dead_struct_fields_loop),
              ("simple_for_merger_loop", simple_for_merger_loop),
              ("simple_zipped_for_merger_loop", simple_zipped_for_merger_loop),
              ("parallel_for_merger_loop", parallel_for_merger_loop),
@@ -1666,16 +2869,41 @@ fn main() {
              ("simple_groupmerger", simple_groupmerger),
              ("complex_groupmerger_with_struct_key", complex_groupmerger_with_struct_key),
              ("simple_parallel_for_dictmerger_loop", simple_parallel_for_dictmerger_loop),
+             ("vectorized_dictmerger_loop", vectorized_dictmerger_loop),
+             ("vectorized_vecmerger_loop", vectorized_vecmerger_loop),
+             ("vectorized_struct_appender_loop", vectorized_struct_appender_loop),
+             ("vectorized_nested_loop", vectorized_nested_loop),
              ("simple_dict_lookup", simple_dict_lookup),
              ("simple_dict_exists", simple_dict_exists),
              ("simple_length", simple_length),
              ("filter_length", filter_length),
+             ("map_filter_map_chain", map_filter_map_chain),
+             ("filter_map_merger_chain", filter_map_merger_chain),
+             ("filter_indexed_consumer", filter_indexed_consumer),
+             ("independent_reductions", independent_reductions),
              ("flat_map_length", flat_map_length),
              ("if_for_loop", if_for_loop),
              ("map_zip_loop", map_zip_loop),
              ("iters_for_loop", iters_for_loop),
              ("serial_parlib_test", serial_parlib_test),
+             ("annotated_grain_size_parallel_loop", annotated_grain_size_parallel_loop),
+             ("conf_grain_size_parallel_loop", conf_grain_size_parallel_loop),
+             ("tiled_matrix_vector_product", tiled_matrix_vector_product),
              ("iters_outofbounds_error_test", iters_outofbounds_error_test),
+             ("zip_with_mapped_vector", zip_with_mapped_vector),
+             ("mismatched_zip_sizes_error_test", mismatched_zip_sizes_error_test),
+             ("c_backend_scalar_programs", c_backend_scalar_programs),
+             ("c_backend_loops", c_backend_loops),
+             ("c_backend_dictionaries", c_backend_dictionaries),
+             ("c_backend_iters_outofbounds_error_test", c_backend_iters_outofbounds_error_test),
+             ("interpreter_scalar_programs", interpreter_scalar_programs),
+             ("interpreter_loops", interpreter_loops),
+             ("interpreter_dictionaries", interpreter_dictionaries),
+             ("interpreter_iters_outofbounds_error_test", interpreter_iters_outofbounds_error_test),
+             ("output_shared_library", output_shared_library),
+             ("output_object_file", output_object_file),
+             ("output_shared_library_reused_path", output_shared_library_reused_path),
+             ("module_load_error_test", module_load_error_test),
              ("outofmemory_error_test", outofmemory_error_test)];
 
 
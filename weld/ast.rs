@@ -100,6 +100,7 @@ pub struct Annotations {
     vectorize: Option<bool>,
     tile_size: Option<i32>,
     grain_size: Option<i32>,
+    bounds_check: Option<bool>,
 
     size: Option<i64>,
     branch_selectivity: Option<i32>, // Fractions of 10,000.
@@ -115,6 +116,7 @@ impl Annotations {
             vectorize: None,
             tile_size: None,
             grain_size: None,
+            bounds_check: None,
             size: None,
             branch_selectivity: None,
             num_keys: None,
@@ -154,6 +156,10 @@ impl Annotations {
         self.tile_size = Some(tile_size)
     }
 
+    pub fn clear_tile_size(&mut self) {
+        self.tile_size = None
+    }
+
     pub fn grain_size(&self) -> &Option<i32> {
         &self.grain_size
     }
@@ -162,6 +168,14 @@ impl Annotations {
         self.grain_size = Some(grain_size)
     }
 
+    pub fn bounds_check(&self) -> &Option<bool> {
+        &self.bounds_check
+    }
+
+    pub fn set_bounds_check(&mut self, bounds_check: bool) {
+        self.bounds_check = Some(bounds_check)
+    }
+
     pub fn size(&self) -> &Option<i64> {
         &self.size
     }
@@ -203,6 +217,9 @@ impl Annotations {
         if let Some(_) = self.grain_size {
             is_empty = false;
         }
+        if let Some(_) = self.bounds_check {
+            is_empty = false;
+        }
         if let Some(_) = self.size {
             is_empty = false;
         }
@@ -234,6 +251,9 @@ impl fmt::Display for Annotations {
         if let Some(ref e) = self.grain_size {
             annotations.push(format!("grain_size:{}", e));
         }
+        if let Some(ref e) = self.bounds_check {
+            annotations.push(format!("bounds_check:{}", e));
+        }
         if let Some(ref e) = self.size {
             annotations.push(format!("size:{}", e));
         }
@@ -704,7 +724,10 @@ impl<T: TypeBounds> Expr<T> {
                                                                                     kind2 => {
                     Ok(true)
                 }
-                (&UnaryOp { .. }, &UnaryOp { .. }) => Ok(true),
+                (&UnaryOp { kind: ref kind1, .. }, &UnaryOp { kind: ref kind2, .. }) if kind1 ==
+                                                                                        kind2 => {
+                    Ok(true)
+                }
                 (&Cast { kind: ref kind1, .. }, &Cast { kind: ref kind2, .. }) if kind1 ==
                                                                                   kind2 => Ok(true),
                 (&ToVec { .. }, &ToVec { .. }) => Ok(true),
@@ -845,6 +868,13 @@ impl<T: TypeBounds> Expr<T> {
         }
     }
 
+    /// Returns the number of nodes in this expression, including itself.
+    pub fn num_nodes(&self) -> usize {
+        let mut count = 0;
+        self.traverse(&mut |_| count += 1);
+        count
+    }
+
     /// Recursively transforms an expression in place by running a function on it and optionally replacing it with another expression.
     pub fn transform_and_continue<F>(&mut self, func: &mut F)
         where F: FnMut(&mut Expr<T>) -> (Option<Expr<T>>, bool)
@@ -16,6 +16,7 @@ use std::fmt;
 use std::collections::HashMap;
 
 use weld::*;
+use weld::ast::ExprKind;
 use weld::parser::*;
 
 enum ReplCommands {
@@ -132,13 +133,26 @@ fn main() {
             continue;
         }
 
-        let result = llvm::compile_program(
-            &program.unwrap(),
-            &conf::DEFAULT_OPTIMIZATION_PASSES,
-            conf::LogLevel::Debug);
+        let mut conf = conf::ParsedConf::default();
+        conf.log_level = conf::LogLevel::Debug;
+        let program = program.unwrap();
+        let result = llvm::compile_program(&program, &conf);
         match result {
             Err(e) => println!("Error during compilation:\n{}\n", e),
-            Ok(_) => println!("Program compiled successfully to LLVM")
+            Ok(_) => {
+                println!("Program compiled successfully to LLVM");
+                // Programs with parameters have no input to evaluate them on.
+                let has_params = match program.body.kind {
+                    ExprKind::Lambda { ref params, .. } => !params.is_empty(),
+                    _ => false,
+                };
+                if !has_params {
+                    match interpreter::evaluate_program(&program, &conf) {
+                        Err(e) => println!("Error during evaluation:\n{}\n", e),
+                        Ok(value) => println!("Result: {}\n", value),
+                    }
+                }
+            }
         }
     }
     rl.save_history(&history_file_path).unwrap();
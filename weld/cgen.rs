@@ -0,0 +1,1617 @@
+//! Generates C code from a SIR program.
+//!
+//! This is a portable alternative to the LLVM backend. The generated code exports the same `run`
+//! function as an LLVM module and uses the same Weld runtime functions and memory layout for
+//! values, so either backend can run a program; the C code is compiled with the system C compiler
+//! into a shared library that is loaded into the process. Loops run sequentially on the calling
+//! worker, and SIMD values are stored as arrays of lanes that statements process one at a time.
+//!
+//! The generated code mirrors the LLVM templates in `resources`, including their hash functions
+//! and dictionary layout, so both backends give the same results, down to the order of the
+//! entries returned for a dictionary.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use easy_ll;
+
+use weld_common::WeldRuntimeErrno;
+
+use super::ast::*;
+use super::ast::Type::*;
+use super::ast::LiteralKind::*;
+use super::ast::ScalarKind::*;
+use super::ast::BuilderKind::*;
+use super::code_builder::CodeBuilder;
+use super::conf::LogLevel;
+use super::conf::ParsedConf;
+use super::error::*;
+use super::exprs::merger_identity_expr;
+use super::llvm::{contains_simd, dictionary_capacity, get_combined_params, get_sym_ty,
+                  lane_type, loop_simd_lanes, default_simd_lanes};
+use super::pretty_print::*;
+use super::shared_library::{c_compiler, compile_and_load, run_compiler, temp_path, SharedLibrary};
+use super::sir::*;
+use super::sir::Statement::*;
+use super::sir::Terminator::*;
+use super::util::IdGenerator;
+
+static PRELUDE_CODE: &'static str = include_str!("resources/prelude.c");
+
+/// Generates C code for a SIR program, compiles it and loads the resulting shared library.
+pub fn compile_sir_program(sir: &SirProgram, conf: &ParsedConf) -> WeldResult<SharedLibrary> {
+    let code = try!(generate_code(sir, conf));
+    compile_module(&code, conf.log_level >= LogLevel::Debug)
+}
+
+/// Generates C code for a SIR program, with its entry point in a function called `run`.
+pub fn generate_code(sir: &SirProgram, conf: &ParsedConf) -> WeldResult<String> {
+    let mut gen = CGenerator::new();
+    if let Some(width) = conf.simd_width {
+        gen.simd_width = width;
+    }
+    try!(gen.add_function_on_pointers("run", sir));
+    let code = gen.result();
+    if conf.log_level >= LogLevel::Debug {
+        println!("C program:\n{}\n", &code);
+    }
+    Ok(code)
+}
+
+/// Compiles C code into a shared library and loads it.
+///
+/// The source, object file and library are written to the temporary directory and deleted once
+/// the library is loaded, unless `keep_files` is set, in which case the code is compiled with
+/// debugging information and the files are kept for use with a debugger.
+pub fn compile_module(code: &str, keep_files: bool) -> WeldResult<SharedLibrary> {
+    let object = temp_path("o");
+    let library = temp_path("so");
+    let result = compile_and_load(&object,
+                                  &library,
+                                  |object| compile_to_object(code, object, keep_files));
+    if !keep_files {
+        let _ = fs::remove_file(&object);
+        let _ = fs::remove_file(&library);
+    }
+    result
+}
+
+/// Compiles C code into a position-independent object file at `object`, with debugging
+/// information if `keep_source` is set. The source is written to the temporary directory, and is
+/// deleted afterwards unless `keep_source` is set.
+pub fn compile_to_object(code: &str, object: &Path, keep_source: bool) -> WeldResult<()> {
+    let source = temp_path("c");
+    if let Err(e) = File::create(&source).and_then(|mut f| f.write_all(code.as_bytes())) {
+        return weld_err!("Could not write C source {}: {}", source.display(), e);
+    }
+
+    let mut command = c_compiler();
+    command.args(&["-c", "-fPIC", "-fwrapv", "-O2"]);
+    if keep_source {
+        command.arg("-g");
+    }
+    command.arg("-o").arg(object).arg(&source);
+    let result = run_compiler(command, &source);
+
+    if keep_source {
+        println!("C module source: {}", source.display());
+    } else {
+        let _ = fs::remove_file(&source);
+    }
+    result
+}
+
+/// Generates C code for one or more SIR functions.
+pub struct CGenerator {
+    /// Track a unique name of the form s0, s1, etc for each struct generated. Structs of SIMD
+    /// values are keyed by their number of lanes as well, and other structs by 0.
+    struct_names: HashMap<(Vec<Type>, u32), String>,
+    struct_ids: IdGenerator,
+
+    /// Track a unique name of the form v0, v1, etc for each vec generated.
+    vec_names: HashMap<Type, String>,
+    vec_ids: IdGenerator,
+
+    /// Track a unique name of the form d0, d1, etc for each dict generated.
+    dict_names: HashMap<Type, String>,
+    dict_ids: IdGenerator,
+
+    /// Track a unique name of the form b0, b1, etc for each builder generated.
+    bld_names: HashMap<BuilderKind, String>,
+    bld_ids: IdGenerator,
+
+    /// Track the names of the SIMD types generated, which are named after their element type and
+    /// number of lanes.
+    simd_names: HashMap<(ScalarKind, u32), String>,
+
+    /// UDFs that have been declared.
+    udf_names: HashSet<String>,
+
+    /// A CodeBuilder for type definitions and their helper functions.
+    prelude_code: CodeBuilder,
+
+    /// A CodeBuilder for the prototypes of generated functions, so they can be called before they
+    /// are defined.
+    prototype_code: CodeBuilder,
+
+    /// A CodeBuilder for the body of the generated functions.
+    body_code: CodeBuilder,
+
+    /// Functions we have already generated.
+    visited: HashSet<FunctionId>,
+
+    /// Width in bits of the SIMD registers to target.
+    pub simd_width: u32,
+
+    /// Number of lanes in the SIMD values of the function being generated.
+    simd_lanes: u32,
+
+    /// Number of lanes in the SIMD values of each loop body function.
+    loop_lanes: HashMap<FunctionId, u32>,
+}
+
+impl CGenerator {
+    /// Return a new C generator.
+    pub fn new() -> CGenerator {
+        let mut generator = CGenerator {
+            struct_names: HashMap::new(),
+            struct_ids: IdGenerator::new("s"),
+            vec_names: HashMap::new(),
+            vec_ids: IdGenerator::new("v"),
+            dict_names: HashMap::new(),
+            dict_ids: IdGenerator::new("d"),
+            bld_names: HashMap::new(),
+            bld_ids: IdGenerator::new("b"),
+            simd_names: HashMap::new(),
+            udf_names: HashSet::new(),
+            prelude_code: CodeBuilder::new(),
+            prototype_code: CodeBuilder::new(),
+            body_code: CodeBuilder::new(),
+            visited: HashSet::new(),
+            simd_width: easy_ll::host_vector_bits(),
+            simd_lanes: 0,
+            loop_lanes: HashMap::new(),
+        };
+        generator.prelude_code.add(PRELUDE_CODE);
+        generator.prelude_code.add("\n");
+        generator
+    }
+
+    /// Add a function to the generated program, passing its parameters and return value through
+    /// pointers encoded as i64. This is used for the main entry point function into Weld modules
+    /// to pass them arbitrary structures.
+    pub fn add_function_on_pointers(&mut self, name: &str, sir: &SirProgram) -> WeldResult<()> {
+        self.loop_lanes = loop_simd_lanes(sir, self.simd_width);
+
+        // First add the function on raw values, which we'll call from the pointer version.
+        try!(self.add_function(sir, &sir.funcs[0], None));
+
+        let args_ty = try!(self.c_type(&Struct(sir.top_params.iter()
+            .map(|p| p.ty.clone())
+            .collect())));
+        let params: BTreeMap<&Symbol, &Type> = sir.funcs[0].params.iter().collect();
+        let main_args_ty = try!(self.c_type(&Struct(params.values()
+            .map(|t| (*t).clone())
+            .collect())));
+
+        // The main function runs as the first task of the runtime, which passes it its arguments.
+        let mut code = CodeBuilder::new();
+        code.add("static void f0_par(work_t *work) {");
+        code.add(format!("{ty} *args = *({ty} **)work;", ty = main_args_ty));
+        let args: Vec<String> = (0..params.len()).map(|i| format!("args->f{}", i)).collect();
+        code.add(format!("f0({});", args.join(", ")));
+        code.add("}");
+        code.add("");
+
+        code.add(format!("int64_t {}(int64_t input) {{", name));
+        code.add("input_arg_t *input_args = (input_arg_t *)(intptr_t)input;");
+        code.add(format!("{ty} *args = ({ty} *)(intptr_t)input_args->input;", ty = args_ty));
+        code.add(format!("{ty} *main_args = ({ty} *)malloc(sizeof({ty}));", ty = main_args_ty));
+        code.add("output_arg_t *output = (output_arg_t *)malloc(sizeof(output_arg_t));");
+        code.add("int64_t run_id;");
+        code.add("set_nworkers(input_args->nworkers);");
+        code.add("weld_rt_init(input_args->mem_limit);");
+        for (i, sym) in params.keys().enumerate() {
+            let index = sir.top_params.iter().position(|p| &p.name == *sym).unwrap();
+            code.add(format!("main_args->f{} = args->f{};", i, index));
+        }
+        code.add("/* The runtime frees main_args once the main function has run. */");
+        code.add("execute(f0_par, main_args);");
+        code.add("run_id = get_runid();");
+        code.add("output->output = (int64_t)(intptr_t)get_result();");
+        code.add("output->run_id = run_id;");
+        code.add("output->errno_value = weld_rt_get_errno(run_id);");
+        code.add("return (int64_t)(intptr_t)output;");
+        code.add("}");
+        self.body_code.add(code.result());
+        Ok(())
+    }
+
+    /// Return all the code generated so far.
+    pub fn result(&mut self) -> String {
+        format!("/* PRELUDE: */\n\n{}\n/* FUNCTIONS: */\n\n{}\n{}",
+                self.prelude_code.result(),
+                self.prototype_code.result(),
+                self.body_code.result())
+    }
+
+    /// Add a function to the generated program. `containing_loop` is the loop whose body this
+    /// function is, if any.
+    fn add_function(&mut self,
+                    sir: &SirProgram,
+                    func: &SirFunction,
+                    containing_loop: Option<&ParallelForData>)
+                    -> WeldResult<()> {
+        if !self.visited.insert(func.id) {
+            return Ok(());
+        }
+
+        // As in the LLVM backend, SIMD values have the number of lanes chosen for their loop.
+        let outer_lanes = self.simd_lanes;
+        let lanes = match self.loop_lanes.get(&func.id) {
+            Some(lanes) => *lanes,
+            None => default_simd_lanes(self.simd_width),
+        };
+        self.simd_lanes = lanes;
+
+        let mut params = try!(self.param_list(&func.params, "_in"));
+        if containing_loop.is_some() {
+            params.push("int64_t lower".to_string());
+            params.push("int64_t upper".to_string());
+        }
+        let signature = format!("static void f{}({})", func.id, param_string(params));
+        self.prototype_code.add(format!("{};", signature));
+
+        let mut code = CodeBuilder::new();
+        code.add(format!("{} {{", signature));
+        // Parameters are copied into locals since statements may assign to them.
+        let sorted_params: BTreeMap<&Symbol, &Type> = func.params.iter().collect();
+        for (sym, ty) in sorted_params {
+            let c_ty = try!(self.c_type(ty));
+            code.add(format!("{} {} = {}_in;", c_ty, c_symbol(sym), c_symbol(sym)));
+        }
+        let sorted_locals: BTreeMap<&Symbol, &Type> = func.locals.iter().collect();
+        for (sym, ty) in sorted_locals {
+            let c_ty = try!(self.c_type(ty));
+            code.add(format!("{} {};", c_ty, c_symbol(sym)));
+        }
+
+        if let Some(par_for) = containing_loop {
+            code.add("for (uint64_t cur_idx = lower; cur_idx < (uint64_t)upper; cur_idx++) {");
+            code.add(format!("{} = {}_in;",
+                             c_symbol(&par_for.builder_arg),
+                             c_symbol(&par_for.builder)));
+            try!(self.gen_loop_element(func, par_for, &mut code));
+        }
+
+        try!(self.gen_function_body(sir, func, &mut code));
+        self.simd_lanes = lanes;
+
+        code.add("body_end: ;");
+        if containing_loop.is_some() {
+            code.add("}");
+        }
+        code.add("}");
+        code.add("");
+        self.body_code.add(code.result());
+
+        if let Some(par_for) = containing_loop {
+            try!(self.add_wrapper(sir, func, par_for));
+        }
+        self.simd_lanes = outer_lanes;
+        Ok(())
+    }
+
+    /// Generate code to load the elements at the current index of a loop's iterators into its
+    /// data argument.
+    fn gen_loop_element(&mut self,
+                        func: &SirFunction,
+                        par_for: &ParallelForData,
+                        code: &mut CodeBuilder)
+                        -> WeldResult<()> {
+        let lanes = self.simd_lanes;
+        let first = &par_for.data[0];
+        code.add("{");
+        let position = match first.kind {
+            IterKind::SimdIter => format!("(int64_t)cur_idx * {}", lanes),
+            IterKind::FringeIter => {
+                code.add(format!("uint64_t total = {};", total_iterations(first)));
+                format!("(int64_t)(total / {l} * {l} + cur_idx)", l = lanes)
+            }
+            IterKind::ScalarIter => "(int64_t)cur_idx".to_string(),
+        };
+        code.add(format!("int64_t position = {};", position));
+
+        let data_ty = try!(get_sym_ty(func, &par_for.data_arg)).clone();
+        for (i, iter) in par_for.data.iter().enumerate() {
+            let target = if par_for.data.len() == 1 {
+                c_symbol(&par_for.data_arg)
+            } else {
+                format!("{}.f{}", c_symbol(&par_for.data_arg), i)
+            };
+            let elem_ty = match data_ty {
+                Struct(ref fields) if par_for.data.len() > 1 => &fields[i],
+                _ => &data_ty,
+            };
+            let data = c_symbol(&iter.data);
+            let index = |offset: &str| match (&iter.start, &iter.stride) {
+                (&Some(ref start), &Some(ref stride)) => {
+                    format!("{} + (position{}) * {}", c_symbol(start), offset, c_symbol(stride))
+                }
+                _ => format!("position{}", offset),
+            };
+            match *elem_ty {
+                Simd(_) => {
+                    code.add(self.lane_loop(&format!("{}.e[lane] = {}.data[{}];",
+                                                     target,
+                                                     data,
+                                                     index(" + lane"))))
+                }
+                _ => code.add(format!("{} = {}.data[{}];", target, data, index(""))),
+            }
+        }
+        code.add(format!("{} = (int64_t)cur_idx;", c_symbol(&par_for.idx_arg)));
+        code.add("}");
+        Ok(())
+    }
+
+    /// Add a wrapper function for a loop, which checks the bounds of its iterators and then runs
+    /// all its iterations followed by its continuation.
+    fn add_wrapper(&mut self,
+                   sir: &SirProgram,
+                   func: &SirFunction,
+                   par_for: &ParallelForData)
+                   -> WeldResult<()> {
+        let params = get_combined_params(sir, par_for);
+        let signature = format!("static void f{}_wrapper({})",
+                                func.id,
+                                param_string(try!(self.param_list(&params, ""))));
+        self.prototype_code.add(format!("{};", signature));
+
+        let lanes = self.simd_lanes;
+        let first = &par_for.data[0];
+        let mut code = CodeBuilder::new();
+        code.add(format!("{} {{", signature));
+        code.add(format!("uint64_t total = {};", total_iterations(first)));
+        let num_iters = match first.kind {
+            IterKind::ScalarIter => "total".to_string(),
+            IterKind::SimdIter => format!("total / {}", lanes),
+            IterKind::FringeIter => format!("total - total / {l} * {l}", l = lanes),
+        };
+        code.add(format!("uint64_t num_iters = {};", num_iters));
+        code.add(format!("uint64_t fringe_start = total / {l} * {l};", l = lanes));
+
+        // As in the LLVM backend, a SimdIter loop is checked over all the iterations of its
+        // iterator.
+        let count = match first.kind {
+            IterKind::SimdIter => "total",
+            _ => "num_iters",
+        };
+        if par_for.bounds_check {
+            for iter in par_for.data.iter() {
+                let start = match (&iter.start, iter.kind == IterKind::FringeIter) {
+                    (&Some(ref start), true) => {
+                        format!("(uint64_t){} + fringe_start * (uint64_t){}",
+                                c_symbol(start),
+                                c_symbol(iter.stride.as_ref().unwrap()))
+                    }
+                    (&Some(ref start), false) => format!("(uint64_t){}", c_symbol(start)),
+                    (&None, true) => "fringe_start".to_string(),
+                    (&None, false) => "0".to_string(),
+                };
+                let stride = match iter.stride {
+                    Some(ref stride) => format!("(uint64_t){}", c_symbol(stride)),
+                    None => "1".to_string(),
+                };
+                code.add(format!("if (!({} * ({} - 1) + {} < (uint64_t){}.size)) goto \
+                                  bounds_check_failed;",
+                                 stride,
+                                 count,
+                                 start,
+                                 c_symbol(&iter.data)));
+            }
+        }
+
+        let mut body_args = sorted_args(&sir.funcs[par_for.body].params);
+        body_args.push("0".to_string());
+        body_args.push("(int64_t)num_iters".to_string());
+        code.add(format!("f{}({});", par_for.body, body_args.join(", ")));
+        code.add(format!("f{}({});",
+                         par_for.cont,
+                         sorted_args(&sir.funcs[par_for.cont].params).join(", ")));
+        code.add("return;");
+        if par_for.bounds_check {
+            code.add("bounds_check_failed:");
+            code.add(format!("weld_rt_set_errno(get_runid(), {});",
+                             WeldRuntimeErrno::BadIteratorLength as i64));
+            code.add("weld_abort_thread();");
+        }
+        code.add("}");
+        code.add("");
+        self.body_code.add(code.result());
+        Ok(())
+    }
+
+    /// Add the basic blocks of a function, each as a label followed by its statements.
+    fn gen_function_body(&mut self,
+                         sir: &SirProgram,
+                         func: &SirFunction,
+                         code: &mut CodeBuilder)
+                         -> WeldResult<()> {
+        for block in func.blocks.iter() {
+            code.add(format!("b{}:", block.id));
+            for statement in block.statements.iter() {
+                try!(self.gen_statement(func, statement, code));
+            }
+            try!(self.gen_terminator(sir, func, &block.terminator, code));
+        }
+        Ok(())
+    }
+
+    /// Generate code for a block's terminator.
+    fn gen_terminator(&mut self,
+                      sir: &SirProgram,
+                      func: &SirFunction,
+                      terminator: &Terminator,
+                      code: &mut CodeBuilder)
+                      -> WeldResult<()> {
+        match *terminator {
+            Branch { ref cond, on_true, on_false } => {
+                code.add(format!("if ({}) goto b{}; else goto b{};",
+                                 c_symbol(cond),
+                                 on_true,
+                                 on_false));
+            }
+
+            JumpBlock(block) => {
+                code.add(format!("goto b{};", block));
+            }
+
+            JumpFunction(target) => {
+                try!(self.add_function(sir, &sir.funcs[target], None));
+                code.add(format!("f{}({});",
+                                 target,
+                                 sorted_args(&sir.funcs[target].params).join(", ")));
+                code.add("goto body_end;");
+            }
+
+            ParallelFor(ref pf) => {
+                try!(self.add_function(sir, &sir.funcs[pf.cont], None));
+                try!(self.add_function(sir, &sir.funcs[pf.body], Some(pf)));
+                code.add(format!("f{}_wrapper({});",
+                                 pf.body,
+                                 sorted_args(&get_combined_params(sir, pf)).join(", ")));
+                code.add("goto body_end;");
+            }
+
+            ProgramReturn(ref sym) => {
+                let ty = try!(self.c_type(try!(get_sym_ty(func, sym))));
+                code.add("{");
+                code.add(format!("{ty} *result = ({ty} *)weld_rt_malloc(get_runid(), \
+                                  sizeof({ty}));",
+                                 ty = ty));
+                code.add(format!("*result = {};", c_symbol(sym)));
+                code.add("set_result(result);");
+                code.add("}");
+                code.add("goto body_end;");
+            }
+
+            EndFunction => {
+                code.add("goto body_end;");
+            }
+
+            Crash => {
+                code.add(format!("weld_rt_set_errno(get_runid(), {});",
+                                 WeldRuntimeErrno::Unknown as i64));
+                code.add("weld_abort_thread();");
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate code for a single statement.
+    fn gen_statement(&mut self,
+                     func: &SirFunction,
+                     statement: &Statement,
+                     code: &mut CodeBuilder)
+                     -> WeldResult<()> {
+        match *statement {
+            MakeStruct { ref output, ref elems } => {
+                for (i, &(ref elem, _)) in elems.iter().enumerate() {
+                    code.add(format!("{}.f{} = {};", c_symbol(output), i, c_symbol(elem)));
+                }
+            }
+
+            MakeVector { ref output, ref elems, ref elem_ty } => {
+                let vec_ty = try!(self.c_type(&Vector(Box::new(elem_ty.clone()))));
+                code.add(format!("{} = {}_new({});", c_symbol(output), vec_ty, elems.len()));
+                for (i, elem) in elems.iter().enumerate() {
+                    code.add(format!("{}.data[{}] = {};", c_symbol(output), i, c_symbol(elem)));
+                }
+            }
+
+            CUDF { ref output, ref symbol_name, ref args } => {
+                if !self.udf_names.contains(symbol_name) {
+                    let mut arg_tys = vec![];
+                    for arg in args.iter() {
+                        arg_tys.push(format!("{} *", try!(self.c_type(try!(get_sym_ty(func, arg))))));
+                    }
+                    arg_tys.push(format!("{} *", try!(self.c_type(try!(get_sym_ty(func, output))))));
+                    self.prelude_code.add(format!("extern void {}({});",
+                                                  symbol_name,
+                                                  arg_tys.join(", ")));
+                    self.udf_names.insert(symbol_name.clone());
+                }
+                let mut arg_ptrs: Vec<String> = args.iter()
+                    .map(|a| format!("&{}", c_symbol(a)))
+                    .collect();
+                arg_ptrs.push(format!("&{}", c_symbol(output)));
+                code.add(format!("{}({});", symbol_name, arg_ptrs.join(", ")));
+            }
+
+            BinOp { ref output, op, ref ty, ref left, ref right } => {
+                let (out, l, r) = (c_symbol(output), c_symbol(left), c_symbol(right));
+                match *ty {
+                    Scalar(kind) => {
+                        code.add(format!("{} = {};", out, try!(c_binop(op, kind, &l, &r))));
+                    }
+                    Simd(kind) => {
+                        let expr = try!(c_binop(op,
+                                                kind,
+                                                &format!("{}.e[lane]", l),
+                                                &format!("{}.e[lane]", r)));
+                        code.add(self.lane_loop(&format!("{}.e[lane] = {};", out, expr)));
+                    }
+                    Vector(_) => {
+                        let (c_op, value) = try!(c_vector_comparison(op, ty));
+                        let vec_ty = try!(self.c_type(ty));
+                        code.add(format!("{} = (uint8_t)({}_cmp({}, {}) {} {});",
+                                         out,
+                                         vec_ty,
+                                         l,
+                                         r,
+                                         c_op,
+                                         value));
+                    }
+                    _ => return weld_err!("Illegal type {} in BinOp", print_type(ty)),
+                }
+            }
+
+            UnaryOp { ref output, op, ref child } => {
+                let child_ty = try!(get_sym_ty(func, child));
+                let (out, c) = (c_symbol(output), c_symbol(child));
+                match *child_ty {
+                    Scalar(kind) => {
+                        code.add(format!("{} = {}({});", out, try!(c_unaryop(op, kind)), c));
+                    }
+                    Simd(kind) => {
+                        code.add(self.lane_loop(&format!("{}.e[lane] = {}({}.e[lane]);",
+                                                         out,
+                                                         try!(c_unaryop(op, kind)),
+                                                         c)));
+                    }
+                    _ => return weld_err!("Illegal type {} in {}", print_type(child_ty), op),
+                }
+            }
+
+            Negate { ref output, ref child } => {
+                let out_ty = try!(get_sym_ty(func, output));
+                let (out, c) = (c_symbol(output), c_symbol(child));
+                match *out_ty {
+                    Scalar(kind) => {
+                        code.add(format!("{} = {};",
+                                         out,
+                                         try!(c_binop(BinOpKind::Subtract, kind, "0", &c))));
+                    }
+                    Simd(kind) => {
+                        let expr = try!(c_binop(BinOpKind::Subtract,
+                                                kind,
+                                                "0",
+                                                &format!("{}.e[lane]", c)));
+                        code.add(self.lane_loop(&format!("{}.e[lane] = {};", out, expr)));
+                    }
+                    _ => return weld_err!("Illegal type {} in Negate", print_type(out_ty)),
+                }
+            }
+
+            Broadcast { ref output, ref child } => {
+                code.add(self.lane_loop(&format!("{}.e[lane] = {};",
+                                                 c_symbol(output),
+                                                 c_symbol(child))));
+            }
+
+            Cast { ref output, ref new_ty, ref child } => {
+                let old_ty = try!(get_sym_ty(func, child));
+                let (out, c) = (c_symbol(output), c_symbol(child));
+                if old_ty == new_ty {
+                    code.add(format!("{} = {};", out, c));
+                } else {
+                    match (old_ty, new_ty) {
+                        (&Scalar(from), &Scalar(to)) => {
+                            code.add(format!("{} = {};", out, c_cast(from, to, &c)));
+                        }
+                        (&Simd(from), &Simd(to)) => {
+                            let expr = c_cast(from, to, &format!("{}.e[lane]", c));
+                            code.add(self.lane_loop(&format!("{}.e[lane] = {};", out, expr)));
+                        }
+                        _ => {
+                            return weld_err!("Can't cast {} to {}",
+                                             print_type(old_ty),
+                                             print_type(new_ty))
+                        }
+                    }
+                }
+            }
+
+            Lookup { ref output, ref child, ref index } => {
+                let child_ty = try!(get_sym_ty(func, child));
+                let (out, c, i) = (c_symbol(output), c_symbol(child), c_symbol(index));
+                match *child_ty {
+                    Vector(_) => code.add(format!("{} = {}.data[{}];", out, c, i)),
+                    Dict(_, _) => {
+                        let dict_ty = try!(self.c_type(child_ty));
+                        code.add(format!("{} = {}_lookup({}, {})->value;", out, dict_ty, c, i));
+                    }
+                    _ => return weld_err!("Illegal type {} in Lookup", print_type(child_ty)),
+                }
+            }
+
+            KeyExists { ref output, ref child, ref key } => {
+                let child_ty = try!(get_sym_ty(func, child));
+                match *child_ty {
+                    Dict(_, _) => {
+                        let dict_ty = try!(self.c_type(child_ty));
+                        code.add(format!("{} = {}_lookup({}, {})->filled;",
+                                         c_symbol(output),
+                                         dict_ty,
+                                         c_symbol(child),
+                                         c_symbol(key)));
+                    }
+                    _ => return weld_err!("Illegal type {} in KeyExists", print_type(child_ty)),
+                }
+            }
+
+            Slice { ref output, ref child, ref index, ref size } => {
+                let child_ty = try!(get_sym_ty(func, child));
+                match *child_ty {
+                    Vector(_) => {
+                        let vec_ty = try!(self.c_type(child_ty));
+                        code.add(format!("{} = {}_slice({}, {}, {});",
+                                         c_symbol(output),
+                                         vec_ty,
+                                         c_symbol(child),
+                                         c_symbol(index),
+                                         c_symbol(size)));
+                    }
+                    _ => return weld_err!("Illegal type {} in Slice", print_type(child_ty)),
+                }
+            }
+
+            Select { ref output, ref cond, ref on_true, ref on_false } => {
+                let cond_ty = try!(get_sym_ty(func, cond));
+                let (out, c, t, f) =
+                    (c_symbol(output), c_symbol(cond), c_symbol(on_true), c_symbol(on_false));
+                match *cond_ty {
+                    Simd(_) => {
+                        code.add(self.lane_loop(&format!("{o}.e[lane] = {c}.e[lane] ? \
+                                                          {t}.e[lane] : {f}.e[lane];",
+                                                         o = out,
+                                                         c = c,
+                                                         t = t,
+                                                         f = f)));
+                    }
+                    _ => code.add(format!("{} = {} ? {} : {};", out, c, t, f)),
+                }
+            }
+
+            ToVec { ref output, ref child } => {
+                let dict_ty = try!(self.c_type(try!(get_sym_ty(func, child))));
+                code.add(format!("{} = {}_tovec({});",
+                                 c_symbol(output),
+                                 dict_ty,
+                                 c_symbol(child)));
+            }
+
+            Length { ref output, ref child } => {
+                code.add(format!("{} = {}.size;", c_symbol(output), c_symbol(child)));
+            }
+
+            Assign { ref output, ref value } => {
+                code.add(format!("{} = {};", c_symbol(output), c_symbol(value)));
+            }
+
+            AssignLiteral { ref output, ref value } => {
+                let out_ty = try!(get_sym_ty(func, output));
+                match *out_ty {
+                    Simd(_) => {
+                        code.add(self.lane_loop(&format!("{}.e[lane] = {};",
+                                                         c_symbol(output),
+                                                         c_literal(value))));
+                    }
+                    _ => code.add(format!("{} = {};", c_symbol(output), c_literal(value))),
+                }
+            }
+
+            GetField { ref output, ref value, index } => {
+                code.add(format!("{} = {}.f{};", c_symbol(output), c_symbol(value), index));
+            }
+
+            Merge { ref builder, ref value } => {
+                let bld_ty = try!(get_sym_ty(func, builder));
+                let bld_name = try!(self.c_type(bld_ty));
+                let bld = c_symbol(builder);
+                match *bld_ty {
+                    Builder(ref bk, _) => {
+                        match *bk {
+                            Appender(_) | DictMerger(_, _, _) => {
+                                for v in try!(self.merge_values(func, value)) {
+                                    code.add(format!("{}_merge({}, {});", bld_name, bld, v));
+                                }
+                            }
+                            GroupMerger(_, _) => {
+                                code.add(format!("{}_merge({}, {});",
+                                                 bld_name,
+                                                 bld,
+                                                 c_symbol(value)));
+                            }
+                            Merger(ref t, op) => {
+                                let value_ty = try!(get_sym_ty(func, value));
+                                let current = format!("(*{})", bld);
+                                let new_value = match *value_ty {
+                                    Simd(_) => format!("{}.e[lane]", c_symbol(value)),
+                                    _ => c_symbol(value),
+                                };
+                                let merge = format!("*{} = {};",
+                                                    bld,
+                                                    try!(self.merge_op(op,
+                                                                       t,
+                                                                       &current,
+                                                                       &new_value)));
+                                match *value_ty {
+                                    Simd(_) => code.add(self.lane_loop(&merge)),
+                                    _ => code.add(merge),
+                                }
+                            }
+                            VecMerger(ref t, op) => {
+                                for v in try!(self.merge_values(func, value)) {
+                                    let elem = format!("{}->data[{}.f0]", bld, v);
+                                    let merged = try!(self.merge_op(op,
+                                                                    t,
+                                                                    &elem,
+                                                                    &format!("{}.f1", v)));
+                                    code.add(format!("{} = {};", elem, merged));
+                                }
+                            }
+                        }
+                    }
+                    _ => return weld_err!("Non builder type {} found in Merge", print_type(bld_ty)),
+                }
+            }
+
+            Res { ref output, ref builder } => {
+                let bld_ty = try!(get_sym_ty(func, builder));
+                let bld_name = try!(self.c_type(bld_ty));
+                let (out, bld) = (c_symbol(output), c_symbol(builder));
+                match *bld_ty {
+                    Builder(ref bk, _) => {
+                        match *bk {
+                            Appender(_) | DictMerger(_, _, _) => {
+                                code.add(format!("{} = {}_result({});", out, bld_name, bld));
+                            }
+                            GroupMerger(_, _) => {
+                                code.add(format!("{} = {}_group_result({});",
+                                                 out,
+                                                 bld_name,
+                                                 bld));
+                            }
+                            Merger(ref t, _) => {
+                                match **t {
+                                    Scalar(_) => code.add(format!("{} = *{};", out, bld)),
+                                    _ => return weld_err!("Invalid non-scalar type in merger"),
+                                }
+                            }
+                            VecMerger(_, _) => code.add(format!("{} = *{};", out, bld)),
+                        }
+                    }
+                    _ => return weld_err!("Non builder type {} found in Res", print_type(bld_ty)),
+                }
+            }
+
+            NewBuilder { ref output, ref arg, ref ty } => {
+                let bld_name = try!(self.c_type(ty));
+                let out = c_symbol(output);
+                match *ty {
+                    Builder(ref bk, ref annotations) => {
+                        match *bk {
+                            Appender(_) => {
+                                let capacity = annotations.size().unwrap_or(16);
+                                code.add(format!("{} = {}_new({});", out, bld_name, capacity));
+                            }
+                            GroupMerger(_, _) => {
+                                let capacity = annotations.size().unwrap_or(16);
+                                code.add(format!("{} = {}_new({});", out, bld_name, capacity));
+                            }
+                            DictMerger(_, _, _) => {
+                                let num_keys = annotations.num_keys()
+                                    .or(*annotations.size())
+                                    .unwrap_or(0);
+                                code.add(format!("{} = {}_new({});",
+                                                 out,
+                                                 bld_name,
+                                                 dictionary_capacity(num_keys)));
+                            }
+                            Merger(ref t, op) => {
+                                let identity = try!(c_merge_identity(op, t));
+                                let init = arg.as_ref().map(c_symbol).unwrap_or(identity);
+                                let elem_ty = try!(self.c_type(t));
+                                code.add(format!("{} = ({})weld_rt_malloc(get_runid(), \
+                                                  sizeof({}));",
+                                                 out,
+                                                 bld_name,
+                                                 elem_ty));
+                                code.add(format!("*{} = {};", out, init));
+                            }
+                            VecMerger(_, op) => {
+                                if op != BinOpKind::Add {
+                                    return weld_err!("VecMerger only supports +");
+                                }
+                                match *arg {
+                                    Some(ref s) => {
+                                        code.add(format!("{} = {}_new({});",
+                                                         out,
+                                                         bld_name,
+                                                         c_symbol(s)));
+                                    }
+                                    None => {
+                                        return weld_err!("Internal error: NewBuilder(VecMerger) \
+                                                          expected argument in C codegen")
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    _ => return weld_err!("Non builder type {} found in NewBuilder", print_type(ty)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the values to merge into a builder for a value that may hold SIMD values: one value
+    /// per lane if it does, or just the value itself otherwise.
+    fn merge_values(&mut self, func: &SirFunction, value: &Symbol) -> WeldResult<Vec<String>> {
+        let ty = try!(get_sym_ty(func, value)).clone();
+        if !contains_simd(&ty) {
+            return Ok(vec![c_symbol(value)]);
+        }
+        let mut values = vec![];
+        for lane in 0..self.simd_lanes {
+            values.push(try!(self.lane_value(&ty, &c_symbol(value), lane)));
+        }
+        Ok(values)
+    }
+
+    /// Returns an expression for one lane of `value`, a SIMD value or a struct containing them.
+    fn lane_value(&mut self, ty: &Type, value: &str, lane: u32) -> WeldResult<String> {
+        match *ty {
+            Simd(_) => Ok(format!("{}.e[{}]", value, lane)),
+            Struct(ref fields) => {
+                let lane_ty = try!(self.c_type(&lane_type(ty)));
+                let mut elems = vec![];
+                for (i, field) in fields.iter().enumerate() {
+                    elems.push(try!(self.lane_value(field, &format!("{}.f{}", value, i), lane)));
+                }
+                Ok(format!("(({}){{{}}})", lane_ty, elems.join(", ")))
+            }
+            _ => Ok(value.to_string()),
+        }
+    }
+
+    /// Returns an expression combining two values of type `ty` with a builder's merge operator.
+    fn merge_op(&mut self, op: BinOpKind, ty: &Type, left: &str, right: &str) -> WeldResult<String> {
+        match *ty {
+            Scalar(kind) => c_binop(op, kind, left, right),
+            Struct(ref fields) => {
+                let struct_ty = try!(self.c_type(ty));
+                let mut elems = vec![];
+                for (i, field) in fields.iter().enumerate() {
+                    elems.push(try!(self.merge_op(op,
+                                                  field,
+                                                  &format!("{}.f{}", left, i),
+                                                  &format!("{}.f{}", right, i))));
+                }
+                Ok(format!("(({}){{{}}})", struct_ty, elems.join(", ")))
+            }
+            _ => weld_err!("Unsupported merge type {}", print_type(ty)),
+        }
+    }
+
+    /// Returns a loop running `statement` for each SIMD lane, which it refers to as `lane`.
+    fn lane_loop(&self, statement: &str) -> String {
+        format!("for (int lane = 0; lane < {}; lane++) {}", self.simd_lanes, statement)
+    }
+
+    /// Returns the C declarations of a function's parameters, sorted by name, with `suffix`
+    /// appended to each name.
+    fn param_list(&mut self,
+                  params: &HashMap<Symbol, Type>,
+                  suffix: &str)
+                  -> WeldResult<Vec<String>> {
+        let sorted: BTreeMap<&Symbol, &Type> = params.iter().collect();
+        let mut result = vec![];
+        for (sym, ty) in sorted {
+            result.push(format!("{} {}{}", try!(self.c_type(ty)), c_symbol(sym), suffix));
+        }
+        Ok(result)
+    }
+
+    /// Returns the name of the C type for a Weld type, generating its definition and helper
+    /// functions the first time it is seen.
+    pub fn c_type(&mut self, ty: &Type) -> WeldResult<String> {
+        match *ty {
+            Scalar(kind) => Ok(c_scalar_type(kind).to_string()),
+            Simd(kind) => {
+                let key = (kind, self.simd_lanes);
+                if !self.simd_names.contains_key(&key) {
+                    let name = format!("simd_{}x{}", kind, self.simd_lanes);
+                    let mut code = CodeBuilder::new();
+                    code.add(format!("typedef struct {n} {{ {t} e[{l}]; }} {n};",
+                                     n = name,
+                                     t = c_scalar_type(kind),
+                                     l = self.simd_lanes));
+                    code.add(format!("static int64_t {}_hash({} value) {{ return 0; }}",
+                                     name,
+                                     name));
+                    code.add(format!("static int32_t {}_cmp({n} a, {n} b) {{ return -1; }}",
+                                     name,
+                                     n = name));
+                    self.prelude_code.add(code.result());
+                    self.prelude_code.add("");
+                    self.simd_names.insert(key, name);
+                }
+                Ok(self.simd_names[&key].clone())
+            }
+            Struct(ref fields) => {
+                let key = (fields.clone(), if contains_simd(ty) { self.simd_lanes } else { 0 });
+                if !self.struct_names.contains_key(&key) {
+                    try!(self.gen_struct_definition(fields));
+                }
+                Ok(self.struct_names[&key].clone())
+            }
+            Vector(ref elem) => {
+                if !self.vec_names.contains_key(elem) {
+                    try!(self.gen_vector_definition(elem));
+                }
+                Ok(self.vec_names[elem].clone())
+            }
+            Dict(_, _) => {
+                if !self.dict_names.contains_key(ty) {
+                    try!(self.gen_dict_definition(ty));
+                }
+                Ok(self.dict_names[ty].clone())
+            }
+            Builder(ref bk, _) => {
+                if !self.bld_names.contains_key(bk) {
+                    try!(self.gen_builder_definition(bk));
+                }
+                Ok(self.bld_names[bk].clone())
+            }
+            Function(_, _) => weld_err!("Unsupported type {}", print_type(ty)),
+        }
+    }
+
+    /// Returns the prefix of the hash and comparison functions for a type.
+    fn helper_prefix(&mut self, ty: &Type) -> WeldResult<String> {
+        match *ty {
+            Scalar(kind) => Ok(format!("{}", kind)),
+            _ => self.c_type(ty),
+        }
+    }
+
+    /// Generate a struct type and its hash and comparison functions. SIMD fields are left out of
+    /// both, as they are in the LLVM backend.
+    fn gen_struct_definition(&mut self, fields: &Vec<Type>) -> WeldResult<()> {
+        let mut field_tys = vec![];
+        let mut field_prefixes = vec![];
+        for field in fields.iter() {
+            field_tys.push(try!(self.c_type(field)));
+            field_prefixes.push(try!(self.helper_prefix(field)));
+        }
+        let name = self.struct_ids.next();
+
+        let mut code = CodeBuilder::new();
+        code.add(format!("typedef struct {} {{", name));
+        for (i, field_ty) in field_tys.iter().enumerate() {
+            code.add(format!("{} f{};", field_ty, i));
+        }
+        if fields.is_empty() {
+            code.add("uint8_t unused;");
+        }
+        code.add(format!("}} {};", name));
+        code.add("");
+
+        code.add(format!("static int64_t {n}_hash({n} value) {{", n = name));
+        code.add("int64_t hash = 0;");
+        for (i, field) in fields.iter().enumerate() {
+            if let Simd(_) = *field {
+                continue;
+            }
+            code.add(format!("hash = hash_combine(hash, {}_hash(value.f{}));",
+                             field_prefixes[i],
+                             i));
+        }
+        code.add("return hash;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static int32_t {n}_cmp({n} a, {n} b) {{", n = name));
+        code.add("int32_t cmp;");
+        for (i, field) in fields.iter().enumerate() {
+            if let Simd(_) = *field {
+                continue;
+            }
+            code.add(format!("cmp = {}_cmp(a.f{i}, b.f{i});", field_prefixes[i], i = i));
+            code.add("if (cmp != 0) return cmp;");
+        }
+        code.add("return 0;");
+        code.add("}");
+        code.add("");
+
+        self.prelude_code.add(code.result());
+        let lanes = if fields.iter().any(contains_simd) { self.simd_lanes } else { 0 };
+        self.struct_names.insert((fields.clone(), lanes), name);
+        Ok(())
+    }
+
+    /// Generate a vector type and its helper functions.
+    fn gen_vector_definition(&mut self, elem: &Type) -> WeldResult<()> {
+        let elem_ty = try!(self.c_type(elem));
+        let elem_prefix = try!(self.helper_prefix(elem));
+        let name = self.vec_ids.next();
+
+        let mut code = CodeBuilder::new();
+        code.add(format!("typedef struct {n} {{ {t} *data; int64_t size; }} {n};",
+                         n = name,
+                         t = elem_ty));
+        code.add("");
+
+        code.add(format!("static {n} {n}_new(int64_t size) {{", n = name));
+        code.add(format!("{} vec;", name));
+        code.add(format!("vec.data = ({t} *)weld_rt_malloc(get_runid(), sizeof({t}) * size);",
+                         t = elem_ty));
+        code.add("vec.size = size;");
+        code.add("return vec;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static {n} {n}_clone({n} vec) {{", n = name));
+        code.add(format!("{n} copy = {n}_new(vec.size);", n = name));
+        code.add(format!("memcpy(copy.data, vec.data, sizeof({}) * vec.size);", elem_ty));
+        code.add("return copy;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static {n} {n}_slice({n} vec, int64_t index, int64_t size) {{",
+                         n = name));
+        code.add(format!("{} slice;", name));
+        code.add("uint64_t remaining = (uint64_t)(vec.size - index);");
+        code.add("slice.data = vec.data + index;");
+        code.add("slice.size = (uint64_t)size > remaining ? (int64_t)remaining : size;");
+        code.add("return slice;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static int64_t {n}_hash({n} vec) {{", n = name));
+        code.add("int64_t hash = 0;");
+        code.add("for (int64_t i = 0; i < vec.size; i++) {");
+        code.add(format!("hash = hash_combine(hash, {}_hash(vec.data[i]));", elem_prefix));
+        code.add("}");
+        code.add("return hash;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static int32_t {n}_cmp({n} a, {n} b) {{", n = name));
+        code.add("int64_t min_size = a.size < b.size ? a.size : b.size;");
+        code.add("for (int64_t i = 0; i < min_size; i++) {");
+        code.add(format!("int32_t cmp = {}_cmp(a.data[i], b.data[i]);", elem_prefix));
+        code.add("if (cmp != 0) return cmp;");
+        code.add("}");
+        code.add("return i64_cmp(a.size, b.size);");
+        code.add("}");
+        code.add("");
+
+        self.prelude_code.add(code.result());
+        self.vec_names.insert(elem.clone(), name);
+        Ok(())
+    }
+
+    /// Generate a dictionary type and its helper functions, using the same open addressing scheme
+    /// as `resources/dictionary.ll`.
+    fn gen_dict_definition(&mut self, ty: &Type) -> WeldResult<()> {
+        let (key, value) = match *ty {
+            Dict(ref key, ref value) => (key.as_ref(), value.as_ref()),
+            _ => return weld_err!("Non dictionary type {} in dictionary definition", print_type(ty)),
+        };
+        let key_ty = try!(self.c_type(key));
+        let key_prefix = try!(self.helper_prefix(key));
+        let value_ty = try!(self.c_type(value));
+        let kv_struct = Struct(vec![key.clone(), value.clone()]);
+        let kv_vec_ty = try!(self.c_type(&Vector(Box::new(kv_struct))));
+        let name = self.dict_ids.next();
+
+        let mut code = CodeBuilder::new();
+        code.add(format!("typedef struct {n}_entry {{ uint8_t filled; {k} key; {v} value; }} \
+                          {n}_entry;",
+                         n = name,
+                         k = key_ty,
+                         v = value_ty));
+        code.add(format!("typedef struct {n} {{ {n}_entry *entries; int64_t size; \
+                          int64_t capacity; }} {n};",
+                         n = name));
+        code.add("");
+
+        code.add(format!("static {n} {n}_new(int64_t capacity) {{", n = name));
+        code.add(format!("{} dict;", name));
+        code.add(format!("int64_t alloc_size = sizeof({}_entry) * capacity;", name));
+        code.add(format!("dict.entries = ({}_entry *)weld_rt_malloc(get_runid(), alloc_size);",
+                         name));
+        code.add("memset(dict.entries, 0, alloc_size);");
+        code.add("dict.size = 0;");
+        code.add("dict.capacity = capacity;");
+        code.add("return dict;");
+        code.add("}");
+        code.add("");
+
+        code.add("/* Returns the slot holding a key, or the empty slot where it should be put. */");
+        code.add(format!("static {n}_entry *{n}_lookup({n} dict, {k} key) {{",
+                         n = name,
+                         k = key_ty));
+        code.add(format!("uint64_t pos = (uint64_t){}_hash(key);", key_prefix));
+        code.add("for (;;) {");
+        code.add(format!("{n}_entry *slot = &dict.entries[pos & (uint64_t)(dict.capacity - 1)];",
+                         n = name));
+        code.add(format!("if (!slot->filled || {}_cmp(key, slot->key) == 0) return slot;",
+                         key_prefix));
+        code.add("pos++;");
+        code.add("}");
+        code.add("}");
+        code.add("");
+
+        code.add("/* Puts a key into its slot, growing the dictionary once it is 70% full. */");
+        code.add(format!("static {n} {n}_put({n} dict, {n}_entry *slot, {k} key, {v} value) {{",
+                         n = name,
+                         k = key_ty,
+                         v = value_ty));
+        code.add("uint8_t filled = slot->filled;");
+        code.add("slot->filled = 1;");
+        code.add("slot->key = key;");
+        code.add("slot->value = value;");
+        code.add("if (filled) return dict;");
+        code.add("if (dict.size * 10 >= dict.capacity * 7) {");
+        code.add(format!("{n} new_dict = {n}_new(dict.capacity * 2);", n = name));
+        code.add("for (int64_t i = 0; i < dict.capacity; i++) {");
+        code.add(format!("{}_entry *entry = &dict.entries[i];", name));
+        code.add("if (entry->filled) {");
+        code.add(format!("new_dict = {n}_put(new_dict, {n}_lookup(new_dict, entry->key), \
+                          entry->key, entry->value);",
+                         n = name));
+        code.add("}");
+        code.add("}");
+        code.add("return new_dict;");
+        code.add("}");
+        code.add("dict.size++;");
+        code.add("return dict;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static {kv} {n}_tovec({n} dict) {{", n = name, kv = kv_vec_ty));
+        code.add(format!("{kv} vec = {kv}_new(dict.size);", kv = kv_vec_ty));
+        code.add("int64_t j = 0;");
+        code.add("for (int64_t i = 0; i < dict.capacity; i++) {");
+        code.add("if (dict.entries[i].filled) {");
+        code.add("vec.data[j].f0 = dict.entries[i].key;");
+        code.add("vec.data[j].f1 = dict.entries[i].value;");
+        code.add("j++;");
+        code.add("}");
+        code.add("}");
+        code.add("return vec;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static int64_t {n}_hash({n} dict) {{ return 0; }}", n = name));
+        code.add(format!("static int32_t {n}_cmp({n} a, {n} b) {{ return -1; }}", n = name));
+        code.add("");
+
+        self.prelude_code.add(code.result());
+        self.dict_names.insert(ty.clone(), name);
+        Ok(())
+    }
+
+    /// Generate a builder type and the functions to create, merge into and get the result of it.
+    /// Since loops run sequentially, each builder has a single copy rather than one per worker.
+    fn gen_builder_definition(&mut self, bk: &BuilderKind) -> WeldResult<()> {
+        let mut code = CodeBuilder::new();
+        let name = match *bk {
+            Appender(ref elem) => {
+                let elem_ty = try!(self.c_type(elem));
+                let vec_ty = try!(self.c_type(&Vector(elem.clone())));
+                let name = self.bld_ids.next();
+                try!(self.gen_appender(&mut code, &name, &elem_ty, &vec_ty));
+                name
+            }
+
+            Merger(ref elem, _) => {
+                let elem_ty = try!(self.c_type(elem));
+                let name = self.bld_ids.next();
+                code.add(format!("typedef {} *{};", elem_ty, name));
+                name
+            }
+
+            DictMerger(ref key, ref value, op) => {
+                let dict_ty = try!(self.c_type(&Dict(key.clone(), value.clone())));
+                let kv_ty = try!(self.c_type(&Struct(vec![*key.clone(), *value.clone()])));
+                let name = self.bld_ids.next();
+                code.add(format!("typedef {} *{};", dict_ty, name));
+                code.add("");
+
+                code.add(format!("static {n} {n}_new(int64_t capacity) {{", n = name));
+                code.add(format!("{n} bld = ({n})weld_rt_malloc(get_runid(), sizeof({d}));",
+                                 n = name,
+                                 d = dict_ty));
+                code.add(format!("*bld = {}_new(capacity);", dict_ty));
+                code.add("return bld;");
+                code.add("}");
+                code.add("");
+
+                code.add(format!("static void {n}_merge({n} bld, {kv} kv) {{",
+                                 n = name,
+                                 kv = kv_ty));
+                code.add(format!("{d}_entry *slot = {d}_lookup(*bld, kv.f0);", d = dict_ty));
+                code.add(format!("{} value = kv.f1;", try!(self.c_type(value))));
+                code.add(format!("if (slot->filled) value = {};",
+                                 try!(self.merge_op(op, value, "slot->value", "value"))));
+                code.add(format!("*bld = {}_put(*bld, slot, kv.f0, value);", dict_ty));
+                code.add("}");
+                code.add("");
+
+                code.add(format!("static {d} {n}_result({n} bld) {{", n = name, d = dict_ty));
+                code.add(format!("{d} result = {d}_new(16);", d = dict_ty));
+                code.add("for (int64_t i = 0; i < bld->capacity; i++) {");
+                code.add(format!("{}_entry *entry = &bld->entries[i];", dict_ty));
+                code.add("if (entry->filled) {");
+                code.add(format!("{d}_entry *slot = {d}_lookup(result, entry->key);",
+                                 d = dict_ty));
+                code.add(format!("{} value = entry->value;", try!(self.c_type(value))));
+                code.add(format!("if (slot->filled) value = {};",
+                                 try!(self.merge_op(op, value, "slot->value", "value"))));
+                code.add(format!("result = {}_put(result, slot, entry->key, value);", dict_ty));
+                code.add("}");
+                code.add("}");
+                code.add("weld_rt_free(get_runid(), bld->entries);");
+                code.add("weld_rt_free(get_runid(), bld);");
+                code.add("return result;");
+                code.add("}");
+                name
+            }
+
+            GroupMerger(ref key, ref value) => {
+                let kv_struct = Struct(vec![*key.clone(), *value.clone()]);
+                let kv_ty = try!(self.c_type(&kv_struct));
+                let kv_vec_ty = try!(self.c_type(&Vector(Box::new(kv_struct))));
+                let value_vec = Vector(value.clone());
+                let value_vec_ty = try!(self.c_type(&value_vec));
+                let dict_ty = try!(self.c_type(&Dict(key.clone(), Box::new(value_vec))));
+                let key_prefix = try!(self.helper_prefix(key));
+                let name = self.bld_ids.next();
+                try!(self.gen_appender(&mut code, &name, &kv_ty, &kv_vec_ty));
+
+                code.add(format!("static int {}_compare_keys(const void *p1, const void *p2) {{",
+                                 name));
+                code.add(format!("return {p}_cmp((({kv} *)p1)->f0, (({kv} *)p2)->f0);",
+                                 p = key_prefix,
+                                 kv = kv_ty));
+                code.add("}");
+                code.add("");
+
+                code.add("/* Sorts the pairs by key and puts the values of each key into a vector. */");
+                code.add(format!("static {d} {n}_group_result({n} bld) {{",
+                                 n = name,
+                                 d = dict_ty));
+                code.add(format!("{} *elements = bld->data;", kv_ty));
+                code.add(format!("{d} dict = {d}_new(16);", d = dict_ty));
+                code.add("int64_t start = 0;");
+                code.add(format!("qsort(elements, bld->size, sizeof({}), {}_compare_keys);",
+                                 kv_ty,
+                                 name));
+                code.add("while (start < bld->size) {");
+                code.add("int64_t end = start + 1;");
+                code.add(format!("{} group;", value_vec_ty));
+                code.add(format!("while (end < bld->size && {}_cmp(elements[start].f0, \
+                                  elements[end].f0) == 0) end++;",
+                                 key_prefix));
+                code.add(format!("group = {}_new(end - start);", value_vec_ty));
+                code.add("for (int64_t j = 0; j < end - start; j++) {");
+                code.add("group.data[j] = elements[start + j].f1;");
+                code.add("}");
+                code.add(format!("dict = {d}_put(dict, {d}_lookup(dict, elements[start].f0), \
+                                  elements[start].f0, group);",
+                                 d = dict_ty));
+                code.add("start = end;");
+                code.add("}");
+                code.add("return dict;");
+                code.add("}");
+                name
+            }
+
+            VecMerger(ref elem, _) => {
+                let vec_ty = try!(self.c_type(&Vector(elem.clone())));
+                let name = self.bld_ids.next();
+                code.add(format!("typedef {} *{};", vec_ty, name));
+                code.add("");
+                code.add(format!("static {n} {n}_new({v} vec) {{", n = name, v = vec_ty));
+                code.add(format!("{n} bld = ({n})weld_rt_malloc(get_runid(), sizeof({v}));",
+                                 n = name,
+                                 v = vec_ty));
+                code.add(format!("*bld = {}_clone(vec);", vec_ty));
+                code.add("return bld;");
+                code.add("}");
+                name
+            }
+        };
+
+        // Builders can be stored in structs, so they need hash and comparison functions like
+        // other types; as in the LLVM backend, they are never equal.
+        code.add("");
+        code.add(format!("static int64_t {n}_hash({n} bld) {{ return 0; }}", n = name));
+        code.add(format!("static int32_t {n}_cmp({n} a, {n} b) {{ return -1; }}", n = name));
+        code.add("");
+        self.prelude_code.add(code.result());
+        self.bld_names.insert(bk.clone(), name);
+        Ok(())
+    }
+
+    /// Generate an appender named `name` of elements of type `elem_ty`, whose result is a vector
+    /// of type `vec_ty`.
+    fn gen_appender(&mut self,
+                    code: &mut CodeBuilder,
+                    name: &str,
+                    elem_ty: &str,
+                    vec_ty: &str)
+                    -> WeldResult<()> {
+        code.add(format!("typedef struct {n}_data {{ {t} *data; int64_t size; \
+                          int64_t capacity; }} {n}_data;",
+                         n = name,
+                         t = elem_ty));
+        code.add(format!("typedef {n}_data *{n};", n = name));
+        code.add("");
+
+        code.add(format!("static {n} {n}_new(int64_t capacity) {{", n = name));
+        code.add(format!("{n} bld = ({n})weld_rt_malloc(get_runid(), sizeof({n}_data));",
+                         n = name));
+        code.add("if (capacity < 1) capacity = 1;");
+        code.add(format!("bld->data = ({t} *)weld_rt_malloc(get_runid(), sizeof({t}) * capacity);",
+                         t = elem_ty));
+        code.add("bld->size = 0;");
+        code.add("bld->capacity = capacity;");
+        code.add("return bld;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static void {n}_merge({n} bld, {t} value) {{", n = name, t = elem_ty));
+        code.add("if (bld->size == bld->capacity) {");
+        code.add("bld->capacity *= 2;");
+        code.add(format!("bld->data = ({t} *)weld_rt_realloc(get_runid(), bld->data, \
+                          sizeof({t}) * bld->capacity);",
+                         t = elem_ty));
+        code.add("}");
+        code.add("bld->data[bld->size++] = value;");
+        code.add("}");
+        code.add("");
+
+        code.add(format!("static {v} {n}_result({n} bld) {{", n = name, v = vec_ty));
+        code.add(format!("{} vec;", vec_ty));
+        code.add("vec.data = bld->data;");
+        code.add("vec.size = bld->size;");
+        code.add("return vec;");
+        code.add("}");
+        Ok(())
+    }
+}
+
+/// Returns the C name of a symbol. Every name gets its ID as a suffix so that it cannot clash with
+/// the names of generated types and functions.
+fn c_symbol(sym: &Symbol) -> String {
+    format!("{}_{}", sym.name, sym.id)
+}
+
+/// Returns the arguments for a call passing the given parameters, sorted by name as in the
+/// function's signature.
+fn sorted_args(params: &HashMap<Symbol, Type>) -> Vec<String> {
+    let sorted: BTreeMap<&Symbol, &Type> = params.iter().collect();
+    sorted.keys().map(|s| c_symbol(s)).collect()
+}
+
+/// Joins parameter declarations into a parameter list.
+fn param_string(params: Vec<String>) -> String {
+    if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.join(", ")
+    }
+}
+
+/// Returns an unsigned expression for the number of elements an iterator covers.
+fn total_iterations(iter: &ParallelForIter) -> String {
+    match (&iter.start, &iter.end, &iter.stride) {
+        (&Some(ref start), &Some(ref end), &Some(ref stride)) => {
+            format!("(uint64_t)({} - {}) / (uint64_t){}",
+                    c_symbol(end),
+                    c_symbol(start),
+                    c_symbol(stride))
+        }
+        _ => format!("(uint64_t){}.size", c_symbol(&iter.data)),
+    }
+}
+
+/// Returns the C type of a scalar.
+pub fn c_scalar_type(kind: ScalarKind) -> &'static str {
+    match kind {
+        Bool => "uint8_t",
+        I8 => "int8_t",
+        I32 => "int32_t",
+        I64 => "int64_t",
+        F32 => "float",
+        F64 => "double",
+    }
+}
+
+/// Returns a C expression for a binary operation on two scalars of the given kind, supporting the
+/// same operations as the LLVM backend.
+fn c_binop(op: BinOpKind, kind: ScalarKind, left: &str, right: &str) -> WeldResult<String> {
+    use ast::BinOpKind::*;
+    let float = kind == F32 || kind == F64;
+    let supported = match op {
+        Add | Subtract | Multiply | Divide => kind != Bool,
+        LessThan | LessThanOrEqual | GreaterThan | GreaterThanOrEqual => kind != Bool,
+        Equal | NotEqual => true,
+        LogicalAnd | LogicalOr => kind == Bool,
+        BitwiseAnd | BitwiseOr | Xor => !float,
+        Modulo => false,
+    };
+    if !supported {
+        return weld_err!("Unsupported binary op: {} on {}", op, print_type(&Scalar(kind)));
+    }
+    let c_op = match op {
+        Add => "+",
+        Subtract => "-",
+        Multiply => "*",
+        Divide => "/",
+        Modulo => "%",
+        Equal => "==",
+        NotEqual => "!=",
+        LessThan => "<",
+        LessThanOrEqual => "<=",
+        GreaterThan => ">",
+        GreaterThanOrEqual => ">=",
+        LogicalAnd | BitwiseAnd => "&",
+        LogicalOr | BitwiseOr => "|",
+        Xor => "^",
+    };
+    let result_ty = if op.is_comparison() {
+        "uint8_t"
+    } else {
+        c_scalar_type(kind)
+    };
+    // LLVM's `fcmp one` is false if either value is NaN, unlike C's `!=`.
+    if op == NotEqual && float {
+        Ok(format!("({})({l} < {r} || {l} > {r})", result_ty, l = left, r = right))
+    } else {
+        Ok(format!("({})({} {} {})", result_ty, left, c_op, right))
+    }
+}
+
+/// Returns the operator and value to compare the result of a vector's comparison function with
+/// to implement a comparison between two vectors.
+fn c_vector_comparison(op: BinOpKind, ty: &Type) -> WeldResult<(&'static str, i32)> {
+    use ast::BinOpKind::*;
+    match op {
+        Equal => Ok(("==", 0)),
+        NotEqual => Ok(("!=", 0)),
+        LessThan => Ok(("==", -1)),
+        LessThanOrEqual => Ok(("!=", 1)),
+        GreaterThan => Ok(("==", 1)),
+        GreaterThanOrEqual => Ok(("!=", -1)),
+        _ => weld_err!("Unsupported binary op: {} on {}", op, print_type(ty)),
+    }
+}
+
+/// Returns the C math function for a unary operation on the given kind of float.
+fn c_unaryop(op: UnaryOpKind, kind: ScalarKind) -> WeldResult<&'static str> {
+    use ast::UnaryOpKind::*;
+    match (op, kind) {
+        (Exp, F32) => Ok("expf"),
+        (Exp, F64) => Ok("exp"),
+        (Log, F32) => Ok("logf"),
+        (Log, F64) => Ok("log"),
+        (Sqrt, F32) => Ok("sqrtf"),
+        (Sqrt, F64) => Ok("sqrt"),
+        (Erf, F32) => Ok("erff"),
+        (Erf, F64) => Ok("erf"),
+        _ => weld_err!("Unsupported unary op: {} on {}", op, print_type(&Scalar(kind))),
+    }
+}
+
+/// Returns a C expression casting a scalar to another kind with the same semantics as the LLVM
+/// backend; in particular, casting an integer to a bool keeps its lowest bit.
+fn c_cast(from: ScalarKind, to: ScalarKind, value: &str) -> String {
+    let from_float = from == F32 || from == F64;
+    match to {
+        Bool if from_float => format!("(uint8_t)((int64_t)({}) & 1)", value),
+        Bool => format!("(uint8_t)(({}) & 1)", value),
+        _ => format!("({})({})", c_scalar_type(to), value),
+    }
+}
+
+/// Returns the identity value for a merger's operator, as given by `merger_identity_expr`.
+fn c_merge_identity(op: BinOpKind, ty: &Type) -> WeldResult<String> {
+    let identity = match *ty {
+        Scalar(kind) => try!(merger_identity_expr(op, kind)),
+        _ => None,
+    };
+    match identity {
+        Some(Expr { kind: ExprKind::Literal(ref value), .. }) => Ok(c_literal(value)),
+        _ => weld_err!("Unsupported identity for binary op: {} on {}", op, print_type(ty)),
+    }
+}
+
+/// Returns a C literal for a value.
+fn c_literal(value: &LiteralKind) -> String {
+    match *value {
+        BoolLiteral(l) => if l { "1" } else { "0" }.to_string(),
+        I8Literal(l) => format!("{}", l),
+        I32Literal(l) if l == ::std::i32::MIN => "INT32_MIN".to_string(),
+        I32Literal(l) => format!("{}", l),
+        I64Literal(l) if l == ::std::i64::MIN => "INT64_MIN".to_string(),
+        I64Literal(l) => format!("INT64_C({})", l),
+        F32Literal(l) => c_float_literal(l as f64, format!("{:e}f", l)),
+        F64Literal(l) => c_float_literal(l, format!("{:e}", l)),
+    }
+}
+
+/// Returns a C literal for a float, given its formatting as a finite value.
+fn c_float_literal(value: f64, finite: String) -> String {
+    if value.is_nan() {
+        "NAN".to_string()
+    } else if value == ::std::f64::INFINITY {
+        "INFINITY".to_string()
+    } else if value == ::std::f64::NEG_INFINITY {
+        "-INFINITY".to_string()
+    } else {
+        finite
+    }
+}
+
+#[test]
+fn types() {
+    let mut gen = CGenerator::new();
+    assert_eq!(gen.c_type(&Scalar(I32)).unwrap(), "int32_t");
+    assert_eq!(gen.c_type(&Scalar(Bool)).unwrap(), "uint8_t");
+    assert_eq!(gen.c_type(&Scalar(F32)).unwrap(), "float");
+    assert_eq!(gen.c_type(&Vector(Box::new(Scalar(I64)))).unwrap(), "v0");
+    assert_eq!(gen.c_type(&Struct(vec![Scalar(I32), Scalar(F64)])).unwrap(), "s0");
+    assert_eq!(gen.c_type(&Vector(Box::new(Scalar(I64)))).unwrap(), "v0");
+
+    // A dictionary defines the struct and vector its entries are returned in.
+    let dict = Dict(Box::new(Scalar(I32)), Box::new(Scalar(I64)));
+    assert_eq!(gen.c_type(&dict).unwrap(), "d0");
+    assert_eq!(gen.c_type(&Struct(vec![Scalar(I32), Scalar(I64)])).unwrap(), "s1");
+    assert_eq!(gen.c_type(&Vector(Box::new(Struct(vec![Scalar(I32), Scalar(I64)])))).unwrap(),
+               "v1");
+
+    assert!(gen.c_type(&Function(vec![], Box::new(Scalar(I32)))).is_err());
+}
@@ -4,14 +4,20 @@ use std::ffi::CString;
 
 use super::WeldConf;
 use super::error::WeldResult;
-use super::passes::OPTIMIZATION_PASSES;
+use super::passes;
 use super::passes::Pass;
 
 // Keys used in textual representation of conf
 pub const MEMORY_LIMIT_KEY: &'static str = "weld.memory.limit";
 pub const THREADS_KEY: &'static str = "weld.threads";
+pub const GRAIN_SIZE_KEY: &'static str = "weld.threads.grainSize";
 pub const LOG_LEVEL_KEY: &'static str = "weld.log.level";
 pub const OPTIMIZATION_PASSES_KEY: &'static str = "weld.optimization.passes";
+pub const VERIFY_PASSES_KEY: &'static str = "weld.compile.verifyPasses";
+pub const MAX_PASS_ITERATIONS_KEY: &'static str = "weld.optimization.maxIterations";
+pub const SIMD_WIDTH_KEY: &'static str = "weld.compile.simdWidth";
+pub const BACKEND_KEY: &'static str = "weld.compile.backend";
+pub const OUTPUT_FILE_KEY: &'static str = "weld.compile.outputFile";
 
 /// Available logging levels; these should be listed in order of verbosity
 /// because code will compare them.
@@ -20,23 +26,92 @@ pub enum LogLevel {
     None, Debug
 }
 
+/// Available code generators for compiled programs.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum Backend {
+    /// Generates LLVM IR and compiles it in memory.
+    Llvm,
+    /// Generates C and compiles it into a shared library with the system C compiler.
+    C,
+}
+
+/// Kinds of files a compiled program can be written to.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub enum OutputKind {
+    /// A relocatable object file, which must be linked with the Weld runtime library.
+    Object,
+    /// A shared library, which can be loaded back into a module with `weld_module_load`.
+    SharedLibrary,
+}
+
+/// A file to write a compiled program to, in addition to loading it into a module.
+#[derive(PartialEq,Eq,Clone,Debug)]
+pub struct OutputFile {
+    pub path: String,
+    pub kind: OutputKind,
+}
+
 // Default values of each key
 pub const DEFAULT_MEMORY_LIMIT: i64 = 1000000000;
 pub const DEFAULT_THREADS: i64 = 1;
+pub const DEFAULT_GRAIN_SIZE: i32 = 4096;
 pub const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::None;
-lazy_static! {
-    pub static ref DEFAULT_OPTIMIZATION_PASSES: Vec<Pass> = {
-        let m = ["inline-apply", "inline-let", "inline-zip", "loop-fusion", "vectorize"];
-        m.iter().map(|e| (*OPTIMIZATION_PASSES.get(e).unwrap()).clone()).collect()
-    };
+pub const DEFAULT_VERIFY_PASSES: bool = false;
+pub const DEFAULT_MAX_PASS_ITERATIONS: usize = 1000;
+pub const DEFAULT_BACKEND: Backend = Backend::Llvm;
+pub const DEFAULT_OPTIMIZATION_PASS_NAMES: &'static [&'static str] =
+    &["inline-apply", "inline-let", "inline-zip", "fold-constants", "loop-fusion",
+      "projection-pushdown", "loop-tiling", "predicate", "cse", "licm", "vectorize",
+      "infer-size"];
+
+/// Returns the passes run when `weld.optimization.passes` is not set: the default passes, along
+/// with any registered passes whose ordering hints place them among the defaults.
+pub fn default_optimization_passes() -> Vec<Pass> {
+    passes::with_registered_passes(DEFAULT_OPTIMIZATION_PASS_NAMES)
+}
+
+/// Returns a value for `weld.optimization.passes` which runs the default passes except the ones
+/// named in `excluded`, e.g. to compile a program without the vectorizer.
+pub fn default_passes_except(excluded: &[&str]) -> String {
+    DEFAULT_OPTIMIZATION_PASS_NAMES.iter()
+        .filter(|p| !excluded.contains(p))
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 // A parsed configuration with correctly typed fields.
 pub struct ParsedConf {
     pub memory_limit: i64,
     pub threads: i64,
+    pub grain_size: i32,
     pub log_level: LogLevel,
-    pub optimization_passes: Vec<Pass>
+    pub optimization_passes: Vec<Pass>,
+    pub verify_passes: bool,
+    pub max_pass_iterations: usize,
+    /// Width in bits of the SIMD registers to generate code for, or `None` to use the host's.
+    pub simd_width: Option<u32>,
+    pub backend: Backend,
+    /// File to write the compiled program to, if any.
+    pub output_file: Option<OutputFile>,
+}
+
+impl Default for ParsedConf {
+    /// Returns a configuration with the default value for every key.
+    fn default() -> ParsedConf {
+        ParsedConf {
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            threads: DEFAULT_THREADS,
+            grain_size: DEFAULT_GRAIN_SIZE,
+            log_level: DEFAULT_LOG_LEVEL,
+            optimization_passes: default_optimization_passes(),
+            verify_passes: DEFAULT_VERIFY_PASSES,
+            max_pass_iterations: DEFAULT_MAX_PASS_ITERATIONS,
+            simd_width: None,
+            backend: DEFAULT_BACKEND,
+            output_file: None,
+        }
+    }
 }
 
 /// Parse a configuration from a WeldConf key-value dictiomary.
@@ -49,19 +124,53 @@ pub fn parse(conf: &WeldConf) -> WeldResult<ParsedConf> {
     let threads = value.map(|s| parse_threads(&s))
                        .unwrap_or(Ok(DEFAULT_THREADS))?;
 
+    let value = get_value(conf, GRAIN_SIZE_KEY);
+    let grain_size = value.map(|s| parse_grain_size(&s))
+                          .unwrap_or(Ok(DEFAULT_GRAIN_SIZE))?;
+
     let value = get_value(conf, LOG_LEVEL_KEY);
     let log_level = value.map(|s| parse_log_level(&s))
                          .unwrap_or(Ok(DEFAULT_LOG_LEVEL))?;
 
     let value = get_value(conf, OPTIMIZATION_PASSES_KEY);
     let passes = value.map(|s| parse_passes(&s))
-                      .unwrap_or(Ok(DEFAULT_OPTIMIZATION_PASSES.clone()))?;
+                      .unwrap_or_else(|| Ok(default_optimization_passes()))?;
+
+    let value = get_value(conf, VERIFY_PASSES_KEY);
+    let verify_passes = value.map(|s| parse_verify_passes(&s))
+                             .unwrap_or(Ok(DEFAULT_VERIFY_PASSES))?;
+
+    let value = get_value(conf, MAX_PASS_ITERATIONS_KEY);
+    let max_pass_iterations = value.map(|s| parse_max_pass_iterations(&s))
+                                   .unwrap_or(Ok(DEFAULT_MAX_PASS_ITERATIONS))?;
+
+    let value = get_value(conf, SIMD_WIDTH_KEY);
+    let simd_width = match value {
+        Some(s) => Some(parse_simd_width(&s)?),
+        None => None,
+    };
+
+    let value = get_value(conf, BACKEND_KEY);
+    let backend = value.map(|s| parse_backend(&s))
+                       .unwrap_or(Ok(DEFAULT_BACKEND))?;
+
+    let value = get_value(conf, OUTPUT_FILE_KEY);
+    let output_file = match value {
+        Some(s) => Some(parse_output_file(&s)?),
+        None => None,
+    };
 
     Ok(ParsedConf {
         memory_limit: memory_limit,
         threads: threads,
+        grain_size: grain_size,
         log_level: log_level,
-        optimization_passes: passes
+        optimization_passes: passes,
+        verify_passes: verify_passes,
+        max_pass_iterations: max_pass_iterations,
+        simd_width: simd_width,
+        backend: backend,
+        output_file: output_file,
     })
 }
 
@@ -78,6 +187,14 @@ fn parse_threads(s: &str) -> WeldResult<i64> {
         _ => weld_err!("Invalid number of threads: {}", s),
     }
 }
+/// Parse a loop grain size.
+fn parse_grain_size(s: &str) -> WeldResult<i32> {
+    match s.parse::<i32>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => weld_err!("Invalid grain size: {}", s),
+    }
+}
+
 /// Parse a memory limit.
 fn parse_memory_limit(s: &str) -> WeldResult<i64> {
     match s.parse::<i64>() {
@@ -93,14 +210,63 @@ fn parse_passes(s: &str) -> WeldResult<Vec<Pass>> {
     }
     let mut result = vec![];
     for piece in s.split(",") {
-        match OPTIMIZATION_PASSES.get(piece) {
-            Some(pass) => result.push(pass.clone()),
+        match passes::get_pass(piece) {
+            Some(pass) => result.push(pass),
             None => return weld_err!("Unknown optimization pass: {}", piece)
         }
     }
     Ok(result)
 }
 
+/// Parse whether to verify the program after each optimization pass.
+fn parse_verify_passes(s: &str) -> WeldResult<bool> {
+    match s {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => weld_err!("Invalid flag for verifying passes: {}", s)
+    }
+}
+
+/// Parse the maximum number of times a pass may run its transforms while looking for a fixpoint.
+fn parse_max_pass_iterations(s: &str) -> WeldResult<usize> {
+    match s.parse::<usize>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => weld_err!("Invalid maximum number of pass iterations: {}", s),
+    }
+}
+
+/// Parse a SIMD register width in bits.
+fn parse_simd_width(s: &str) -> WeldResult<u32> {
+    match s.parse::<u32>() {
+        Ok(v) if v >= 8 && v.is_power_of_two() => Ok(v),
+        _ => weld_err!("Invalid SIMD width: {}", s),
+    }
+}
+
+/// Parse a code generation backend.
+fn parse_backend(s: &str) -> WeldResult<Backend> {
+    match s {
+        "llvm" => Ok(Backend::Llvm),
+        "c" => Ok(Backend::C),
+        _ => weld_err!("Invalid backend: {}", s)
+    }
+}
+
+/// Parse the path of a file to write a compiled program to, whose extension gives its kind.
+fn parse_output_file(s: &str) -> WeldResult<OutputFile> {
+    let kind = if s.ends_with(".o") {
+        OutputKind::Object
+    } else if s.ends_with(".so") || s.ends_with(".dylib") {
+        OutputKind::SharedLibrary
+    } else {
+        return weld_err!("Invalid output file (expected a .o, .so or .dylib file): {}", s);
+    };
+    Ok(OutputFile {
+        path: s.to_string(),
+        kind: kind,
+    })
+}
+
 /// Parse a log level.
 fn parse_log_level(s: &str) -> WeldResult<LogLevel> {
     match s {
@@ -116,6 +282,10 @@ fn conf_parsing() {
     assert_eq!(parse_threads("2").unwrap(), 2);
     assert!(parse_threads("").is_err());
 
+    assert_eq!(parse_grain_size("64").unwrap(), 64);
+    assert!(parse_grain_size("0").is_err());
+    assert!(parse_grain_size("").is_err());
+
     assert_eq!(parse_memory_limit("1000000").unwrap(), 1000000);
     assert!(parse_memory_limit("").is_err());
 
@@ -124,6 +294,38 @@ fn conf_parsing() {
     assert_eq!(parse_passes("").unwrap().len(), 0);
     assert!(parse_passes("non-existent-pass").is_err());
 
+    passes::register_transform("conf-parsing-test-pass", passes::PassOrder::Manual, false, |_| ())
+        .unwrap();
+    assert_eq!(parse_passes("inline-let,conf-parsing-test-pass").unwrap().len(), 2);
+    let scalar_passes = default_passes_except(&["vectorize"]);
+    assert!(!scalar_passes.contains("vectorize"));
+    assert_eq!(parse_passes(&scalar_passes).unwrap().len(),
+               DEFAULT_OPTIMIZATION_PASS_NAMES.len() - 1);
+
+    assert_eq!(parse_verify_passes("true").unwrap(), true);
+    assert_eq!(parse_verify_passes("false").unwrap(), false);
+    assert!(parse_verify_passes("").is_err());
+
+    assert_eq!(parse_max_pass_iterations("10").unwrap(), 10);
+    assert!(parse_max_pass_iterations("0").is_err());
+    assert!(parse_max_pass_iterations("-1").is_err());
+
+    assert_eq!(parse_simd_width("256").unwrap(), 256);
+    assert!(parse_simd_width("0").is_err());
+    assert!(parse_simd_width("100").is_err());
+    assert!(parse_simd_width("").is_err());
+
+    assert_eq!(parse_backend("llvm").unwrap(), Backend::Llvm);
+    assert_eq!(parse_backend("c").unwrap(), Backend::C);
+    assert!(parse_backend("C").is_err());
+    assert!(parse_backend("").is_err());
+
+    assert_eq!(parse_output_file("/tmp/q.o").unwrap().kind, OutputKind::Object);
+    assert_eq!(parse_output_file("q.so").unwrap().kind, OutputKind::SharedLibrary);
+    assert_eq!(parse_output_file("q.dylib").unwrap().path, "q.dylib");
+    assert!(parse_output_file("q.c").is_err());
+    assert!(parse_output_file("").is_err());
+
     assert_eq!(parse_log_level("debug").unwrap(), LogLevel::Debug);
     assert_eq!(parse_log_level("none").unwrap(), LogLevel::None);
     assert!(parse_log_level("").is_err());
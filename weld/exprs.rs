@@ -27,6 +27,25 @@ pub fn literal_expr(kind: LiteralKind) -> WeldResult<Expr<Type>> {
                     }))
 }
 
+/// Returns the identity element of a merger with the given operator and element type, or `None`
+/// if the operator has no identity that predication can use.
+pub fn merger_identity_expr(op: BinOpKind, kind: ScalarKind) -> WeldResult<Option<Expr<Type>>> {
+    let literal = match (op, kind) {
+        (BinOpKind::Add, ScalarKind::I8) => I8Literal(0),
+        (BinOpKind::Add, ScalarKind::I32) => I32Literal(0),
+        (BinOpKind::Add, ScalarKind::I64) => I64Literal(0),
+        (BinOpKind::Add, ScalarKind::F32) => F32Literal(0.0),
+        (BinOpKind::Add, ScalarKind::F64) => F64Literal(0.0),
+        (BinOpKind::Multiply, ScalarKind::I8) => I8Literal(1),
+        (BinOpKind::Multiply, ScalarKind::I32) => I32Literal(1),
+        (BinOpKind::Multiply, ScalarKind::I64) => I64Literal(1),
+        (BinOpKind::Multiply, ScalarKind::F32) => F32Literal(1.0),
+        (BinOpKind::Multiply, ScalarKind::F64) => F64Literal(1.0),
+        _ => return Ok(None),
+    };
+    Ok(Some(literal_expr(literal)?))
+}
+
 pub fn ident_expr(symbol: Symbol, ty: Type) -> WeldResult<Expr<Type>> {
     new_expr(Ident(symbol.clone()), ty.clone())
 }
@@ -35,7 +54,14 @@ pub fn binop_expr(kind: BinOpKind, left: Expr<Type>, right: Expr<Type>) -> WeldR
     if left.ty != right.ty {
         weld_err!("Internal error: Mismatched types in binop_expr")
     } else {
-        let ty = left.ty.clone();
+        let ty = if kind.is_comparison() {
+            match left.ty {
+                Simd(_) => Simd(ScalarKind::Bool),
+                _ => Scalar(ScalarKind::Bool),
+            }
+        } else {
+            left.ty.clone()
+        };
         new_expr(BinOp {
                      kind: kind,
                      left: Box::new(left),
@@ -480,6 +506,11 @@ fn binop_test() {
 
     assert_eq!(print_expr_without_indent(&expr), "(1+1)");
     assert_eq!(expr.ty, Scalar(ScalarKind::I32));
+
+    let right = literal_expr(LiteralKind::I32Literal(1)).unwrap();
+    let left = literal_expr(LiteralKind::I32Literal(1)).unwrap();
+    let expr = binop_expr(BinOpKind::LessThan, left, right).unwrap();
+    assert_eq!(expr.ty, Scalar(ScalarKind::Bool));
 }
 
 #[test]
@@ -0,0 +1,1386 @@
+//! An interpreter for typed Weld expressions.
+//!
+//! The interpreter evaluates an expression directly instead of generating code for it, which makes
+//! it useful where compiling a program is not worth it, such as in the REPL, and as a reference to
+//! test compiled code against. It follows the semantics of the generated code, including the hash
+//! functions and table layout of dictionaries, so a dictionary's entries come out in the same
+//! order as from a module run on one thread. Where compiled code would read out of bounds, the
+//! interpreter returns an error instead. Loops run sequentially, and the SIMD values of each loop
+//! have as many lanes as in a module compiled for the same SIMD width (see
+//! `llvm::loop_simd_lanes`).
+//!
+//! Programs take their arguments and return their results in the runtime memory layout, like
+//! compiled modules, so the same input and the same code to read results work for both.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use libc;
+use libc::c_void;
+
+use super::ast::*;
+use super::ast::ExprKind::*;
+use super::ast::Type::*;
+use super::ast::LiteralKind::*;
+use super::ast::ScalarKind::*;
+use super::ast::BuilderKind::*;
+use super::conf::ParsedConf;
+use super::error::*;
+use super::exprs;
+use super::llvm;
+use super::llvm::dictionary_capacity;
+use super::macro_processor;
+use super::pretty_print::*;
+use super::program::Program;
+use super::sir;
+use super::transforms;
+use super::type_inference;
+
+use easy_ll;
+
+extern "C" {
+    fn erf(x: f64) -> f64;
+    fn erff(x: f32) -> f32;
+}
+
+/// A value computed by the interpreter.
+#[derive(Clone)]
+pub enum Value {
+    Bool(bool),
+    I8(i8),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Simd(Vec<Value>),
+    Vector(Rc<Vec<Value>>),
+    Dict(Rc<Dictionary>),
+    Struct(Vec<Value>),
+    /// Builders are updated in place by merges, so all copies of a builder value refer to the same
+    /// builder.
+    Builder(Rc<RefCell<BuilderState>>),
+    Function(Rc<Closure>),
+}
+
+/// A dictionary, stored in a hash table with the same layout and probing scheme as the runtime's.
+#[derive(Clone)]
+pub struct Dictionary {
+    slots: Vec<Option<(Value, Value)>>,
+    size: usize,
+}
+
+/// The values merged into a builder so far.
+pub enum BuilderState {
+    Appender(Vec<Value>),
+    Merger(BinOpKind, Value),
+    DictMerger(BinOpKind, Dictionary),
+    GroupMerger(Vec<(Value, Value)>),
+    VecMerger(BinOpKind, Vec<Value>),
+}
+
+/// A lambda, along with the values of the symbols in scope where it was defined.
+pub struct Closure {
+    params: Vec<Symbol>,
+    body: TypedExpr,
+    env: Env,
+}
+
+/// The values of the symbols in scope, along with the width in bits of SIMD registers and the
+/// number of lanes in the SIMD values of the loop being evaluated.
+#[derive(Clone)]
+struct Env {
+    bindings: HashMap<Symbol, Value>,
+    simd_width: u32,
+    simd_lanes: usize,
+}
+
+/// Memory holding a value in the runtime layout, along with the vectors and dictionaries it points
+/// to. The memory is freed when this is dropped.
+pub struct ValueMemory {
+    buffers: Vec<Vec<u64>>,
+    data: *mut u8,
+}
+
+impl ValueMemory {
+    /// Returns a pointer to the value.
+    pub fn data(&self) -> *const c_void {
+        self.data as *const c_void
+    }
+
+    /// Allocates zeroed memory for `size` bytes, aligned for any scalar.
+    fn alloc(&mut self, size: usize) -> *mut u8 {
+        let mut buffer = vec![0u64; cmp::max(size, 1) / 8 + 1];
+        let ptr = buffer.as_mut_ptr() as *mut u8;
+        self.buffers.push(buffer);
+        ptr
+    }
+}
+
+impl Dictionary {
+    fn new(capacity: usize) -> Dictionary {
+        Dictionary {
+            slots: vec![None; capacity],
+            size: 0,
+        }
+    }
+
+    /// Returns the index of the slot holding `key`, or of the empty slot where it should be put.
+    fn lookup(&self, key: &Value) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut pos = hash(key) as usize;
+        loop {
+            let slot = pos & mask;
+            match self.slots[slot] {
+                Some((ref k, _)) if compare(key, k) != 0 => pos = pos.wrapping_add(1),
+                _ => return slot,
+            }
+        }
+    }
+
+    /// Returns the value for `key`, if it is in the dictionary.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        match self.slots[self.lookup(key)] {
+            Some((_, ref value)) => Some(value),
+            None => None,
+        }
+    }
+
+    /// Puts an entry into the slot returned by `lookup` for its key. As in the runtime, the table
+    /// doubles in size once it is 70% full.
+    fn put(&mut self, slot: usize, key: Value, value: Value) {
+        let filled = self.slots[slot].is_some();
+        self.slots[slot] = Some((key, value));
+        if filled {
+            return;
+        }
+        if self.size * 10 >= self.slots.len() * 7 {
+            let mut grown = Dictionary::new(self.slots.len() * 2);
+            for entry in self.slots.drain(..) {
+                if let Some((k, v)) = entry {
+                    let slot = grown.lookup(&k);
+                    grown.put(slot, k, v);
+                }
+            }
+            *self = grown;
+        } else {
+            self.size += 1;
+        }
+    }
+
+    /// Merges a value into the entry for `key` with a builder's merge operator.
+    fn merge(&mut self, key: Value, value: Value, op: BinOpKind) -> WeldResult<()> {
+        let slot = self.lookup(&key);
+        let value = match self.slots[slot] {
+            Some((_, ref old)) => try!(binop(op, old, &value)),
+            None => value,
+        };
+        self.put(slot, key, value);
+        Ok(())
+    }
+
+    /// Returns the entries of the dictionary, in the order of their slots.
+    pub fn entries(&self) -> Vec<(&Value, &Value)> {
+        self.slots.iter().filter_map(|s| s.as_ref().map(|&(ref k, ref v)| (k, v))).collect()
+    }
+}
+
+/// Applies macros to a program and infers its types, giving the expression the interpreter runs.
+pub fn typed_program(program: &Program) -> WeldResult<TypedExpr> {
+    let mut expr = try!(macro_processor::process_program(program));
+    try!(transforms::uniquify(&mut expr));
+    try!(type_inference::infer_types(&mut expr));
+    expr.to_typed()
+}
+
+/// Returns the width in bits of the SIMD registers that `conf` compiles for, which is
+/// `weld.compile.simdWidth` or else the width of the host's SIMD registers.
+pub fn simd_width(conf: &ParsedConf) -> u32 {
+    conf.simd_width.unwrap_or_else(easy_ll::host_vector_bits)
+}
+
+/// Evaluates a program that takes no parameters.
+pub fn evaluate_program(program: &Program, conf: &ParsedConf) -> WeldResult<Value> {
+    let expr = try!(typed_program(program));
+    let width = simd_width(conf);
+    match expr.kind {
+        Lambda { ref params, ref body } if params.is_empty() => {
+            evaluate(body, &HashMap::new(), width)
+        }
+        Lambda { .. } => weld_err!("Cannot evaluate a program with parameters without an input"),
+        _ => evaluate(&expr, &HashMap::new(), width),
+    }
+}
+
+/// Runs a program on an input like a compiled module. `input` points to a struct holding the
+/// arguments of the program's lambda in the runtime memory layout, and the result is returned in
+/// the same layout.
+pub unsafe fn interpret_program(program: &Program,
+                                conf: &ParsedConf,
+                                input: *const c_void)
+                                -> WeldResult<ValueMemory> {
+    let expr = try!(typed_program(program));
+    interpret_function(&expr, simd_width(conf), input)
+}
+
+/// Runs a typed lambda on an input in the runtime memory layout, as in `interpret_program`, with
+/// SIMD registers of `simd_width` bits.
+pub unsafe fn interpret_function(expr: &TypedExpr,
+                                 simd_width: u32,
+                                 input: *const c_void)
+                                 -> WeldResult<ValueMemory> {
+    match expr.kind {
+        Lambda { ref params, ref body } => {
+            let mut env = Env {
+                bindings: HashMap::new(),
+                simd_width,
+                simd_lanes: llvm::default_simd_lanes(simd_width) as usize,
+            };
+            if !params.is_empty() {
+                let args_ty = Struct(params.iter().map(|p| p.ty.clone()).collect());
+                if let Value::Struct(args) = try!(read_value(input as *const u8, &args_ty)) {
+                    for (param, arg) in params.iter().zip(args) {
+                        env.bindings.insert(param.name.clone(), arg);
+                    }
+                }
+            }
+            let result = try!(eval(body, &mut env));
+            to_memory(&result, &body.ty)
+        }
+        _ => weld_err!("Interpreted function must be a lambda"),
+    }
+}
+
+/// Evaluates a typed expression with the given values bound to its free symbols and SIMD
+/// registers of `simd_width` bits.
+pub fn evaluate(expr: &TypedExpr,
+                bindings: &HashMap<Symbol, Value>,
+                simd_width: u32)
+                -> WeldResult<Value> {
+    let mut env = Env {
+        bindings: bindings.clone(),
+        simd_width,
+        simd_lanes: llvm::default_simd_lanes(simd_width) as usize,
+    };
+    eval(expr, &mut env)
+}
+
+fn eval(expr: &TypedExpr, env: &mut Env) -> WeldResult<Value> {
+    match expr.kind {
+        Literal(ref lit) => Ok(literal_value(lit)),
+
+        Ident(ref sym) => {
+            match env.bindings.get(sym) {
+                Some(value) => Ok(value.clone()),
+                None => weld_err!("Undefined symbol {} in interpreter", sym),
+            }
+        }
+
+        Negate(ref child) => {
+            let value = try!(eval(child, env));
+            map_lanes(&value, &negate)
+        }
+
+        Broadcast(ref child) => {
+            let value = try!(eval(child, env));
+            Ok(Value::Simd(vec![value; env.simd_lanes]))
+        }
+
+        BinOp { kind, ref left, ref right } => {
+            let left = try!(eval(left, env));
+            let right = try!(eval(right, env));
+            binop(kind, &left, &right)
+        }
+
+        UnaryOp { kind, ref value } => {
+            let value = try!(eval(value, env));
+            map_lanes(&value, &|v| unaryop(kind, v))
+        }
+
+        Cast { kind, ref child_expr } => {
+            let value = try!(eval(child_expr, env));
+            map_lanes(&value, &|v| cast(kind, v))
+        }
+
+        ToVec { ref child_expr } => {
+            match try!(eval(child_expr, env)) {
+                Value::Dict(ref dict) => {
+                    let entries = dict.entries()
+                        .into_iter()
+                        .map(|(k, v)| Value::Struct(vec![k.clone(), v.clone()]))
+                        .collect();
+                    Ok(Value::Vector(Rc::new(entries)))
+                }
+                other => weld_err!("ToVec expects a dictionary, got {}", other),
+            }
+        }
+
+        MakeStruct { ref elems } => Ok(Value::Struct(try!(eval_all(elems, env)))),
+
+        MakeVector { ref elems } => Ok(Value::Vector(Rc::new(try!(eval_all(elems, env))))),
+
+        Zip { ref vectors } => {
+            let mut vecs = vec![];
+            for value in try!(eval_all(vectors, env)) {
+                match value {
+                    Value::Vector(v) => vecs.push(v),
+                    other => return weld_err!("Zip expects vectors, got {}", other),
+                }
+            }
+            let len = vecs.first().map(|v| v.len()).unwrap_or(0);
+            if vecs.iter().any(|v| v.len() != len) {
+                return weld_err!("Zipped vectors have different lengths");
+            }
+            let elems = (0..len)
+                .map(|i| Value::Struct(vecs.iter().map(|v| v[i].clone()).collect()))
+                .collect();
+            Ok(Value::Vector(Rc::new(elems)))
+        }
+
+        GetField { ref expr, index } => {
+            match try!(eval(expr, env)) {
+                Value::Struct(ref fields) if (index as usize) < fields.len() => {
+                    Ok(fields[index as usize].clone())
+                }
+                other => weld_err!("No field {} in {}", index, other),
+            }
+        }
+
+        Length { ref data } => {
+            match try!(eval(data, env)) {
+                Value::Vector(ref elems) => Ok(Value::I64(elems.len() as i64)),
+                other => weld_err!("Length expects a vector, got {}", other),
+            }
+        }
+
+        Lookup { ref data, ref index } => {
+            let data = try!(eval(data, env));
+            let index = try!(eval(index, env));
+            match data {
+                Value::Vector(ref elems) => {
+                    let i = try!(as_i64(&index));
+                    if i < 0 || i as usize >= elems.len() {
+                        return weld_err!("Index {} out of bounds in vector of length {}",
+                                         i,
+                                         elems.len());
+                    }
+                    Ok(elems[i as usize].clone())
+                }
+                Value::Dict(ref dict) => {
+                    match dict.get(&index) {
+                        Some(value) => Ok(value.clone()),
+                        None => weld_err!("Key {} not found in dictionary", index),
+                    }
+                }
+                other => weld_err!("Lookup expects a vector or dictionary, got {}", other),
+            }
+        }
+
+        KeyExists { ref data, ref key } => {
+            let data = try!(eval(data, env));
+            let key = try!(eval(key, env));
+            match data {
+                Value::Dict(ref dict) => Ok(Value::Bool(dict.get(&key).is_some())),
+                other => weld_err!("KeyExists expects a dictionary, got {}", other),
+            }
+        }
+
+        Slice { ref data, ref index, ref size } => {
+            let data = try!(eval(data, env));
+            let index = try!(as_i64(&try!(eval(index, env))));
+            let size = try!(as_i64(&try!(eval(size, env))));
+            match data {
+                Value::Vector(ref elems) => {
+                    if index < 0 || index as usize > elems.len() || size < 0 {
+                        return weld_err!("Invalid slice of {} elements at {} in vector of length {}",
+                                         size,
+                                         index,
+                                         elems.len());
+                    }
+                    // As in the generated code, slices are cut short at the end of the vector.
+                    let end = cmp::min(index as usize + size as usize, elems.len());
+                    Ok(Value::Vector(Rc::new(elems[index as usize..end].to_vec())))
+                }
+                other => weld_err!("Slice expects a vector, got {}", other),
+            }
+        }
+
+        Let { ref name, ref value, ref body } => {
+            let value = try!(eval(value, env));
+            bind_and_eval(&[name.clone()], vec![value], body, env)
+        }
+
+        If { ref cond, ref on_true, ref on_false } => {
+            match try!(eval(cond, env)) {
+                Value::Bool(true) => eval(on_true, env),
+                Value::Bool(false) => eval(on_false, env),
+                other => weld_err!("If expects a bool condition, got {}", other),
+            }
+        }
+
+        Select { ref cond, ref on_true, ref on_false } => {
+            let cond = try!(eval(cond, env));
+            let on_true = try!(eval(on_true, env));
+            let on_false = try!(eval(on_false, env));
+            select(&cond, on_true, on_false)
+        }
+
+        Lambda { ref params, ref body } => {
+            Ok(Value::Function(Rc::new(Closure {
+                params: params.iter().map(|p| p.name.clone()).collect(),
+                body: body.as_ref().clone(),
+                env: env.clone(),
+            })))
+        }
+
+        Apply { ref func, ref params } => {
+            let args = try!(eval_all(params, env));
+            match func.kind {
+                Lambda { params: ref lambda_params, ref body } => {
+                    let names: Vec<Symbol> = lambda_params.iter().map(|p| p.name.clone()).collect();
+                    bind_and_eval(&names, args, body, env)
+                }
+                _ => {
+                    match try!(eval(func, env)) {
+                        Value::Function(ref closure) => {
+                            let mut closure_env = closure.env.clone();
+                            bind_and_eval(&closure.params, args, &closure.body, &mut closure_env)
+                        }
+                        other => weld_err!("Cannot apply {}", other),
+                    }
+                }
+            }
+        }
+
+        CUDF { ref sym_name, ref args, ref return_ty } => {
+            let values = try!(eval_all(args, env));
+            let arg_tys: Vec<Type> = args.iter().map(|a| a.ty.clone()).collect();
+            unsafe { call_udf(sym_name, &values, &arg_tys, return_ty) }
+        }
+
+        NewBuilder(ref arg) => {
+            let arg = match *arg {
+                Some(ref arg) => Some(try!(eval(arg, env))),
+                None => None,
+            };
+            new_builder(&expr.ty, arg)
+        }
+
+        For { ref iters, ref builder, ref func } => eval_for(iters, builder, func, env),
+
+        Merge { ref builder, ref value } => {
+            let builder = try!(eval(builder, env));
+            let value = try!(eval(value, env));
+            try!(merge(&builder, value, env.simd_lanes));
+            Ok(builder)
+        }
+
+        Res { ref builder } => result(&try!(eval(builder, env))),
+    }
+}
+
+fn eval_all(exprs: &[TypedExpr], env: &mut Env) -> WeldResult<Vec<Value>> {
+    let mut values = vec![];
+    for expr in exprs.iter() {
+        values.push(try!(eval(expr, env)));
+    }
+    Ok(values)
+}
+
+/// Evaluates `body` with `names` bound to `values`, restoring the previous bindings afterwards.
+fn bind_and_eval(names: &[Symbol],
+                 values: Vec<Value>,
+                 body: &TypedExpr,
+                 env: &mut Env)
+                 -> WeldResult<Value> {
+    if names.len() != values.len() {
+        return weld_err!("Expected {} arguments but got {}", names.len(), values.len());
+    }
+    let mut previous = vec![];
+    for (name, value) in names.iter().zip(values) {
+        previous.push(env.bindings.insert(name.clone(), value));
+    }
+    let result = eval(body, env);
+    for (name, old) in names.iter().zip(previous).rev() {
+        match old {
+            Some(value) => env.bindings.insert(name.clone(), value),
+            None => env.bindings.remove(name),
+        };
+    }
+    result
+}
+
+/// An iterator of a For loop, evaluated.
+struct LoopIter {
+    data: Rc<Vec<Value>>,
+    start: i64,
+    stride: i64,
+    kind: IterKind,
+}
+
+impl LoopIter {
+    /// Returns the element at `position` of the iterator, which is a SIMD value with `lanes` lanes
+    /// for a SimdIter.
+    fn element(&self, position: i64, lanes: i64) -> Value {
+        let index = |p: i64| (self.start + p * self.stride) as usize;
+        match self.kind {
+            IterKind::SimdIter => {
+                Value::Simd((0..lanes)
+                    .map(|lane| self.data[index(position + lane)].clone())
+                    .collect())
+            }
+            _ => self.data[index(position)].clone(),
+        }
+    }
+}
+
+/// Evaluates a For loop. As in the generated code, the first iterator determines the number of
+/// iterations, and a SimdIter and a FringeIter split the elements between them.
+fn eval_for(iters: &[Iter<Type>],
+            builder: &TypedExpr,
+            func: &TypedExpr,
+            env: &mut Env)
+            -> WeldResult<Value> {
+    let mut loop_iters = vec![];
+    let mut total = 0;
+    for (i, iter) in iters.iter().enumerate() {
+        let data = match try!(eval(&iter.data, env)) {
+            Value::Vector(data) => data,
+            other => return weld_err!("For expects vectors to iterate over, got {}", other),
+        };
+        let (start, end, stride) = match (&iter.start, &iter.end, &iter.stride) {
+            (&Some(ref start), &Some(ref end), &Some(ref stride)) => {
+                (try!(as_i64(&try!(eval(start, env)))),
+                 try!(as_i64(&try!(eval(end, env)))),
+                 try!(as_i64(&try!(eval(stride, env)))))
+            }
+            _ => (0, data.len() as i64, 1),
+        };
+        if start < 0 || end < start || stride <= 0 {
+            return weld_err!("Invalid iterator from {} to {} with stride {}", start, end, stride);
+        }
+        if i == 0 {
+            total = (end - start) / stride;
+        }
+        loop_iters.push(LoopIter {
+            data: data,
+            start: start,
+            stride: stride,
+            kind: iter.kind.clone(),
+        });
+    }
+
+    let lanes = match iters[0].kind {
+        IterKind::ScalarIter => env.simd_lanes,
+        _ => loop_lanes(iters, builder, func, env.simd_width),
+    };
+    let outer_lanes = env.simd_lanes;
+    env.simd_lanes = lanes;
+    let result = eval_loop(&loop_iters, total, builder, func, env);
+    env.simd_lanes = outer_lanes;
+    result
+}
+
+/// Returns the number of lanes in the SIMD values of a SimdIter or FringeIter loop, chosen as in
+/// `llvm::loop_simd_lanes`: values of the widest element type used by a SimdIter loop, including
+/// the type it merges into a merger, fill a register, and a FringeIter loop uses the same number
+/// of lanes as the SimdIter loop that it finishes.
+fn loop_lanes(iters: &[Iter<Type>],
+              builder: &TypedExpr,
+              func: &TypedExpr,
+              simd_width: u32)
+              -> usize {
+    if iters[0].kind == IterKind::FringeIter {
+        return match builder.kind {
+            For { iters: ref simd_iters, builder: ref simd_builder, func: ref simd_func }
+                if simd_iters[0].kind == IterKind::SimdIter => {
+                loop_lanes(simd_iters, simd_builder, simd_func, simd_width)
+            }
+            _ => llvm::default_simd_lanes(simd_width) as usize,
+        };
+    }
+    let mut widest = 0;
+    func.traverse(&mut |e| {
+        widest = cmp::max(widest, llvm::simd_elem_bits(&e.ty));
+        if let Lambda { ref params, .. } = e.kind {
+            for p in params.iter() {
+                widest = cmp::max(widest, llvm::simd_elem_bits(&p.ty));
+            }
+        }
+    });
+    if let Builder(Merger(ref elem, _), _) = builder.ty {
+        if let Scalar(kind) = **elem {
+            widest = cmp::max(widest, llvm::simd_elem_bits(&Simd(kind)));
+        }
+    }
+    if widest == 0 {
+        llvm::default_simd_lanes(simd_width) as usize
+    } else {
+        cmp::max(simd_width / widest, 1) as usize
+    }
+}
+
+/// Runs the iterations of a For loop over the evaluated iterators `loop_iters`, whose first
+/// iterator has `total` elements, with as many lanes in SIMD values as `env` specifies.
+fn eval_loop(loop_iters: &[LoopIter],
+             total: i64,
+             builder: &TypedExpr,
+             func: &TypedExpr,
+             env: &mut Env)
+             -> WeldResult<Value> {
+    let kind = loop_iters[0].kind.clone();
+    let lanes = env.simd_lanes as i64;
+    let fringe_start = total / lanes * lanes;
+    let (num_iters, first_position) = match kind {
+        IterKind::ScalarIter => (total, 0),
+        IterKind::SimdIter => (total / lanes, 0),
+        IterKind::FringeIter => (total - fringe_start, fringe_start),
+    };
+    let positions = if kind == IterKind::SimdIter { num_iters * lanes } else { num_iters };
+    if positions > 0 {
+        for iter in loop_iters.iter() {
+            let last = iter.start + (first_position + positions - 1) * iter.stride;
+            if last as usize >= iter.data.len() {
+                return weld_err!("Iterator reads element {} of a vector of length {}",
+                                 last,
+                                 iter.data.len());
+            }
+        }
+    }
+
+    let mut bld = try!(eval(builder, env));
+    let position = |i: i64| match kind {
+        IterKind::SimdIter => i * lanes,
+        _ => first_position + i,
+    };
+    let element = |i: i64| if loop_iters.len() == 1 {
+        loop_iters[0].element(position(i), lanes)
+    } else {
+        Value::Struct(loop_iters.iter().map(|it| it.element(position(i), lanes)).collect())
+    };
+    match func.kind {
+        Lambda { ref params, ref body } => {
+            let names: Vec<Symbol> = params.iter().map(|p| p.name.clone()).collect();
+            for i in 0..num_iters {
+                bld = try!(bind_and_eval(&names, vec![bld, Value::I64(i), element(i)], body, env));
+            }
+        }
+        _ => {
+            match try!(eval(func, env)) {
+                Value::Function(ref closure) => {
+                    let mut closure_env = closure.env.clone();
+                    closure_env.simd_lanes = env.simd_lanes;
+                    for i in 0..num_iters {
+                        bld = try!(bind_and_eval(&closure.params,
+                                                 vec![bld, Value::I64(i), element(i)],
+                                                 &closure.body,
+                                                 &mut closure_env));
+                    }
+                }
+                other => return weld_err!("For expects a function, got {}", other),
+            }
+        }
+    }
+    Ok(bld)
+}
+
+/// Returns a new builder of the given type.
+fn new_builder(ty: &Type, arg: Option<Value>) -> WeldResult<Value> {
+    let state = match *ty {
+        Builder(ref bk, ref annotations) => {
+            match *bk {
+                Appender(_) => BuilderState::Appender(vec![]),
+                Merger(ref elem_ty, op) => {
+                    let init = match arg {
+                        Some(value) => value,
+                        None => try!(merge_identity(op, elem_ty)),
+                    };
+                    BuilderState::Merger(op, init)
+                }
+                DictMerger(_, _, op) => {
+                    let num_keys = annotations.num_keys().or(*annotations.size()).unwrap_or(0);
+                    BuilderState::DictMerger(op,
+                                             Dictionary::new(dictionary_capacity(num_keys) as usize))
+                }
+                GroupMerger(_, _) => BuilderState::GroupMerger(vec![]),
+                VecMerger(_, op) => {
+                    match arg {
+                        Some(Value::Vector(ref elems)) => BuilderState::VecMerger(op, (**elems).clone()),
+                        _ => return weld_err!("VecMerger expects an initial vector"),
+                    }
+                }
+            }
+        }
+        _ => return weld_err!("Non builder type {} found in NewBuilder", print_type(ty)),
+    };
+    Ok(Value::Builder(Rc::new(RefCell::new(state))))
+}
+
+/// Merges a value into a builder. As in the generated code, a SIMD value or a struct of them is
+/// merged one lane at a time.
+fn merge(builder: &Value, value: Value, lanes: usize) -> WeldResult<()> {
+    let state = match *builder {
+        Value::Builder(ref state) => state,
+        ref other => return weld_err!("Cannot merge into {}", other),
+    };
+    let mut state = state.borrow_mut();
+    for value in lane_values(value, lanes) {
+        match *state {
+            BuilderState::Appender(ref mut elems) => elems.push(value),
+            BuilderState::Merger(op, ref mut current) => {
+                *current = try!(binop(op, current, &value));
+            }
+            BuilderState::DictMerger(op, ref mut dict) => {
+                let (key, value) = try!(key_value(value));
+                try!(dict.merge(key, value, op));
+            }
+            BuilderState::GroupMerger(ref mut pairs) => pairs.push(try!(key_value(value))),
+            BuilderState::VecMerger(op, ref mut elems) => {
+                let (index, value) = try!(key_value(value));
+                let i = try!(as_i64(&index));
+                if i < 0 || i as usize >= elems.len() {
+                    return weld_err!("Index {} out of bounds in vecmerger of length {}",
+                                     i,
+                                     elems.len());
+                }
+                elems[i as usize] = try!(binop(op, &elems[i as usize], &value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the result of a builder.
+fn result(builder: &Value) -> WeldResult<Value> {
+    let state = match *builder {
+        Value::Builder(ref state) => state.borrow(),
+        ref other => return weld_err!("Cannot get the result of {}", other),
+    };
+    match *state {
+        BuilderState::Appender(ref elems) => Ok(Value::Vector(Rc::new(elems.clone()))),
+        BuilderState::Merger(_, ref value) => Ok(value.clone()),
+        BuilderState::DictMerger(op, ref dict) => {
+            // The runtime combines the dictionaries of all workers into a new one.
+            let mut result = Dictionary::new(16);
+            for (key, value) in dict.entries() {
+                try!(result.merge(key.clone(), value.clone(), op));
+            }
+            Ok(Value::Dict(Rc::new(result)))
+        }
+        BuilderState::GroupMerger(ref pairs) => {
+            let mut sorted = pairs.clone();
+            sorted.sort_by(|a, b| compare(&a.0, &b.0).cmp(&0));
+            let mut dict = Dictionary::new(16);
+            let mut start = 0;
+            while start < sorted.len() {
+                let mut end = start + 1;
+                while end < sorted.len() && compare(&sorted[start].0, &sorted[end].0) == 0 {
+                    end += 1;
+                }
+                let group = sorted[start..end].iter().map(|p| p.1.clone()).collect();
+                let key = sorted[start].0.clone();
+                let slot = dict.lookup(&key);
+                dict.put(slot, key, Value::Vector(Rc::new(group)));
+                start = end;
+            }
+            Ok(Value::Dict(Rc::new(dict)))
+        }
+        BuilderState::VecMerger(_, ref elems) => Ok(Value::Vector(Rc::new(elems.clone()))),
+    }
+}
+
+/// Returns the identity of a merger's operator, as given by `merger_identity_expr`.
+fn merge_identity(op: BinOpKind, ty: &Type) -> WeldResult<Value> {
+    let identity = match *ty {
+        Scalar(kind) => try!(exprs::merger_identity_expr(op, kind)),
+        _ => None,
+    };
+    match identity {
+        Some(Expr { kind: Literal(ref value), .. }) => Ok(literal_value(value)),
+        _ => weld_err!("Unsupported identity for binary op: {} on {}", op, print_type(ty)),
+    }
+}
+
+/// Splits a {key, value} struct merged into a builder.
+fn key_value(value: Value) -> WeldResult<(Value, Value)> {
+    match value {
+        Value::Struct(mut fields) => {
+            if fields.len() == 2 {
+                let value = fields.pop().unwrap();
+                let key = fields.pop().unwrap();
+                return Ok((key, value));
+            }
+            weld_err!("Expected a pair, got {}", Value::Struct(fields))
+        }
+        other => weld_err!("Expected a pair, got {}", other),
+    }
+}
+
+/// Returns whether a value is a SIMD value or a struct containing one.
+fn contains_simd(value: &Value) -> bool {
+    match *value {
+        Value::Simd(_) => true,
+        Value::Struct(ref fields) => fields.iter().any(contains_simd),
+        _ => false,
+    }
+}
+
+/// Returns one lane of a value containing SIMD values.
+fn lane(value: &Value, lane_index: usize) -> Value {
+    match *value {
+        Value::Simd(ref lanes) => lanes[lane_index].clone(),
+        Value::Struct(ref fields) => {
+            Value::Struct(fields.iter().map(|f| lane(f, lane_index)).collect())
+        }
+        ref other => other.clone(),
+    }
+}
+
+/// Returns the values to merge for a value: each of its `lanes` lanes if it contains SIMD values,
+/// or the value itself otherwise.
+fn lane_values(value: Value, lanes: usize) -> Vec<Value> {
+    if contains_simd(&value) {
+        (0..lanes).map(|i| lane(&value, i)).collect()
+    } else {
+        vec![value]
+    }
+}
+
+/// Applies a scalar function to a value, or to each lane of a SIMD value.
+fn map_lanes(value: &Value, f: &Fn(&Value) -> WeldResult<Value>) -> WeldResult<Value> {
+    match *value {
+        Value::Simd(ref lanes) => {
+            let mut result = vec![];
+            for lane in lanes.iter() {
+                result.push(try!(f(lane)));
+            }
+            Ok(Value::Simd(result))
+        }
+        ref other => f(other),
+    }
+}
+
+fn select(cond: &Value, on_true: Value, on_false: Value) -> WeldResult<Value> {
+    match (cond, on_true, on_false) {
+        (&Value::Bool(c), on_true, on_false) => Ok(if c { on_true } else { on_false }),
+        (&Value::Simd(ref conds), Value::Simd(on_true), Value::Simd(on_false)) => {
+            let mut result = vec![];
+            for ((c, t), f) in conds.iter().zip(on_true).zip(on_false) {
+                result.push(try!(select(c, t, f)));
+            }
+            Ok(Value::Simd(result))
+        }
+        (other, _, _) => weld_err!("Select expects a bool condition, got {}", other),
+    }
+}
+
+fn literal_value(lit: &LiteralKind) -> Value {
+    match *lit {
+        BoolLiteral(v) => Value::Bool(v),
+        I8Literal(v) => Value::I8(v),
+        I32Literal(v) => Value::I32(v),
+        I64Literal(v) => Value::I64(v),
+        F32Literal(v) => Value::F32(v),
+        F64Literal(v) => Value::F64(v),
+    }
+}
+
+fn as_i64(value: &Value) -> WeldResult<i64> {
+    match *value {
+        Value::I64(v) => Ok(v),
+        ref other => weld_err!("Expected an i64, got {}", other),
+    }
+}
+
+/// Applies a binary operator to integers, wrapping around on overflow like the generated code.
+macro_rules! int_binop {
+    ($op:expr, $variant:path, $a:expr, $b:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinOpKind::Add => Ok($variant(a.wrapping_add(b))),
+            BinOpKind::Subtract => Ok($variant(a.wrapping_sub(b))),
+            BinOpKind::Multiply => Ok($variant(a.wrapping_mul(b))),
+            BinOpKind::Divide | BinOpKind::Modulo if b == 0 => weld_err!("Division by zero"),
+            BinOpKind::Divide => Ok($variant(a.wrapping_div(b))),
+            BinOpKind::Modulo => Ok($variant(a.wrapping_rem(b))),
+            BinOpKind::Equal => Ok(Value::Bool(a == b)),
+            BinOpKind::NotEqual => Ok(Value::Bool(a != b)),
+            BinOpKind::LessThan => Ok(Value::Bool(a < b)),
+            BinOpKind::LessThanOrEqual => Ok(Value::Bool(a <= b)),
+            BinOpKind::GreaterThan => Ok(Value::Bool(a > b)),
+            BinOpKind::GreaterThanOrEqual => Ok(Value::Bool(a >= b)),
+            BinOpKind::BitwiseAnd => Ok($variant(a & b)),
+            BinOpKind::BitwiseOr => Ok($variant(a | b)),
+            BinOpKind::Xor => Ok($variant(a ^ b)),
+            BinOpKind::LogicalAnd | BinOpKind::LogicalOr => {
+                weld_err!("Unsupported binary op: {} on integers", $op)
+            }
+        }
+    }}
+}
+
+/// Applies a binary operator to floats. As with LLVM's ordered comparisons, `!=` is false if
+/// either value is NaN.
+macro_rules! float_binop {
+    ($op:expr, $variant:path, $a:expr, $b:expr) => {{
+        let (a, b) = ($a, $b);
+        match $op {
+            BinOpKind::Add => Ok($variant(a + b)),
+            BinOpKind::Subtract => Ok($variant(a - b)),
+            BinOpKind::Multiply => Ok($variant(a * b)),
+            BinOpKind::Divide => Ok($variant(a / b)),
+            BinOpKind::Modulo => Ok($variant(a % b)),
+            BinOpKind::Equal => Ok(Value::Bool(a == b)),
+            BinOpKind::NotEqual => Ok(Value::Bool(a < b || a > b)),
+            BinOpKind::LessThan => Ok(Value::Bool(a < b)),
+            BinOpKind::LessThanOrEqual => Ok(Value::Bool(a <= b)),
+            BinOpKind::GreaterThan => Ok(Value::Bool(a > b)),
+            BinOpKind::GreaterThanOrEqual => Ok(Value::Bool(a >= b)),
+            _ => weld_err!("Unsupported binary op: {} on floats", $op),
+        }
+    }}
+}
+
+fn binop(op: BinOpKind, left: &Value, right: &Value) -> WeldResult<Value> {
+    match (left, right) {
+        (&Value::Bool(a), &Value::Bool(b)) => {
+            match op {
+                BinOpKind::Equal => Ok(Value::Bool(a == b)),
+                BinOpKind::NotEqual => Ok(Value::Bool(a != b)),
+                BinOpKind::LogicalAnd | BinOpKind::BitwiseAnd => Ok(Value::Bool(a & b)),
+                BinOpKind::LogicalOr | BinOpKind::BitwiseOr => Ok(Value::Bool(a | b)),
+                BinOpKind::Xor => Ok(Value::Bool(a ^ b)),
+                _ => weld_err!("Unsupported binary op: {} on bools", op),
+            }
+        }
+        (&Value::I8(a), &Value::I8(b)) => int_binop!(op, Value::I8, a, b),
+        (&Value::I32(a), &Value::I32(b)) => int_binop!(op, Value::I32, a, b),
+        (&Value::I64(a), &Value::I64(b)) => int_binop!(op, Value::I64, a, b),
+        (&Value::F32(a), &Value::F32(b)) => float_binop!(op, Value::F32, a, b),
+        (&Value::F64(a), &Value::F64(b)) => float_binop!(op, Value::F64, a, b),
+        (&Value::Simd(ref a), &Value::Simd(ref b)) => {
+            let mut result = vec![];
+            for (x, y) in a.iter().zip(b.iter()) {
+                result.push(try!(binop(op, x, y)));
+            }
+            Ok(Value::Simd(result))
+        }
+        (&Value::Vector(_), &Value::Vector(_)) => {
+            let cmp = compare(left, right);
+            match op {
+                BinOpKind::Equal => Ok(Value::Bool(cmp == 0)),
+                BinOpKind::NotEqual => Ok(Value::Bool(cmp != 0)),
+                BinOpKind::LessThan => Ok(Value::Bool(cmp == -1)),
+                BinOpKind::LessThanOrEqual => Ok(Value::Bool(cmp != 1)),
+                BinOpKind::GreaterThan => Ok(Value::Bool(cmp == 1)),
+                BinOpKind::GreaterThanOrEqual => Ok(Value::Bool(cmp != -1)),
+                _ => weld_err!("Unsupported binary op: {} on vectors", op),
+            }
+        }
+        _ => weld_err!("Unsupported binary op: {} on {} and {}", op, left, right),
+    }
+}
+
+fn negate(value: &Value) -> WeldResult<Value> {
+    match *value {
+        Value::I8(v) => Ok(Value::I8(v.wrapping_neg())),
+        Value::I32(v) => Ok(Value::I32(v.wrapping_neg())),
+        Value::I64(v) => Ok(Value::I64(v.wrapping_neg())),
+        Value::F32(v) => Ok(Value::F32(-v)),
+        Value::F64(v) => Ok(Value::F64(-v)),
+        ref other => weld_err!("Cannot negate {}", other),
+    }
+}
+
+fn unaryop(op: UnaryOpKind, value: &Value) -> WeldResult<Value> {
+    match (op, value) {
+        (UnaryOpKind::Exp, &Value::F32(v)) => Ok(Value::F32(v.exp())),
+        (UnaryOpKind::Exp, &Value::F64(v)) => Ok(Value::F64(v.exp())),
+        (UnaryOpKind::Log, &Value::F32(v)) => Ok(Value::F32(v.ln())),
+        (UnaryOpKind::Log, &Value::F64(v)) => Ok(Value::F64(v.ln())),
+        (UnaryOpKind::Sqrt, &Value::F32(v)) => Ok(Value::F32(v.sqrt())),
+        (UnaryOpKind::Sqrt, &Value::F64(v)) => Ok(Value::F64(v.sqrt())),
+        (UnaryOpKind::Erf, &Value::F32(v)) => Ok(Value::F32(unsafe { erff(v) })),
+        (UnaryOpKind::Erf, &Value::F64(v)) => Ok(Value::F64(unsafe { erf(v) })),
+        (_, other) => weld_err!("Unsupported unary op: {} on {}", op, other),
+    }
+}
+
+/// Casts a scalar to another kind with the same semantics as the generated code; in particular,
+/// casting an integer to a bool keeps its lowest bit.
+fn cast(kind: ScalarKind, value: &Value) -> WeldResult<Value> {
+    let float = match *value {
+        Value::F32(v) => Some(v as f64),
+        Value::F64(v) => Some(v),
+        _ => None,
+    };
+    if let Some(v) = float {
+        return Ok(match kind {
+            Bool => Value::Bool((v as i64) & 1 == 1),
+            I8 => Value::I8(v as i8),
+            I32 => Value::I32(v as i32),
+            I64 => Value::I64(v as i64),
+            F32 => Value::F32(v as f32),
+            F64 => Value::F64(v),
+        });
+    }
+    let v = match *value {
+        Value::Bool(v) => v as i64,
+        Value::I8(v) => v as i64,
+        Value::I32(v) => v as i64,
+        Value::I64(v) => v,
+        ref other => return weld_err!("Cannot cast {} to {}", other, kind),
+    };
+    Ok(match kind {
+        Bool => Value::Bool(v & 1 == 1),
+        I8 => Value::I8(v as i8),
+        I32 => Value::I32(v as i32),
+        I64 => Value::I64(v),
+        F32 => Value::F32(v as f32),
+        F64 => Value::F64(v as f64),
+    })
+}
+
+/// Same as Boost's hash_combine, as in the runtime.
+fn hash_combine(seed: u64, value: u64) -> u64 {
+    seed ^ value.wrapping_add(2654435769).wrapping_add(seed << 6).wrapping_add(seed >> 2)
+}
+
+/// Returns the hash of a value used by dictionaries in the runtime.
+fn hash(value: &Value) -> u64 {
+    match *value {
+        Value::Bool(v) => v as u64,
+        Value::I8(v) => v as u8 as u64,
+        Value::I32(v) => v as u32 as u64,
+        Value::I64(v) => v as u64,
+        Value::F32(v) => v.to_bits() as u64,
+        Value::F64(v) => v.to_bits(),
+        Value::Struct(ref fields) => {
+            fields.iter()
+                .filter(|f| !is_simd(f))
+                .fold(0, |h, f| hash_combine(h, hash(f)))
+        }
+        Value::Vector(ref elems) => elems.iter().fold(0, |h, e| hash_combine(h, hash(e))),
+        _ => 0,
+    }
+}
+
+fn is_simd(value: &Value) -> bool {
+    match *value {
+        Value::Simd(_) => true,
+        _ => false,
+    }
+}
+
+/// Compares two values as the runtime does, returning -1, 0 or 1. Vectors are compared
+/// lexicographically, and dictionaries and builders are never equal.
+fn compare(a: &Value, b: &Value) -> i32 {
+    fn order<T: PartialOrd>(a: T, b: T) -> i32 {
+        if a == b {
+            0
+        } else if a < b {
+            -1
+        } else {
+            1
+        }
+    }
+    match (a, b) {
+        (&Value::Bool(a), &Value::Bool(b)) => order(a, b),
+        (&Value::I8(a), &Value::I8(b)) => order(a, b),
+        (&Value::I32(a), &Value::I32(b)) => order(a, b),
+        (&Value::I64(a), &Value::I64(b)) => order(a, b),
+        (&Value::F32(a), &Value::F32(b)) => order(a, b),
+        (&Value::F64(a), &Value::F64(b)) => order(a, b),
+        (&Value::Struct(ref a), &Value::Struct(ref b)) => {
+            for (x, y) in a.iter().zip(b.iter()) {
+                if is_simd(x) {
+                    continue;
+                }
+                let cmp = compare(x, y);
+                if cmp != 0 {
+                    return cmp;
+                }
+            }
+            0
+        }
+        (&Value::Vector(ref a), &Value::Vector(ref b)) => {
+            for (x, y) in a.iter().zip(b.iter()) {
+                let cmp = compare(x, y);
+                if cmp != 0 {
+                    return cmp;
+                }
+            }
+            order(a.len(), b.len())
+        }
+        _ => -1,
+    }
+}
+
+/// Calls a C UDF found with `dlsym`, passing pointers to its arguments and to memory for its
+/// result.
+unsafe fn call_udf(name: &str,
+                   args: &[Value],
+                   arg_tys: &[Type],
+                   return_ty: &Type)
+                   -> WeldResult<Value> {
+    let c_name = match CString::new(name) {
+        Ok(n) => n,
+        Err(_) => return weld_err!("Invalid UDF name {}", name),
+    };
+    let func = libc::dlsym(libc::RTLD_DEFAULT, c_name.as_ptr());
+    if func.is_null() {
+        return weld_err!("UDF {} not found", name);
+    }
+
+    let mut arg_memory = vec![];
+    for (arg, ty) in args.iter().zip(arg_tys.iter()) {
+        arg_memory.push(try!(to_memory(arg, ty)));
+    }
+    let p: Vec<*const c_void> = arg_memory.iter().map(|m| m.data()).collect();
+    let mut output = ValueMemory {
+        buffers: vec![],
+        data: ptr::null_mut(),
+    };
+    let (size, _) = try!(layout(return_ty));
+    let out = output.alloc(size) as *const c_void;
+
+    type P = *const c_void;
+    match p.len() {
+        0 => mem::transmute::<_, extern "C" fn(P)>(func)(out),
+        1 => mem::transmute::<_, extern "C" fn(P, P)>(func)(p[0], out),
+        2 => mem::transmute::<_, extern "C" fn(P, P, P)>(func)(p[0], p[1], out),
+        3 => mem::transmute::<_, extern "C" fn(P, P, P, P)>(func)(p[0], p[1], p[2], out),
+        4 => {
+            mem::transmute::<_, extern "C" fn(P, P, P, P, P)>(func)(p[0], p[1], p[2], p[3], out)
+        }
+        5 => {
+            mem::transmute::<_, extern "C" fn(P, P, P, P, P, P)>(func)(p[0], p[1], p[2], p[3],
+                                                                       p[4], out)
+        }
+        6 => {
+            mem::transmute::<_, extern "C" fn(P, P, P, P, P, P, P)>(func)(p[0], p[1], p[2], p[3],
+                                                                          p[4], p[5], out)
+        }
+        n => return weld_err!("UDF {} has {} arguments; at most 6 are supported", name, n),
+    }
+    read_value(out as *const u8, return_ty)
+}
+
+/// Returns the size and alignment of a type in the runtime memory layout.
+fn layout(ty: &Type) -> WeldResult<(usize, usize)> {
+    match *ty {
+        Scalar(kind) => {
+            let size = match kind {
+                Bool | I8 => 1,
+                I32 | F32 => 4,
+                I64 | F64 => 8,
+            };
+            Ok((size, size))
+        }
+        Vector(_) => Ok((16, 8)),
+        Dict(_, _) => Ok((24, 8)),
+        Struct(ref fields) => {
+            let (_, size, align) = try!(struct_layout(fields));
+            Ok((size, align))
+        }
+        _ => weld_err!("Values of type {} cannot be passed in memory", print_type(ty)),
+    }
+}
+
+/// Returns the offsets of the fields of a struct, along with its size and alignment.
+fn struct_layout(fields: &[Type]) -> WeldResult<(Vec<usize>, usize, usize)> {
+    let round_up = |n: usize, align: usize| (n + align - 1) / align * align;
+    let mut offsets = vec![];
+    let mut offset = 0;
+    let mut align = 1;
+    for field in fields.iter() {
+        let (field_size, field_align) = try!(layout(field));
+        offset = round_up(offset, field_align);
+        offsets.push(offset);
+        offset += field_size;
+        align = cmp::max(align, field_align);
+    }
+    Ok((offsets, round_up(offset, align), align))
+}
+
+/// Returns the type of the entries of a dictionary, which start with a flag marking them as filled.
+fn dict_entry_type(key: &Type, value: &Type) -> Type {
+    Struct(vec![Scalar(Bool), key.clone(), value.clone()])
+}
+
+/// Reads a value of the given type from memory in the runtime layout.
+unsafe fn read_value(ptr: *const u8, ty: &Type) -> WeldResult<Value> {
+    match *ty {
+        Scalar(Bool) => Ok(Value::Bool(*ptr & 1 == 1)),
+        Scalar(I8) => Ok(Value::I8(*(ptr as *const i8))),
+        Scalar(I32) => Ok(Value::I32(*(ptr as *const i32))),
+        Scalar(I64) => Ok(Value::I64(*(ptr as *const i64))),
+        Scalar(F32) => Ok(Value::F32(*(ptr as *const f32))),
+        Scalar(F64) => Ok(Value::F64(*(ptr as *const f64))),
+        Vector(ref elem) => {
+            let data = *(ptr as *const *const u8);
+            let len = *(ptr.offset(8) as *const i64);
+            let (elem_size, _) = try!(layout(elem));
+            let mut elems = vec![];
+            for i in 0..len as usize {
+                elems.push(try!(read_value(data.offset((i * elem_size) as isize), elem)));
+            }
+            Ok(Value::Vector(Rc::new(elems)))
+        }
+        Dict(ref key, ref value) => {
+            let entries = *(ptr as *const *const u8);
+            let size = *(ptr.offset(8) as *const i64);
+            let capacity = *(ptr.offset(16) as *const i64);
+            let entry_ty = dict_entry_type(key, value);
+            let (entry_size, _) = try!(layout(&entry_ty));
+            let mut dict = Dictionary::new(capacity as usize);
+            dict.size = size as usize;
+            for i in 0..capacity as usize {
+                if let Value::Struct(mut fields) =
+                    try!(read_value(entries.offset((i * entry_size) as isize), &entry_ty)) {
+                    if let Value::Bool(true) = fields[0] {
+                        let v = fields.pop().unwrap();
+                        let k = fields.pop().unwrap();
+                        dict.slots[i] = Some((k, v));
+                    }
+                }
+            }
+            Ok(Value::Dict(Rc::new(dict)))
+        }
+        Struct(ref fields) => {
+            let (offsets, _, _) = try!(struct_layout(fields));
+            let mut values = vec![];
+            for (field, offset) in fields.iter().zip(offsets) {
+                values.push(try!(read_value(ptr.offset(offset as isize), field)));
+            }
+            Ok(Value::Struct(values))
+        }
+        _ => weld_err!("Values of type {} cannot be passed in memory", print_type(ty)),
+    }
+}
+
+/// Copies a value into memory in the runtime layout.
+fn to_memory(value: &Value, ty: &Type) -> WeldResult<ValueMemory> {
+    let mut memory = ValueMemory {
+        buffers: vec![],
+        data: ptr::null_mut(),
+    };
+    let (size, _) = try!(layout(ty));
+    let data = memory.alloc(size);
+    unsafe {
+        try!(write_value(value, ty, data, &mut memory));
+    }
+    memory.data = data;
+    Ok(memory)
+}
+
+/// Writes a value into memory in the runtime layout, allocating the vectors and dictionaries it
+/// points to from `memory`.
+unsafe fn write_value(value: &Value,
+                      ty: &Type,
+                      ptr: *mut u8,
+                      memory: &mut ValueMemory)
+                      -> WeldResult<()> {
+    match (value, ty) {
+        (&Value::Bool(v), &Scalar(Bool)) => *ptr = v as u8,
+        (&Value::I8(v), &Scalar(I8)) => *(ptr as *mut i8) = v,
+        (&Value::I32(v), &Scalar(I32)) => *(ptr as *mut i32) = v,
+        (&Value::I64(v), &Scalar(I64)) => *(ptr as *mut i64) = v,
+        (&Value::F32(v), &Scalar(F32)) => *(ptr as *mut f32) = v,
+        (&Value::F64(v), &Scalar(F64)) => *(ptr as *mut f64) = v,
+        (&Value::Vector(ref elems), &Vector(ref elem_ty)) => {
+            let (elem_size, _) = try!(layout(elem_ty));
+            let data = memory.alloc(elem_size * elems.len());
+            for (i, elem) in elems.iter().enumerate() {
+                try!(write_value(elem, elem_ty, data.offset((i * elem_size) as isize), memory));
+            }
+            *(ptr as *mut *mut u8) = data;
+            *(ptr.offset(8) as *mut i64) = elems.len() as i64;
+        }
+        (&Value::Dict(ref dict), &Dict(ref key_ty, ref value_ty)) => {
+            let entry_ty = dict_entry_type(key_ty, value_ty);
+            let (entry_size, _) = try!(layout(&entry_ty));
+            let entries = memory.alloc(entry_size * dict.slots.len());
+            for (i, slot) in dict.slots.iter().enumerate() {
+                if let Some((ref k, ref v)) = *slot {
+                    let entry = Value::Struct(vec![Value::Bool(true), k.clone(), v.clone()]);
+                    try!(write_value(&entry,
+                                     &entry_ty,
+                                     entries.offset((i * entry_size) as isize),
+                                     memory));
+                }
+            }
+            *(ptr as *mut *mut u8) = entries;
+            *(ptr.offset(8) as *mut i64) = dict.size as i64;
+            *(ptr.offset(16) as *mut i64) = dict.slots.len() as i64;
+        }
+        (&Value::Struct(ref values), &Struct(ref fields)) if values.len() == fields.len() => {
+            let (offsets, _, _) = try!(struct_layout(fields));
+            for ((value, field), offset) in values.iter().zip(fields.iter()).zip(offsets) {
+                try!(write_value(value, field, ptr.offset(offset as isize), memory));
+            }
+        }
+        _ => return weld_err!("Cannot store {} as a value of type {}", value, print_type(ty)),
+    }
+    Ok(())
+}
+
+/// Writes a comma-separated list of values.
+fn write_list<'a, I>(f: &mut fmt::Formatter, values: I) -> fmt::Result
+    where I: Iterator<Item = &'a Value>
+{
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            try!(write!(f, ","));
+        }
+        try!(write!(f, "{}", value));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Value {
+    /// Formats a value like the literal expression that would construct it, with dictionaries
+    /// written as `{key->value,...}`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Bool(v) => write!(f, "{}", print_literal(&BoolLiteral(v))),
+            Value::I8(v) => write!(f, "{}", print_literal(&I8Literal(v))),
+            Value::I32(v) => write!(f, "{}", print_literal(&I32Literal(v))),
+            Value::I64(v) => write!(f, "{}", print_literal(&I64Literal(v))),
+            Value::F32(v) => write!(f, "{}", print_literal(&F32Literal(v))),
+            Value::F64(v) => write!(f, "{}", print_literal(&F64Literal(v))),
+            Value::Simd(ref lanes) => {
+                try!(write!(f, "<"));
+                try!(write_list(f, lanes.iter()));
+                write!(f, ">")
+            }
+            Value::Vector(ref elems) => {
+                try!(write!(f, "["));
+                try!(write_list(f, elems.iter()));
+                write!(f, "]")
+            }
+            Value::Struct(ref fields) => {
+                try!(write!(f, "{{"));
+                try!(write_list(f, fields.iter()));
+                write!(f, "}}")
+            }
+            Value::Dict(ref dict) => {
+                try!(write!(f, "{{"));
+                for (i, (key, value)) in dict.entries().into_iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write!(f, "{}->{}", key, value));
+                }
+                write!(f, "}}")
+            }
+            Value::Builder(_) => write!(f, "<builder>"),
+            Value::Function(_) => write!(f, "<function>"),
+        }
+    }
+}
@@ -15,6 +15,7 @@ use std::collections::HashMap;
 use std::error::Error;
 use libc::{c_char, c_void};
 use std::ffi::{CStr, CString};
+use std::path::Path;
 
 use weld_common::WeldRuntimeErrno;
 
@@ -26,6 +27,7 @@ macro_rules! weld_err {
 }
 
 pub mod ast;
+pub mod cgen;
 pub mod code_builder;
 pub mod error;
 pub mod llvm;
@@ -35,13 +37,17 @@ pub mod parser;
 pub mod partial_types;
 pub mod pretty_print;
 pub mod program;
+pub mod shared_library;
 pub mod sir;
+pub mod sir_transforms;
 pub mod tokenizer;
 pub mod transforms;
 pub mod type_inference;
 pub mod conf;
 pub mod util;
 pub mod exprs;
+pub mod interpreter;
+pub mod verifier;
 
 // TODO not the right place for this.
 pub mod vectorizer;
@@ -50,8 +56,46 @@ extern "C" {
     pub fn free(ptr: *mut c_void);
 }
 
-/// A clean alias for a compiled LLVM module.
-pub type WeldModule = easy_ll::CompiledModule;
+/// The compiled code of a module, from whichever backend generated it.
+enum ModuleCode {
+    /// An LLVM module compiled in memory.
+    Llvm(easy_ll::CompiledModule),
+    /// A shared library loaded into the process.
+    Library(shared_library::SharedLibrary),
+}
+
+/// A compiled module, along with statistics about the optimization passes that produced it.
+pub struct WeldModule {
+    code: ModuleCode,
+    pass_stats: Vec<passes::PassStats>,
+    pass_stats_report: CString,
+}
+
+impl WeldModule {
+    fn new(code: ModuleCode, pass_stats: Vec<passes::PassStats>) -> WeldModule {
+        let report: Vec<String> = pass_stats.iter().map(|s| s.to_string()).collect();
+        WeldModule {
+            code: code,
+            pass_stats: pass_stats,
+            pass_stats_report: CString::new(report.join("\n")).unwrap(),
+        }
+    }
+
+    /// Returns statistics for each optimization pass run while compiling this module, in the
+    /// order in which the passes ran.
+    pub fn pass_stats(&self) -> &[passes::PassStats] {
+        &self.pass_stats
+    }
+
+    /// Calls the module's `run` function on a pointer to its input arguments, returning a pointer
+    /// to its output arguments.
+    fn run(&self, arg: i64) -> i64 {
+        match self.code {
+            ModuleCode::Llvm(ref module) => module.run(arg),
+            ModuleCode::Library(ref library) => library.run(arg),
+        }
+    }
+}
 
 /// An error passed as an opaque pointer using the runtime API.
 pub struct WeldError {
@@ -241,8 +285,7 @@ pub unsafe extern "C" fn weld_module_compile(code: *const c_char,
         return std::ptr::null_mut();
     }
 
-    let module = llvm::compile_program(
-        &parsed.unwrap(), &conf.optimization_passes, conf.log_level);
+    let module = llvm::compile_program(&parsed.unwrap(), &conf);
 
     if let Err(ref e) = module {
         err.errno = WeldRuntimeErrno::CompileError;
@@ -252,6 +295,39 @@ pub unsafe extern "C" fn weld_module_compile(code: *const c_char,
     Box::into_raw(Box::new(module.unwrap()))
 }
 
+#[no_mangle]
+/// Loads a module from a shared library written by `weld_module_compile` with the
+/// `weld.compile.outputFile` configuration, without compiling the program again.
+pub unsafe extern "C" fn weld_module_load(path: *const c_char,
+                                          err_ptr: *mut WeldError)
+                                          -> *mut WeldModule {
+    assert!(!path.is_null());
+    assert!(!err_ptr.is_null());
+    let mut err = &mut *err_ptr;
+
+    let path = CStr::from_ptr(path);
+    let path = path.to_str().unwrap();
+
+    // The library's references to the runtime are resolved against the loaded runtime library.
+    if let Err(e) = util::load_runtime_library() {
+        err.errno = WeldRuntimeErrno::RuntimeLibraryError;
+        err.message = CString::new(e).unwrap();
+        return std::ptr::null_mut();
+    }
+
+    match shared_library::SharedLibrary::open_copy(Path::new(path)) {
+        Ok(library) => {
+            let module = WeldModule::new(ModuleCode::Library(library), vec![]);
+            Box::into_raw(Box::new(module))
+        }
+        Err(e) => {
+            err.errno = WeldRuntimeErrno::CompileError;
+            err.message = CString::new(e.description().to_string()).unwrap();
+            std::ptr::null_mut()
+        }
+    }
+}
+
 #[no_mangle]
 /// Runs a module.
 ///
@@ -311,12 +387,20 @@ pub unsafe extern "C" fn weld_module_run(module: *mut WeldModule,
     }
 }
 
+#[no_mangle]
+/// Returns statistics for each optimization pass run while compiling a module, one pass per line.
+pub unsafe extern "C" fn weld_module_pass_stats(module: *const WeldModule) -> *const c_char {
+    assert!(!module.is_null());
+    let module = &*module;
+    module.pass_stats_report.as_ptr() as *const c_char
+}
+
 #[no_mangle]
 /// Frees a module.
 ///
 /// Freeing a module does not free the memory it may have allocated. Values returned by the module
 /// must be freed explicitly using `weld_value_free`.
-pub unsafe extern "C" fn weld_module_free(ptr: *mut easy_ll::CompiledModule) {
+pub unsafe extern "C" fn weld_module_free(ptr: *mut WeldModule) {
     if ptr.is_null() {
         return;
     }
@@ -1,6 +1,9 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use easy_ll;
 
@@ -11,28 +14,37 @@ use super::ast::Type::*;
 use super::ast::LiteralKind::*;
 use super::ast::ScalarKind::*;
 use super::ast::BuilderKind::*;
+use super::cgen;
 use super::code_builder::CodeBuilder;
+use super::conf::Backend;
 use super::conf::LogLevel;
+use super::conf::{OutputFile, OutputKind};
+use super::conf::ParsedConf;
+use super::conf::DEFAULT_GRAIN_SIZE;
 use super::error::*;
 use super::macro_processor;
-use super::passes::*;
 use super::pretty_print::*;
 use super::program::Program;
 use super::sir;
 use super::sir::*;
 use super::sir::Statement::*;
 use super::sir::Terminator::*;
+use super::shared_library;
+use super::shared_library::SharedLibrary;
+use super::sir_transforms;
 use super::transforms;
 use super::type_inference;
 use super::util::IdGenerator;
 use super::util::MERGER_BC;
+use super::verifier;
+use super::ModuleCode;
+use super::WeldModule;
 
 #[cfg(test)]
 use super::parser::*;
 
 static PRELUDE_CODE: &'static str = include_str!("resources/prelude.ll");
 static VECTOR_CODE: &'static str = include_str!("resources/vector.ll");
-static VVECTOR_CODE: &'static str = include_str!("resources/vvector.ll");
 static MERGER_CODE: &'static str = include_str!("resources/merger/merger.ll");
 static DICTIONARY_CODE: &'static str = include_str!("resources/dictionary.ll");
 static DICTMERGER_CODE: &'static str = include_str!("resources/dictmerger.ll");
@@ -56,48 +68,143 @@ pub struct WeldOutputArgs {
     pub errno: WeldRuntimeErrno,
 }
 
-/// Generate a compiled LLVM module from a program whose body is a function.
+/// Compile a program whose body is a function into a module, using the backend chosen by the
+/// configuration. If the configuration names an output file, the program is also written to it.
 pub fn compile_program(program: &Program,
-                       opt_passes: &Vec<Pass>,
-                       log_level: LogLevel)
-                       -> WeldResult<easy_ll::CompiledModule> {
+                       conf: &ParsedConf)
+                       -> WeldResult<WeldModule> {
     let mut expr = try!(macro_processor::process_program(program));
-    if log_level >= LogLevel::Debug {
+    if conf.log_level >= LogLevel::Debug {
         println!("After macro substitution:\n{}\n", print_expr(&expr));
     }
 
     let _ = try!(transforms::uniquify(&mut expr));
     try!(type_inference::infer_types(&mut expr));
     let mut expr = try!(expr.to_typed());
-    if log_level >= LogLevel::Debug {
+    if conf.log_level >= LogLevel::Debug {
         println!("After type inference:\n{}\n", print_expr(&expr));
     }
 
-    for pass in opt_passes {
-        try!(pass.transform(&mut expr));
-        if log_level >= LogLevel::Debug {
-            println!("After {} pass:\n{}", pass.pass_name(), print_expr(&expr));
+    if conf.verify_passes {
+        if let Err(e) = verifier::verify(&expr) {
+            return weld_err!("Verification failed after type inference: {}", e);
+        }
+    }
+
+    let mut pass_stats = vec![];
+    for pass in &conf.optimization_passes {
+        let stats = try!(pass.transform(&mut expr, conf.max_pass_iterations));
+        if conf.log_level >= LogLevel::Debug {
+            println!("After {} pass ({}):\n{}", pass.pass_name(), stats, print_expr(&expr));
+        }
+        pass_stats.push(stats);
+        if conf.verify_passes {
+            if let Err(e) = verifier::verify(&expr) {
+                return weld_err!("Verification failed after {} pass: {}", pass.pass_name(), e);
+            }
         }
     }
 
     try!(transforms::uniquify(&mut expr));
-    if log_level >= LogLevel::Debug {
+    if conf.log_level >= LogLevel::Debug {
         println!("After uniquify:\n{}\n", print_expr(&expr));
     }
 
-    let sir_prog = try!(sir::ast_to_sir(&expr));
-    if log_level >= LogLevel::Debug {
+    // Lengths are tracked by symbol, so this relies on symbols being unique.
+    try!(transforms::check_zip_lengths(&mut expr));
+
+    let mut sir_prog = try!(sir::ast_to_sir(&expr));
+    if conf.log_level >= LogLevel::Debug {
         println!("SIR program:\n{}\n", &sir_prog);
     }
+    if conf.verify_passes {
+        if let Err(e) = verifier::verify_sir(&sir_prog) {
+            return weld_err!("Verification failed after SIR generation: {}", e);
+        }
+    }
 
-    let mut gen = LlvmGenerator::new();
-    try!(gen.add_function_on_pointers("run", &sir_prog));
-    let llvm_code = gen.result();
-    if log_level >= LogLevel::Debug {
-        println!("LLVM program:\n{}\n", &llvm_code);
+    for &(name, pass) in sir_transforms::SIR_PASSES.iter() {
+        pass(&mut sir_prog);
+        if conf.log_level >= LogLevel::Debug {
+            println!("After SIR {} pass:\n{}\n", name, &sir_prog);
+        }
+        if conf.verify_passes {
+            if let Err(e) = verifier::verify_sir(&sir_prog) {
+                return weld_err!("Verification failed after SIR {} pass: {}", name, e);
+            }
+        }
     }
 
-    Ok(try!(easy_ll::compile_module(&llvm_code, Some(MERGER_BC))))
+    let code = match conf.backend {
+        Backend::Llvm => {
+            let mut gen = LlvmGenerator::new();
+            gen.default_grain_size = conf.grain_size;
+            if let Some(width) = conf.simd_width {
+                gen.simd_width = width;
+            }
+            try!(gen.add_function_on_pointers("run", &sir_prog));
+            let llvm_code = gen.result();
+            if conf.log_level >= LogLevel::Debug {
+                println!("LLVM program:\n{}\n", &llvm_code);
+            }
+            match conf.output_file {
+                Some(ref output) => {
+                    let compile_object = |object: &Path| {
+                        let path = object.to_string_lossy();
+                        Ok(try!(easy_ll::compile_module_to_object(&llvm_code,
+                                                                  Some(MERGER_BC),
+                                                                  &path)))
+                    };
+                    try!(compile_output_file(output, compile_object))
+                }
+                None => {
+                    ModuleCode::Llvm(try!(easy_ll::compile_module(&llvm_code, Some(MERGER_BC))))
+                }
+            }
+        }
+        Backend::C => {
+            let c_code = try!(cgen::generate_code(&sir_prog, conf));
+            let debug = conf.log_level >= LogLevel::Debug;
+            match conf.output_file {
+                Some(ref output) => {
+                    let compile_object = |object: &Path| {
+                        cgen::compile_to_object(&c_code, object, debug)
+                    };
+                    try!(compile_output_file(output, compile_object))
+                }
+                None => ModuleCode::Library(try!(cgen::compile_module(&c_code, debug))),
+            }
+        }
+    };
+    Ok(WeldModule::new(code, pass_stats))
+}
+
+/// Compiles a program into the output file chosen by the configuration with `compile_object`,
+/// which writes an object file to the given path, and loads it. Object files are written directly
+/// and loaded by linking them into a temporary shared library, so the program is only compiled
+/// once. Shared libraries are linked from a temporary object file and loaded from a copy, since a
+/// module compiled earlier may be loaded from the same path.
+fn compile_output_file<F>(output: &OutputFile, compile_object: F) -> WeldResult<ModuleCode>
+    where F: FnOnce(&Path) -> WeldResult<()>
+{
+    let path = PathBuf::from(&output.path);
+    match output.kind {
+        OutputKind::Object => {
+            try!(compile_object(&path));
+            let library = shared_library::temp_path("so");
+            let result = shared_library::link(&path, &library)
+                .and_then(|_| SharedLibrary::open(&library.to_string_lossy()));
+            let _ = fs::remove_file(&library);
+            Ok(ModuleCode::Library(try!(result)))
+        }
+        OutputKind::SharedLibrary => {
+            let object = shared_library::temp_path("o");
+            let result = compile_object(&object).and_then(|_| shared_library::link(&object, &path));
+            let _ = fs::remove_file(&object);
+            try!(result);
+            Ok(ModuleCode::Library(try!(SharedLibrary::open_copy(&path))))
+        }
+    }
 }
 
 /// Generates a small program which, when called with a `run_id`, frees
@@ -110,7 +217,8 @@ pub fn generate_runtime_interface_module() -> WeldResult<easy_ll::CompiledModule
 /// Generates LLVM code for one or more modules.
 pub struct LlvmGenerator {
     /// LLVM type name of the form %s0, %s1, etc for each struct generated.
-    struct_names: HashMap<Vec<Type>, String>,
+    /// Structs of SIMD values are keyed by their number of lanes as well, and other structs by 0.
+    struct_names: HashMap<(Vec<Type>, u32), String>,
     struct_ids: IdGenerator,
 
     /// LLVM type name of the form %v0, %v1, etc for each vec generated.
@@ -128,8 +236,11 @@ pub struct LlvmGenerator {
     /// LLVM type names for various builder types
     bld_names: HashMap<BuilderKind, String>,
 
-    /// LLVM SIMD vector names for various scalar types.
-    simd_names: HashMap<ScalarKind, String>,
+    /// LLVM SIMD vector names for various scalar types and numbers of lanes.
+    simd_names: HashMap<(ScalarKind, u32), String>,
+
+    /// SIMD math functions already declared or defined in the prelude.
+    simd_functions: HashSet<String>,
 
     /// A CodeBuilder and ID generator for prelude functions such as type and struct definitions.
     prelude_code: CodeBuilder,
@@ -140,6 +251,19 @@ pub struct LlvmGenerator {
 
     /// Functions we have already visited when generating code.
     visited: HashSet<sir::FunctionId>,
+
+    /// Grain size used for innermost loops without a `grain_size` annotation.
+    default_grain_size: i32,
+
+    /// Width in bits of the SIMD registers to generate code for.
+    simd_width: u32,
+
+    /// Number of lanes in the SIMD values of the function being generated.
+    simd_lanes: u32,
+
+    /// Number of lanes in the SIMD values of each loop body function, chosen from `simd_width`
+    /// when a program is added.
+    loop_lanes: HashMap<sir::FunctionId, u32>,
 }
 
 impl LlvmGenerator {
@@ -154,12 +278,18 @@ impl LlvmGenerator {
             dict_names: HashMap::new(),
             dict_ids: IdGenerator::new("%d"),
             simd_names: HashMap::new(),
+            simd_functions: HashSet::new(),
             bld_names: HashMap::new(),
             prelude_code: CodeBuilder::new(),
             prelude_var_ids: IdGenerator::new("%p.p"),
             body_code: CodeBuilder::new(),
             visited: HashSet::new(),
+            default_grain_size: DEFAULT_GRAIN_SIZE,
+            simd_width: easy_ll::host_vector_bits(),
+            simd_lanes: 0,
+            loop_lanes: HashMap::new(),
         };
+        generator.simd_lanes = default_simd_lanes(generator.simd_width);
         generator.prelude_code.add(PRELUDE_CODE);
         generator.prelude_code.add("\n");
         generator
@@ -271,6 +401,11 @@ impl LlvmGenerator {
             return Ok(());
         }
 
+        // SIMD values have the number of lanes chosen for the loop this function is the body of.
+        let outer_lanes = self.simd_lanes;
+        let lanes = self.function_lanes(func.id);
+        self.simd_lanes = lanes;
+
         let mut ctx = &mut FunctionContext::new();
         let mut arg_types = try!(self.get_arg_str(&func.params, ".in"));
         if containing_loop.is_some() {
@@ -322,17 +457,59 @@ impl LlvmGenerator {
 
             let idx_cmp = ctx.var_ids.next();
 
-            if par_for.data[0].kind == IterKind::SimdIter {
-                let check_with_vec = ctx.var_ids.next();
-                let vector_len = format!("{}", vec_size(&elem_ty)?);
-                // Would need to compute stride, etc. here.
-                ctx.code.add(format!("{} = add i64 {}, {}", check_with_vec, idx_tmp, vector_len));
-                ctx.code.add(format!("{} = icmp ule i64 {}, %upper.idx", idx_cmp, check_with_vec));
-            } else {
-                ctx.code.add(format!("{} = icmp ult i64 {}, %upper.idx", idx_cmp, idx_tmp));
-            }
+            ctx.code.add(format!("{} = icmp ult i64 {}, %upper.idx", idx_cmp, idx_tmp));
             ctx.code.add(format!("br i1 {}, label %loop.body, label %loop.end", idx_cmp));
             ctx.code.add("loop.body:");
+            // The FringeIter loop starts at the first iteration which the SimdIter loop skipped.
+            // This is computed from the first iterator so that every iterator uses the same one.
+            let fringe_offset = if par_for.data[0].kind == IterKind::FringeIter {
+                let ref first = par_for.data[0];
+                let data_ty_str = self.llvm_type(func.params.get(&first.data).unwrap())?.to_string();
+                let data_str = self.load_var(llvm_symbol(&first.data).as_str(), &data_ty_str, ctx)?;
+                let data_prefix = format!("@{}", data_ty_str.replace("%", ""));
+                let vector_len = format!("{}", self.simd_lanes);
+                let total = ctx.var_ids.next();
+                if first.start.is_none() {
+                    ctx.code.add(format!("{} = call i64 {}.size({} {})",
+                                            total,
+                                            data_prefix,
+                                            &data_ty_str,
+                                            data_str));
+                } else {
+                    let start_str = self.load_var(llvm_symbol(first.start.as_ref().unwrap()).as_str(), "i64", ctx)?;
+                    let end_str = self.load_var(llvm_symbol(first.end.as_ref().unwrap()).as_str(), "i64", ctx)?;
+                    let stride_str = self.load_var(llvm_symbol(first.stride.as_ref().unwrap()).as_str(), "i64", ctx)?;
+                    let diff = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = sub i64 {}, {}", diff, end_str, start_str));
+                    ctx.code.add(format!("{} = udiv i64 {}, {}", total, diff, stride_str));
+                }
+                let tmp = ctx.var_ids.next();
+                let offset = ctx.var_ids.next();
+                ctx.code.add(format!("{} = udiv i64 {}, {}", tmp, total, vector_len));
+                ctx.code.add(format!("{} = mul i64 {}, {}", offset, tmp, vector_len));
+                Some(offset)
+            } else {
+                None
+            };
+
+            // The position of this iteration in the iterators, counted in elements of the
+            // iterators rather than of the vectors. Each iteration of a SimdIter loop covers a
+            // whole SIMD vector of elements.
+            let position = match fringe_offset {
+                Some(ref offset) => {
+                    let position = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = add i64 {}, {}", position, offset, idx_tmp));
+                    position
+                }
+                None if par_for.data[0].kind == IterKind::SimdIter => {
+                    let position = ctx.var_ids.next();
+                    let vector_len = self.simd_lanes;
+                    ctx.code.add(format!("{} = mul i64 {}, {}", position, idx_tmp, vector_len));
+                    position
+                }
+                None => idx_tmp.clone(),
+            };
+
             let mut prev_ref = String::from("undef");
             let elem_ty_str = self.llvm_type(&elem_ty)?.to_string();
             for (i, iter) in par_for.data.iter().enumerate() {
@@ -340,78 +517,79 @@ impl LlvmGenerator {
                 let data_str = self.load_var(llvm_symbol(&iter.data).as_str(), &data_ty_str, ctx)?;
                 let data_prefix = format!("@{}", data_ty_str.replace("%", ""));
                 let inner_elem_tmp_ptr = ctx.var_ids.next();
-                let inner_elem_ty_str = if par_for.data.len() == 1 {
-                    elem_ty_str.clone()
+                let inner_elem_ty = if par_for.data.len() == 1 {
+                    elem_ty.clone()
                 } else {
                     match *elem_ty {
-                        Struct(ref v) => self.llvm_type(&v[i])?.to_string(),
+                        Struct(ref v) => v[i].clone(),
                         _ => weld_err!("Internal error: invalid element type {}", print_type(elem_ty))?,
                     }
                 };
+                let inner_elem_ty_str = self.llvm_type(&inner_elem_ty)?.to_string();
 
-                let arr_idx = if iter.start.is_some() {
-                    // TODO(shoumik) implement. This needs to be a gather instead of a
-                    // sequential load.
-                    if iter.kind == IterKind::SimdIter {
-                        return weld_err!("Unimplemented: vectorized iterators do not support non-unit stride.");
-                    }
-                    let offset = ctx.var_ids.next();
+                let strided = if iter.start.is_some() {
                     let stride_str = self.load_var(llvm_symbol(&iter.stride.clone().unwrap()).as_str(), "i64", ctx)?;
                     let start_str = self.load_var(llvm_symbol(&iter.start.clone().unwrap()).as_str(), "i64", ctx)?;
-                    ctx.code.add(format!("{} = mul i64 {}, {}", offset, idx_tmp, stride_str));
-                    let final_idx = ctx.var_ids.next();
-                    ctx.code.add(format!("{} = add i64 {}, {}", final_idx, start_str, offset));
-                    final_idx
+                    Some((start_str, stride_str))
                 } else {
-                    if iter.kind == IterKind::FringeIter {
-                        let vector_len = format!("{}", vec_size(&elem_ty)?);
-                        let tmp = ctx.var_ids.next();
-                        let arr_len = ctx.var_ids.next();
-                        let offset = ctx.var_ids.next();
-                        let final_idx = ctx.var_ids.next();
-
-                        ctx.code.add(format!("{} = call i64 {}.size({} {})",
-                                                arr_len,
-                                                data_prefix,
-                                                &data_ty_str,
-                                                data_str));
-
-                        ctx.code.add(format!("{} = udiv i64 {}, {}", tmp, arr_len, vector_len));
-
-                        // tmp2 is also where the iteration for the FringeIter starts (the
-                        // offset).
-                        ctx.code.add(format!("{} = mul i64 {}, {}", offset, tmp, vector_len));
-
-                        // Compute the number of iterations.
-                        ctx.code.add(format!("{} = add i64 {}, {}", final_idx, offset, idx_tmp));
-
-                        final_idx
-                    } else {
-                        idx_tmp.clone()
-                    }
+                    None
                 };
 
-                match iter.kind {
-                    IterKind::ScalarIter | IterKind::FringeIter => {
+                let inner_elem_tmp = match (&iter.kind, &strided) {
+                    (&IterKind::SimdIter, &Some((ref start_str, ref stride_str))) => {
+                        // The elements are not contiguous, so gather them one lane at a time.
+                        try!(self.gen_gather(&inner_elem_ty,
+                                             &data_prefix,
+                                             &data_ty_str,
+                                             &data_str,
+                                             &position,
+                                             start_str,
+                                             stride_str,
+                                             ctx))
+                    }
+                    (&IterKind::SimdIter, &None) => {
+                        // The elements are contiguous, so load them as a whole SIMD vector.
+                        let scalar_ty_str = match inner_elem_ty {
+                            Simd(kind) => self.llvm_type(&Scalar(kind))?.to_string(),
+                            _ => weld_err!("Internal error: invalid SIMD element type {}",
+                                           print_type(&inner_elem_ty))?,
+                        };
+                        let elem_ptr = ctx.var_ids.next();
                         ctx.code.add(format!("{} = call {}* {}.at({} {}, i64 {})",
-                                                inner_elem_tmp_ptr,
-                                                &inner_elem_ty_str,
+                                                elem_ptr,
+                                                scalar_ty_str,
                                                 data_prefix,
                                                 &data_ty_str,
                                                 data_str,
-                                                arr_idx));
+                                                position));
+                        ctx.code.add(format!("{} = bitcast {}* {} to {}*",
+                                                inner_elem_tmp_ptr,
+                                                scalar_ty_str,
+                                                elem_ptr,
+                                                &inner_elem_ty_str));
+                        try!(self.load_var(&inner_elem_tmp_ptr, &inner_elem_ty_str, ctx))
                     }
-                    IterKind::SimdIter => {
-                        ctx.code.add(format!("{} = call {}* {}.vat({} {}, i64 {})",
+                    (_, _) => {
+                        let arr_idx = match strided {
+                            Some((ref start_str, ref stride_str)) => {
+                                let offset = ctx.var_ids.next();
+                                ctx.code.add(format!("{} = mul i64 {}, {}", offset, position, stride_str));
+                                let final_idx = ctx.var_ids.next();
+                                ctx.code.add(format!("{} = add i64 {}, {}", final_idx, start_str, offset));
+                                final_idx
+                            }
+                            None => position.clone(),
+                        };
+                        ctx.code.add(format!("{} = call {}* {}.at({} {}, i64 {})",
                                                 inner_elem_tmp_ptr,
                                                 &inner_elem_ty_str,
                                                 data_prefix,
                                                 &data_ty_str,
                                                 data_str,
                                                 arr_idx));
+                        try!(self.load_var(&inner_elem_tmp_ptr, &inner_elem_ty_str, ctx))
                     }
                 };
-                let inner_elem_tmp = try!(self.load_var(&inner_elem_tmp_ptr, &inner_elem_ty_str, ctx));
                 if par_for.data.len() == 1 {
                     prev_ref.clear();
                     prev_ref.push_str(&inner_elem_tmp);
@@ -436,23 +614,17 @@ impl LlvmGenerator {
         // Jump to block 0.
         ctx.code.add(format!("br label %b.b{}", func.blocks[0].id));
 
-        // Generate an expression for the function body.
+        // Generate an expression for the function body. This adds the functions it calls, which
+        // may choose a different number of lanes.
         self.gen_function(sir, func, ctx)?;
+        self.simd_lanes = lanes;
         ctx.code.add("body.end:");
         if containing_loop.is_some() {
-            // TODO - should take the minimum vector size of all elements here?
-            let vectorized = containing_loop.as_ref().unwrap().data[0].kind == IterKind::SimdIter;
-            let fetch_width = if vectorized {
-                vec_size(func.locals.get(&containing_loop.as_ref().unwrap().data_arg).unwrap())?
-            } else {
-                1
-            };
-
             ctx.code.add("br label %loop.terminator");
             ctx.code.add("loop.terminator:");
             let idx_tmp = self.load_var("%cur.idx", "i64", ctx)?;
             let idx_inc = ctx.var_ids.next();
-            ctx.code.add(format!("{} = add i64 {}, {}", idx_inc, idx_tmp, format!("{}", fetch_width)));
+            ctx.code.add(format!("{} = add i64 {}, 1", idx_inc, idx_tmp));
             ctx.code.add(format!("store i64 {}, i64* %cur.idx", idx_inc));
             ctx.code.add("br label %loop.start");
             ctx.code.add("loop.end:");
@@ -480,57 +652,63 @@ impl LlvmGenerator {
             let num_iters_str = wrap_ctx.var_ids.next();
             let mut fringe_start_str = None;
 
-            if par_for.data[0].kind == IterKind::SimdIter || par_for.data[0].kind == IterKind::ScalarIter {
-                if par_for.data[0].start.is_none() {
-                    // set num_iters_str to len(first_data)
-                    wrap_ctx.code.add(format!("{} = call i64 {}.size({} {})",
-                                                num_iters_str,
-                                                data_prefix,
-                                                data_ty_str,
-                                                data_str));
-                } else {
-                    // TODO(shoumik): Don't support non-unit stride right now.
-                    if par_for.data[0].kind == IterKind::SimdIter {
-                        return weld_err!("vector iterator does not support non-unit stride");
-                    }
-                    // set num_iters_str to (end - start) / stride
-                    let start_str = llvm_symbol(&par_for.data[0].start.clone().unwrap());
-                    let end_str = llvm_symbol(&par_for.data[0].end.clone().unwrap());
-                    let stride_str = llvm_symbol(&par_for.data[0].stride.clone().unwrap());
-                    let diff_tmp = wrap_ctx.var_ids.next();
-                    wrap_ctx.code.add(format!("{} = sub i64 {}, {}", diff_tmp, end_str, start_str));
-                    wrap_ctx.code.add(format!("{} = udiv i64 {}, {}", num_iters_str, diff_tmp, stride_str));
-                }
+            // The total number of iterations of the first iterator. The ScalarIter loop runs all
+            // of them, the SimdIter loop runs as many whole SIMD vectors of them as it can, and
+            // the FringeIter loop runs the ones left over after the SimdIter loop.
+            let total_iters_str = if par_for.data[0].kind == IterKind::ScalarIter {
+                num_iters_str.clone()
             } else {
-                // FringeIter
-                // TODO(shoumik): Don't support non-unit stride right now.
-                if par_for.data[0].start.is_some() {
-                    return weld_err!("fringe iterator does not support non-unit stride");
-                }
-                let arr_len = wrap_ctx.var_ids.next();
+                wrap_ctx.var_ids.next()
+            };
+            if par_for.data[0].start.is_none() {
+                // set total_iters_str to len(first_data)
+                wrap_ctx.code.add(format!("{} = call i64 {}.size({} {})",
+                                            total_iters_str,
+                                            data_prefix,
+                                            data_ty_str,
+                                            data_str));
+            } else {
+                // set total_iters_str to (end - start) / stride
+                let start_str = llvm_symbol(&par_for.data[0].start.clone().unwrap());
+                let end_str = llvm_symbol(&par_for.data[0].end.clone().unwrap());
+                let stride_str = llvm_symbol(&par_for.data[0].stride.clone().unwrap());
+                let diff_tmp = wrap_ctx.var_ids.next();
+                wrap_ctx.code.add(format!("{} = sub i64 {}, {}", diff_tmp, end_str, start_str));
+                wrap_ctx.code.add(format!("{} = udiv i64 {}, {}", total_iters_str, diff_tmp, stride_str));
+            }
+
+            let vector_len = format!("{}", self.simd_lanes);
+            if par_for.data[0].kind == IterKind::SimdIter {
+                // Each iteration of the loop covers one SIMD vector, so that however the
+                // iterations are split among threads, every vector is loaded whole.
+                wrap_ctx.code.add(format!("{} = udiv i64 {}, {}", num_iters_str, total_iters_str, vector_len));
+            } else if par_for.data[0].kind == IterKind::FringeIter {
                 let tmp = wrap_ctx.var_ids.next();
                 let tmp2 = wrap_ctx.var_ids.next();
-                let vector_len = format!("{}", vec_size(get_sym_ty(func, &first_data)?)?);
-
-                wrap_ctx
-                    .code
-                    .add(format!("{} = call i64 {}.size({} {})", arr_len, data_prefix, data_ty_str, data_str));
 
                 // Compute the number of iterations:
-                // tmp = arr_len / vec_size
+                // tmp = total_iters / vec_size
                 // tmp2 = tmp * vec_size
-                // num_iters = arr_len - vec_size
-                wrap_ctx.code.add(format!("{} = udiv i64 {}, {}", tmp, arr_len, vector_len));
-                // tmp2 is also where the iteration for the FringeIter starts.
+                // num_iters = total_iters - tmp2
+                wrap_ctx.code.add(format!("{} = udiv i64 {}, {}", tmp, total_iters_str, vector_len));
+                // tmp2 is also the iteration at which the FringeIter starts.
                 wrap_ctx.code.add(format!("{} = mul i64 {}, {}", tmp2, tmp, vector_len));
                 // Compute the number of iterations.
-                wrap_ctx.code.add(format!("{} = sub i64 {}, {}", num_iters_str, arr_len, tmp2));
+                wrap_ctx.code.add(format!("{} = sub i64 {}, {}", num_iters_str, total_iters_str, tmp2));
 
                 fringe_start_str = Some(tmp2);
             }
 
-            // Perform a bounds check on each of the data items before launching the loop
-            for iter in par_for.data.iter() {
+            // Perform a bounds check on each of the data items before launching the loop. Loops
+            // whose iterators are known to fit in their vectors skip the check. A SimdIter loop
+            // is checked over all the iterations of its iterator.
+            let checked_count_str = if par_for.data[0].kind == IterKind::SimdIter {
+                total_iters_str.clone()
+            } else {
+                num_iters_str.clone()
+            };
+            let checked_iters = if par_for.bounds_check { &par_for.data[..] } else { &[] };
+            for iter in checked_iters.iter() {
                 // Vector LLVM information for the current iter.
                 let data_str = llvm_symbol(&iter.data);
                 let data_ty_str = try!(self.llvm_type(func.params.get(&iter.data).unwrap())).to_string();
@@ -541,8 +719,6 @@ impl LlvmGenerator {
                     .add(format!("{} = call i64 {}.size({} {})", vec_size_str, data_prefix, data_ty_str, data_str));
 
                 let (start_str, stride_str) = if iter.start.is_none() {
-                    // We already checked to make sure the FringeIter doesn't have a start,
-                    // etc.
                     let start_str = if iter.kind == IterKind::FringeIter {
                         fringe_start_str.as_ref().unwrap().to_string()
                     } else {
@@ -551,7 +727,21 @@ impl LlvmGenerator {
                     let stride_str = "1".to_string();
                     (start_str, stride_str)
                 } else {
-                    (llvm_symbol(iter.start.as_ref().unwrap()), llvm_symbol(iter.stride.as_ref().unwrap()))
+                    let start_str = llvm_symbol(iter.start.as_ref().unwrap());
+                    let stride_str = llvm_symbol(iter.stride.as_ref().unwrap());
+                    if iter.kind == IterKind::FringeIter {
+                        // The FringeIter loop starts fringe_start iterations into the iterator.
+                        let offset = wrap_ctx.var_ids.next();
+                        let fringe_start = wrap_ctx.var_ids.next();
+                        wrap_ctx.code.add(format!("{} = mul i64 {}, {}",
+                                                  offset,
+                                                  fringe_start_str.as_ref().unwrap(),
+                                                  stride_str));
+                        wrap_ctx.code.add(format!("{} = add i64 {}, {}", fringe_start, start_str, offset));
+                        (fringe_start, stride_str)
+                    } else {
+                        (start_str, stride_str)
+                    }
                 };
 
                 let t0 = wrap_ctx.var_ids.next();
@@ -568,7 +758,7 @@ impl LlvmGenerator {
                 // br i1 cond, label %nextCheck, label %checkFailed
                 // nextCheck:
                 // (loop)
-                wrap_ctx.code.add(format!("{} = sub i64 {}, 1", t0, num_iters_str));
+                wrap_ctx.code.add(format!("{} = sub i64 {}, 1", t0, checked_count_str));
                 wrap_ctx.code.add(format!("{} = mul i64 {}, {}", t1, stride_str, t0));
                 wrap_ctx.code.add(format!("{} = add i64 {}, {}", t2, t1, start_str));
                 wrap_ctx.code.add(format!("{} = icmp ult i64 {}, {}", cond, t2, vec_size_str));
@@ -590,8 +780,10 @@ impl LlvmGenerator {
             wrap_ctx.code.add(format!("br label %fn.end"));
             wrap_ctx.code.add(format!("fn.boundcheckpassed:"));
 
+            // An explicit grain size annotation applies to any loop. Otherwise, innermost loops use
+            // the configured default and outer loops hand out one iteration at a time.
             let bound_cmp = wrap_ctx.var_ids.next();
-            let mut grain_size = 4096;
+            let mut grain_size = par_for.grain_size.unwrap_or(self.default_grain_size);
             if par_for.innermost {
                 wrap_ctx.code.add(format!("{} = icmp ule i64 {}, {}", bound_cmp, num_iters_str, grain_size));
                 wrap_ctx.code.add(format!("br i1 {}, label %for.ser, label %for.par", bound_cmp));
@@ -604,7 +796,9 @@ impl LlvmGenerator {
                 wrap_ctx.code.add(format!("br label %fn.end"));
             } else {
                 wrap_ctx.code.add("br label %for.par");
-                grain_size = 1;
+                if par_for.grain_size.is_none() {
+                    grain_size = 1;
+                }
             }
             wrap_ctx.code.add(format!("for.par:"));
             let body_struct = try!(self.get_arg_struct(&func.params, &mut wrap_ctx));
@@ -678,13 +872,31 @@ impl LlvmGenerator {
             self.body_code.add(&par_top_ctx.code.result());
         }
 
+        self.simd_lanes = outer_lanes;
         Ok(())
     }
 
+    /// Returns the number of lanes in the SIMD values of the function `func_id`.
+    fn function_lanes(&self, func_id: sir::FunctionId) -> u32 {
+        match self.loop_lanes.get(&func_id) {
+            Some(lanes) => *lanes,
+            None => default_simd_lanes(self.simd_width),
+        }
+    }
+
+    /// Returns the number of lanes in the vector part of a merger of `kind` values, which is as
+    /// many as fit in a register. Loops whose widest values are wider than `kind` merge fewer
+    /// lanes into it; see `gen_widen_merge_value`.
+    fn merger_lanes(&self, kind: ScalarKind) -> u32 {
+        cmp::max(self.simd_width / simd_elem_bits(&Simd(kind)), 1)
+    }
+
     /// Add a function to the generated program, passing its parameters and return value through
     /// pointers encoded as i64. This is used for the main entry point function into Weld modules
     /// to pass them arbitrary structures.
     pub fn add_function_on_pointers(&mut self, name: &str, sir: &SirProgram) -> WeldResult<()> {
+        self.loop_lanes = loop_simd_lanes(sir, self.simd_width);
+
         // First add the function on raw values, which we'll call from the pointer version.
         try!(self.add_function(sir, &sir.funcs[0], None));
 
@@ -767,6 +979,7 @@ impl LlvmGenerator {
 
     /// Return the LLVM type name corresponding to a Weld type.
     fn llvm_type(&mut self, ty: &Type) -> WeldResult<&str> {
+        let lanes = self.simd_lanes;
         match *ty {
             Scalar(Bool) => Ok("i1"),
             Scalar(I8) => Ok("i8"),
@@ -775,15 +988,16 @@ impl LlvmGenerator {
             Scalar(F32) => Ok("float"),
             Scalar(F64) => Ok("double"),
 
-            Simd(Bool) => Ok(self.simd_names.entry(Bool).or_insert(format!("<{} x i1>", vec_size(&Scalar(Bool))?))),
-            Simd(I8) => Ok(self.simd_names.entry(I8).or_insert(format!("<{} x i8>", vec_size(&Scalar(I8))?))),
-            Simd(I32) => Ok(self.simd_names.entry(I32).or_insert(format!("<{} x i32>", vec_size(&Scalar(I32))?))),
-            Simd(I64) => Ok(self.simd_names.entry(I64).or_insert(format!("<{} x i64>", vec_size(&Scalar(I64))?))),
-            Simd(F32) => Ok(self.simd_names.entry(F32).or_insert(format!("<{} x float>", vec_size(&Scalar(F32))?))),
-            Simd(F64) => Ok(self.simd_names.entry(F64).or_insert(format!("<{} x double>", vec_size(&Scalar(F64))?))),
+            Simd(kind) => {
+                let elem = self.llvm_type(&Scalar(kind))?.to_string();
+                let name = format!("<{} x {}>", lanes, elem);
+                Ok(self.simd_names.entry((kind, lanes)).or_insert(name))
+            }
 
             Struct(ref fields) => {
-                if self.struct_names.get(fields) == None {
+                // Structs of SIMD values are declared once for each number of lanes.
+                let key = (fields.clone(), if contains_simd(ty) { lanes } else { 0 });
+                if self.struct_names.get(&key) == None {
                     // Declare the struct in prelude_code
                     let name = self.struct_ids.next();
                     let mut field_types: Vec<String> = Vec::new();
@@ -857,9 +1071,9 @@ impl LlvmGenerator {
                     self.prelude_code.add_line(format!(""));
 
                     // Add it into our map so we remember its name
-                    self.struct_names.insert(fields.clone(), name);
+                    self.struct_names.insert(key.clone(), name);
                 }
-                Ok(self.struct_names.get(fields).unwrap())
+                Ok(self.struct_names.get(&key).unwrap())
             }
 
             Vector(ref elem) => {
@@ -873,16 +1087,6 @@ impl LlvmGenerator {
                     let name_replaced = elem_replaced.replace("$NAME", &name.replace("%", ""));
                     self.prelude_code.add(&name_replaced);
                     self.prelude_code.add("\n");
-
-                    // Supports vectorization, so splice in the vector extensions.
-                    if let Scalar(_) = *elem.as_ref() {
-                        let replaced = VVECTOR_CODE.replace("$ELEM_PREFIX", &elem_prefix);
-                        let replaced = replaced.replace("$ELEM", &elem_ty);
-                        let replaced = replaced.replace("$VECSIZE", &format!("{}", vec_size(elem)?));
-                        let replaced = replaced.replace("$NAME", &name.replace("%", ""));
-                        self.prelude_code.add(&replaced);
-                        self.prelude_code.add("\n");
-                    }
                 }
                 Ok(self.vec_names.get(elem).unwrap())
             }
@@ -929,8 +1133,12 @@ impl LlvmGenerator {
                                 self.merger_names.insert(*t.clone(), name.clone());
                                 let prefix_replaced = MERGER_CODE.replace("$ELEM_PREFIX", &elem_prefix);
                                 let elem_replaced = prefix_replaced.replace("$ELEM", &elem_ty);
-                                // TODO!
-                                let vecsize_replaced = elem_replaced.replace("$VECSIZE", &format!("{}", vec_size(t)?));
+                                let merger_lanes = match **t {
+                                    Scalar(kind) => self.merger_lanes(kind),
+                                    _ => self.simd_lanes,
+                                };
+                                let vecsize_replaced =
+                                    elem_replaced.replace("$VECSIZE", &format!("{}", merger_lanes));
                                 let name_replaced = vecsize_replaced.replace("$NAME", &name.replace("%", ""));
                                 self.prelude_code.add(&name_replaced);
                                 self.prelude_code.add("\n");
@@ -993,13 +1201,62 @@ impl LlvmGenerator {
         Ok(var)
     }
 
+    /// Loads a SIMD vector of type `simd_ty` whose lanes are the elements of the vector `data` at
+    /// the iterator positions `position`, `position + 1`, etc. of a strided iterator, i.e., at the
+    /// indices `start + position * stride`, `start + (position + 1) * stride`, etc.
+    fn gen_gather(&mut self,
+                  simd_ty: &Type,
+                  data_prefix: &str,
+                  data_ty_str: &str,
+                  data_str: &str,
+                  position: &str,
+                  start_str: &str,
+                  stride_str: &str,
+                  ctx: &mut FunctionContext)
+                  -> WeldResult<String> {
+        let scalar_ty = match *simd_ty {
+            Simd(kind) => Scalar(kind),
+            _ => return weld_err!("Internal error: gather into non-SIMD type {}", print_type(simd_ty)),
+        };
+        let simd_ty_str = self.llvm_type(simd_ty)?.to_string();
+        let scalar_ty_str = self.llvm_type(&scalar_ty)?.to_string();
+        let mut gathered = String::from("undef");
+        for lane in 0..self.simd_lanes {
+            let lane_position = ctx.var_ids.next();
+            let offset = ctx.var_ids.next();
+            let index = ctx.var_ids.next();
+            let elem_ptr = ctx.var_ids.next();
+            ctx.code.add(format!("{} = add i64 {}, {}", lane_position, position, lane));
+            ctx.code.add(format!("{} = mul i64 {}, {}", offset, lane_position, stride_str));
+            ctx.code.add(format!("{} = add i64 {}, {}", index, start_str, offset));
+            ctx.code.add(format!("{} = call {}* {}.at({} {}, i64 {})",
+                                 elem_ptr,
+                                 scalar_ty_str,
+                                 data_prefix,
+                                 data_ty_str,
+                                 data_str,
+                                 index));
+            let elem = self.load_var(&elem_ptr, &scalar_ty_str, ctx)?;
+            let next = ctx.var_ids.next();
+            ctx.code.add(format!("{} = insertelement {} {}, {} {}, i32 {}",
+                                 next,
+                                 simd_ty_str,
+                                 gathered,
+                                 scalar_ty_str,
+                                 elem,
+                                 lane));
+            gathered = next;
+        }
+        Ok(gathered)
+    }
+
     fn generate_vector_literal(&mut self,
                                output: &str,
                                value: &LiteralKind,
                                vec_ty: &Type,
                                ctx: &mut FunctionContext)
                                -> WeldResult<()> {
-        let size = vec_size(vec_ty)?;
+        let size = self.simd_lanes;
         let vec_ty_str = self.llvm_type(vec_ty)?.to_string();
         let size_str = format!("{}", size);
         let insert_str = match *value {
@@ -1030,6 +1287,42 @@ impl LlvmGenerator {
         Ok(())
     }
 
+    /// Generates code to widen a SIMD value of `kind` values with the current number of lanes to
+    /// the number of lanes of a merger of `kind` values, filling the extra lanes with the identity
+    /// of the merger's operator `op`. Returns the LLVM names of the widened value and its type.
+    fn gen_widen_merge_value(&mut self,
+                             value: &str,
+                             kind: ScalarKind,
+                             op: BinOpKind,
+                             ctx: &mut FunctionContext)
+                             -> WeldResult<(String, String)> {
+        let lanes = self.simd_lanes;
+        let merger_lanes = self.merger_lanes(kind);
+        let value_ty_str = self.llvm_type(&Simd(kind))?.to_string();
+        if lanes == merger_lanes {
+            return Ok((value.to_string(), value_ty_str));
+        }
+        let elem_ty_str = self.llvm_type(&Scalar(kind))?.to_string();
+        let identity = binop_identity(op, &Scalar(kind))?;
+        let identities = (0..lanes)
+            .map(|_| format!("{} {}", elem_ty_str, identity))
+            .collect::<Vec<_>>();
+        // Lanes past the end of the value select the first lane of the vector of identities.
+        let mask = (0..merger_lanes)
+            .map(|i| format!("i32 {}", cmp::min(i, lanes)))
+            .collect::<Vec<_>>();
+        let widened = ctx.var_ids.next();
+        ctx.code.add(format!("{} = shufflevector {} {}, {} <{}>, <{} x i32> <{}>",
+                             widened,
+                             value_ty_str,
+                             value,
+                             value_ty_str,
+                             identities.join(", "),
+                             merger_lanes,
+                             mask.join(", ")));
+        Ok((widened, format!("<{} x {}>", merger_lanes, elem_ty_str)))
+    }
+
     /// Given a pointer to a some data retrieved from a builder, generates code to merge a value
     /// into the builder using a binary operation. The result will be stored back into the
     /// pointer to complete the merge. `builder_ptr` is the pointer into which the original value
@@ -1097,21 +1390,68 @@ impl LlvmGenerator {
                     op_kind: UnaryOpKind)
                     -> WeldResult<()> {
         let child_ty = try!(get_sym_ty(func, child));
-        if let Scalar(ref ty) = *child_ty {
-            let child_ll_ty = try!(self.llvm_type(&child_ty)).to_string();
-            let child_tmp = try!(self.load_var(llvm_symbol(child).as_str(), &child_ll_ty, ctx));
-            let res_tmp = ctx.var_ids.next();
-            let op_name = try!(llvm_unaryop(op_kind, ty));
-            ctx.code.add(format!("{} = call {} {} ({} {})", res_tmp, child_ll_ty, op_name, child_ll_ty, child_tmp));
-            let out_ty = try!(get_sym_ty(func, output));
-            let out_ty_str = try!(self.llvm_type(&out_ty)).to_string();
-            ctx.code.add(format!("store {} {}, {}* {}", out_ty_str, res_tmp, out_ty_str, llvm_symbol(output)));
-        } else {
-            weld_err!("Illegal type {} in {}", print_type(child_ty), op_kind)?;
-        }
+        let op_name = match *child_ty {
+            Scalar(ref ty) => try!(llvm_unaryop(op_kind, ty)).to_string(),
+            Simd(ref ty) => try!(self.simd_unaryop(op_kind, *ty)),
+            _ => return weld_err!("Illegal type {} in {}", print_type(child_ty), op_kind),
+        };
+        let child_ll_ty = try!(self.llvm_type(&child_ty)).to_string();
+        let child_tmp = try!(self.load_var(llvm_symbol(child).as_str(), &child_ll_ty, ctx));
+        let res_tmp = ctx.var_ids.next();
+        ctx.code.add(format!("{} = call {} {} ({} {})", res_tmp, child_ll_ty, op_name, child_ll_ty, child_tmp));
+        let out_ty = try!(get_sym_ty(func, output));
+        let out_ty_str = try!(self.llvm_type(&out_ty)).to_string();
+        ctx.code.add(format!("store {} {}, {}* {}", out_ty_str, res_tmp, out_ty_str, llvm_symbol(output)));
         Ok(())
     }
 
+    /// Returns the name of the function computing a unary operation on each lane of a SIMD value,
+    /// declaring or defining it in the prelude the first time it is used.
+    ///
+    /// `exp`, `log` and `sqrt` use LLVM's vector intrinsics, which compute the same results as the
+    /// scalar intrinsics. `erf` has no intrinsic, so it calls libm's `erf` or `erff` on each lane,
+    /// like the scalar version.
+    fn simd_unaryop(&mut self, op_kind: UnaryOpKind, kind: ScalarKind) -> WeldResult<String> {
+        let ll_ty = try!(self.llvm_type(&Simd(kind))).to_string();
+        let suffix = match kind {
+            F32 => format!("v{}f32", self.simd_lanes),
+            F64 => format!("v{}f64", self.simd_lanes),
+            _ => return weld_err!("Unsupported unary op: {} on {}", op_kind, print_type(&Simd(kind))),
+        };
+        let name = match op_kind {
+            UnaryOpKind::Exp => format!("@llvm.exp.{}", suffix),
+            UnaryOpKind::Log => format!("@llvm.log.{}", suffix),
+            UnaryOpKind::Sqrt => format!("@llvm.sqrt.{}", suffix),
+            UnaryOpKind::Erf => format!("@simd.erf.{}", suffix),
+        };
+        if !self.simd_functions.insert(name.clone()) {
+            return Ok(name);
+        }
+        if op_kind != UnaryOpKind::Erf {
+            self.prelude_code.add_line(format!("declare {} {}({})", ll_ty, name, ll_ty));
+            return Ok(name);
+        }
+
+        let (elem_ty, elem_fn) = if kind == F64 { ("double", "@erf") } else { ("float", "@erff") };
+        self.prelude_code.add_line(format!("define {} {}({} %x) {{", ll_ty, name, ll_ty));
+        let mut res = "undef".to_string();
+        for i in 0..self.simd_lanes {
+            let elem = self.prelude_var_ids.next();
+            let elem_erf = self.prelude_var_ids.next();
+            let next = self.prelude_var_ids.next();
+            self.prelude_code.add_line(format!("{} = extractelement {} %x, i32 {}", elem, ll_ty, i));
+            self.prelude_code.add_line(format!("{} = call {} {}({} {})",
+                                               elem_erf, elem_ty, elem_fn, elem_ty, elem));
+            self.prelude_code.add_line(format!("{} = insertelement {} {}, {} {}, i32 {}",
+                                               next, ll_ty, res, elem_ty, elem_erf, i));
+            res = next;
+        }
+        self.prelude_code.add_line(format!("ret {} {}", ll_ty, res));
+        self.prelude_code.add_line(format!("}}"));
+        self.prelude_code.add_line(format!(""));
+        Ok(name)
+    }
+
     /// Generate code for a function and append it to its FunctionContext.
     fn gen_function(&mut self, sir: &SirProgram, func: &SirFunction, ctx: &mut FunctionContext) -> WeldResult<()> {
         for b in func.blocks.iter() {
@@ -1262,7 +1602,7 @@ impl LlvmGenerator {
                 let vec_ty_str = self.llvm_type(&ty)?.to_string();
 
                 let elem = self.load_var(llvm_symbol(child).as_str(), &elem_ty_str, ctx)?;
-                let size = vec_size(&elem_ty)?;
+                let size = self.simd_lanes;
 
                 let mut prev_name = "undef".to_string();
                 for i in 0..size {
@@ -1584,6 +1924,68 @@ impl LlvmGenerator {
         Ok(())
     }
 
+    /// Generates code to load the value merged by a `Merge` statement. Vectorized loops merge SIMD
+    /// values, or structs of them, into appenders, dictmergers and vecmergers; these are split
+    /// into one scalar value per lane so that lanes can be merged one at a time. Returns the LLVM
+    /// names of the values to merge.
+    fn gen_load_merge_values(&mut self,
+                             value: &Symbol,
+                             func: &SirFunction,
+                             ctx: &mut FunctionContext)
+                             -> WeldResult<Vec<String>> {
+        let value_ty = get_sym_ty(func, value)?;
+        let value_ty_str = self.llvm_type(value_ty)?.to_string();
+        let value_tmp = self.load_var(llvm_symbol(value).as_str(), &value_ty_str, ctx)?;
+        if !contains_simd(value_ty) {
+            return Ok(vec![value_tmp]);
+        }
+        let mut lanes = vec![];
+        for lane in 0..self.simd_lanes {
+            lanes.push(self.gen_extract_lane(value_ty, &value_tmp, lane, ctx)?);
+        }
+        Ok(lanes)
+    }
+
+    /// Generates code to extract one lane of a SIMD value or a struct of them, returning the LLVM
+    /// name of the scalar (or struct of scalars) in that lane.
+    fn gen_extract_lane(&mut self,
+                        ty: &Type,
+                        value_tmp: &str,
+                        lane: u32,
+                        ctx: &mut FunctionContext)
+                        -> WeldResult<String> {
+        let ty_str = self.llvm_type(ty)?.to_string();
+        match *ty {
+            Simd(_) => {
+                let elem = ctx.var_ids.next();
+                ctx.code.add(format!("{} = extractelement {} {}, i32 {}", elem, ty_str, value_tmp, lane));
+                Ok(elem)
+            }
+            Struct(ref field_tys) => {
+                let lane_ty = Struct(field_tys.iter().map(lane_type).collect());
+                let lane_ty_str = self.llvm_type(&lane_ty)?.to_string();
+                let mut cur = "undef".to_string();
+                for (i, field_ty) in field_tys.iter().enumerate() {
+                    let field = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = extractvalue {} {}, {}", field, ty_str, value_tmp, i));
+                    let field_lane = self.gen_extract_lane(field_ty, &field, lane, ctx)?;
+                    let field_lane_ty_str = self.llvm_type(&lane_type(field_ty))?.to_string();
+                    let next = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = insertvalue {} {}, {} {}, {}",
+                                         next,
+                                         lane_ty_str,
+                                         cur,
+                                         field_lane_ty_str,
+                                         field_lane,
+                                         i));
+                    cur = next;
+                }
+                Ok(cur)
+            }
+            _ => weld_err!("Cannot extract a SIMD lane from type {}", print_type(ty)),
+        }
+    }
+
     /// Generate code for a Merge instruction, appending it to the given FunctionContext.
     fn gen_merge(&mut self,
                  builder_kind: &BuilderKind,
@@ -1601,30 +2003,32 @@ impl LlvmGenerator {
             Appender(ref t) => {
                 let bld_tmp = try!(self.load_var(llvm_symbol(builder).as_str(), &bld_ty_str, ctx));
                 let elem_ty_str = try!(self.llvm_type(t)).to_string();
-                let elem_tmp = try!(self.load_var(llvm_symbol(value).as_str(), &elem_ty_str, ctx));
-                ctx.code.add(format!("call {} {}.merge({} {}, {} {}, \
-                                        i32 %cur.tid)",
-                                        bld_ty_str,
-                                        bld_prefix,
-                                        bld_ty_str,
-                                        bld_tmp,
-                                        elem_ty_str,
-                                        elem_tmp));
+                for elem_tmp in self.gen_load_merge_values(value, func, ctx)? {
+                    ctx.code.add(format!("call {} {}.merge({} {}, {} {}, \
+                                            i32 %cur.tid)",
+                                            bld_ty_str,
+                                            bld_prefix,
+                                            bld_ty_str,
+                                            bld_tmp,
+                                            elem_ty_str,
+                                            elem_tmp));
+                }
             }
             
             DictMerger(ref kt, ref vt, _) => {
                 let bld_tmp = try!(self.load_var(llvm_symbol(builder).as_str(), &bld_ty_str, ctx));
                 let elem_ty = Struct(vec![*kt.clone(), *vt.clone()]);
                 let elem_ty_str = try!(self.llvm_type(&elem_ty)).to_string();
-                let elem_tmp = try!(self.load_var(llvm_symbol(value).as_str(), &elem_ty_str, ctx));
-                ctx.code.add(format!(
-                    "call {} {}.merge({} {}, {} {}, i32 %cur.tid)",
-                    bld_ty_str,
-                    bld_prefix,
-                    bld_ty_str,
-                    bld_tmp,
-                    elem_ty_str,
-                    elem_tmp));
+                for elem_tmp in self.gen_load_merge_values(value, func, ctx)? {
+                    ctx.code.add(format!(
+                        "call {} {}.merge({} {}, {} {}, i32 %cur.tid)",
+                        bld_ty_str,
+                        bld_prefix,
+                        bld_ty_str,
+                        bld_tmp,
+                        elem_ty_str,
+                        elem_tmp));
+                }
             }
             
             GroupMerger(ref kt, ref vt) => {
@@ -1645,8 +2049,8 @@ impl LlvmGenerator {
             Merger(ref t, ref op) => {
                 let bld_tmp = self.load_var(llvm_symbol(builder).as_str(), &bld_ty_str, ctx)?;
                 let value_ty = get_sym_ty(func, value)?;
-                let elem_ty_str = self.llvm_type(value_ty)?.to_string();
-                let elem_tmp = self.load_var(llvm_symbol(value).as_str(), &elem_ty_str, ctx)?;
+                let mut elem_ty_str = self.llvm_type(value_ty)?.to_string();
+                let mut elem_tmp = self.load_var(llvm_symbol(value).as_str(), &elem_ty_str, ctx)?;
                 let bld_ptr_raw = ctx.var_ids.next();
                 let bld_ptr = ctx.var_ids.next();
                 ctx.code.add(format!(
@@ -1657,7 +2061,11 @@ impl LlvmGenerator {
                     bld_tmp=bld_tmp));
 
                 // If the argument is vectorized, load the vector element.
-                if let Simd(_) = *value_ty {
+                if let Simd(kind) = *value_ty {
+                    let (widened, widened_ty_str) =
+                        self.gen_widen_merge_value(&elem_tmp, kind, *op, ctx)?;
+                    elem_tmp = widened;
+                    elem_ty_str = widened_ty_str;
                     ctx.code.add(format!(
                         "{bld_ptr} = call {elem_ty_str}* {bld_prefix}.vectorMergePtr({bld_ty_str} {bld_ptr_raw})",
                         bld_ptr=bld_ptr,
@@ -1665,7 +2073,6 @@ impl LlvmGenerator {
                         bld_prefix=bld_prefix,
                         bld_ty_str=bld_ty_str,
                         bld_ptr_raw=bld_ptr_raw));
-
                 } else {
                     ctx.code.add(format!(
                         "{bld_ptr} = call {elem_ty_str}* {bld_prefix}.scalarMergePtr({bld_ty_str} {bld_ptr_raw})",
@@ -1684,24 +2091,25 @@ impl LlvmGenerator {
                 let merge_ty = Struct(vec![Scalar(ScalarKind::I64), *t.clone()]);
                 let merge_ty_str = self.llvm_type(&merge_ty)?.to_string();
                 let bld_tmp = self.load_var(llvm_symbol(builder).as_str(), &bld_ty_str, ctx)?;
-                let elem_tmp = self.load_var(llvm_symbol(value).as_str(), &merge_ty_str, ctx)?;
-                let index_var = ctx.var_ids.next();
-                let elem_var = ctx.var_ids.next();
-                ctx.code.add(format!("{} = extractvalue {} {}, 0", index_var, merge_ty_str, elem_tmp));
-                ctx.code.add(format!("{} = extractvalue {} {}, 1", elem_var, merge_ty_str, elem_tmp));
-                let bld_ptr_raw = ctx.var_ids.next();
-                let bld_ptr = ctx.var_ids.next();
-                ctx.code.add(format!("{} = call i8* {}.merge_ptr({} {}, i64 {}, i32 %cur.tid)",
-                                        bld_ptr_raw,
-                                        bld_prefix,
-                                        bld_ty_str,
-                                        bld_tmp,
-                                        index_var));
-                ctx.code.add(format!("{} = bitcast i8* {} to {}*",
-                                        bld_ptr,
-                                        bld_ptr_raw,
-                                        elem_ty_str));
-                self.gen_merge_op(&bld_ptr, &elem_var, &elem_ty_str, op, t, ctx)?;
+                for elem_tmp in self.gen_load_merge_values(value, func, ctx)? {
+                    let index_var = ctx.var_ids.next();
+                    let elem_var = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = extractvalue {} {}, 0", index_var, merge_ty_str, elem_tmp));
+                    ctx.code.add(format!("{} = extractvalue {} {}, 1", elem_var, merge_ty_str, elem_tmp));
+                    let bld_ptr_raw = ctx.var_ids.next();
+                    let bld_ptr = ctx.var_ids.next();
+                    ctx.code.add(format!("{} = call i8* {}.merge_ptr({} {}, i64 {}, i32 %cur.tid)",
+                                            bld_ptr_raw,
+                                            bld_prefix,
+                                            bld_ty_str,
+                                            bld_tmp,
+                                            index_var));
+                    ctx.code.add(format!("{} = bitcast i8* {} to {}*",
+                                            bld_ptr,
+                                            bld_ptr_raw,
+                                            elem_ty_str));
+                    self.gen_merge_op(&bld_ptr, &elem_var, &elem_ty_str, op, t, ctx)?;
+                }
             }
         }
         
@@ -1744,16 +2152,16 @@ impl LlvmGenerator {
                 // Type of element to merge.
                 let elem_ty_str = self.llvm_type(t)?.to_string();
 
-                let output_str = format!("%{}", output);
+                let output_str = llvm_symbol(output);
 
-                // Vector type.
-                let ref vec_type = if let Scalar(ref k) = **t {
-                    Simd(k.clone())
+                // Vector type, which has as many lanes as the merger's vector part.
+                let merger_lanes = if let Scalar(ref k) = **t {
+                    self.merger_lanes(*k)
                 } else {
                     return weld_err!("Invalid non-scalar type in merger");
                 };
 
-                let elem_vec_ty_str = self.llvm_type(vec_type)?.to_string();
+                let elem_vec_ty_str = format!("<{} x {}>", merger_lanes, elem_ty_str);
 
                 // Builder type.
                 let bld_ty_str = try!(self.llvm_type(&bld_ty)).to_string();
@@ -1800,7 +2208,7 @@ impl LlvmGenerator {
                 let entry_label_v = label_ids.next();
                 let body_label_v = label_ids.next();
                 let done_label_v = label_ids.next();
-                let vector_width = format!("{}", vec_size(t)?);
+                let vector_width = format!("{}", merger_lanes);
 
                 ctx.code.add(format!(include_str!("resources/merger/merger_result_start.ll"),
                                         t0 = t0,
@@ -2059,7 +2467,6 @@ impl LlvmGenerator {
             builder_size = e.clone();
         }
 
-        // TODO(Deepak): Do more with annotations here...
         match *builder_kind {
             Appender(_) => {
                 let bld_tmp = ctx.var_ids.next();
@@ -2136,12 +2543,16 @@ impl LlvmGenerator {
                                         llvm_symbol(output)));
             }
             DictMerger(_, _, _) => {
+                // Prefer the expected number of keys to the raw size hint, since the dictionary
+                // must be sized to stay under its load factor.
+                let num_keys = annotations.num_keys().or(*annotations.size()).unwrap_or(0);
+                let capacity = dictionary_capacity(num_keys);
                 let bld_tmp = ctx.var_ids.next();
                 ctx.code.add(format!("{} = call {} {}.new(i64 {})",
                                         bld_tmp,
                                         bld_ty_str,
                                         bld_prefix,
-                                        builder_size));
+                                        capacity));
                 ctx.code.add(format!("store {} {}, {}* {}",
                                         bld_ty_str,
                                         bld_tmp,
@@ -2309,6 +2720,16 @@ fn binop_identity(op_kind: BinOpKind, ty: &Type) -> WeldResult<String> {
     }
 }
 
+/// Return the initial capacity of a dictionary expected to hold `num_keys` keys. Dictionaries
+/// require a power-of-two capacity and grow once they are 70% full, so this rounds up to the
+/// smallest power of two that holds `num_keys` without resizing. Huge annotations are clamped to
+/// the largest power-of-two capacity that fits in an `i64`.
+pub fn dictionary_capacity(num_keys: i64) -> i64 {
+    const MAX_CAPACITY: u64 = 1 << 62;
+    let min_capacity = (cmp::max(num_keys, 1) as u64).saturating_mul(10) / 7 + 1;
+    min_capacity.clamp(16, MAX_CAPACITY).next_power_of_two() as i64
+}
+
 /// Return the name of the LLVM instruction for a binary operation on a specific type.
 fn llvm_binop(op_kind: BinOpKind, ty: &Type) -> WeldResult<&'static str> {
     match (op_kind, ty) {
@@ -2488,19 +2909,24 @@ fn llvm_binop_vector(op_kind: BinOpKind, ty: &Type) -> WeldResult<(&'static str,
 
 /// Return the name of hte LLVM instruction for a cast operation between specific types.
 fn llvm_castop(ty1: &Type, ty2: &Type) -> WeldResult<&'static str> {
-    match (ty1, ty2) {
-        (&Scalar(F64), &Scalar(Bool)) => Ok("fptoui"),
-        (&Scalar(F32), &Scalar(Bool)) => Ok("fptoui"),
-        (&Scalar(Bool), &Scalar(F64)) => Ok("uitofp"),
-        (&Scalar(Bool), &Scalar(F32)) => Ok("uitofp"),
-        (&Scalar(F64), &Scalar(F32)) => Ok("fptrunc"),
-        (&Scalar(F32), &Scalar(F64)) => Ok("fpext"),
-        (&Scalar(F64), _) => Ok("fptosi"),
-        (&Scalar(F32), _) => Ok("fptosi"),
-        (_, &Scalar(F64)) => Ok("sitofp"),
-        (_, &Scalar(F32)) => Ok("sitofp"),
-        (&Scalar(Bool), _) => Ok("zext"),
-        (_, &Scalar(I64)) => Ok("sext"),
+    // Casts of SIMD values convert each lane, using the same instruction as for scalars.
+    let kinds = match (ty1, ty2) {
+        (&Scalar(k1), &Scalar(k2)) | (&Simd(k1), &Simd(k2)) => (k1, k2),
+        _ => return weld_err!("Can't cast {} to {}", print_type(ty1), print_type(ty2)),
+    };
+    match kinds {
+        (F64, Bool) => Ok("fptoui"),
+        (F32, Bool) => Ok("fptoui"),
+        (Bool, F64) => Ok("uitofp"),
+        (Bool, F32) => Ok("uitofp"),
+        (F64, F32) => Ok("fptrunc"),
+        (F32, F64) => Ok("fpext"),
+        (F64, _) => Ok("fptosi"),
+        (F32, _) => Ok("fptosi"),
+        (_, F64) => Ok("sitofp"),
+        (_, F32) => Ok("sitofp"),
+        (Bool, _) => Ok("zext"),
+        (_, I64) => Ok("sext"),
         _ => Ok("trunc"),
     }
 }
@@ -2535,7 +2961,7 @@ impl FunctionContext {
     }
 }
 
-fn get_combined_params(sir: &SirProgram, par_for: &ParallelForData) -> HashMap<Symbol, Type> {
+pub fn get_combined_params(sir: &SirProgram, par_for: &ParallelForData) -> HashMap<Symbol, Type> {
     let mut body_params = sir.funcs[par_for.body].params.clone();
     for (arg, ty) in sir.funcs[par_for.cont].params.iter() {
         body_params.insert(arg.clone(), ty.clone());
@@ -2543,7 +2969,7 @@ fn get_combined_params(sir: &SirProgram, par_for: &ParallelForData) -> HashMap<S
     body_params
 }
 
-fn get_sym_ty<'a>(func: &'a SirFunction, sym: &Symbol) -> WeldResult<&'a Type> {
+pub fn get_sym_ty<'a>(func: &'a SirFunction, sym: &Symbol) -> WeldResult<&'a Type> {
     if func.locals.get(sym).is_some() {
         Ok(func.locals.get(sym).unwrap())
     } else if func.params.get(sym).is_some() {
@@ -2553,12 +2979,96 @@ fn get_sym_ty<'a>(func: &'a SirFunction, sym: &Symbol) -> WeldResult<&'a Type> {
     }
 }
 
-/// Returns a vector size for a type. If a Vetor is passed in, returns the vector size of the
-/// element type.
+/// Returns `true` if a value of the given type is a SIMD value or a struct containing one.
+pub fn contains_simd(ty: &Type) -> bool {
+    match *ty {
+        Simd(_) => true,
+        Struct(ref field_tys) => field_tys.iter().any(contains_simd),
+        _ => false,
+    }
+}
+
+/// Returns the type of one lane of a SIMD value or a struct of them.
+pub fn lane_type(ty: &Type) -> Type {
+    match *ty {
+        Simd(kind) => Scalar(kind),
+        Struct(ref field_tys) => Struct(field_tys.iter().map(lane_type).collect()),
+        _ => ty.clone(),
+    }
+}
+
+/// Returns the number of lanes in the SIMD values of each vectorized loop of a program that
+/// targets SIMD registers of `simd_width` bits, keyed by the loop's body function.
 ///
-/// TODO for now just returning 4 for all types.
-fn vec_size(_: &Type) -> WeldResult<u32> {
-    Ok(4)
+/// Vectorized code mixes SIMD values of different element types (e.g. comparing two `simd[i64]`
+/// values gives a `simd[bool]`), so all the values in a loop must have the same number of lanes.
+/// We choose it so that values of the widest element type used by the loop, including the type
+/// it merges into a merger, fill a register; other loops do not narrow it. A FringeIter loop
+/// finishes the SimdIter loop whose builder it continues, so it uses the same number of lanes.
+pub fn loop_simd_lanes(sir: &SirProgram, simd_width: u32) -> HashMap<FunctionId, u32> {
+    let mut lanes = HashMap::new();
+    let mut builder_lanes = HashMap::new();
+    let mut fringe_loops = vec![];
+    for func in sir.funcs.iter() {
+        for block in func.blocks.iter() {
+            if let ParallelFor(ref pf) = block.terminator {
+                match pf.data[0].kind {
+                    IterKind::SimdIter => {
+                        let body = &sir.funcs[pf.body];
+                        let mut widest = 0;
+                        for ty in body.params.values().chain(body.locals.values()) {
+                            widest = cmp::max(widest, simd_elem_bits(ty));
+                        }
+                        // Mergers of vectorized loops keep a SIMD partial result per thread.
+                        let merger_elem = match body.params.get(&pf.builder) {
+                            Some(&Builder(Merger(ref elem, _), _)) => Some(elem),
+                            _ => None,
+                        };
+                        if let Some(&Scalar(kind)) = merger_elem.map(|e| &**e) {
+                            widest = cmp::max(widest, simd_elem_bits(&Simd(kind)));
+                        }
+                        let loop_lanes = if widest == 0 {
+                            default_simd_lanes(simd_width)
+                        } else {
+                            cmp::max(simd_width / widest, 1)
+                        };
+                        lanes.insert(pf.body, loop_lanes);
+                        builder_lanes.insert(pf.builder.clone(), loop_lanes);
+                    }
+                    IterKind::FringeIter => fringe_loops.push((pf.body, pf.builder.clone())),
+                    IterKind::ScalarIter => (),
+                }
+            }
+        }
+    }
+    for (body, builder) in fringe_loops {
+        if let Some(loop_lanes) = builder_lanes.get(&builder) {
+            lanes.insert(body, *loop_lanes);
+        }
+    }
+    lanes
+}
+
+/// Returns the number of lanes used outside vectorized loops, and in FringeIter loops which do not
+/// finish a SimdIter loop: as many 32-bit values as fit in a register of `simd_width` bits.
+pub fn default_simd_lanes(simd_width: u32) -> u32 {
+    cmp::max(simd_width / 32, 1)
+}
+
+/// Returns the size in bits of the widest element of any SIMD value stored in a value of the given
+/// type, or 0 if it holds none.
+pub fn simd_elem_bits(ty: &Type) -> u32 {
+    match *ty {
+        Simd(kind) => match kind {
+            Bool | I8 => 8,
+            I32 | F32 => 32,
+            I64 | F64 => 64,
+        },
+        Vector(ref elem) => simd_elem_bits(elem),
+        Dict(ref key, ref value) => cmp::max(simd_elem_bits(key), simd_elem_bits(value)),
+        Struct(ref fields) => fields.iter().map(simd_elem_bits).max().unwrap_or(0),
+        _ => 0,
+    }
 }
 
 #[test]
@@ -2579,3 +3089,15 @@ fn types() {
     let struct2 = parse_type("{i32,bool}").unwrap().to_type().unwrap();
     assert_eq!(gen.llvm_type(&struct2).unwrap(), "%s1");
 }
+
+#[test]
+fn dictionary_capacities() {
+    assert_eq!(dictionary_capacity(0), 16);
+    assert_eq!(dictionary_capacity(10), 16);
+    assert_eq!(dictionary_capacity(11), 16);
+    assert_eq!(dictionary_capacity(12), 32);
+    assert_eq!(dictionary_capacity(1000), 2048);
+    assert_eq!(dictionary_capacity(-5), 16);
+    assert_eq!(dictionary_capacity(1 << 61), 1 << 62);
+    assert_eq!(dictionary_capacity(i64::max_value()), 1 << 62);
+}
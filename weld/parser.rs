@@ -3,11 +3,13 @@
 //! Weld is designed to be parseable in one left-to-right pass through the input, without
 //! backtracking, so we simply track a position as we go and keep incrementing it.
 
+use std::collections::HashMap;
 use std::vec::Vec;
 
 use super::ast::Annotations;
 use super::ast::Symbol;
 use super::ast::Iter;
+use super::ast::{BinOpKind, LiteralKind, Type, TypedParameter};
 use super::ast::BinOpKind::*;
 use super::ast::UnaryOpKind::*;
 use super::ast::BuilderImplementationKind::*;
@@ -20,10 +22,10 @@ use super::partial_types::*;
 use super::partial_types::PartialBuilderKind::*;
 use super::partial_types::PartialType::*;
 use super::program::*;
+use super::sir::*;
 use super::tokenizer::*;
 use super::tokenizer::Token::*;
 
-#[cfg(test)]
 use super::pretty_print::*;
 
 /// Parse the complete input string as a Weld program (optional macros plus one expression).
@@ -70,11 +72,26 @@ pub fn parse_type(input: &str) -> WeldResult<PartialType> {
     res
 }
 
+/// Parse the complete input string as a SIR program, in the form printed by its `Display`
+/// implementation.
+pub fn parse_sir(input: &str) -> WeldResult<SirProgram> {
+    let tokens = try!(tokenize(input));
+    let mut parser = Parser::new(&tokens);
+    parser.unsuffixed_sizes = true;
+    let res = parser.sir_program();
+    if res.is_ok() && !parser.is_done() {
+        return weld_err!("Unexpected token: {}", parser.peek());
+    }
+    res
+}
+
 /// A stateful object that parses a sequence of tokens, tracking its position at each point.
 /// Assumes that the tokens end with a TEndOfInput.
 struct Parser<'t> {
     tokens: &'t [Token],
     position: usize,
+    /// Whether `size` and `num_keys` annotations may be i32 literals, as they are in printed types.
+    unsuffixed_sizes: bool,
 }
 
 impl<'t> Parser<'t> {
@@ -82,6 +99,7 @@ impl<'t> Parser<'t> {
         Parser {
             tokens: tokens,
             position: 0,
+            unsuffixed_sizes: false,
         }
     }
 
@@ -90,6 +108,16 @@ impl<'t> Parser<'t> {
         &self.tokens[self.position]
     }
 
+    /// Look at the token `n` tokens after the next one, or the end of the input if there is none.
+    fn peek_ahead(&self, n: usize) -> &'t Token {
+        let last = self.tokens.len() - 1;
+        if self.position + n < last {
+            &self.tokens[self.position + n]
+        } else {
+            &self.tokens[last]
+        }
+    }
+
     /// Consume and return the next token.
     fn next(&mut self) -> &'t Token {
         let token = &self.tokens[self.position];
@@ -551,26 +579,35 @@ impl<'t> Parser<'t> {
                             "grain_size" => {
                                 self.consume(TIdent("grain_size".to_string()))?;
                                 try!(self.consume(TColon));
-                                if let TI32Literal(l) = *self.next() {
-                                    annotations.set_grain_size(l);
-                                } else {
-                                    return weld_err!("Invalid tile size (must be a i32)");
+                                match *self.next() {
+                                    TI32Literal(l) if l > 0 => annotations.set_grain_size(l),
+                                    _ => {
+                                        return weld_err!("Invalid grain size (must be a positive \
+                                                          i32)")
+                                    }
                                 }
                             }
+                            "bounds_check" => {
+                                // Only set by the compiler on loops whose iterators provably fit.
+                                return weld_err!("Invalid annotation type: bounds_check is \
+                                                  internal");
+                            }
                             "size" => {
                                 self.consume(TIdent("size".to_string()))?;
                                 try!(self.consume(TColon));
-                                if let TI64Literal(l) = *self.next() {
-                                    annotations.set_size(l);
-                                } else {
-                                    return weld_err!("Invalid vector size (must be a i32)");
+                                match *self.next() {
+                                    TI64Literal(l) => annotations.set_size(l),
+                                    TI32Literal(l) if self.unsuffixed_sizes => {
+                                        annotations.set_size(l as i64)
+                                    }
+                                    _ => return weld_err!("Invalid vector size (must be a i32)"),
                                 }
                             }
                             "selectivity" => {
                                 self.consume(TIdent("selectivity".to_string()))?;
                                 try!(self.consume(TColon));
                                 if let TF32Literal(l) = *self.next() {
-                                    annotations.set_selectivity((l * 100000.0) as i32);
+                                    annotations.set_selectivity((l * 10000.0) as i32);
                                 } else {
                                     return weld_err!("Invalid selectivity (must be a f32)");
                                 }
@@ -578,10 +615,14 @@ impl<'t> Parser<'t> {
                             "num_keys" => {
                                 self.consume(TIdent("num_keys".to_string()))?;
                                 try!(self.consume(TColon));
-                                if let TI64Literal(l) = *self.next() {
-                                    annotations.set_num_keys(l);
-                                } else {
-                                    return weld_err!("Invalid number of keys (must be a i32)");
+                                match *self.next() {
+                                    TI64Literal(l) => annotations.set_num_keys(l),
+                                    TI32Literal(l) if self.unsuffixed_sizes => {
+                                        annotations.set_num_keys(l as i64)
+                                    }
+                                    _ => {
+                                        return weld_err!("Invalid number of keys (must be a i32)")
+                                    }
                                 }
                             }
                             _ => return weld_err!("Invalid annotation type"),
@@ -713,11 +754,13 @@ impl<'t> Parser<'t> {
                 try!(self.consume(TComma));
                 let on_false = try!(self.expr());
                 try!(self.consume(TCloseParen));
-                Ok(expr_box(If {
-                                cond: cond,
-                                on_true: on_true,
-                                on_false: on_false,
-                            }))
+                let mut expr = expr_box(If {
+                                            cond: cond,
+                                            on_true: on_true,
+                                            on_false: on_false,
+                                        });
+                expr.annotations = annotations;
+                Ok(expr)
             }
 
             TSelect => {
@@ -807,11 +850,13 @@ impl<'t> Parser<'t> {
                 try!(self.consume(TComma));
                 let body = try!(self.expr());
                 try!(self.consume(TCloseParen));
-                Ok(expr_box(For {
-                                iters: iters,
-                                builder: builders,
-                                func: body,
-                            }))
+                let mut expr = expr_box(For {
+                                            iters: iters,
+                                            builder: builders,
+                                            func: body,
+                                        });
+                expr.annotations = annotations;
+                Ok(expr)
             }
 
             TLen => {
@@ -1153,6 +1198,18 @@ impl<'t> Parser<'t> {
                            annotations))
             }
 
+            TGroupMerger => {
+                try!(self.consume(TOpenBracket));
+                let key_type = try!(self.type_());
+                try!(self.consume(TComma));
+                let value_type = try!(self.type_());
+                try!(self.consume(TCloseBracket));
+                Ok(Builder(GroupMerger(Box::new(key_type.clone()),
+                                       Box::new(value_type.clone()),
+                                       Box::new(Struct(vec![key_type, value_type]))),
+                           annotations))
+            }
+
             TVecMerger => {
                 let elem_type: PartialType;
                 let bin_op: _;
@@ -1197,11 +1254,663 @@ impl<'t> Parser<'t> {
                 Ok(Struct(types))
             }
 
+            TIdent(ref name) if name == "dict" => {
+                try!(self.consume(TOpenBracket));
+                let key_type = try!(self.type_());
+                try!(self.consume(TComma));
+                let value_type = try!(self.type_());
+                try!(self.consume(TCloseBracket));
+                Ok(Dict(Box::new(key_type), Box::new(value_type)))
+            }
+
             TQuestion => Ok(Unknown),
 
             ref other => weld_err!("Expected type but got '{}'", other),
         }
     }
+
+    /// Is the token `n` tokens after the next one the start of a type?
+    fn at_type(&self, n: usize) -> bool {
+        match *self.peek_ahead(n) {
+            TI32 | TI64 | TF32 | TF64 | TI8 | TBool | TVec | TSimd | TAppender | TMerger |
+            TDictMerger | TGroupMerger | TVecMerger | TOpenBrace | TAtMark => true,
+            TIdent(ref name) => name == "dict" && *self.peek_ahead(n + 1) == TOpenBracket,
+            _ => false,
+        }
+    }
+
+    /// Parse a SIR program: its return type and arguments, followed by its functions in order.
+    fn sir_program(&mut self) -> WeldResult<SirProgram> {
+        try!(self.sir_heading("Returns"));
+        let ret_ty = try!(try!(self.type_()).to_type());
+        try!(self.sir_heading("Arguments"));
+        let mut top_params = vec![];
+        while self.at_sir_declaration() {
+            let (name, ty) = try!(self.sir_declaration());
+            top_params.push(TypedParameter { name: name, ty: ty });
+        }
+
+        let mut funcs = vec![];
+        while !self.is_done() {
+            let func = try!(self.sir_function());
+            if func.id != funcs.len() {
+                return weld_err!("Expected F{} but got F{}", funcs.len(), func.id);
+            }
+            funcs.push(func);
+        }
+        if funcs.is_empty() {
+            return weld_err!("Expected a SIR function");
+        }
+        let ret_ty = Type::Function(top_params.iter().map(|p| p.ty.clone()).collect(),
+                                    Box::new(ret_ty));
+        Ok(SirProgram::from_functions(&ret_ty, &top_params, funcs))
+    }
+
+    /// Parse a heading such as "Params:".
+    fn sir_heading(&mut self, name: &str) -> WeldResult<()> {
+        try!(self.consume(TIdent(name.to_string())));
+        self.consume(TColon)
+    }
+
+    /// Parse a function or block label such as "F1" or "B2" and return its ID.
+    fn sir_label(&mut self, prefix: char) -> WeldResult<usize> {
+        match *self.next() {
+            TIdent(ref name) => {
+                match sir_label_id(name, prefix) {
+                    Some(id) => Ok(id),
+                    None => weld_err!("Expected {}<id> but got '{}'", prefix, name),
+                }
+            }
+            ref other => weld_err!("Expected {}<id> but got '{}'", prefix, other),
+        }
+    }
+
+    /// Is the next input a label such as "B1:" starting a block or function?
+    fn at_sir_label(&self, prefix: char) -> bool {
+        let is_label = match *self.peek() {
+            TIdent(ref name) => sir_label_id(name, prefix).is_some(),
+            _ => false,
+        };
+        is_label && *self.peek_ahead(1) == TColon && !self.at_sir_declaration()
+    }
+
+    /// Parse a SIR symbol, which is printed as its name followed by "#<id>" if its ID is nonzero.
+    fn sir_symbol(&mut self) -> WeldResult<Symbol> {
+        let mut sym = try!(self.symbol());
+        if *self.peek() == THash {
+            try!(self.consume(THash));
+            match *self.next() {
+                TI32Literal(id) => sym.id = id,
+                ref other => return weld_err!("Expected symbol ID but got '{}'", other),
+            }
+        }
+        Ok(sym)
+    }
+
+    /// Is the next input the declaration of a symbol's type, such as "x#1: i32"?
+    fn at_sir_declaration(&self) -> bool {
+        let colon = match (self.peek(), self.peek_ahead(1)) {
+            (&TIdent(_), &THash) => 3,
+            (&TIdent(_), _) => 1,
+            _ => return false,
+        };
+        *self.peek_ahead(colon) == TColon && self.at_type(colon + 1)
+    }
+
+    /// Parse the declaration of a symbol's type.
+    fn sir_declaration(&mut self) -> WeldResult<(Symbol, Type)> {
+        let sym = try!(self.sir_symbol());
+        try!(self.consume(TColon));
+        let ty = try!(try!(self.type_()).to_type());
+        Ok((sym, ty))
+    }
+
+    /// Parse a SIR function: its label, parameters, locals and blocks.
+    fn sir_function(&mut self) -> WeldResult<SirFunction> {
+        let id = try!(self.sir_label('F'));
+        try!(self.consume(TColon));
+        try!(self.sir_heading("Params"));
+        let mut params = HashMap::new();
+        while self.at_sir_declaration() {
+            let (sym, ty) = try!(self.sir_declaration());
+            params.insert(sym, ty);
+        }
+        try!(self.sir_heading("Locals"));
+        let mut locals = HashMap::new();
+        while self.at_sir_declaration() {
+            let (sym, ty) = try!(self.sir_declaration());
+            locals.insert(sym, ty);
+        }
+        let mut func = SirFunction {
+            id: id,
+            params: params,
+            locals: locals,
+            blocks: vec![],
+        };
+        while self.at_sir_label('B') {
+            let block = try!(self.sir_block(&func));
+            if block.id != func.blocks.len() {
+                return weld_err!("Expected B{} in F{} but got B{}", func.blocks.len(), id, block.id);
+            }
+            func.blocks.push(block);
+        }
+        Ok(func)
+    }
+
+    /// Parse a basic block of `func`: its label, statements and terminator.
+    fn sir_block(&mut self, func: &SirFunction) -> WeldResult<BasicBlock> {
+        let id = try!(self.sir_label('B'));
+        try!(self.consume(TColon));
+        let mut statements = vec![];
+        while !self.at_sir_terminator() {
+            statements.push(try!(self.sir_statement(func)));
+        }
+        let terminator = try!(self.sir_terminator());
+        Ok(BasicBlock {
+               id: id,
+               statements: statements,
+               terminator: terminator,
+           })
+    }
+
+    /// Is the next input a terminator rather than a statement?
+    fn at_sir_terminator(&self) -> bool {
+        match *self.peek() {
+            TFor | TEndOfInput => true,
+            TIdent(ref name) => {
+                ["branch", "jump", "return", "end", "crash"].contains(&name.as_str()) &&
+                *self.peek_ahead(1) != TEqual && *self.peek_ahead(1) != THash
+            }
+            _ => false,
+        }
+    }
+
+    /// Parse a SIR statement in `func`. Types which are not printed as part of a statement are
+    /// taken from the declarations of its symbols.
+    fn sir_statement(&mut self, func: &SirFunction) -> WeldResult<Statement> {
+        if *self.peek() == TMerge {
+            try!(self.consume(TMerge));
+            let builder = try!(self.sir_symbol());
+            let value = try!(self.sir_symbol());
+            return Ok(Statement::Merge {
+                          builder: builder,
+                          value: value,
+                      });
+        }
+
+        let output = try!(self.sir_symbol());
+        try!(self.consume(TEqual));
+        let output_ty = try!(sir_declared_type(func, &output)).clone();
+
+        if let Some(op) = sir_binop(self.peek()) {
+            // A minus sign may also start a negation or a negative literal.
+            if op != Subtract || self.at_type(1) {
+                self.next();
+                let ty = try!(try!(self.type_()).to_type());
+                let left = try!(self.sir_symbol());
+                let right = try!(self.sir_symbol());
+                return Ok(Statement::BinOp {
+                              output: output,
+                              op: op,
+                              ty: ty,
+                              left: left,
+                              right: right,
+                          });
+            }
+            if let TIdent(_) = *self.peek_ahead(1) {
+                self.next();
+                let child = try!(self.sir_symbol());
+                return Ok(Statement::Negate {
+                              output: output,
+                              child: child,
+                          });
+            }
+        }
+
+        match *self.peek() {
+            TExp | TLog | TSqrt | TErf => {
+                let op = match *self.next() {
+                    TExp => Exp,
+                    TLog => Log,
+                    TSqrt => Sqrt,
+                    _ => Erf,
+                };
+                let args = try!(self.sir_arguments());
+                let child = try!(sir_single_argument(args));
+                Ok(Statement::UnaryOp {
+                       output: output,
+                       op: op,
+                       child: child,
+                   })
+            }
+
+            TBroadcast => {
+                self.next();
+                let child = try!(sir_single_argument(try!(self.sir_arguments())));
+                Ok(Statement::Broadcast {
+                       output: output,
+                       child: child,
+                   })
+            }
+
+            TLen => {
+                self.next();
+                let child = try!(sir_single_argument(try!(self.sir_arguments())));
+                Ok(Statement::Length {
+                       output: output,
+                       child: child,
+                   })
+            }
+
+            TLookup | TKeyExists | TSlice | TSelect => {
+                let token = self.next();
+                let mut args = try!(self.sir_arguments()).into_iter();
+                let expected = if *token == TSlice || *token == TSelect { 3 } else { 2 };
+                if args.len() != expected {
+                    return weld_err!("Expected {} arguments to {}", expected, token);
+                }
+                let (first, second) = (args.next().unwrap(), args.next().unwrap());
+                Ok(match *token {
+                       TLookup => {
+                           Statement::Lookup {
+                               output: output,
+                               child: first,
+                               index: second,
+                           }
+                       }
+                       TKeyExists => {
+                           Statement::KeyExists {
+                               output: output,
+                               child: first,
+                               key: second,
+                           }
+                       }
+                       TSlice => {
+                           Statement::Slice {
+                               output: output,
+                               child: first,
+                               index: second,
+                               size: args.next().unwrap(),
+                           }
+                       }
+                       _ => {
+                           Statement::Select {
+                               output: output,
+                               cond: first,
+                               on_true: second,
+                               on_false: args.next().unwrap(),
+                           }
+                       }
+                   })
+            }
+
+            TResult => {
+                self.next();
+                let builder = try!(self.sir_symbol());
+                Ok(Statement::Res {
+                       output: output,
+                       builder: builder,
+                   })
+            }
+
+            TCUDF => {
+                self.next();
+                try!(self.consume(TOpenBracket));
+                let symbol_name = match *self.next() {
+                    TIdent(ref name) => name.clone(),
+                    ref other => return weld_err!("Expected UDF name but got '{}'", other),
+                };
+                try!(self.consume(TCloseBracket));
+                let args = try!(self.sir_arguments());
+                Ok(Statement::CUDF {
+                       output: output,
+                       symbol_name: symbol_name,
+                       args: args,
+                   })
+            }
+
+            TIdent(ref name) if name == "cast" && *self.peek_ahead(1) == TOpenParen => {
+                self.next();
+                try!(self.consume(TOpenParen));
+                let child = try!(self.sir_symbol());
+                try!(self.consume(TComma));
+                let new_ty = try!(try!(self.type_()).to_type());
+                try!(self.consume(TCloseParen));
+                Ok(Statement::Cast {
+                       output: output,
+                       new_ty: new_ty,
+                       child: child,
+                   })
+            }
+
+            TIdent(ref name) if name == "toVec" && *self.peek_ahead(1) == TOpenParen => {
+                self.next();
+                let child = try!(sir_single_argument(try!(self.sir_arguments())));
+                Ok(Statement::ToVec {
+                       output: output,
+                       child: child,
+                   })
+            }
+
+            TIdent(ref name) if name == "new" &&
+                                (self.at_type(1) ||
+                                 *self.peek_ahead(1) == TOpenBracket) => {
+                self.next();
+                self.sir_constructor(func, output, &output_ty)
+            }
+
+            TIdent(_) => {
+                let value = try!(self.sir_symbol());
+                if *self.peek() != TDot {
+                    return Ok(Statement::Assign {
+                                  output: output,
+                                  value: value,
+                              });
+                }
+                try!(self.consume(TDot));
+                let index = match *self.next() {
+                    TIdent(ref field) if field.starts_with("$") => field[1..].parse::<u32>().ok(),
+                    _ => None,
+                };
+                match index {
+                    Some(index) => {
+                        Ok(Statement::GetField {
+                               output: output,
+                               value: value,
+                               index: index,
+                           })
+                    }
+                    None => weld_err!("Expected struct field index after {}", value),
+                }
+            }
+
+            _ => {
+                let value = try!(self.sir_literal(&output_ty));
+                Ok(Statement::AssignLiteral {
+                       output: output,
+                       value: value,
+                   })
+            }
+        }
+    }
+
+    /// Parse the rest of a statement starting with "new", which makes a struct, a vector or a
+    /// builder assigned to `output`.
+    fn sir_constructor(&mut self,
+                       func: &SirFunction,
+                       output: Symbol,
+                       output_ty: &Type)
+                       -> WeldResult<Statement> {
+        match *self.peek() {
+            TOpenBrace => {
+                self.next();
+                let mut elems = vec![];
+                while *self.peek() != TCloseBrace {
+                    let sym = try!(self.sir_symbol());
+                    let ty = try!(sir_declared_type(func, &sym)).clone();
+                    elems.push((sym, ty));
+                    if *self.peek() == TComma {
+                        self.next();
+                    }
+                }
+                try!(self.consume(TCloseBrace));
+                Ok(Statement::MakeStruct {
+                       output: output,
+                       elems: elems,
+                   })
+            }
+
+            TOpenBracket => {
+                self.next();
+                let mut elems = vec![];
+                while *self.peek() != TCloseBracket {
+                    elems.push(try!(self.sir_symbol()));
+                    if *self.peek() == TComma {
+                        self.next();
+                    }
+                }
+                try!(self.consume(TCloseBracket));
+                let elem_ty = match *output_ty {
+                    Type::Vector(ref elem_ty) => elem_ty.as_ref().clone(),
+                    _ => return weld_err!("Expected {} to be a vector", output),
+                };
+                Ok(Statement::MakeVector {
+                       output: output,
+                       elems: elems,
+                       elem_ty: elem_ty,
+                   })
+            }
+
+            _ => {
+                let ty = try!(try!(self.type_()).to_type());
+                let mut args = try!(self.sir_arguments());
+                if args.len() > 1 {
+                    return weld_err!("Expected at most one argument to new {}", output);
+                }
+                Ok(Statement::NewBuilder {
+                       output: output,
+                       arg: args.pop(),
+                       ty: ty,
+                   })
+            }
+        }
+    }
+
+    /// Parse a parenthesized, comma-separated list of symbols.
+    fn sir_arguments(&mut self) -> WeldResult<Vec<Symbol>> {
+        try!(self.consume(TOpenParen));
+        let mut args = vec![];
+        while *self.peek() != TCloseParen {
+            args.push(try!(self.sir_symbol()));
+            if *self.peek() == TComma {
+                self.next();
+            } else if *self.peek() != TCloseParen {
+                return weld_err!("Expected ',' or ')'");
+            }
+        }
+        try!(self.consume(TCloseParen));
+        Ok(args)
+    }
+
+    /// Parse a literal assigned to a symbol of type `ty`. Integer literals are printed without a
+    /// suffix for both i8 and i32, so the literal's kind is taken from the symbol's type.
+    fn sir_literal(&mut self, ty: &Type) -> WeldResult<LiteralKind> {
+        let negative = *self.peek() == TMinus;
+        if negative {
+            self.next();
+        }
+        let token = self.next();
+        let literal = match (token, ty) {
+            (&TBoolLiteral(v), &Type::Scalar(ScalarKind::Bool)) if !negative => BoolLiteral(v),
+            (&TI32Literal(v), &Type::Scalar(ScalarKind::I8)) => {
+                I8Literal((if negative { -v } else { v }) as i8)
+            }
+            (&TI32Literal(v), &Type::Scalar(ScalarKind::I32)) => {
+                I32Literal(if negative { -v } else { v })
+            }
+            (&TI64Literal(v), &Type::Scalar(ScalarKind::I64)) => {
+                I64Literal(if negative { -v } else { v })
+            }
+            (&TF32Literal(v), &Type::Scalar(ScalarKind::F32)) => {
+                F32Literal(if negative { -v } else { v })
+            }
+            (&TF64Literal(v), &Type::Scalar(ScalarKind::F64)) => {
+                F64Literal(if negative { -v } else { v })
+            }
+            _ => return weld_err!("Expected a literal of type {} but got '{}'", print_type(ty), token),
+        };
+        Ok(literal)
+    }
+
+    /// Parse a SIR terminator.
+    fn sir_terminator(&mut self) -> WeldResult<Terminator> {
+        match *self.next() {
+            TFor => self.sir_parallel_for(),
+            TIdent(ref name) if name == "branch" => {
+                let cond = try!(self.sir_symbol());
+                let on_true = try!(self.sir_label('B'));
+                let on_false = try!(self.sir_label('B'));
+                Ok(Terminator::Branch {
+                       cond: cond,
+                       on_true: on_true,
+                       on_false: on_false,
+                   })
+            }
+            TIdent(ref name) if name == "jump" => {
+                let to_function = match *self.peek() {
+                    TIdent(ref label) => sir_label_id(label, 'F').is_some(),
+                    _ => false,
+                };
+                if to_function {
+                    Ok(Terminator::JumpFunction(try!(self.sir_label('F'))))
+                } else {
+                    Ok(Terminator::JumpBlock(try!(self.sir_label('B'))))
+                }
+            }
+            TIdent(ref name) if name == "return" => {
+                Ok(Terminator::ProgramReturn(try!(self.sir_symbol())))
+            }
+            TIdent(ref name) if name == "end" => Ok(Terminator::EndFunction),
+            TIdent(ref name) if name == "crash" => Ok(Terminator::Crash),
+            ref other => weld_err!("Expected terminator but got '{}'", other),
+        }
+    }
+
+    /// Parse the rest of a parallel for loop terminator after "for".
+    fn sir_parallel_for(&mut self) -> WeldResult<Terminator> {
+        try!(self.consume(TOpenBracket));
+        let mut data = vec![];
+        while *self.peek() != TCloseBracket {
+            data.push(try!(self.sir_iter()));
+            try!(self.consume(TComma));
+        }
+        try!(self.consume(TCloseBracket));
+        let builder = try!(self.sir_symbol());
+        let builder_arg = try!(self.sir_symbol());
+        let idx_arg = try!(self.sir_symbol());
+        let data_arg = try!(self.sir_symbol());
+        let body = try!(self.sir_label('F'));
+        let cont = try!(self.sir_label('F'));
+        let innermost = match *self.next() {
+            TBoolLiteral(innermost) => innermost,
+            ref other => return weld_err!("Expected true or false but got '{}'", other),
+        };
+
+        let mut grain_size = None;
+        if *self.peek() == TIdent("grain_size".to_string()) {
+            self.next();
+            try!(self.consume(TEqual));
+            match *self.next() {
+                TI32Literal(size) if size > 0 => grain_size = Some(size),
+                ref other => return weld_err!("Expected positive grain size but got '{}'", other),
+            }
+        }
+        let mut bounds_check = true;
+        if *self.peek() == TIdent("bounds_check".to_string()) {
+            self.next();
+            try!(self.consume(TEqual));
+            try!(self.consume(TBoolLiteral(false)));
+            bounds_check = false;
+        }
+
+        Ok(Terminator::ParallelFor(ParallelForData {
+                                       data: data,
+                                       builder: builder,
+                                       data_arg: data_arg,
+                                       builder_arg: builder_arg,
+                                       idx_arg: idx_arg,
+                                       body: body,
+                                       cont: cont,
+                                       innermost: innermost,
+                                       grain_size: grain_size,
+                                       bounds_check: bounds_check,
+                                   }))
+    }
+
+    /// Parse one iterator of a parallel for loop: either a vector, or an iterator such as
+    /// "simditer(v)" or "iter(v, start, end, stride)".
+    fn sir_iter(&mut self) -> WeldResult<ParallelForIter> {
+        let kind = match *self.peek() {
+            TScalarIter => ScalarIter,
+            TSimdIter => SimdIter,
+            TFringeIter => FringeIter,
+            _ => {
+                return Ok(ParallelForIter {
+                              data: try!(self.sir_symbol()),
+                              start: None,
+                              end: None,
+                              stride: None,
+                              kind: ScalarIter,
+                          })
+            }
+        };
+        self.next();
+        let mut args = try!(self.sir_arguments()).into_iter();
+        let data = match args.next() {
+            Some(data) => data,
+            None => return weld_err!("Expected vector in iterator"),
+        };
+        let bounds: Vec<Symbol> = args.collect();
+        if bounds.len() != 0 && bounds.len() != 3 {
+            return weld_err!("Expected start, end and stride in iterator over {}", data);
+        }
+        let mut bounds = bounds.into_iter();
+        Ok(ParallelForIter {
+               data: data,
+               start: bounds.next(),
+               end: bounds.next(),
+               stride: bounds.next(),
+               kind: kind,
+           })
+    }
+}
+
+/// Returns the ID in a SIR label such as "F1", if `name` is a label with the given prefix.
+fn sir_label_id(name: &str, prefix: char) -> Option<usize> {
+    if name.starts_with(prefix) {
+        name[1..].parse::<usize>().ok()
+    } else {
+        None
+    }
+}
+
+/// Returns the binary operator printed as `token` in SIR statements, if any.
+fn sir_binop(token: &Token) -> Option<BinOpKind> {
+    match *token {
+        TPlus => Some(Add),
+        TMinus => Some(Subtract),
+        TTimes => Some(Multiply),
+        TDivide => Some(Divide),
+        TModulo => Some(Modulo),
+        TEqualEqual => Some(Equal),
+        TNotEqual => Some(NotEqual),
+        TLessThan => Some(LessThan),
+        TLessThanOrEqual => Some(LessThanOrEqual),
+        TGreaterThan => Some(GreaterThan),
+        TGreaterThanOrEqual => Some(GreaterThanOrEqual),
+        TLogicalAnd => Some(LogicalAnd),
+        TLogicalOr => Some(LogicalOr),
+        TBitwiseAnd => Some(BitwiseAnd),
+        TBar => Some(BitwiseOr),
+        TXor => Some(Xor),
+        _ => None,
+    }
+}
+
+/// Returns the only symbol in `args`.
+fn sir_single_argument(args: Vec<Symbol>) -> WeldResult<Symbol> {
+    if args.len() != 1 {
+        return weld_err!("Expected one argument but got {}", args.len());
+    }
+    Ok(args.into_iter().next().unwrap())
+}
+
+/// Returns the type `func` declares for `sym` as a parameter or local.
+fn sir_declared_type<'a>(func: &'a SirFunction, sym: &Symbol) -> WeldResult<&'a Type> {
+    match func.locals.get(sym).or(func.params.get(sym)) {
+        Some(ty) => Ok(ty),
+        None => weld_err!("Symbol {} is not declared in F{}", sym, func.id),
+    }
 }
 
 #[test]
@@ -1293,6 +2002,9 @@ fn basic_parsing() {
 
     let t = parse_type("{}").unwrap();
     assert_eq!(print_type(&t), "{}");
+
+    let t = parse_type("{dict[i32,vec[i64]], groupmerger[i32,f64]}").unwrap();
+    assert_eq!(print_type(&t), "{dict[i32,vec[i64]],groupmerger[i32,f64]}");
 }
 
 #[test]
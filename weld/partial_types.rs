@@ -407,7 +407,255 @@ impl PartialExpr {
         Ok(TypedExpr {
                ty: try!(self.ty.to_type()),
                kind: new_kind,
-               annotations: Annotations::new(),
+               annotations: self.annotations.clone(),
            })
     }
 }
+
+impl Type {
+    /// Convert a Type to the equivalent complete PartialType.
+    pub fn to_partial(&self) -> PartialType {
+        use self::PartialBuilderKind::*;
+        match *self {
+            Type::Scalar(kind) => PartialType::Scalar(kind),
+            Type::Simd(kind) => PartialType::Simd(kind),
+            Type::Vector(ref elem) => PartialType::Vector(Box::new(elem.to_partial())),
+            Type::Dict(ref kt, ref vt) => {
+                PartialType::Dict(Box::new(kt.to_partial()), Box::new(vt.to_partial()))
+            }
+            Type::Builder(ref kind, ref annotations) => {
+                let partial_kind = match *kind {
+                    BuilderKind::Appender(ref elem) => Appender(Box::new(elem.to_partial())),
+                    BuilderKind::DictMerger(ref kt, ref vt, op) => {
+                        let (kt, vt) = (kt.to_partial(), vt.to_partial());
+                        DictMerger(Box::new(kt.clone()),
+                                   Box::new(vt.clone()),
+                                   Box::new(PartialType::Struct(vec![kt, vt])),
+                                   op)
+                    }
+                    BuilderKind::GroupMerger(ref kt, ref vt) => {
+                        let (kt, vt) = (kt.to_partial(), vt.to_partial());
+                        GroupMerger(Box::new(kt.clone()),
+                                    Box::new(vt.clone()),
+                                    Box::new(PartialType::Struct(vec![kt, vt])))
+                    }
+                    BuilderKind::VecMerger(ref elem, op) => {
+                        let elem = elem.to_partial();
+                        VecMerger(Box::new(elem.clone()),
+                                  Box::new(PartialType::Struct(vec![PartialType::Scalar(ScalarKind::I64),
+                                                                    elem])),
+                                  op)
+                    }
+                    BuilderKind::Merger(ref elem, op) => Merger(Box::new(elem.to_partial()), op),
+                };
+                PartialType::Builder(partial_kind, annotations.clone())
+            }
+            Type::Struct(ref elems) => {
+                PartialType::Struct(elems.iter().map(|e| e.to_partial()).collect())
+            }
+            Type::Function(ref params, ref res) => {
+                PartialType::Function(params.iter().map(|p| p.to_partial()).collect(),
+                                      Box::new(res.to_partial()))
+            }
+        }
+    }
+}
+
+impl TypedParameter {
+    pub fn to_partial(&self) -> PartialParameter {
+        PartialParameter {
+            name: self.name.clone(),
+            ty: self.ty.to_partial(),
+        }
+    }
+}
+
+impl TypedExpr {
+    /// Convert a typed expression to a partially typed one with the same (complete) types, for
+    /// example to check its types again with `type_inference::infer_types`.
+    pub fn to_partial(&self) -> PartialExpr {
+        use ast::ExprKind::*;
+
+        fn partial_box(expr: &Box<TypedExpr>) -> Box<PartialExpr> {
+            Box::new(expr.to_partial())
+        }
+
+        fn partial_option(expr: &Option<Box<TypedExpr>>) -> Option<Box<PartialExpr>> {
+            expr.as_ref().map(partial_box)
+        }
+
+        fn partial_vec(exprs: &Vec<TypedExpr>) -> Vec<PartialExpr> {
+            exprs.iter().map(|e| e.to_partial()).collect()
+        }
+
+        let new_kind: ExprKind<PartialType> = match self.kind {
+            Literal(kind) => Literal(kind),
+            Ident(ref name) => Ident(name.clone()),
+            CUDF {
+                ref sym_name,
+                ref args,
+                ref return_ty,
+            } => {
+                CUDF {
+                    sym_name: sym_name.clone(),
+                    args: partial_vec(args),
+                    return_ty: Box::new(return_ty.to_partial()),
+                }
+            }
+            BinOp {
+                kind,
+                ref left,
+                ref right,
+            } => {
+                BinOp {
+                    kind: kind,
+                    left: partial_box(left),
+                    right: partial_box(right),
+                }
+            }
+            UnaryOp { kind, ref value } => {
+                UnaryOp {
+                    kind: kind,
+                    value: partial_box(value),
+                }
+            }
+            Cast {
+                kind,
+                ref child_expr,
+            } => {
+                Cast {
+                    kind: kind,
+                    child_expr: partial_box(child_expr),
+                }
+            }
+            ToVec { ref child_expr } => ToVec { child_expr: partial_box(child_expr) },
+            Let {
+                ref name,
+                ref value,
+                ref body,
+            } => {
+                Let {
+                    name: name.clone(),
+                    value: partial_box(value),
+                    body: partial_box(body),
+                }
+            }
+            Lambda {
+                ref params,
+                ref body,
+            } => {
+                Lambda {
+                    params: params.iter().map(|p| p.to_partial()).collect(),
+                    body: partial_box(body),
+                }
+            }
+            MakeVector { ref elems } => MakeVector { elems: partial_vec(elems) },
+            Zip { ref vectors } => Zip { vectors: partial_vec(vectors) },
+            MakeStruct { ref elems } => MakeStruct { elems: partial_vec(elems) },
+            GetField { ref expr, index } => {
+                GetField {
+                    expr: partial_box(expr),
+                    index: index,
+                }
+            }
+            Length { ref data } => Length { data: partial_box(data) },
+            Lookup {
+                ref data,
+                ref index,
+            } => {
+                Lookup {
+                    data: partial_box(data),
+                    index: partial_box(index),
+                }
+            }
+            KeyExists { ref data, ref key } => {
+                KeyExists {
+                    data: partial_box(data),
+                    key: partial_box(key),
+                }
+            }
+            Slice {
+                ref data,
+                ref index,
+                ref size,
+            } => {
+                Slice {
+                    data: partial_box(data),
+                    index: partial_box(index),
+                    size: partial_box(size),
+                }
+            }
+            Merge {
+                ref builder,
+                ref value,
+            } => {
+                Merge {
+                    builder: partial_box(builder),
+                    value: partial_box(value),
+                }
+            }
+            Res { ref builder } => Res { builder: partial_box(builder) },
+            For {
+                ref iters,
+                ref builder,
+                ref func,
+            } => {
+                For {
+                    iters: iters.iter()
+                        .map(|iter| {
+                                 Iter {
+                                     data: partial_box(&iter.data),
+                                     start: partial_option(&iter.start),
+                                     end: partial_option(&iter.end),
+                                     stride: partial_option(&iter.stride),
+                                     kind: iter.kind.clone(),
+                                 }
+                             })
+                        .collect(),
+                    builder: partial_box(builder),
+                    func: partial_box(func),
+                }
+            }
+            If {
+                ref cond,
+                ref on_true,
+                ref on_false,
+            } => {
+                If {
+                    cond: partial_box(cond),
+                    on_true: partial_box(on_true),
+                    on_false: partial_box(on_false),
+                }
+            }
+            Select {
+                ref cond,
+                ref on_true,
+                ref on_false,
+            } => {
+                Select {
+                    cond: partial_box(cond),
+                    on_true: partial_box(on_true),
+                    on_false: partial_box(on_false),
+                }
+            }
+            Apply {
+                ref func,
+                ref params,
+            } => {
+                Apply {
+                    func: partial_box(func),
+                    params: partial_vec(params),
+                }
+            }
+            NewBuilder(ref arg) => NewBuilder(partial_option(arg)),
+            Negate(ref expr) => Negate(partial_box(expr)),
+            Broadcast(ref expr) => Broadcast(partial_box(expr)),
+        };
+
+        PartialExpr {
+            ty: self.ty.to_partial(),
+            kind: new_kind,
+            annotations: self.annotations.clone(),
+        }
+    }
+}
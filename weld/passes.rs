@@ -4,42 +4,78 @@ use super::transforms;
 use super::vectorizer;
 
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+/// A transform run by a pass. Any closure of the right type, or a trait object wrapping one, can
+/// be used as a transform.
+pub type Transform = Arc<Fn(&mut Expr<Type>) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Pass {
-    transforms: Vec<fn(&mut Expr<Type>)>,
+    transforms: Vec<Transform>,
     pass_name: String,
+    fixpoint: bool,
 }
 
-/// Manually implement Clone for Pass because it cannot be #derived due to the fn type inside it.
-impl Clone for Pass {
-    fn clone(&self) -> Pass {
+impl Pass {
+    pub fn new(transforms: Vec<fn(&mut Expr<Type>)>, pass_name: &'static str) -> Pass {
+        let transforms = transforms.into_iter().map(|t| Arc::new(t) as Transform).collect();
+        Pass::with_transforms(transforms, pass_name, true)
+    }
+
+    /// Returns a pass which runs `transforms` in order. If `fixpoint` is set, the transforms are
+    /// run repeatedly until the expression stops changing; otherwise they are run once.
+    pub fn with_transforms(transforms: Vec<Transform>, pass_name: &str, fixpoint: bool) -> Pass {
         Pass {
-            transforms: self.transforms.iter().map(|p| *p).collect::<Vec<_>>(),
-            pass_name: self.pass_name.clone()
+            transforms: transforms,
+            pass_name: String::from(pass_name),
+            fixpoint: fixpoint,
         }
     }
-}
 
-impl Pass {
-    pub fn new(transforms: Vec<fn(&mut Expr<Type>)>, pass_name: &'static str) -> Pass {
-        return Pass {
-                   transforms: transforms,
-                   pass_name: String::from(pass_name),
-               };
-    }
+    /// Runs the pass on `expr` and returns statistics about the run. A fixpoint pass which is still
+    /// changing the expression after `max_iterations` iterations returns an error.
+    pub fn transform(&self,
+                     mut expr: &mut Expr<Type>,
+                     max_iterations: usize)
+                     -> WeldResult<PassStats> {
+        let start = Instant::now();
+        let nodes_before = expr.num_nodes();
+        let mut iterations = 0;
 
-    pub fn transform(&self, mut expr: &mut Expr<Type>) -> WeldResult<()> {
-        let mut expr_copy = expr.clone();
-        let mut continue_pass = true;
-        while continue_pass {
+        if !self.fixpoint {
             for transform in &self.transforms {
                 transform(&mut expr);
             }
+            iterations += 1;
+        } else {
+            let mut expr_copy = expr.clone();
+            let mut continue_pass = true;
+            while continue_pass {
+                if iterations == max_iterations {
+                    return weld_err!("Optimization pass {} did not converge after {} iterations",
+                                     self.pass_name,
+                                     max_iterations);
+                }
+                for transform in &self.transforms {
+                    transform(&mut expr);
+                }
+                iterations += 1;
 
-            continue_pass = !try!(expr.compare_ignoring_symbols(&expr_copy));
-            expr_copy = expr.clone();
+                continue_pass = !try!(expr.compare_ignoring_symbols(&expr_copy));
+                expr_copy = expr.clone();
+            }
         }
-        Ok(())
+
+        Ok(PassStats {
+            pass_name: self.pass_name.clone(),
+            iterations: iterations,
+            time: start.elapsed(),
+            nodes_before: nodes_before,
+            nodes_after: expr.num_nodes(),
+        })
     }
 
     pub fn pass_name(&self) -> String {
@@ -47,6 +83,45 @@ impl Pass {
     }
 }
 
+/// Statistics about one run of an optimization pass.
+#[derive(Clone, Debug)]
+pub struct PassStats {
+    pub pass_name: String,
+    /// The number of times the pass's transforms were run. This is always 1 for a pass which does
+    /// not run to a fixpoint.
+    pub iterations: usize,
+    /// The wall time spent in the pass, including the comparisons made to detect a fixpoint.
+    pub time: Duration,
+    /// The number of nodes in the expression before the pass ran.
+    pub nodes_before: usize,
+    /// The number of nodes in the expression after the pass ran.
+    pub nodes_after: usize,
+}
+
+impl fmt::Display for PassStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.time.as_secs() as f64 * 1e3 + self.time.subsec_nanos() as f64 / 1e6;
+        write!(f,
+               "{}: {} iterations, {:.3} ms, {} -> {} nodes",
+               self.pass_name,
+               self.iterations,
+               millis,
+               self.nodes_before,
+               self.nodes_after)
+    }
+}
+
+/// Where a registered pass runs when `weld.optimization.passes` does not list the passes to run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PassOrder {
+    /// The pass only runs when it is listed in `weld.optimization.passes`.
+    Manual,
+    /// The pass runs immediately before the given built-in pass in the default pipeline.
+    Before(String),
+    /// The pass runs immediately after the given built-in pass in the default pipeline.
+    After(String),
+}
+
 lazy_static! {
     pub static ref OPTIMIZATION_PASSES: HashMap<&'static str, Pass> = {
         let mut m = HashMap::new();
@@ -56,13 +131,129 @@ lazy_static! {
                  Pass::new(vec![transforms::inline_let], "inline-let"));
         m.insert("inline-zip",
                  Pass::new(vec![transforms::inline_zips], "inline-zip"));
+        m.insert("fold-constants",
+                 Pass::new(vec![transforms::fold_constants], "fold-constants"));
         m.insert("loop-fusion",
                  Pass::new(vec![transforms::fuse_loops_horizontal,
-                                transforms::fuse_loops_vertical],
+                                transforms::fuse_loops_vertical,
+                                transforms::fuse_independent_loops],
                  "loop-fusion"));
+        m.insert("projection-pushdown",
+                 Pass::new(vec![transforms::eliminate_dead_fields], "projection-pushdown"));
+        m.insert("loop-tiling",
+                 Pass::new(vec![transforms::tile_loops], "loop-tiling"));
+        m.insert("predicate",
+                 Pass::new(vec![transforms::predicate], "predicate"));
+        m.insert("cse",
+                 Pass::new(vec![transforms::common_subexpression_elimination], "cse"));
+        m.insert("licm",
+                 Pass::new(vec![transforms::hoist_loop_invariants], "licm"));
         m.insert("vectorize",
                  Pass::new(vec![vectorizer::vectorize],
                  "vectorize"));
+        m.insert("infer-size",
+                 Pass::new(vec![transforms::infer_size],
+                 "infer-size"));
         m
     };
 }
+
+lazy_static! {
+    /// Passes added with `register_pass`, in the order in which they were registered.
+    static ref REGISTERED_PASSES: RwLock<Vec<(Pass, PassOrder)>> = RwLock::new(vec![]);
+}
+
+/// Registers a pass so that `weld.optimization.passes` can list it by name. `order` determines
+/// where the pass runs when the default pipeline is used.
+///
+/// Returns an error if a pass with the same name already exists, or if `order` refers to a pass
+/// which is not built in.
+pub fn register_pass(pass: Pass, order: PassOrder) -> WeldResult<()> {
+    match order {
+        PassOrder::Before(ref name) |
+        PassOrder::After(ref name) => {
+            if !OPTIMIZATION_PASSES.contains_key(name.as_str()) {
+                return weld_err!("Unknown optimization pass in ordering hint: {}", name);
+            }
+        }
+        PassOrder::Manual => (),
+    }
+    let mut registered = REGISTERED_PASSES.write().unwrap();
+    if OPTIMIZATION_PASSES.contains_key(pass.pass_name.as_str()) ||
+       registered.iter().any(|r| r.0.pass_name == pass.pass_name) {
+        return weld_err!("Optimization pass {} already exists", pass.pass_name);
+    }
+    registered.push((pass, order));
+    Ok(())
+}
+
+/// Registers a pass which runs a single closure. See `register_pass`.
+pub fn register_transform<F>(pass_name: &str,
+                             order: PassOrder,
+                             fixpoint: bool,
+                             transform: F)
+                             -> WeldResult<()>
+    where F: Fn(&mut Expr<Type>) + Send + Sync + 'static
+{
+    register_pass(Pass::with_transforms(vec![Arc::new(transform)], pass_name, fixpoint),
+                  order)
+}
+
+/// Removes a pass added with `register_pass`, returning whether a pass with the given name was
+/// registered.
+pub fn unregister_pass(pass_name: &str) -> bool {
+    let mut registered = REGISTERED_PASSES.write().unwrap();
+    let count = registered.len();
+    registered.retain(|r| r.0.pass_name != pass_name);
+    registered.len() != count
+}
+
+/// Returns the built-in or registered pass with the given name.
+pub fn get_pass(pass_name: &str) -> Option<Pass> {
+    if let Some(pass) = OPTIMIZATION_PASSES.get(pass_name) {
+        return Some(pass.clone());
+    }
+    let registered = REGISTERED_PASSES.read().unwrap();
+    registered.iter().find(|r| r.0.pass_name == pass_name).map(|r| r.0.clone())
+}
+
+/// Returns the built-in passes named in `pass_names`, with the registered passes whose ordering
+/// hints refer to them inserted before or after them.
+pub fn with_registered_passes(pass_names: &[&str]) -> Vec<Pass> {
+    let registered = REGISTERED_PASSES.read().unwrap();
+    order_passes(pass_names, &registered)
+}
+
+/// Returns the built-in passes named in `pass_names`, with the passes in `registered` inserted
+/// according to their ordering hints.
+fn order_passes(pass_names: &[&str], registered: &[(Pass, PassOrder)]) -> Vec<Pass> {
+    let mut result = vec![];
+    for name in pass_names {
+        let before = PassOrder::Before(name.to_string());
+        let after = PassOrder::After(name.to_string());
+        result.extend(registered.iter().filter(|r| r.1 == before).map(|r| r.0.clone()));
+        if let Some(pass) = OPTIMIZATION_PASSES.get(name) {
+            result.push(pass.clone());
+        }
+        result.extend(registered.iter().filter(|r| r.1 == after).map(|r| r.0.clone()));
+    }
+    result
+}
+
+#[test]
+fn pass_ordering() {
+    let noop = |name: &str| {
+        Pass::with_transforms(vec![Arc::new(|_: &mut Expr<Type>| ())], name, false)
+    };
+    let registered = vec![(noop("after-inline-let"), PassOrder::After("inline-let".to_string())),
+                          (noop("before-inline-apply"),
+                           PassOrder::Before("inline-apply".to_string())),
+                          (noop("manual"), PassOrder::Manual)];
+    let names = order_passes(&["inline-apply", "inline-let", "inline-zip"], &registered)
+        .iter()
+        .map(|p| p.pass_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names,
+               vec!["before-inline-apply", "inline-apply", "inline-let", "after-inline-let",
+                    "inline-zip"]);
+}
@@ -404,7 +404,8 @@ fn print_expr_impl<T: PrintableType>(expr: &Expr<T>,
             ref builder,
             ref func,
         } => {
-            format!("for(\n{}{},\n{}{},\n{}{}\n{})",
+            format!("{}for(\n{}{},\n{}{},\n{}{}\n{})",
+                    expr.annotations,
                     indent_str,
                     print_iters(iters, typed, indent + 2, should_indent),
                     indent_str,
@@ -419,7 +420,8 @@ fn print_expr_impl<T: PrintableType>(expr: &Expr<T>,
             ref on_true,
             ref on_false,
         } => {
-            format!("if(\n{}{},\n{}{},\n{}{}\n{})",
+            format!("{}if(\n{}{},\n{}{},\n{}{}\n{})",
+                    expr.annotations,
                     indent_str,
                     print_expr_impl(cond, typed, indent + 2, should_indent),
                     indent_str,
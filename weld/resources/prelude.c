@@ -0,0 +1,58 @@
+/* Common prelude added at the start of generated C modules. */
+
+#include <math.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* Weld runtime functions, resolved against the runtime library loaded into the process. */
+typedef struct work_t work_t;
+
+extern void weld_rt_init(int64_t mem_limit);
+extern void *weld_rt_malloc(int64_t run_id, int64_t size);
+extern void *weld_rt_realloc(int64_t run_id, void *data, int64_t size);
+extern void weld_rt_free(int64_t run_id, void *data);
+extern void weld_rt_set_errno(int64_t run_id, int64_t errno_value);
+extern int64_t weld_rt_get_errno(int64_t run_id);
+extern void weld_abort_thread(void);
+extern void set_nworkers(int32_t nworkers);
+extern int64_t get_runid(void);
+extern void set_result(void *result);
+extern void *get_result(void);
+extern void execute(void (*run)(work_t *), void *data);
+
+/* Input argument (input data pointer, nworkers, mem_limit). */
+typedef struct input_arg_t {
+  int64_t input;
+  int32_t nworkers;
+  int64_t mem_limit;
+} input_arg_t;
+
+/* Return type (output data pointer, run ID, errno). */
+typedef struct output_arg_t {
+  int64_t output;
+  int64_t run_id;
+  int64_t errno_value;
+} output_arg_t;
+
+/* Same as hash_combine in prelude.ll, so that dictionaries iterate in the same order. */
+static int64_t hash_combine(int64_t seed, int64_t value) {
+  uint64_t s = (uint64_t)seed;
+  return (int64_t)(s ^ ((uint64_t)value + UINT64_C(2654435769) + (s << 6) + (s >> 2)));
+}
+
+/* Hash functions on scalars. */
+static int64_t bool_hash(uint8_t value) { return (int64_t)value; }
+static int64_t i8_hash(int8_t value) { return (int64_t)(uint8_t)value; }
+static int64_t i32_hash(int32_t value) { return (int64_t)(uint32_t)value; }
+static int64_t i64_hash(int64_t value) { return value; }
+static int64_t f32_hash(float value) { uint32_t bits; memcpy(&bits, &value, sizeof(bits)); return (int64_t)bits; }
+static int64_t f64_hash(double value) { int64_t bits; memcpy(&bits, &value, sizeof(bits)); return bits; }
+
+/* Comparison functions on scalars, returning -1, 0 or 1. */
+static int32_t bool_cmp(uint8_t a, uint8_t b) { return a == b ? 0 : (a < b ? -1 : 1); }
+static int32_t i8_cmp(int8_t a, int8_t b) { return a == b ? 0 : (a < b ? -1 : 1); }
+static int32_t i32_cmp(int32_t a, int32_t b) { return a == b ? 0 : (a < b ? -1 : 1); }
+static int32_t i64_cmp(int64_t a, int64_t b) { return a == b ? 0 : (a < b ? -1 : 1); }
+static int32_t f32_cmp(float a, float b) { return a == b ? 0 : (a < b ? -1 : 1); }
+static int32_t f64_cmp(double a, double b) { return a == b ? 0 : (a < b ? -1 : 1); }
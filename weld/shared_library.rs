@@ -0,0 +1,156 @@
+//! Loading of compiled Weld modules from shared libraries.
+//!
+//! A shared library holding a Weld module exports a `run` function with the same signature as the
+//! one in JIT-compiled LLVM modules: it takes a pointer to a `WeldInputArgs` and returns a pointer
+//! to a `WeldOutputArgs`, both passed as `i64`. Its references to the Weld runtime are resolved
+//! against the runtime library already loaded into the process.
+//!
+//! `dlopen` returns the library already loaded from a path instead of loading it again, even if the
+//! file has since been replaced, so libraries at paths chosen by users are loaded from a copy with a
+//! unique name.
+//!
+//! Backends build shared libraries by compiling a module into an object file and linking it with
+//! the system C compiler, which is the one named by the `CC` environment variable, or `cc` if it is
+//! not set.
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+use super::error::*;
+
+/// Counter used to give each temporary file a unique name.
+static TEMP_FILE_IDS: AtomicUsize = AtomicUsize::new(0);
+
+/// A shared library opened with `dlopen`, which is closed when this is dropped.
+pub struct SharedLibrary {
+    handle: *mut libc::c_void,
+    run_function: extern "C" fn(i64) -> i64,
+}
+
+impl SharedLibrary {
+    /// Opens the shared library at `path` and looks up its `run` function.
+    pub fn open(path: &str) -> WeldResult<SharedLibrary> {
+        SharedLibrary::open_as(path, path)
+    }
+
+    /// Opens a copy of the shared library at `path`, so that a library loaded from `path` before
+    /// it was replaced is not returned instead.
+    pub fn open_copy(path: &Path) -> WeldResult<SharedLibrary> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("so");
+        let copy = temp_path(extension);
+        if let Err(e) = fs::copy(path, &copy) {
+            return weld_err!("Could not load shared library {}: {}", path.display(), e);
+        }
+        let result = SharedLibrary::open_as(&copy.to_string_lossy(), &path.to_string_lossy());
+        let _ = fs::remove_file(&copy);
+        result
+    }
+
+    /// Opens the shared library at `path`, naming it `name` in error messages.
+    fn open_as(path: &str, name: &str) -> WeldResult<SharedLibrary> {
+        let c_path = match CString::new(path) {
+            Ok(p) => p,
+            Err(_) => return weld_err!("Invalid shared library path: {}", name),
+        };
+        unsafe {
+            let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return weld_err!("Could not load shared library {}: {}", name, dl_error());
+            }
+            let run_name = CString::new("run").unwrap();
+            let run_function = libc::dlsym(handle, run_name.as_ptr());
+            if run_function.is_null() {
+                let message = dl_error();
+                libc::dlclose(handle);
+                return weld_err!("No run function in shared library {}: {}", name, message);
+            }
+            Ok(SharedLibrary {
+                handle: handle,
+                run_function: mem::transmute::<*mut libc::c_void, extern "C" fn(i64) -> i64>(
+                    run_function),
+            })
+        }
+    }
+
+    /// Calls the library's `run` function.
+    pub fn run(&self, arg: i64) -> i64 {
+        (self.run_function)(arg)
+    }
+}
+
+impl Drop for SharedLibrary {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+/// Returns the message describing the last error from `dlopen` or `dlsym`.
+fn dl_error() -> String {
+    unsafe {
+        let message = libc::dlerror();
+        if message.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(message).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Returns a new path in the temporary directory for a file with the given extension.
+pub fn temp_path(extension: &str) -> PathBuf {
+    let id = TEMP_FILE_IDS.fetch_add(1, Ordering::SeqCst);
+    env::temp_dir().join(format!("weld-{}-{}.{}", process::id(), id, extension))
+}
+
+/// Returns a command that runs the system C compiler.
+pub fn c_compiler() -> Command {
+    Command::new(env::var("CC").unwrap_or("cc".to_string()))
+}
+
+/// Links an object file into a shared library. References to the Weld runtime are left undefined,
+/// to be resolved when the library is loaded.
+pub fn link(object: &Path, library: &Path) -> WeldResult<()> {
+    let mut command = c_compiler();
+    command.arg("-shared");
+    if cfg!(target_os = "macos") {
+        command.args(&["-undefined", "dynamic_lookup"]);
+    }
+    command.arg("-o").arg(library).arg(object).arg("-lm");
+    run_compiler(command, object)
+}
+
+/// Compiles a module into an object file with `compile_object`, then links it into a shared
+/// library and loads it.
+pub fn compile_and_load<F>(object: &Path,
+                           library: &Path,
+                           compile_object: F)
+                           -> WeldResult<SharedLibrary>
+    where F: FnOnce(&Path) -> WeldResult<()>
+{
+    try!(compile_object(object));
+    try!(link(object, library));
+    SharedLibrary::open(&library.to_string_lossy())
+}
+
+/// Runs a C compiler command on `input`, returning its error messages if it fails.
+pub fn run_compiler(mut command: Command, input: &Path) -> WeldResult<()> {
+    match command.output() {
+        Ok(ref output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            weld_err!("Compiling {} failed:\n{}",
+                      input.display(),
+                      String::from_utf8_lossy(&output.stderr))
+        }
+        Err(e) => weld_err!("Could not run C compiler: {}", e),
+    }
+}
@@ -106,6 +106,11 @@ pub struct ParallelForData {
     pub body: FunctionId,
     pub cont: FunctionId,
     pub innermost: bool,
+    /// Number of iterations handed to a worker at a time, if set by a `grain_size` annotation.
+    pub grain_size: Option<i32>,
+    /// Whether the iterators must be checked against the lengths of their vectors before the loop
+    /// runs. This is false if `check_zip_lengths` proved that they fit.
+    pub bounds_check: bool,
 }
 
 /// A terminating statement inside a basic block.
@@ -160,6 +165,29 @@ impl SirProgram {
         prog
     }
 
+    /// Creates a program from existing functions, such as those of a parsed SIR program. Locals
+    /// added to it are given IDs that do not clash with the symbols already in `funcs`.
+    pub fn from_functions(ret_ty: &Type,
+                          top_params: &Vec<TypedParameter>,
+                          funcs: Vec<SirFunction>)
+                          -> SirProgram {
+        let mut sym_gen = SymbolGenerator::new();
+        for param in top_params {
+            sym_gen.add_symbol(&param.name);
+        }
+        for func in funcs.iter() {
+            for sym in func.params.keys().chain(func.locals.keys()) {
+                sym_gen.add_symbol(sym);
+            }
+        }
+        SirProgram {
+            funcs: funcs,
+            ret_ty: ret_ty.clone(),
+            top_params: top_params.clone(),
+            sym_gen: sym_gen,
+        }
+    }
+
     pub fn add_func(&mut self) -> FunctionId {
         let func = SirFunction {
             id: self.funcs.len(),
@@ -203,6 +231,172 @@ impl BasicBlock {
     }
 }
 
+impl Statement {
+    /// Returns the symbol this statement assigns, if any.
+    pub fn output(&self) -> Option<&Symbol> {
+        use self::Statement::*;
+        match *self {
+            BinOp { ref output, .. } |
+            UnaryOp { ref output, .. } |
+            Negate { ref output, .. } |
+            Broadcast { ref output, .. } |
+            Cast { ref output, .. } |
+            Lookup { ref output, .. } |
+            KeyExists { ref output, .. } |
+            Slice { ref output, .. } |
+            Select { ref output, .. } |
+            CUDF { ref output, .. } |
+            ToVec { ref output, .. } |
+            Length { ref output, .. } |
+            Assign { ref output, .. } |
+            AssignLiteral { ref output, .. } |
+            Res { ref output, .. } |
+            NewBuilder { ref output, .. } |
+            MakeStruct { ref output, .. } |
+            MakeVector { ref output, .. } |
+            GetField { ref output, .. } => Some(output),
+            Merge { .. } => None,
+        }
+    }
+
+    /// Returns a mutable reference to the symbol this statement assigns, if any.
+    pub fn output_mut(&mut self) -> Option<&mut Symbol> {
+        use self::Statement::*;
+        match *self {
+            BinOp { ref mut output, .. } |
+            UnaryOp { ref mut output, .. } |
+            Negate { ref mut output, .. } |
+            Broadcast { ref mut output, .. } |
+            Cast { ref mut output, .. } |
+            Lookup { ref mut output, .. } |
+            KeyExists { ref mut output, .. } |
+            Slice { ref mut output, .. } |
+            Select { ref mut output, .. } |
+            CUDF { ref mut output, .. } |
+            ToVec { ref mut output, .. } |
+            Length { ref mut output, .. } |
+            Assign { ref mut output, .. } |
+            AssignLiteral { ref mut output, .. } |
+            Res { ref mut output, .. } |
+            NewBuilder { ref mut output, .. } |
+            MakeStruct { ref mut output, .. } |
+            MakeVector { ref mut output, .. } |
+            GetField { ref mut output, .. } => Some(output),
+            Merge { .. } => None,
+        }
+    }
+
+    /// Returns the symbols this statement reads, in order.
+    pub fn children(&self) -> Vec<&Symbol> {
+        use self::Statement::*;
+        match *self {
+            BinOp { ref left, ref right, .. } => vec![left, right],
+            UnaryOp { ref child, .. } |
+            Negate { ref child, .. } |
+            Broadcast { ref child, .. } |
+            Cast { ref child, .. } |
+            ToVec { ref child, .. } |
+            Length { ref child, .. } => vec![child],
+            Lookup { ref child, ref index, .. } => vec![child, index],
+            KeyExists { ref child, ref key, .. } => vec![child, key],
+            Slice { ref child, ref index, ref size, .. } => vec![child, index, size],
+            Select { ref cond, ref on_true, ref on_false, .. } => vec![cond, on_true, on_false],
+            CUDF { ref args, .. } => args.iter().collect(),
+            Assign { ref value, .. } => vec![value],
+            AssignLiteral { .. } => vec![],
+            Merge { ref builder, ref value } => vec![builder, value],
+            Res { ref builder, .. } => vec![builder],
+            NewBuilder { ref arg, .. } => arg.iter().collect(),
+            MakeStruct { ref elems, .. } => elems.iter().map(|e| &e.0).collect(),
+            MakeVector { ref elems, .. } => elems.iter().collect(),
+            GetField { ref value, .. } => vec![value],
+        }
+    }
+
+    /// Returns mutable references to the symbols this statement reads, in order.
+    pub fn children_mut(&mut self) -> Vec<&mut Symbol> {
+        use self::Statement::*;
+        match *self {
+            BinOp { ref mut left, ref mut right, .. } => vec![left, right],
+            UnaryOp { ref mut child, .. } |
+            Negate { ref mut child, .. } |
+            Broadcast { ref mut child, .. } |
+            Cast { ref mut child, .. } |
+            ToVec { ref mut child, .. } |
+            Length { ref mut child, .. } => vec![child],
+            Lookup { ref mut child, ref mut index, .. } => vec![child, index],
+            KeyExists { ref mut child, ref mut key, .. } => vec![child, key],
+            Slice { ref mut child, ref mut index, ref mut size, .. } => vec![child, index, size],
+            Select { ref mut cond, ref mut on_true, ref mut on_false, .. } => {
+                vec![cond, on_true, on_false]
+            }
+            CUDF { ref mut args, .. } => args.iter_mut().collect(),
+            Assign { ref mut value, .. } => vec![value],
+            AssignLiteral { .. } => vec![],
+            Merge { ref mut builder, ref mut value } => vec![builder, value],
+            Res { ref mut builder, .. } => vec![builder],
+            NewBuilder { ref mut arg, .. } => arg.iter_mut().collect(),
+            MakeStruct { ref mut elems, .. } => elems.iter_mut().map(|e| &mut e.0).collect(),
+            MakeVector { ref mut elems, .. } => elems.iter_mut().collect(),
+            GetField { ref mut value, .. } => vec![value],
+        }
+    }
+}
+
+impl Terminator {
+    /// Returns the symbols this terminator reads, in order.
+    pub fn children(&self) -> Vec<&Symbol> {
+        use self::Terminator::*;
+        match *self {
+            Branch { ref cond, .. } => vec![cond],
+            ProgramReturn(ref sym) => vec![sym],
+            ParallelFor(ref pf) => {
+                let mut syms = vec![];
+                for iter in pf.data.iter() {
+                    syms.push(&iter.data);
+                    syms.extend(iter.start.iter());
+                    syms.extend(iter.end.iter());
+                    syms.extend(iter.stride.iter());
+                }
+                syms.push(&pf.builder);
+                syms
+            }
+            JumpBlock(_) | JumpFunction(_) | EndFunction | Crash => vec![],
+        }
+    }
+
+    /// Returns mutable references to the symbols this terminator reads, in order.
+    pub fn children_mut(&mut self) -> Vec<&mut Symbol> {
+        use self::Terminator::*;
+        match *self {
+            Branch { ref mut cond, .. } => vec![cond],
+            ProgramReturn(ref mut sym) => vec![sym],
+            ParallelFor(ref mut pf) => {
+                let mut syms = vec![];
+                for iter in pf.data.iter_mut() {
+                    syms.push(&mut iter.data);
+                    syms.extend(iter.start.iter_mut());
+                    syms.extend(iter.end.iter_mut());
+                    syms.extend(iter.stride.iter_mut());
+                }
+                syms.push(&mut pf.builder);
+                syms
+            }
+            JumpBlock(_) | JumpFunction(_) | EndFunction | Crash => vec![],
+        }
+    }
+
+    /// Returns the functions this terminator calls.
+    pub fn callees(&self) -> Vec<FunctionId> {
+        use self::Terminator::*;
+        match *self {
+            ParallelFor(ref pf) => vec![pf.body, pf.cont],
+            JumpFunction(func) => vec![func],
+            Branch { .. } | JumpBlock(_) | ProgramReturn(_) | EndFunction | Crash => vec![],
+        }
+    }
+}
+
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Statement::*;
@@ -306,7 +500,7 @@ impl fmt::Display for Statement {
                 write!(f,
                        "{} = new {}",
                        output,
-                       join("{", ",", "}", elems.iter().map(|e| e.0.name.clone())))
+                       join("{", ",", "}", elems.iter().map(|e| e.0.to_string())))
             }
             MakeVector {
                 ref output,
@@ -316,7 +510,7 @@ impl fmt::Display for Statement {
                 write!(f,
                        "{} = new {}",
                        output,
-                       join("[", ",", "]", elems.iter().map(|e| e.name.clone())))
+                       join("[", ",", "]", elems.iter().map(|e| e.to_string())))
             }
             CUDF {
                 ref output,
@@ -328,13 +522,13 @@ impl fmt::Display for Statement {
                        "{} = cudf[{}]{}",
                        output,
                        symbol_name,
-                       join("(", ",", ")", args.iter().map(|e| e.name.clone())))
+                       join("(", ",", ")", args.iter().map(|e| e.to_string())))
             }
             GetField {
                 ref output,
                 ref value,
                 index,
-            } => write!(f, "{} = {}.{}", output, value, index),
+            } => write!(f, "{} = {}.${}", output, value, index),
         }
     }
 }
@@ -363,6 +557,12 @@ impl fmt::Display for Terminator {
                        pf.body,
                        pf.cont,
                        pf.innermost)?;
+                if let Some(grain_size) = pf.grain_size {
+                    write!(f, " grain_size={}", grain_size)?;
+                }
+                if !pf.bounds_check {
+                    write!(f, " bounds_check=false")?;
+                }
                 Ok(())
             }
             JumpBlock(block) => write!(f, "jump B{}", block),
@@ -432,6 +632,16 @@ impl fmt::Display for SirFunction {
 
 impl fmt::Display for SirProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ret_ty = match self.ret_ty {
+            Type::Function(_, ref ret_ty) => ret_ty.as_ref(),
+            ref other => other,
+        };
+        write!(f, "Returns: {}\n", print_type(ret_ty))?;
+        write!(f, "Arguments:\n")?;
+        for param in &self.top_params {
+            write!(f, "  {}: {}\n", param.name, print_type(&param.ty))?;
+        }
+        write!(f, "\n")?;
         for func in &self.funcs {
             write!(f, "{}\n", func)?;
         }
@@ -455,130 +665,12 @@ fn sir_param_correction_helper(prog: &mut SirProgram,
     }
     // All symbols are unique, so there is no need to remove stuff from env at any point.
     for block in prog.funcs[func_id].blocks.clone() {
+        // Any existing symbols that are used (but not assigned) by the block.
         let mut vars = vec![];
         for statement in &block.statements {
-            use self::Statement::*;
-            match *statement {
-                // push any existing symbols that are used (but not assigned) by the statement
-                BinOp {
-                    ref left,
-                    ref right,
-                    ..
-                } => {
-                    vars.push(left.clone());
-                    vars.push(right.clone());
-                }
-                UnaryOp {
-                    ref child,
-                    ..
-                } => {
-                    vars.push(child.clone());
-                }
-                Cast { ref child, .. } => {
-                    vars.push(child.clone());
-                }
-                Negate { ref child, .. } => {
-                    vars.push(child.clone());
-                }
-                Broadcast { ref child, .. } => {
-                    vars.push(child.clone());
-                }
-                Lookup {
-                    ref child,
-                    ref index,
-                    ..
-                } => {
-                    vars.push(child.clone());
-                    vars.push(index.clone());
-                }
-                KeyExists { ref child, ref key, .. } => {
-                    vars.push(child.clone());
-                    vars.push(key.clone());
-                }
-                Slice {
-                    ref child,
-                    ref index,
-                    ref size,
-                    ..
-                } => {
-                    vars.push(child.clone());
-                    vars.push(index.clone());
-                    vars.push(size.clone());
-                }
-                Select {
-                    ref cond,
-                    ref on_true,
-                    ref on_false,
-                    ..
-                } => {
-                    vars.push(cond.clone());
-                    vars.push(on_true.clone());
-                    vars.push(on_false.clone());
-                }
-                ToVec { ref child, .. } => {
-                    vars.push(child.clone());
-                }
-                Length { ref child, .. } => {
-                    vars.push(child.clone());
-                }
-                Assign { ref value, .. } => vars.push(value.clone()),
-                Merge {
-                    ref builder,
-                    ref value,
-                } => {
-                    vars.push(builder.clone());
-                    vars.push(value.clone());
-                }
-                Res { ref builder, .. } => vars.push(builder.clone()),
-                GetField { ref value, .. } => vars.push(value.clone()),
-                AssignLiteral { .. } => {}
-                NewBuilder { ref arg, .. } => {
-                    if let Some(ref a) = *arg {
-                        vars.push(a.clone());
-                    }
-                }
-                MakeStruct { ref elems, .. } => {
-                    for elem in elems {
-                        vars.push(elem.0.clone());
-                    }
-                }
-                MakeVector { ref elems, .. } => {
-                    for elem in elems {
-                        vars.push(elem.clone());
-                    }
-                }
-                CUDF { ref args, .. } => {
-                    for arg in args {
-                        vars.push(arg.clone());
-                    }
-                }
-            }
-        }
-        use self::Terminator::*;
-        match block.terminator {
-            // push any existing symbols that are used by the terminator
-            Branch { ref cond, .. } => {
-                vars.push(cond.clone());
-            }
-            ProgramReturn(ref sym) => {
-                vars.push(sym.clone());
-            }
-            ParallelFor(ref pf) => {
-                for iter in pf.data.iter() {
-                    vars.push(iter.data.clone());
-                    if iter.start.is_some() {
-                        vars.push(iter.start.clone().unwrap());
-                        vars.push(iter.end.clone().unwrap());
-                        vars.push(iter.stride.clone().unwrap());
-                    }
-                }
-                vars.push(pf.builder.clone());
-            }
-            JumpBlock(_) => {}
-            JumpFunction(_) => {}
-            EndFunction => {}
-            Crash => {}
+            vars.extend(statement.children().into_iter().cloned());
         }
+        vars.extend(block.terminator.children().into_iter().cloned());
         for var in &vars {
             if prog.funcs[func_id].locals.get(&var) == None {
                 prog.funcs[func_id]
@@ -587,21 +679,10 @@ fn sir_param_correction_helper(prog: &mut SirProgram,
                 closure.insert(var.clone());
             }
         }
+        // Make a recursive call for other functions referenced by the terminator.
         let mut inner_closure = HashSet::new();
-        match block.terminator {
-            // make a recursive call for other functions referenced by the terminator
-            ParallelFor(ref pf) => {
-                sir_param_correction_helper(prog, pf.body, env, &mut inner_closure);
-                sir_param_correction_helper(prog, pf.cont, env, &mut inner_closure);
-            }
-            JumpFunction(jump_func) => {
-                sir_param_correction_helper(prog, jump_func, env, &mut inner_closure);
-            }
-            Branch { .. } => {}
-            JumpBlock(_) => {}
-            ProgramReturn(_) => {}
-            EndFunction => {}
-            Crash => {}
+        for callee in block.terminator.callees() {
+            sir_param_correction_helper(prog, callee, env, &mut inner_closure);
         }
         for var in inner_closure {
             if prog.funcs[func_id].locals.get(&var) == None {
@@ -1103,6 +1184,8 @@ fn gen_expr(expr: &TypedExpr,
                                     body: body_func,
                                     cont: cont_func,
                                     innermost: is_innermost,
+                                    grain_size: expr.annotations.grain_size().clone(),
+                                    bounds_check: expr.annotations.bounds_check().unwrap_or(true),
                                 });
                 Ok((cont_func, cont_block, builder_sym))
             } else {
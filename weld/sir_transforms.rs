@@ -0,0 +1,270 @@
+//! Optimization passes over SIR programs, run before code generation.
+//!
+//! `ast_to_sir` assigns the value of every subexpression to a new local, and the value of every
+//! `Let` to a copy of it. These passes remove the copies, and then the statements and locals which
+//! are no longer used.
+
+use std::collections::{HashMap, HashSet};
+
+use super::ast::*;
+use super::sir::*;
+use super::sir::Statement::*;
+
+/// The SIR passes run before code generation, in order.
+pub const SIR_PASSES: &'static [(&'static str, fn(&mut SirProgram))] =
+    &[("copy-propagation", copy_propagation),
+      ("dead-statement-elimination", eliminate_dead_statements),
+      ("unused-local-removal", remove_unused_locals)];
+
+/// Removes `Assign` statements which copy one local into another.
+///
+/// A copy `x = y` whose source `y` is a temporary, assigned once by an earlier statement in the
+/// same block and used only by the copy, is removed by assigning `x` in that statement instead.
+/// Otherwise, if `x` is assigned only by the copy, is used in no other function and `y` holds the
+/// same value everywhere `x` is used, the uses of `x` are replaced with `y`.
+pub fn copy_propagation(prog: &mut SirProgram) {
+    let mut changed = true;
+    while changed {
+        let uses = use_counts(prog);
+        let owners = owner_counts(prog);
+        changed = false;
+        for func in prog.funcs.iter_mut() {
+            changed |= coalesce_copies(func, &uses, &owners);
+        }
+        let loop_args = loop_args(prog);
+        let owners = owner_counts(prog);
+        for func in prog.funcs.iter_mut() {
+            let args = loop_args.get(&func.id).cloned().unwrap_or(vec![]);
+            changed |= propagate_copies(func, &args, &owners);
+        }
+    }
+}
+
+/// Removes the copies in `func` whose source is a temporary assigned earlier in the same block,
+/// returning whether any were removed.
+fn coalesce_copies(func: &mut SirFunction,
+                   uses: &HashMap<Symbol, usize>,
+                   owners: &HashMap<Symbol, usize>)
+                   -> bool {
+    let defs = definition_counts(func);
+    let mut changed = false;
+    for block in func.blocks.iter_mut() {
+        let mut i = 0;
+        while i < block.statements.len() {
+            let (output, value) = match block.statements[i] {
+                Assign { ref output, ref value } => (output.clone(), value.clone()),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let coalescable = uses.get(&value) == Some(&1) && defs.get(&value) == Some(&1) &&
+                              owners.get(&value) == Some(&1) &&
+                              func.locals.get(&value).is_some() &&
+                              func.locals.get(&value) == func.locals.get(&output);
+            // The statement assigning the source, if it is earlier in this block and nothing
+            // between it and the copy uses the destination.
+            let mut def_index = None;
+            if coalescable {
+                for j in (0..i).rev() {
+                    if block.statements[j].output() == Some(&value) {
+                        def_index = Some(j);
+                        break;
+                    }
+                }
+            }
+            if let Some(j) = def_index {
+                let mentions_output = block.statements[j..i].iter().any(|s| {
+                    s.output() == Some(&output) || s.children().contains(&&output)
+                });
+                if !mentions_output {
+                    *block.statements[j].output_mut().unwrap() = output;
+                    block.statements.remove(i);
+                    func.locals.remove(&value);
+                    changed = true;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+    changed
+}
+
+/// Replaces the uses of locals in `func` which are copies of another symbol with that symbol,
+/// returning whether any were replaced. `loop_args` are the symbols assigned when `func` starts
+/// because it is a loop body.
+fn propagate_copies(func: &mut SirFunction,
+                    loop_args: &Vec<Symbol>,
+                    owners: &HashMap<Symbol, usize>)
+                    -> bool {
+    let mut changed = false;
+    loop {
+        let mut defs = definition_counts(func);
+        for arg in loop_args.iter() {
+            *defs.entry(arg.clone()).or_insert(0) += 1;
+        }
+        let mut copy = None;
+        for block in func.blocks.iter() {
+            for statement in block.statements.iter() {
+                if let Assign { ref output, ref value } = *statement {
+                    // The source must hold the same value wherever the copy is used.
+                    let fixed_value = if func.params.contains_key(value) {
+                        defs.get(value).is_none()
+                    } else {
+                        defs.get(value) == Some(&1)
+                    };
+                    if fixed_value && defs.get(output) == Some(&1) &&
+                       owners.get(output) == Some(&1) && func.locals.contains_key(output) &&
+                       func.locals.get(output) == func.locals.get(value).or(func.params.get(value)) {
+                        copy = Some((output.clone(), value.clone()));
+                        break;
+                    }
+                }
+            }
+            if copy.is_some() {
+                break;
+            }
+        }
+
+        let (output, value) = match copy {
+            Some(copy) => copy,
+            None => return changed,
+        };
+        for block in func.blocks.iter_mut() {
+            block.statements.retain(|s| match *s {
+                Assign { output: ref o, .. } => *o != output,
+                _ => true,
+            });
+            for statement in block.statements.iter_mut() {
+                for sym in statement.children_mut() {
+                    if *sym == output {
+                        *sym = value.clone();
+                    }
+                }
+            }
+            for sym in block.terminator.children_mut() {
+                if *sym == output {
+                    *sym = value.clone();
+                }
+            }
+        }
+        func.locals.remove(&output);
+        changed = true;
+    }
+}
+
+/// Removes statements without side effects whose outputs are never used, and the parameters which
+/// pass such outputs between functions.
+pub fn eliminate_dead_statements(prog: &mut SirProgram) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let uses = use_counts(prog);
+        for func in prog.funcs.iter_mut() {
+            for block in func.blocks.iter_mut() {
+                let before = block.statements.len();
+                block.statements.retain(|s| {
+                    !is_pure(s) || s.output().map(|o| uses.contains_key(o)).unwrap_or(true)
+                });
+                changed |= block.statements.len() != before;
+            }
+        }
+    }
+    // The main function's parameters are the program's arguments, so they are kept.
+    let uses = use_counts(prog);
+    for func in prog.funcs.iter_mut().skip(1) {
+        let unused: Vec<Symbol> = func.params.keys().filter(|p| !uses.contains_key(p)).cloned().collect();
+        for sym in unused {
+            func.params.remove(&sym);
+        }
+    }
+}
+
+/// Returns whether `statement` can be removed if its output is unused. Lookups may report an
+/// error, UDFs may have side effects and results may release a builder's resources.
+fn is_pure(statement: &Statement) -> bool {
+    match *statement {
+        Merge { .. } | CUDF { .. } | Lookup { .. } | Res { .. } => false,
+        _ => true,
+    }
+}
+
+/// Removes locals which are not assigned or used in their function.
+pub fn remove_unused_locals(prog: &mut SirProgram) {
+    let loop_args = loop_args(prog);
+    for func in prog.funcs.iter_mut() {
+        let mut used = HashSet::new();
+        if let Some(args) = loop_args.get(&func.id) {
+            used.extend(args.iter().cloned());
+        }
+        for block in func.blocks.iter() {
+            for statement in block.statements.iter() {
+                used.extend(statement.output().into_iter().cloned());
+                used.extend(statement.children().into_iter().cloned());
+            }
+            used.extend(block.terminator.children().into_iter().cloned());
+        }
+        let unused: Vec<Symbol> = func.locals.keys().filter(|l| !used.contains(l)).cloned().collect();
+        for sym in unused {
+            func.locals.remove(&sym);
+        }
+    }
+}
+
+/// Returns the number of times each symbol is used by a statement or terminator in `prog`.
+fn use_counts(prog: &SirProgram) -> HashMap<Symbol, usize> {
+    let mut uses = HashMap::new();
+    for func in prog.funcs.iter() {
+        for block in func.blocks.iter() {
+            for statement in block.statements.iter() {
+                for sym in statement.children() {
+                    *uses.entry(sym.clone()).or_insert(0) += 1;
+                }
+            }
+            for sym in block.terminator.children() {
+                *uses.entry(sym.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+    uses
+}
+
+/// Returns the number of statements in `func` assigning each symbol.
+fn definition_counts(func: &SirFunction) -> HashMap<Symbol, usize> {
+    let mut defs = HashMap::new();
+    for block in func.blocks.iter() {
+        for statement in block.statements.iter() {
+            if let Some(sym) = statement.output() {
+                *defs.entry(sym.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+    defs
+}
+
+/// Returns the number of functions in `prog` which have each symbol as a parameter or local.
+fn owner_counts(prog: &SirProgram) -> HashMap<Symbol, usize> {
+    let mut owners = HashMap::new();
+    for func in prog.funcs.iter() {
+        for sym in func.params.keys().chain(func.locals.keys()) {
+            *owners.entry(sym.clone()).or_insert(0) += 1;
+        }
+    }
+    owners
+}
+
+/// Returns the builder, index and element arguments of each loop body function in `prog`, which
+/// are assigned when the function starts.
+fn loop_args(prog: &SirProgram) -> HashMap<FunctionId, Vec<Symbol>> {
+    let mut args = HashMap::new();
+    for func in prog.funcs.iter() {
+        for block in func.blocks.iter() {
+            if let Terminator::ParallelFor(ref pf) = block.terminator {
+                args.insert(pf.body,
+                            vec![pf.builder_arg.clone(), pf.idx_arg.clone(), pf.data_arg.clone()]);
+            }
+        }
+    }
+    args
+}
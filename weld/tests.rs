@@ -1,14 +1,43 @@
-use super::ast::{Annotations, Expr, Type, ExprKind, Symbol};
+use std::collections::HashMap;
+
+use libc;
+
+use weld_common::WeldRuntimeErrno;
+
+use super::ast::{Annotations, Expr, Type, ExprKind, IterKind, LiteralKind, ScalarKind, Symbol};
+use super::ast::Type::Builder;
 use super::partial_types::PartialType::Unknown;
-use super::parser::parse_expr;
+use super::parser::{parse_expr, parse_sir};
 use super::pretty_print::*;
 use super::type_inference::*;
 
 // Transforms. TODO(shoumik) move these tests somewhere else?
 use super::transforms::fuse_loops_vertical;
 use super::transforms::fuse_loops_horizontal;
+use super::transforms::fuse_independent_loops;
 use super::transforms::inline_let;
+use super::transforms::infer_size;
+use super::transforms::check_zip_lengths;
+use super::transforms::tile_loops;
+use super::transforms::predicate;
+use super::transforms::common_subexpression_elimination;
+use super::transforms::fold_constants;
+use super::transforms::hoist_loop_invariants;
+use super::transforms::eliminate_dead_fields;
 use super::transforms::uniquify;
+use super::verifier::{verify, verify_sir};
+use super::sir::{ast_to_sir, SirProgram, Statement, Terminator};
+use super::llvm::{generate_runtime_interface_module, loop_simd_lanes, LlvmGenerator,
+                  WeldInputArgs, WeldOutputArgs};
+use super::cgen::{compile_module, CGenerator};
+use super::conf::DEFAULT_MEMORY_LIMIT;
+use super::util::load_runtime_library;
+use super::interpreter;
+use super::interpreter::evaluate;
+use super::sir_transforms::SIR_PASSES;
+use super::vectorizer::vectorize;
+use super::passes::{get_pass, register_transform, unregister_pass, with_registered_passes,
+                    PassOrder};
 
 /// Returns a typed expression.
 #[cfg(test)]
@@ -143,6 +172,19 @@ fn parse_and_print_for_expressions() {
     let e = parse_expr("for(zip(a,b,iter(c,0L,4L,1L),iter(d)), appender, |e| e+1)").unwrap();
     assert_eq!(print_expr_without_indent(&e).as_str(),
                "for(zip(a,b,iter(c,0L,4L,1L),d),appender[?],|e|(e+1))");
+
+    let e = parse_expr("@(grain_size:128) for(d, appender, |e| e+1)").unwrap();
+    assert_eq!(print_expr_without_indent(&e).as_str(),
+               "@(grain_size:128)for(d,appender[?],|e|(e+1))");
+    assert!(parse_expr("@(grain_size:0) for(d, appender, |e| e+1)").is_err());
+    assert!(parse_expr("@(grain_size:-4) for(d, appender, |e| e+1)").is_err());
+
+    // Selectivities are stored in fractions of 10,000.
+    let e = parse_expr("@(selectivity:0.25f) if(x > 3, 1, 2)").unwrap();
+    assert_eq!(*e.annotations.selectivity(), Some(2500));
+
+    // bounds_check is only set by the compiler.
+    assert!(parse_expr("@(bounds_check:false) for(zip(d,e), appender, |e| e+1)").is_err());
 }
 
 #[test]
@@ -199,6 +241,20 @@ fn parse_and_print_typed_expr_without_indentessions() {
     infer_types(&mut e).unwrap();
     assert_eq!(print_typed_expr_without_indent(&e).as_str(),
                "for([1],appender[i32],|b:appender[i32],i:i64,x:i32|merge(b:appender[i32],x:i32))");
+
+    // SIMD values merge one value per lane, which must match the builder's merge type.
+    let mut e = parse_expr("|x:vec[i32]| for(simditer(x), merger[i32,+], |b,i,e:simd[i32]| \
+                            merge(b, e))")
+            .unwrap();
+    infer_types(&mut e).unwrap();
+    let inputs = vec!["|x:vec[i32]| for(simditer(x), merger[f32,+], |b,i,e:simd[i32]| merge(b, e))",
+                      "|x:vec[i32]| for(simditer(x), appender[{i32,f32}], |b,i,e:simd[i32]| \
+                       merge(b, {e, e}))",
+                      "|x:simd[i32]| result(merge(appender[f32], x))"];
+    for input in inputs {
+        let mut e = parse_expr(input).unwrap();
+        assert!(infer_types(&mut e).is_err());
+    }
 }
 
 #[test]
@@ -227,6 +283,11 @@ fn compare_expressions() {
     // Symbols don't match up.
     let e2 = parse_expr("|c, d| d + c").unwrap();
     assert!(!e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Different unary operators on the same value.
+    let e1 = parse_expr("|a:f64| exp(a)").unwrap();
+    let e2 = parse_expr("|a:f64| log(a)").unwrap();
+    assert!(!e1.compare_ignoring_symbols(&e2).unwrap());
 }
 
 #[test]
@@ -282,6 +343,71 @@ fn simple_horizontal_loop_fusion() {
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 }
 
+#[test]
+fn independent_loop_fusion() {
+    let mut e1 = typed_expression("|v:vec[i64]| let a = result(for(v, merger[i64,+], |b,i,e| \
+                                   merge(b, i * e))); let m = result(for(v, merger[i64,*], \
+                                   |b,i,e| merge(b, i + e))); {a, m}");
+    fuse_independent_loops(&mut e1);
+    let e2 = typed_expression("|v:vec[i64]| let bs = for(v, {merger[i64,+], merger[i64,*]}, \
+                               |bs,i,e| {merge(bs.$0, i * e), merge(bs.$1, i + e)}); \
+                               let a = result(bs.$0); let m = result(bs.$1); {a, m}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Only loops over the same iterators are fused.
+    let mut e1 = typed_expression("|v:vec[i64], w:vec[i64]| {result(for(v, merger[i64,+], \
+                                   |b,i,e| merge(b, e + i))), result(for(w, merger[i64,+], \
+                                   |b,i,e| merge(b, e + i))), result(for(v, appender[i64], \
+                                   |b,i,e| merge(b, e * i)))}");
+    fuse_independent_loops(&mut e1);
+    let e2 = typed_expression("|v:vec[i64], w:vec[i64]| let bs = for(v, {merger[i64,+], \
+                               appender[i64]}, |bs,i,e| {merge(bs.$0, e + i), \
+                               merge(bs.$1, e * i)}); {result(bs.$0), result(for(w, \
+                               merger[i64,+], |b,i,e| merge(b, e + i))), result(bs.$1)}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Cheap values bound to names would hide that the loops share iterators and make them depend
+    // on the Let-bound names; inlining them first lets the loops fuse.
+    let mut e1 = typed_expression("|x:{vec[i64],i64}| let v = x.$0; let k = x.$1; \
+                                   {result(for(v, merger[i64,+], |b,i,e| merge(b, e * k + i))), \
+                                   result(for(v, merger[i64,*], |b,i,e| merge(b, e + k + i)))}");
+    inline_let(&mut e1);
+    fuse_independent_loops(&mut e1);
+    let e2 = typed_expression("|x:{vec[i64],i64}| let bs = for(x.$0, {merger[i64,+], \
+                               merger[i64,*]}, |bs,i,e| {merge(bs.$0, e * x.$1 + i), \
+                               merge(bs.$1, e + x.$1 + i)}); {result(bs.$0), result(bs.$1)}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // The fused loop defines new parameters rather than reusing those of the first loop.
+    let mut e1 = typed_expression("|v:vec[i64]| {result(for(v, merger[i64,+], |b,i,e| \
+                                   merge(b, i * e))), result(for(v, merger[i64,*], |b,i,e| \
+                                   merge(b, i + e)))}");
+    fuse_independent_loops(&mut e1);
+    let mut params = vec![];
+    e1.traverse(&mut |e| if let ExprKind::Lambda { params: ref p, .. } = e.kind {
+                    params.extend(p.iter().map(|p| p.name.to_string()));
+                });
+    assert_eq!(params, vec!["v", "bs", "i#1", "e#1"]);
+
+    // Loops which depend on each other, are evaluated conditionally or could be vectorized on
+    // their own are not fused.
+    let inputs = vec!["|v:vec[i64]| let a = result(for(v, merger[i64,+], |b,i,e| \
+                       merge(b, e + i))); result(for(v, merger[i64,+], |b,i,e| \
+                       merge(b, e + a + i)))",
+                      "|v:vec[i64], c:bool| if(c, result(for(v, merger[i64,+], |b,i,e| \
+                       merge(b, e + i))), result(for(v, merger[i64,*], |b,i,e| merge(b, e + i))))",
+                      "|v:vec[i32]| {result(for(v, merger[i32,+], |b,i,e| merge(b, e))), \
+                       result(for(v, merger[i32,*], |b,i,e| merge(b, e)))}",
+                      "|v:vec[i64]| {result(for(v, merger[i64,+], |b,i,e| merge(b, e))), \
+                       result(for(v, merger[i64,*], |b,i,e| merge(b, e + i)))}"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        fuse_independent_loops(&mut e1);
+        let e2 = typed_expression(input);
+        assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+    }
+}
+
 #[test]
 fn simple_vertical_loop_fusion() {
     // Two loops.
@@ -330,6 +456,30 @@ fn simple_vertical_loop_fusion() {
                                merge(b, (e+2)+1))");
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 
+    // Conditional merges into a different builder.
+    let mut e1 = typed_expression("for(result(for([1,2,3], appender, |b,i,e| if(e>1, merge(b,e), \
+                                   b))), merger[i64,+], |b,h,f| merge(b, i64(f)))");
+    fuse_loops_vertical(&mut e1);
+    let e2 = typed_expression("for([1,2,3], merger[i64,+], |b,i,e| if(e>1, merge(b, i64(e)), \
+                               b))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // The consumer's index is not the producer's index if the producer filters.
+    let input = "for(result(for([1,2,3], appender, |b,i,e| if(e>1, merge(b,e), b))), \
+                 appender[i64], |b,h,f| merge(b, h))";
+    let mut e1 = typed_expression(input);
+    fuse_loops_vertical(&mut e1);
+    let e2 = typed_expression(input);
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Builders passed through other expressions cannot be followed.
+    let input = "for(result(for([1,2,3], appender, |b,i,e| merge(if(e>1, merge(b,e), b), e))), \
+                 appender, |b,h,f| merge(b, f+1))";
+    let mut e1 = typed_expression(input);
+    fuse_loops_vertical(&mut e1);
+    let e2 = typed_expression(input);
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
     // Inner data not consumed fully.
     let mut e1 = typed_expression("for(result(for(iter([1,2,3], 0L, 1L, 1L), appender, |b,i,e| \
                                    merge(b,e+2))), appender, |b,h,f| merge(b, f+1))");
@@ -347,22 +497,977 @@ fn inline_lets() {
     let e2 = typed_expression("1 + 2");
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 
-    let mut e1 = typed_expression("let a = 1; a + a + 2");
+    let mut e1 = typed_expression("|x:i32| let a = x * 2; a + a + 2");
     // The transform should fail since the identifier is used more than once.
     inline_let(&mut e1);
-    let e2 = typed_expression("let a = 1; a + a + 2");
+    let e2 = typed_expression("|x:i32| let a = x * 2; a + a + 2");
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 
-    let mut e1 = typed_expression("let a = 1L; for([1L,2L,3L], appender, |b,i,e| merge(b, e + a \
-                                   + 2L))");
+    let mut e1 = typed_expression("|x:i64| let a = x * 2L; for([1L,2L,3L], appender, |b,i,e| \
+                                   merge(b, e + a + 2L))");
     inline_let(&mut e1);
     // The transform should fail since the identifier is used in a loop.
-    let e2 = typed_expression("let a = 1L; for([1L,2L,3L], appender, |b,i,e| merge(b, e + a + \
-                               2L))");
+    let e2 = typed_expression("|x:i64| let a = x * 2L; for([1L,2L,3L], appender, |b,i,e| \
+                               merge(b, e + a + 2L))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Literals, identifiers and struct fields are cheap, so they are inlined into every use,
+    // including uses in loops.
+    let mut e1 = typed_expression("|x:{i64,i64}| let a = 1L; let k = x.$1; let y = x; \
+                                   for([1L,2L,3L], appender, |b,i,e| merge(b, e + a + k + y.$0 + \
+                                   a))");
+    inline_let(&mut e1);
+    let e2 = typed_expression("|x:{i64,i64}| for([1L,2L,3L], appender, |b,i,e| merge(b, e + 1L + \
+                               x.$1 + x.$0 + 1L))");
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 
+    // Loops and UDF calls are never duplicated.
+    let inputs = vec!["|v:vec[i32]| let a = result(for(v, merger[i32,+], |b,i,e| merge(b, e))); \
+                       a + a",
+                      "|x:i32| let a = cudf[f,i32](x); a + a"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        inline_let(&mut e1);
+        let e2 = typed_expression(input);
+        assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+    }
+
     let mut e1 = typed_expression("let a = 1; let b = 2; let c = 3; a + b + c");
     inline_let(&mut e1);
     let e2 = typed_expression("1 + 2 + 3");
     assert!(e1.compare_ignoring_symbols(&e2).unwrap());
 }
+
+/// Returns the annotations on the builder of the outermost For loop in an expression.
+#[cfg(test)]
+fn for_builder_annotations(expr: &Expr<Type>) -> Annotations {
+    let mut annotations = None;
+    expr.traverse(&mut |ref e| if let ExprKind::For { ref builder, .. } = e.kind {
+        if annotations.is_none() {
+            if let Builder(_, ref a) = builder.ty {
+                annotations = Some(a.clone());
+            }
+        }
+    });
+    annotations.unwrap()
+}
+
+#[test]
+fn infer_builder_sizes() {
+    // Map over a vector literal.
+    let mut e1 = typed_expression("for([1,2,3], appender, |b,i,e| merge(b,e+1))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).size(), Some(3));
+
+    // Iterators with literal bounds.
+    let mut e1 = typed_expression("let a = [1,2,3,4]; for(iter(a, 0L, 4L, 2L), appender, \
+                                   |b,i,e| merge(b,e))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).size(), Some(2));
+
+    // Huge literal bounds are capped like dictionary sizes.
+    let mut e1 = typed_expression("|v:vec[i32]| for(iter(v, 0L, 1000000000L, 1L), appender, \
+                                   |b,i,e| merge(b,e))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).size(), Some(1 << 20));
+    let mut e1 = typed_expression("|v:vec[i32]| for(iter(v, 0L, 1000000000L, 1L), \
+                                   dictmerger[i32,i32,+], |b,i,e| merge(b,{e,e}))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).num_keys(), Some(1 << 20));
+
+    // Dictionaries are sized by their number of keys.
+    let mut e1 = typed_expression("for([1,2,3], dictmerger[i32,i32,+], |b,i,e| merge(b,{e,e}))");
+    infer_size(&mut e1);
+    let annotations = for_builder_annotations(&e1);
+    assert_eq!(*annotations.num_keys(), Some(3));
+    assert_eq!(*annotations.size(), None);
+
+    // Unknown lengths are left alone.
+    let mut e1 = typed_expression("|v:vec[i32]| for(v, appender, |b,i,e| merge(b,e+1))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).size(), None);
+
+    // Explicit annotations are never overwritten.
+    let mut e1 = typed_expression("for([1,2,3], @(size:100L) appender[i32], |b,i,e| merge(b,e))");
+    infer_size(&mut e1);
+    assert_eq!(*for_builder_annotations(&e1).size(), Some(100));
+}
+
+/// Returns the `bounds_check` annotation of the first For loop in `expr`.
+fn for_bounds_check(expr: &Expr<Type>) -> Option<bool> {
+    let mut bounds_check = None;
+    let mut found = false;
+    expr.traverse(&mut |ref e| if let ExprKind::For { .. } = e.kind {
+        if !found {
+            bounds_check = e.annotations.bounds_check().clone();
+            found = true;
+        }
+    });
+    bounds_check
+}
+
+#[test]
+fn zip_length_checks() {
+    // A vector zipped with a map over itself.
+    let mut e1 = typed_expression("|x:vec[i32]| result(for(zip(x, result(for(x, appender[i32], \
+                                   |b,i,e| merge(b, e*2)))), merger[i32,+], |b,i,e| \
+                                   merge(b, e.$0 + e.$1)))");
+    check_zip_lengths(&mut e1).unwrap();
+    assert_eq!(for_bounds_check(&e1), Some(false));
+
+    // Slices and iterators over vectors of known length.
+    let inputs = vec!["for(zip(slice([1,2,3,4], 1L, 5L), iter([5,6,7,8], 0L, 3L, 1L)), \
+                       appender[i32], |b,i,e| merge(b, e.$0 + e.$1))",
+                      "let a = [1,2,3,4]; for(zip(a, iter(a, 0L, len(a), 1L)), appender[i32], \
+                       |b,i,e| merge(b, e.$0 + e.$1))"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        check_zip_lengths(&mut e1).unwrap();
+        assert_eq!(for_bounds_check(&e1), Some(false));
+    }
+
+    // Lengths which cannot be proven to fit keep the runtime check.
+    let inputs = vec!["|x:vec[i32], y:vec[i32]| for(zip(x, y), appender[i32], |b,i,e| \
+                       merge(b, e.$0 + e.$1))",
+                      "|x:vec[i32]| for(iter(x, 0L, 20000L, 1L), appender[i32], |b,i,e| \
+                       merge(b, e))",
+                      "let a = [1,2,3,4]; for(zip(a, iter(a, 1L, 5L, 1L)), appender[i32], \
+                       |b,i,e| merge(b, e.$0 + e.$1))"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        check_zip_lengths(&mut e1).unwrap();
+        assert_eq!(for_bounds_check(&e1), None);
+    }
+
+    // Lengths which provably differ are reported.
+    let inputs = vec!["for(zip([1,2,3], [1,2]), appender[i32], |b,i,e| merge(b, e.$0 + e.$1))",
+                      "|x:vec[i32]| let a = [1,2]; for(zip(a, result(for(iter(x, 0L, 3L, 1L), \
+                       appender[i32], |b,i,e| merge(b, e)))), appender[i32], |b,i,e| \
+                       merge(b, e.$0 + e.$1))"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        assert!(check_zip_lengths(&mut e1).is_err());
+    }
+
+    // Loops which might not run keep the runtime check instead.
+    let inputs = vec!["|c:bool| if(c, for(zip([1,2,3], [1,2]), appender[i32], |b,i,e| \
+                       merge(b, e.$0 + e.$1)), appender[i32])",
+                      "|x:vec[i32]| for(x, appender[i32], |b,i,e| for(zip([1,2,3], [1,2]), b, \
+                       |b2,j,f| merge(b2, f.$0 + f.$1)))"];
+    for input in inputs {
+        let mut e1 = typed_expression(input);
+        check_zip_lengths(&mut e1).unwrap();
+        assert!(!print_expr_without_indent(&e1).contains("@(bounds_check:false)for(zip("));
+    }
+}
+
+#[test]
+fn simple_loop_tiling() {
+    let mut e1 = typed_expression("|r:vec[i32],x:vec[i32]| @(tile_size:2) for(r, \
+                                   vecmerger[i32,+](r), |b,i,e| for(x, b, |b2,j,f| \
+                                   merge(b2, {i, e*f+i32(j)})))");
+    tile_loops(&mut e1);
+    let e2 = typed_expression("|r:vec[i32],x:vec[i32]| for(iter(x, 0L, len(x)+1L, 2L), \
+                               vecmerger[i32,+](r), |bt,t,te| for(r, bt, |b,i,e| \
+                               for(iter(x, t*2L, select(t*2L+2L<len(x), t*2L+2L, len(x)), 1L), \
+                               b, |b2,j,f| merge(b2, {i, e*f+i32(t*2L+j)}))))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // The tiled loop nest keeps no tile_size annotation, so tiling again leaves it unchanged.
+    let tiled = print_expr_without_indent(&e1);
+    assert!(!tiled.contains("tile_size"));
+    tile_loops(&mut e1);
+    assert_eq!(print_expr_without_indent(&e1), tiled);
+
+    // Loops whose inner data depends on the outer loop cannot be interchanged.
+    let mut e1 = typed_expression("|r:vec[vec[i32]]| @(tile_size:2) for(r, merger[i32,+], \
+                                   |b,i,e| for(e, b, |b2,j,f| merge(b2, f)))");
+    tile_loops(&mut e1);
+    let e2 = typed_expression("|r:vec[vec[i32]]| for(r, merger[i32,+], |b,i,e| for(e, b, \
+                               |b2,j,f| merge(b2, f)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Appenders depend on merge order and are never tiled.
+    let mut e1 = typed_expression("|r:vec[i32],x:vec[i32]| @(tile_size:2) for(r, appender[i32], \
+                                   |b,i,e| for(x, b, |b2,j,f| merge(b2, e*f)))");
+    tile_loops(&mut e1);
+    let e2 = typed_expression("|r:vec[i32],x:vec[i32]| for(r, appender[i32], |b,i,e| for(x, b, \
+                               |b2,j,f| merge(b2, e*f)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+}
+
+#[test]
+fn predicate_branches() {
+    // Conditional merges into a merger.
+    let mut e1 = typed_expression("|v:vec[i32]| result(for(v, merger[i32,+], |b,i,e| \
+                                   @(predicate:true) if(e>3, merge(b,e), b)))");
+    predicate(&mut e1);
+    let e2 = typed_expression("|v:vec[i32]| result(for(v, merger[i32,+], |b,i,e| \
+                               merge(b, select(e>3, e, 0))))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Annotating the loop predicates every branch in it, including loops using the index.
+    let mut e1 = typed_expression("|v:vec[i32]| result(@(predicate:true) for(v, merger[i64,*], \
+                                   |b,i,e| if(e>3, b, merge(b,i))))");
+    predicate(&mut e1);
+    let e2 = typed_expression("|v:vec[i32]| result(for(v, merger[i64,*], |b,i,e| \
+                               merge(b, select(e>3, 1L, i))))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Scalar branches.
+    let mut e1 = typed_expression("|x:i32| @(predicate:true) if(x>3, x+1, x*2)");
+    predicate(&mut e1);
+    let e2 = typed_expression("|x:i32| select(x>3, x+1, x*2)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Unpredictable branches are predicated without an explicit annotation.
+    let mut e1 = typed_expression("|x:i32| @(selectivity:0.5f) if(x>3, x+1, x*2)");
+    predicate(&mut e1);
+    let e2 = typed_expression("|x:i32| select(x>3, x+1, x*2)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Unannotated and predictable branches are left alone.
+    let mut e1 = typed_expression("|x:i32| if(x>3, x+1, x*2)");
+    predicate(&mut e1);
+    let e2 = typed_expression("|x:i32| if(x>3, x+1, x*2)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    let mut e1 = typed_expression("|x:i32| @(selectivity:0.99f) if(x>3, x+1, x*2)");
+    predicate(&mut e1);
+    let e2 = typed_expression("|x:i32| if(x>3, x+1, x*2)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Branches which may fail are never evaluated unconditionally.
+    let mut e1 = typed_expression("|x:i32| @(predicate:true) if(x>0, 10/x, 0)");
+    predicate(&mut e1);
+    let e2 = typed_expression("|x:i32| if(x>0, 10/x, 0)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+}
+
+#[test]
+fn common_subexpressions() {
+    let mut e1 = typed_expression("|x:i32| (x+1)*(x+1)");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|x:i32| let c = x+1; c*c");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Subexpressions are shared within a loop body, but not across scopes that bind different
+    // values to the same names.
+    let mut e1 = typed_expression("|v:vec[i32]| for(v, merger[i32,+], |b,i,e| merge(b, \
+                                   (e*2)+(e*2)))");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|v:vec[i32]| for(v, merger[i32,+], |b,i,e| let c = e*2; \
+                               merge(b, c+c))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Shared subexpressions may use each other, whichever of them is found first.
+    let mut e1 = typed_expression("|x:i32| {x*2, (x*2)+4, (x*2)+4}");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|x:i32| let c = x*2; let d = c+4; {c, d, d}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    let mut e1 = typed_expression("|x:i32| {(x+1)*2, (x+1)*2, x+1}");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|x:i32| let c = x+1; let d = c*2; {d, d, c}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Expressions which are only evaluated on one branch are not hoisted out of it.
+    let mut e1 = typed_expression("|x:i32| if(x>0, x/2, 0) + if(x>1, x/2, 1)");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|x:i32| if(x>0, x/2, 0) + if(x>1, x/2, 1)");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Builders are never shared.
+    let mut e1 = typed_expression("|v:vec[i32]| {result(for(v, merger[i32,+], |b,i,e| \
+                                   merge(b, e))), result(for(v, merger[i32,+], |b,i,e| \
+                                   merge(b, e)))}");
+    common_subexpression_elimination(&mut e1);
+    let e2 = typed_expression("|v:vec[i32]| {result(for(v, merger[i32,+], |b,i,e| \
+                               merge(b, e))), result(for(v, merger[i32,+], |b,i,e| \
+                               merge(b, e)))}");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+}
+
+#[test]
+fn constant_folding() {
+    let check = |input: &str, expected: &str| {
+        let mut e1 = typed_expression(input);
+        fold_constants(&mut e1);
+        let e2 = typed_expression(expected);
+        assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+    };
+
+    check("1 + 2 * 3", "7");
+    check("|x:i32| x * (2 - 1) + 0", "|x:i32| x");
+    check("if(1L < 2L, 3.0, 4.0)", "3.0");
+    check("|x:i32| select(false, x, x + 1)", "|x:i32| x + 1");
+    check("{1, 2.0, true}.$1", "2.0");
+    check("len([1, 2, 3])", "3L");
+    check("|x:i32| {x + 1, 2}.$1", "|x:i32| 2");
+
+    // Dropped values that may fail at runtime are kept.
+    check("|v:vec[i32]| {lookup(v, 5L), 2}.$1", "|v:vec[i32]| {lookup(v, 5L), 2}.$1");
+    check("|x:i32| len([x / 0, 1])", "|x:i32| len([x / 0, 1])");
+    check("|v:vec[i32]| select(true, 1, lookup(v, 5L))",
+          "|v:vec[i32]| select(true, 1, lookup(v, 5L))");
+    check("-(2.0f) + 3.0f", "1.0f");
+    check("sqrt(4.0)", "2.0");
+
+    // Integer arithmetic wraps around, and casts truncate or sign extend.
+    check("i64(2147483647 + 1) + 2147483648L", "0L");
+    check("i32(127c + 1c) + 128", "0");
+    check("i8(300)", "44c");
+    check("i32(2.9)", "2");
+
+    // Undefined or non-finite results are left for the runtime.
+    check("1 / 0", "1 / 0");
+    check("1.0 / 0.0", "1.0 / 0.0");
+    check("i8(1000.0)", "i8(1000.0)");
+
+    // -0.0 + 0.0 is 0.0, so adding zero to a float is not removed.
+    check("|x:f64| x + 0.0", "|x:f64| x + 0.0");
+    check("|x:f64| x * 1.0 - 0.0", "|x:f64| x");
+}
+
+#[test]
+fn loop_invariant_code_motion() {
+    let mut e1 = typed_expression("|v:vec[i32],w:vec[i32]| result(for(v, appender[i64], |b,i,e| \
+                                   merge(b, len(w) + i64(e))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|v:vec[i32],w:vec[i32]| let n = len(w); result(for(v, \
+                               appender[i64], |b,i,e| merge(b, n + i64(e))))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Expressions using symbols defined in the loop stay in it, and lookups are only hoisted when
+    // they are evaluated on every iteration.
+    let mut e1 = typed_expression("|v:vec[i32],w:vec[i32],x:i32| result(for(v, merger[i32,+], \
+                                   |b,i,e| let y = e + 1; if(e > 0, merge(b, lookup(w, 0L)), \
+                                   merge(b, y * (x * 2)))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|v:vec[i32],w:vec[i32],x:i32| let z = x * 2; result(for(v, \
+                               merger[i32,+], |b,i,e| let y = e + 1; if(e > 0, merge(b, \
+                               lookup(w, 0L)), merge(b, y * z))))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Invariants of an inner loop move out of both loops when they do not depend on either, but
+    // lookups only move out of the version of a loop which runs when its vector is not empty.
+    let mut e1 = typed_expression("|v:vec[i32],w:vec[i32]| result(for(v, merger[i32,+], |b,i,e| \
+                                   for(w, b, |b2,j,f| merge(b2, \
+                                   f + lookup(w, 0L) + i32(len(w))))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|v:vec[i32],w:vec[i32]| let c = len(w) > 0L; let n = i32(len(w)); \
+                               result(for(v, merger[i32,+], |b,i,e| if(c, let a = lookup(w, 0L); \
+                               for(w, b, |b2,j,f| merge(b2, f + a + n)), b)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Loops over vector parameters are versioned on their length, which leaves a loop the
+    // vectorizer can handle.
+    let mut e1 = typed_expression("|v:vec[f32],w:vec[f32]| result(for(v, merger[f32,+], |b,i,e| \
+                                   merge(b, e * lookup(w, 0L))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|v:vec[f32],w:vec[f32]| if(len(v) > 0L, let a = lookup(w, 0L); \
+                               result(for(v, merger[f32,+], |b,i,e| merge(b, e * a))), \
+                               result(merger[f32,+]))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+    vectorize(&mut e1);
+    assert!(print_expr(&e1).contains("simditer("));
+
+    // Both versions of a loop share its initial builder.
+    let mut e1 = typed_expression("|v:vec[i32],w:vec[i32]| result(for(v, merge(merger[i32,+], 1), \
+                                   |b,i,e| merge(b, e * lookup(w, 0L))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|v:vec[i32],w:vec[i32]| let b0 = merge(merger[i32,+], 1); \
+                               if(len(v) > 0L, let a = lookup(w, 0L); result(for(v, b0, \
+                               |b,i,e| merge(b, e * a))), result(b0))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Loops in the body of a loop that can be vectorized are not versioned.
+    let input = "|v:vec[i32],w:vec[i32]| result(for(v, merger[i32,+], |b,i,e| merge(b, e + \
+                 result(for(w, merger[i32,+], |b2,j,f| merge(b2, f * lookup(w, 0L)))))))";
+    let mut e1 = typed_expression(input);
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression(input);
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+    vectorize(&mut e1);
+    assert!(print_expr(&e1).contains("simditer("));
+
+    // Loops over non-empty vector literals always run, so their lookups are hoisted.
+    let mut e1 = typed_expression("|w:vec[i32]| result(for([1, 2], merger[i32,+], |b,i,e| \
+                                   merge(b, e + lookup(w, 0L))))");
+    hoist_loop_invariants(&mut e1);
+    let e2 = typed_expression("|w:vec[i32]| let a = lookup(w, 0L); result(for([1, 2], \
+                               merger[i32,+], |b,i,e| merge(b, e + a)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+}
+
+#[test]
+fn dead_field_elimination() {
+    let mut e1 = typed_expression("|x:vec[i32]| let v = result(for(x, appender[{i32,i64,i32}], \
+                                   |b,i,e| merge(b, {e, i, e+1}))); result(for(v, \
+                                   merger[i32,+], |b,i,e| merge(b, e.$2 + lookup(v, 0L).$2)))");
+    eliminate_dead_fields(&mut e1);
+    let e2 = typed_expression("|x:vec[i32]| let v = result(for(x, appender[{i32}], |b,i,e| \
+                               merge(b, {e+1}))); result(for(v, merger[i32,+], |b,i,e| \
+                               merge(b, e.$0 + lookup(v, 0L).$0)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Loops reading the result directly.
+    let mut e1 = typed_expression("|x:vec[i32]| for(result(for(x, appender[{i32,i32}], |b,i,e| \
+                                   merge(b, {e, e*2}))), appender[i32], |b,i,e| merge(b, e.$1))");
+    eliminate_dead_fields(&mut e1);
+    let e2 = typed_expression("|x:vec[i32]| for(result(for(x, appender[{i32}], |b,i,e| \
+                               merge(b, {e*2}))), appender[i32], |b,i,e| merge(b, e.$0))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Fields whose values might fail are kept even if they are never read.
+    let mut e1 = typed_expression("|x:vec[i32],y:vec[i32]| let v = result(for(x, \
+                                   appender[{i32,i32,i32}], |b,i,e| merge(b, {e, lookup(y, i), \
+                                   e * 2}))); result(for(v, merger[i32,+], |b,i,e| \
+                                   merge(b, e.$0)))");
+    eliminate_dead_fields(&mut e1);
+    let e2 = typed_expression("|x:vec[i32],y:vec[i32]| let v = result(for(x, \
+                               appender[{i32,i32}], |b,i,e| merge(b, {e, lookup(y, i)}))); \
+                               result(for(v, merger[i32,+], |b,i,e| merge(b, e.$0)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Builders of the same type are projected separately.
+    let mut e1 = typed_expression("|x:vec[i32]| let v = result(for(x, appender[{i32,i32}], \
+                                   |b,i,e| let w = result(for(x, appender[{i32,i32}], |b2,j,f| \
+                                   merge(b2, {f, f*2}))); merge(b, {e, lookup(w, 0L).$1}))); \
+                                   result(for(v, merger[i32,+], |b,i,e| merge(b, e.$1)))");
+    eliminate_dead_fields(&mut e1);
+    let e2 = typed_expression("|x:vec[i32]| let v = result(for(x, appender[{i32}], |b,i,e| \
+                               let w = result(for(x, appender[{i32}], |b2,j,f| \
+                               merge(b2, {f*2}))); merge(b, {lookup(w, 0L).$0}))); \
+                               result(for(v, merger[i32,+], |b,i,e| merge(b, e.$0)))");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Vectors whose elements escape keep all their fields.
+    let input = "|x:vec[i32]| let v = result(for(x, appender[{i32,i32}], |b,i,e| merge(b, \
+                 {e, e*2}))); {v, len(v)}";
+    let mut e1 = typed_expression(input);
+    eliminate_dead_fields(&mut e1);
+    let e2 = typed_expression(input);
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+}
+
+#[test]
+fn verify_invariants() {
+    let valid = vec!["|x:vec[i32]| result(for(x, appender[i32], |b,i,e| merge(b, e)))",
+                     "|c:bool| let b = appender[i32]; result(if(c, merge(b, 1), merge(b, 2)))",
+                     "|x:vec[i32]| for(x, {appender[i32], appender[i32]}, |bs,i,e| \
+                      {merge(bs.$0, e), merge(bs.$1, e)})"];
+    for input in valid {
+        assert!(verify(&typed_expression(input)).is_ok());
+    }
+
+    // Literals in vectorized loops have SIMD types.
+    let mut e = typed_expression("|x:vec[i32]| result(for(x, merger[i32,+], |b,i,e| \
+                                  merge(b, e * 2)))");
+    vectorize(&mut e);
+    assert!(verify(&e).is_ok());
+
+    // Builders used more than once, shadowed symbols and undefined symbols.
+    let invalid = vec!["let b = appender[i32]; {result(merge(b, 1)), result(merge(b, 2))}",
+                       "|x:vec[i32]| for(x, {appender[i32], appender[i32]}, |bs,i,e| \
+                        {merge(bs.$0, e), bs.$0})",
+                       "|x:i32| let x = x + 1; x"];
+    for input in invalid {
+        assert!(verify(&typed_expression(input)).is_err());
+    }
+    let mut e = typed_expression("|x:i32| x + 1");
+    if let ExprKind::Lambda { ref mut body, .. } = e.kind {
+        body.kind = ExprKind::Ident(Symbol::name("y"));
+    }
+    assert!(verify(&e).is_err());
+
+    // Mismatched types.
+    let mut e = typed_expression("|x:i32| x + 1");
+    if let ExprKind::Lambda { ref mut body, .. } = e.kind {
+        body.ty = Type::Scalar(ScalarKind::I64);
+    }
+    assert!(verify(&e).is_err());
+}
+
+#[test]
+fn vectorized_loops() {
+    let check = |input: &str, expected: &str| {
+        let mut e1 = typed_expression(input);
+        vectorize(&mut e1);
+        assert!(verify(&e1).is_ok());
+        let e2 = typed_expression(expected);
+        assert!(e1.compare_ignoring_symbols(&e2).unwrap(),
+                "{}",
+                print_expr_without_indent(&e1));
+    };
+
+    // Vectorized loops merge structs of SIMD values into appenders, dictmergers and vecmergers,
+    // followed by a scalar loop over the fringe of the data.
+    check("|x:vec[i32],y:vec[f32],c:i32| result(for(zip(x,y), dictmerger[{i32,i32},f32,+], \
+           |b,i,e| merge(b, {{e.$0 / c, e.$0}, e.$1})))",
+          "|x:vec[i32],y:vec[f32],c:i32| let a = x; let a1 = y; result(for(zip(fringeiter(a), \
+           fringeiter(a1)), for(zip(simditer(a), simditer(a1)), dictmerger[{i32,i32},f32,+], \
+           |b,i,e:{simd[i32],simd[f32]}| merge(b, {{e.$0 / broadcast(c), e.$0}, e.$1})), \
+           |b,i,e| merge(b, {{e.$0 / c, e.$0}, e.$1})))");
+    check("|x:vec[i8],y:vec[f64],c:f64| result(for(zip(x,y), vecmerger[f64,+](y), \
+           |b,i,e| merge(b, {i64(e.$0), e.$1 * c})))",
+          "|x:vec[i8],y:vec[f64],c:f64| let a = x; let a1 = y; result(for(zip(fringeiter(a), \
+           fringeiter(a1)), for(zip(simditer(a), simditer(a1)), vecmerger[f64,+](y), \
+           |b,i,e:{simd[i8],simd[f64]}| merge(b, {i64(e.$0), e.$1 * broadcast(c)})), \
+           |b,i,e| merge(b, {i64(e.$0), e.$1 * c})))");
+    check("|x:vec[f64],y:vec[f64]| result(for(zip(x,y), appender[{f64,f64}], \
+           |b,i,e| let s = {e.$0 + e.$1, e.$0 * e.$1}; merge(b, {s.$1, s.$0})))",
+          "|x:vec[f64],y:vec[f64]| let a = x; let a1 = y; result(for(zip(fringeiter(a), \
+           fringeiter(a1)), for(zip(simditer(a), simditer(a1)), appender[{f64,f64}], \
+           |b,i,e:{simd[f64],simd[f64]}| let s = {e.$0 + e.$1, e.$0 * e.$1}; \
+           merge(b, {s.$1, s.$0})), |b,i,e| let s = {e.$0 + e.$1, e.$0 * e.$1}; \
+           merge(b, {s.$1, s.$0})))");
+    check("|x:vec[f32]| result(for(x, appender[f32], |b,i,e| \
+           merge(b, exp(e) + log(e) + sqrt(e) + erf(e))))",
+          "|x:vec[f32]| let a = x; result(for(fringeiter(a), for(simditer(a), appender[f32], \
+           |b,i,e:simd[f32]| merge(b, exp(e) + log(e) + sqrt(e) + erf(e))), \
+           |b,i,e| merge(b, exp(e) + log(e) + sqrt(e) + erf(e))))");
+
+    // Loops containing inner loops are split so that the outer loop is vectorized.
+    check("|x:vec[i64],y:vec[i64]| result(for(x, merger[i64,+], |b,i,e| \
+           merge(b, e + result(for(y, merger[i64,+], |b2,i2,f| merge(b2, f * e))))))",
+          "|x:vec[i64],y:vec[i64]| let a = x; let t = result(@(vectorize:false) for(a, \
+           appender[i64], |b1,i1,e1:i64| merge(b1, let a1 = y; result(for(fringeiter(a1), \
+           for(simditer(a1), merger[i64,+], |b2,i2,f:simd[i64]| merge(b2, f * broadcast(e1))), \
+           |b2,i2,f| merge(b2, f * e1)))))); let a2 = a; let a3 = t; \
+           result(for(zip(fringeiter(a2), fringeiter(a3)), for(zip(simditer(a2), simditer(a3)), \
+           merger[i64,+], |b,i,e2:{simd[i64],simd[i64]}| let e = e2.$0; merge(b, e + e2.$1)), \
+           |b,i,e2| let e = e2.$0; merge(b, e + e2.$1)))");
+
+    // Inner loops which depend on the builder or index stay in the outer loop, which is then not
+    // vectorized.
+    check("|x:vec[i64],y:vec[i64]| result(for(x, merger[i64,+], |b,i,e| \
+           merge(b, result(for(y, merger[i64,+], |b2,i2,f| merge(b2, f * i))))))",
+          "|x:vec[i64],y:vec[i64]| result(for(x, merger[i64,+], |b,i:i64,e| merge(b, let a = y; \
+           result(for(fringeiter(a), for(simditer(a), merger[i64,+], |b2,i2,f:simd[i64]| \
+           merge(b2, f * broadcast(i))), |b2,i2,f| merge(b2, f * i))))))");
+}
+
+/// Unregisters a pass when dropped, so that tests registering passes do not leave them behind for
+/// other tests, even if they fail.
+struct RegisteredPass(&'static str);
+
+impl Drop for RegisteredPass {
+    fn drop(&mut self) {
+        unregister_pass(self.0);
+    }
+}
+
+#[test]
+fn registered_passes() {
+    use super::ast::ExprKind::Literal;
+    use super::ast::LiteralKind::I32Literal;
+
+    // A pass which increments every i32 literal once.
+    let _registered = RegisteredPass("increment-literals");
+    register_transform("increment-literals", PassOrder::Manual, false, |e| {
+        e.transform(&mut |ref mut e| {
+            if let Literal(I32Literal(l)) = e.kind {
+                e.kind = Literal(I32Literal(l + 1));
+            }
+            None
+        })
+    }).unwrap();
+
+    let mut e1 = typed_expression("|x:i32| x + 1");
+    get_pass("increment-literals").unwrap().transform(&mut e1, 1).unwrap();
+    let e2 = typed_expression("|x:i32| x + 2");
+    assert!(e1.compare_ignoring_symbols(&e2).unwrap());
+
+    // Passes without an ordering hint are not added to the built-in ones.
+    let names = with_registered_passes(&["inline-apply", "inline-let"])
+        .iter()
+        .map(|p| p.pass_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["inline-apply", "inline-let"]);
+
+    // Names must be unique, and hints must refer to built-in passes.
+    assert!(register_transform("increment-literals", PassOrder::Manual, false, |_| ()).is_err());
+    assert!(register_transform("inline-let", PassOrder::Manual, false, |_| ()).is_err());
+    assert!(register_transform("bad-hint", PassOrder::Before("none".to_string()), false, |_| ())
+                .is_err());
+
+    // Unregistered passes can no longer be found.
+    assert!(unregister_pass("increment-literals"));
+    assert!(get_pass("increment-literals").is_none());
+    assert!(!unregister_pass("inline-let"));
+}
+
+#[test]
+fn pass_stats_and_iteration_limits() {
+    let mut e = typed_expression("|x:i32| let a = x + 1; let b = a + 2; b");
+    let nodes_before = e.num_nodes();
+    let stats = get_pass("inline-let").unwrap().transform(&mut e, 10).unwrap();
+    assert_eq!(stats.pass_name, "inline-let");
+    assert_eq!(stats.nodes_before, nodes_before);
+    assert_eq!(stats.nodes_after, e.num_nodes());
+    assert!(stats.nodes_after < stats.nodes_before);
+    // One iteration inlines both lets and a second one finds nothing left to change.
+    assert_eq!(stats.iterations, 2);
+
+    // A fixpoint pass which never converges stops at the iteration limit.
+    let _registered = RegisteredPass("diverging");
+    register_transform("diverging", PassOrder::Manual, true, |e| {
+        e.transform(&mut |ref mut e| {
+            if let ExprKind::Literal(LiteralKind::I32Literal(l)) = e.kind {
+                e.kind = ExprKind::Literal(LiteralKind::I32Literal(l + 1));
+            }
+            None
+        })
+    }).unwrap();
+    let mut e = typed_expression("|x:i32| x + 1");
+    assert!(get_pass("diverging").unwrap().transform(&mut e, 5).is_err());
+}
+
+/// Returns the SIR program generated from an expression.
+#[cfg(test)]
+fn sir_program(s: &str) -> SirProgram {
+    let mut e = typed_expression(s);
+    uniquify(&mut e).unwrap();
+    ast_to_sir(&e).unwrap()
+}
+
+#[test]
+fn sir_verifier() {
+    let valid = vec!["|x:i32| x + 1",
+                     "|x:i32| let a = if(x > 1, x, 2); a * 2",
+                     "|x:vec[i32], c:i32| result(for(x, appender[i32], |b,i,e| \
+                      if(e > c, merge(b, e + c), b)))"];
+    for input in valid {
+        assert!(verify_sir(&sir_program(input)).is_ok());
+    }
+
+    // A block without a terminator.
+    let mut prog = sir_program("|x:i32| x + 1");
+    prog.funcs[0].blocks[0].terminator = Terminator::Crash;
+    assert!(verify_sir(&prog).is_err());
+
+    // A symbol used before it is defined.
+    let mut prog = sir_program("|x:i32| let a = x + 1; a * 2");
+    prog.funcs[0].blocks[0].statements.remove(0);
+    assert!(verify_sir(&prog).is_err());
+
+    // A symbol defined on only one path to its use.
+    let mut prog = sir_program("|x:i32| if(x > 1, x, 2) + 1");
+    prog.funcs[0].blocks[2].statements.clear();
+    assert!(verify_sir(&prog).is_err());
+
+    // A local whose type does not match the statement assigning it.
+    let mut prog = sir_program("|x:i32| x + 1");
+    for ty in prog.funcs[0].locals.values_mut() {
+        *ty = Type::Scalar(ScalarKind::I64);
+    }
+    assert!(verify_sir(&prog).is_err());
+}
+
+#[test]
+fn sir_passes() {
+    let run_passes = |input: &str| {
+        let mut prog = sir_program(input);
+        for &(name, pass) in SIR_PASSES.iter() {
+            pass(&mut prog);
+            if let Err(e) = verify_sir(&prog) {
+                panic!("verification failed after {} on {}: {}", name, input, e);
+            }
+        }
+        prog
+    };
+    let statements = |prog: &SirProgram| {
+        prog.funcs.iter()
+            .flat_map(|f| f.blocks.iter())
+            .flat_map(|b| b.statements.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let is_copy = |s: &Statement| if let Statement::Assign { .. } = *s { true } else { false };
+
+    // Copies of temporaries and of symbols are removed, along with their locals.
+    let inputs = vec!["|x:i32| let a = x + 1; let b = a; let c = b * 2; c",
+                      "|x:vec[i32], c:i32| let d = c * 2; result(for(x, merger[i32,+], |b,i,e| \
+                       let y = e; let z = y + d; merge(b, z)))"];
+    for input in inputs {
+        let prog = run_passes(input);
+        assert!(!statements(&prog).iter().any(|s| is_copy(s)), "copies left in {}", input);
+        for func in prog.funcs.iter() {
+            let assigned = func.blocks.iter()
+                .flat_map(|b| b.statements.iter())
+                .filter_map(|s| s.output())
+                .collect::<Vec<_>>();
+            for local in func.locals.keys() {
+                let loop_arg = func.id != 0 && ["b", "i", "e"].contains(&local.name.as_str());
+                assert!(assigned.contains(&local) || loop_arg, "{} left in {}", local, input);
+            }
+        }
+    }
+
+    // Unused values are not computed, but values assigned on several paths are still copied.
+    let prog = run_passes("|x:i32| let unused = x * 3; let a = if(x > 1, x, 2); a");
+    let statements = statements(&prog);
+    assert!(!statements.iter().any(|s| s.output().map(|o| o.name == "unused").unwrap_or(false)));
+    assert!(!prog.funcs[0].locals.keys().any(|l| l.name == "unused"));
+    assert!(statements.iter().any(|s| is_copy(s)));
+}
+
+#[test]
+fn sir_parse_and_print() {
+    let inputs = vec!["|x:i32| let a = if(x > 1, x, -2); a * 2",
+                      "|x:vec[i32], c:i32| result(@(grain_size:128) for(x, appender[i32], \
+                       |b,i,e| if(e > c, merge(b, e + c), b)))",
+                      "|x:vec[i64]| let d = result(for(x, @(impl:local, num_keys:12L) \
+                       dictmerger[i64,i64,+], |b,i,e| merge(b, {e, 1L}))); \
+                       {lookup(d, 1L), keyexists(d, 2L)}",
+                      "|x:vec[i64]| tovec(result(for(x, dictmerger[i64,i64,+], |b,i,e| \
+                       merge(b, {e, 1L}))))",
+                      "|x:vec[f64], y:i8| {slice(x, 1L, 2L), select(y > 0c, -1.5, sqrt(lookup(x, 0L))), \
+                       -y, i64(y), [y, 3c], {y, 1.5f}.$1, cudf[foo,f32](y, 2.5f), true}",
+                      "|x:vec[i32]| result(for(x, vecmerger[i32,+](x), |b,i,e| merge(b, {i, e})))",
+                      "|x:vec[{i32,i32}]| result(for(x, groupmerger[i32,i32], |b,i,e| merge(b, e)))",
+                      "|x:vec[i32]| result(for(iter(x, 1L, 3L, 1L), merger[i32,+], |b,i,e| merge(b, e)))"];
+    let mut programs: Vec<SirProgram> = inputs.iter().map(|s| sir_program(s)).collect();
+
+    // A loop whose runtime bounds check is left out.
+    let mut e = typed_expression("|x:vec[i32]| result(for(zip(x, x), appender[i32], |b,i,e| \
+                                  merge(b, e.$0 + e.$1)))");
+    uniquify(&mut e).unwrap();
+    check_zip_lengths(&mut e).unwrap();
+    programs.push(ast_to_sir(&e).unwrap());
+
+    // A vectorized loop, with SIMD and fringe iterators, broadcasts and unary operators.
+    let mut e = typed_expression("|x:vec[f64], c:f64| result(for(x, merger[f64,+], |b,i,e| \
+                                  merge(b, exp(e) * c)))");
+    vectorize(&mut e);
+    uniquify(&mut e).unwrap();
+    programs.push(ast_to_sir(&e).unwrap());
+
+    // A program after the SIR passes, with copies removed and symbols renamed.
+    let mut prog = sir_program("|x:vec[i32]| let s = {x, 2}; result(for(s.$0, merger[i32,+], \
+                                |b,i,e| let y = e * s.$1; merge(b, y)))");
+    for &(_, pass) in SIR_PASSES.iter() {
+        pass(&mut prog);
+    }
+    programs.push(prog);
+
+    for prog in programs {
+        let printed = prog.to_string();
+        let parsed = match parse_sir(&printed) {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("could not parse {}: {}", printed, e),
+        };
+        assert_eq!(parsed.to_string(), printed);
+        assert_eq!(parsed.ret_ty, prog.ret_ty);
+        if let Err(e) = verify_sir(&parsed) {
+            panic!("{}\n{}", printed, e);
+        }
+    }
+
+    // Statements must use declared symbols, and blocks must be in order.
+    assert!(parse_sir("Returns: i32\nArguments:\n  x: i32\n\nF0:\nParams:\n  x: i32\nLocals:\n\
+                       B0:\n  y = + i32 x x\n  return y\n").is_err());
+    assert!(parse_sir("Returns: i32\nArguments:\n  x: i32\n\nF0:\nParams:\n  x: i32\nLocals:\n\
+                       B1:\n  return x\n").is_err());
+
+    // Grain sizes must be positive.
+    let printed = sir_program("|x:vec[i32]| result(@(grain_size:128) for(x, appender[i32], \
+                               |b,i,e| merge(b, e)))").to_string();
+    assert!(printed.contains(" grain_size=128"));
+    assert!(parse_sir(&printed.replace(" grain_size=128", " grain_size=0")).is_err());
+}
+
+#[test]
+fn sir_fixture_codegen() {
+    // The sum of the elements of x plus c, written directly as SIR.
+    let fixture = "Returns: i64
+                   Arguments:
+                     x: vec[i64]
+                     c: i64
+
+                   F0:
+                   Params:
+                     c: i64
+                     x: vec[i64]
+                   Locals:
+                     sum: merger[i64,+]
+                   B0:
+                     sum = new merger[i64,+]()
+                     for [x, ] sum b i e F1 F2 true
+
+                   F1:
+                   Params:
+                     c: i64
+                     sum: merger[i64,+]
+                     x: vec[i64]
+                   Locals:
+                     b: merger[i64,+]
+                     e: i64
+                     i: i64
+                     y: i64
+                   B0:
+                     y = + i64 e c
+                     merge b y
+                     end
+
+                   F2:
+                   Params:
+                     sum: merger[i64,+]
+                   Locals:
+                     r: i64
+                   B0:
+                     r = result sum
+                     return r
+                   ";
+    let prog = parse_sir(fixture).unwrap();
+    assert!(verify_sir(&prog).is_ok());
+    let mut gen = LlvmGenerator::new();
+    gen.add_function_on_pointers("run", &prog).unwrap();
+    let code = gen.result();
+    assert!(code.contains("define i64 @run(i64 %r.input)"));
+    assert!(code.contains("define void @f1("));
+    assert!(code.contains("add i64"));
+}
+
+#[test]
+fn c_codegen() {
+    let prog = sir_program("|x:vec[i32], y:i32| result(for(x, merger[i32,+], |b,i,e| \
+                            merge(b, e * y)))");
+    let mut gen = CGenerator::new();
+    gen.add_function_on_pointers("run", &prog).unwrap();
+    let code = gen.result();
+    assert!(code.contains("int64_t run(int64_t input) {"));
+    assert!(code.contains("static void f1_wrapper("));
+    assert!(code.contains("(int32_t)(e_"));
+}
+
+#[test]
+fn c_compile_and_run() {
+    load_runtime_library().unwrap();
+    let prog = sir_program("|x:vec[i32], y:i32| result(for(x, merger[i32,+], |b,i,e| \
+                            merge(b, e * y)))");
+    let mut gen = CGenerator::new();
+    gen.add_function_on_pointers("run", &prog).unwrap();
+    let library = compile_module(&gen.result(), false).unwrap();
+
+    #[allow(dead_code)]
+    struct Args {
+        data: *const i32,
+        len: i64,
+        y: i32,
+    }
+    let x = vec![1, 2, 3, 4, 5];
+    let args = Args {
+        data: x.as_ptr(),
+        len: x.len() as i64,
+        y: 3,
+    };
+    let input = WeldInputArgs {
+        input: &args as *const Args as i64,
+        nworkers: 1,
+        mem_limit: DEFAULT_MEMORY_LIMIT,
+    };
+
+    let output = library.run(&input as *const WeldInputArgs as i64) as *mut WeldOutputArgs;
+    let (result, run_id, errno) = unsafe {
+        ((*output).output, (*output).run_id, (*output).errno.clone())
+    };
+    assert_eq!(errno, WeldRuntimeErrno::Success);
+    assert_eq!(unsafe { *(result as *const i32) }, 45);
+
+    unsafe { libc::free(output as *mut libc::c_void) };
+    let _ = generate_runtime_interface_module().unwrap().run_named("rt_run_free", run_id);
+}
+
+#[test]
+fn simd_lane_counts() {
+    let vectorized_sir = |s: &str| {
+        let mut e = typed_expression(s);
+        vectorize(&mut e);
+        uniquify(&mut e).unwrap();
+        ast_to_sir(&e).unwrap()
+    };
+
+    // Returns the number of lanes chosen for the body of each SimdIter and FringeIter loop,
+    // named by its iterator kind and the type of its element.
+    let loop_lanes = |prog: &SirProgram, simd_width: u32| {
+        let lanes = loop_simd_lanes(prog, simd_width);
+        let mut named = vec![];
+        for func in prog.funcs.iter() {
+            for block in func.blocks.iter() {
+                if let Terminator::ParallelFor(ref pf) = block.terminator {
+                    if pf.data[0].kind != IterKind::ScalarIter {
+                        let elem_ty = &prog.funcs[pf.body].locals[&pf.data_arg];
+                        let name = format!("{:?} {}", pf.data[0].kind, print_type(elem_ty));
+                        named.push((name, lanes.get(&pf.body).cloned()));
+                    }
+                }
+            }
+        }
+        named.sort();
+        named
+    };
+
+    // The widest element type of a vectorized loop fills the register.
+    let prog = vectorized_sir("|x:vec[f64]| result(for(x, merger[f64,+], |b,i,e| merge(b, e)))");
+    assert_eq!(loop_lanes(&prog, 256),
+               vec![("FringeIter f64".to_string(), Some(4)),
+                    ("SimdIter simd[f64]".to_string(), Some(4))]);
+
+    // Each vectorized loop gets its own number of lanes, and loops which are not vectorized do
+    // not reduce the number of lanes of the others.
+    let prog = vectorized_sir("|x:vec[i8], y:vec[f64], z:vec[i64]| {result(for(x, merger[i8,+], \
+                               |b,i,e| merge(b, e))), result(for(y, merger[f64,+], |b,i,e| \
+                               merge(b, e))), result(for(z, merger[i64,+], |b,i,e| \
+                               merge(b, e + i)))}");
+    assert_eq!(loop_lanes(&prog, 256),
+               vec![("FringeIter f64".to_string(), Some(4)),
+                    ("FringeIter i8".to_string(), Some(32)),
+                    ("SimdIter simd[f64]".to_string(), Some(4)),
+                    ("SimdIter simd[i8]".to_string(), Some(32))]);
+    assert_eq!(loop_lanes(&prog, 128)[3], ("SimdIter simd[i8]".to_string(), Some(16)));
+}
+
+#[test]
+fn interpreter() {
+    let eval = |s: &str| {
+        evaluate(&typed_expression(s), &HashMap::new(), 128).map(|v| format!("{}", v))
+    };
+    assert_eq!(eval("let x = 5L; x * 2L + 1L").unwrap(), "11L");
+    assert_eq!(eval("result(for([1,2,3], appender[i32], |b,i,e| merge(b, e * 2)))").unwrap(),
+               "[2,4,6]");
+    assert_eq!(eval("result(for(iter([1,2,3,4,5], 1L, 5L, 2L), merger[i32,+], |b,i,e| \
+                     merge(b, e)))")
+                       .unwrap(),
+               "6");
+    assert_eq!(eval("result(for(simditer([1,2,3,4,5,6,7,8]), merger[i32,+], |b,i,e:simd[i32]| \
+                     merge(b, e)))")
+                       .unwrap(),
+               "36");
+    assert_eq!(eval("result(for([3,1,3], dictmerger[i32,i64,+], |b,i,e| merge(b, {e, i})))")
+                   .unwrap(),
+               "{1->1L,3->2L}");
+    assert_eq!(eval("{lookup([1.0,2.0], 1L), len([1,2,3])}").unwrap(), "{2.0,3L}");
+    assert!(eval("lookup([1,2,3], 3L)").is_err());
+    assert!(eval("result(for(iter([1,2,3], 0L, 4L, 1L), appender[i32], |b,i,e| \
+                  merge(b, e)))")
+                .is_err());
+}
+
+#[test]
+fn interpreter_simd_lanes() {
+    let eval = |s: &str, simd_width: u32| {
+        evaluate(&typed_expression(s), &HashMap::new(), simd_width).map(|v| format!("{}", v))
+    };
+    // A SimdIter only covers whole groups of lanes, leaving the rest to a FringeIter.
+    let code = "result(for(simditer([1,2,3,4,5,6,7,8,9,10,11,12]), merger[i32,+], \
+                |b,i,e:simd[i32]| merge(b, e)))";
+    assert_eq!(eval(code, 256).unwrap(), "36");
+    assert_eq!(eval(code, 128).unwrap(), "78");
+
+    // The lanes of a loop depend on its own element type: 16 lanes of i8 fit in 128 bits.
+    let code = "result(for(simditer([1c,2c,3c,4c,5c,6c,7c,8c,9c,10c,11c,12c,13c,14c,15c,16c,\
+                17c]), merger[i8,+], |b,i,e:simd[i8]| merge(b, e)))";
+    assert_eq!(eval(code, 128).unwrap(), "-120");
+}
@@ -73,6 +73,7 @@ pub enum Token {
     TEqual,
     TBar, // |
     TAtMark, // @
+    THash, // #
     TDot,
     TColon,
     TSemicolon,
@@ -96,14 +97,14 @@ pub fn tokenize(input: &str) -> WeldResult<Vec<Token>> {
         // Regular expression for splitting up tokens.
         static ref TOKEN_RE: Regex = Regex::new(concat!(
             r"[0-9]+\.[0-9]+([eE]-?[0-9]+)?[fF]?|[0-9]+[eE]-?[0-9]+[fF]?|",
-            r"[A-Za-z0-9$_]+|==|!=|>=|<=|&&|\|\||[-+/*%,=()[\]{}|@&\.:;?&\|^<>]|\S+"
+            r"[A-Za-z0-9$_]+|==|!=|>=|<=|&&|\|\||[-+/*%,=()[\]{}|@#&\.:;?&\|^<>]|\S+"
         )).unwrap();
 
         // Regular expressions for various types of tokens.
         static ref KEYWORD_RE: Regex = Regex::new(
-            "if|for|zip|len|lookup|keyexists|slice|exp|log|erf|sqrt|simd|select|broadcast|cudf|simditer|fringeiter|iter|merge|result|let|true|false|macro|\
+            "^(if|for|zip|len|lookup|keyexists|slice|exp|log|erf|sqrt|simd|select|broadcast|cudf|simditer|fringeiter|iter|merge|result|let|true|false|macro|\
              i8|i32|i64|f32|f64|bool|vec|appender|merger|vecmerger|dictmerger|groupmerger|\
-             tovec").unwrap();
+             tovec)$").unwrap();
 
         static ref IDENT_RE: Regex = Regex::new(r"^[A-Za-z$_][A-Za-z0-9$_]*$").unwrap();
 
@@ -219,6 +220,7 @@ pub fn tokenize(input: &str) -> WeldResult<Vec<Token>> {
                             "}" => TCloseBrace,
                             "|" => TBar,
                             "@" => TAtMark,
+                            "#" => THash,
                             "," => TComma,
                             "=" => TEqual,
                             "." => TDot,
@@ -318,6 +320,7 @@ impl fmt::Display for Token {
                     TEqual => "=",
                     TBar => "|",
                     TAtMark => "@",
+                    THash => "#",
                     TDot => ".",
                     TColon => ":",
                     TSemicolon => ";",
@@ -390,6 +393,10 @@ fn basic_tokenize() {
                     TI32,
                     TCloseBracket,
                     TEndOfInput]);
+    assert_eq!(tokenize("selectivity iterations").unwrap(),
+               vec![TIdent("selectivity".into()),
+                    TIdent("iterations".into()),
+                    TEndOfInput]);
 
     assert_eq!(tokenize("= == | || & &&").unwrap(),
                vec![TEqual,
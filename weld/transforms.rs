@@ -7,7 +7,12 @@ use super::ast::BuilderKind::*;
 use super::ast::LiteralKind::*;
 use super::error::*;
 
+use std::cmp;
+use std::mem;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Div, Mul, Rem, Sub};
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 
 use super::util::SymbolGenerator;
 
@@ -45,7 +50,7 @@ pub fn inline_zips(expr: &mut Expr<Type>) {
                                         builder: builder.clone(),
                                         func: func.clone(),
                                     },
-                                    annotations: Annotations::new(),
+                                    annotations: e.annotations.clone(),
                                 });
                 }
             }
@@ -222,7 +227,10 @@ pub fn inline_apply<T: TypeBounds>(expr: &mut Expr<T>) {
 }
 
 /// Inlines Let calls if the symbol defined by the Let statement is used
-/// less than one time.
+/// less than one time, or if its value is cheap enough to recompute at every use.
+///
+/// Duplicating a cheap value lets later passes see through the binding; for example, loops over
+/// `x.$0` bound to a name used twice can only be fused once both refer to `x.$0` directly.
 pub fn inline_let(expr: &mut Expr<Type>) {
     if let Ok(_) = uniquify(expr) { 
         expr.transform(&mut |ref mut expr| {
@@ -231,7 +239,7 @@ pub fn inline_let(expr: &mut Expr<Type>) {
                 ref mut value,
                 ref mut body,
             } = expr.kind {
-                if symbol_usage_count(name, body) <= 1 {
+                if is_trivially_cheap(value) || symbol_usage_count(name, body) <= 1 {
                     body.transform(&mut |ref mut expr| {
                         if let Ident(ref symbol) = expr.kind {
                             if symbol == name {
@@ -248,6 +256,17 @@ pub fn inline_let(expr: &mut Expr<Type>) {
     }
 }
 
+/// Returns whether `expr` is cheap enough that duplicating it, even into a loop body, costs no
+/// more than reading a bound symbol. Loops, function calls and anything else with a cost or a
+/// side effect must only be evaluated as many times as the program says.
+fn is_trivially_cheap(expr: &Expr<Type>) -> bool {
+    match expr.kind {
+        Literal(_) | Ident(_) => true,
+        GetField { expr: ref e, .. } => is_trivially_cheap(e),
+        _ => false,
+    }
+}
+
 fn symbol_usage_count(sym: &Symbol, expr: &Expr<Type>) -> u32 {
     let mut usage_count = 0;
     expr.traverse(&mut |ref e| {
@@ -279,9 +298,9 @@ fn symbol_usage_count(sym: &Symbol, expr: &Expr<Type>) -> u32 {
 /// will become for(result(for(a, ...))) where the nested for will produce a vector of structs with
 /// two elements.
 ///
-/// Caveats:
-///     - Like all Zip-based transforms, this function currently assumes that the output of each
-///     expression in the Zip is the same length.
+/// Each fused loop runs over the same iterators and merges once per iteration, so the outputs in
+/// the Zip always have the same length. Zips of vectors whose lengths differ are left to
+/// `check_zip_lengths` and the runtime bounds check.
 ///
 pub fn fuse_loops_horizontal(expr: &mut Expr<Type>) {
     expr.transform(&mut |ref mut expr| {
@@ -417,7 +436,7 @@ pub fn fuse_loops_horizontal(expr: &mut Expr<Type>) {
                             stride: all_iters[0].stride.clone(),
                             kind: all_iters[0].kind.clone(),
                         }], builder: outer_bldr.clone(), func: outer_func.clone()},
-                        annotations: Annotations::new(),
+                        annotations: expr.annotations.clone(),
                     });
                 }
             }
@@ -448,6 +467,9 @@ pub fn fuse_loops_vertical(expr: &mut Expr<Type>) {
                             if let NewBuilder(_) = bldr2.kind {
                                 if let Builder(ref kind, _) = bldr2.ty {
                                     if let Appender(_) = *kind {
+                                        if !can_fuse_vertically(lambda, nested) {
+                                            return None;
+                                        }
                                         let e = Expr {
                                             ty: expr.ty.clone(),
                                             kind: For {
@@ -457,7 +479,7 @@ pub fn fuse_loops_vertical(expr: &mut Expr<Type>) {
                                                                                nested,
                                                                                &mut sym_gen)),
                                             },
-                                            annotations: Annotations::new(),
+                                            annotations: expr.annotations.clone(),
                                         };
                                         return Some(e);
                                     }
@@ -472,6 +494,112 @@ pub fn fuse_loops_vertical(expr: &mut Expr<Type>) {
     });
 }
 
+/// Returns whether `replace_builder` can correctly fuse the function `lambda` of a loop producing
+/// an appender with the function `nested` of a loop consuming its result.
+///
+/// `replace_builder` applies the consumer wherever the producer merges, so a producer which merges
+/// conditionally, as `filter` does, only passes on the values it merges. The consumer's index is
+/// then no longer the producer's index, so a consumer that uses its index is only fused with a
+/// producer that merges exactly once per iteration. The producer's builder must also only flow
+/// through expressions that `replace_builder` follows.
+fn can_fuse_vertically(lambda: &Expr<Type>, nested: &Expr<Type>) -> bool {
+    if let (&Lambda {
+                 ref params,
+                 ref body,
+             },
+            &Lambda {
+                 params: ref nested_params,
+                 body: ref nested_body,
+             }) = (&lambda.kind, &nested.kind) {
+        if !merges_directly(body, &params[0].name) {
+            return false;
+        }
+        return merges_once(body, &params[0].name) ||
+               !uses_symbol(nested_body, &nested_params[1].name);
+    }
+    false
+}
+
+/// Returns whether `expr`, the body of a loop with the builder `bldr`, only ever returns `bldr`
+/// itself or a merge into it, possibly on a branch of an If, after some Lets, or through an inner
+/// loop of the same form. Builders passed through any other expression cannot be followed by
+/// `replace_builder`.
+fn merges_directly(expr: &Expr<Type>, bldr: &Symbol) -> bool {
+    match expr.kind {
+        Ident(ref sym) => sym == bldr,
+        Merge {
+            ref builder,
+            ref value,
+        } => {
+            match builder.kind {
+                Ident(ref sym) => sym == bldr && !uses_symbol(value, bldr),
+                _ => false,
+            }
+        }
+        If {
+            ref cond,
+            ref on_true,
+            ref on_false,
+        } => {
+            !uses_symbol(cond, bldr) && merges_directly(on_true, bldr) &&
+            merges_directly(on_false, bldr)
+        }
+        Let {
+            ref value,
+            ref body,
+            ..
+        } => !uses_symbol(value, bldr) && merges_directly(body, bldr),
+        For {
+            ref iters,
+            ref builder,
+            ref func,
+        } => {
+            let iters_use_bldr = iters.iter().any(|i| {
+                let bounds = vec![&i.start, &i.end, &i.stride];
+                uses_symbol(&i.data, bldr) ||
+                bounds.iter().any(|b| b.as_ref().map_or(false, |e| uses_symbol(e, bldr)))
+            });
+            let merges_from_func = match func.kind {
+                Lambda {
+                    ref params,
+                    ref body,
+                } => !uses_symbol(body, bldr) && merges_directly(body, &params[0].name),
+                _ => false,
+            };
+            match builder.kind {
+                Ident(ref sym) => sym == bldr && !iters_use_bldr && merges_from_func,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether `expr`, the body of a loop with the builder `bldr`, merges into it exactly once.
+fn merges_once(expr: &Expr<Type>, bldr: &Symbol) -> bool {
+    match expr.kind {
+        Let { ref body, .. } => merges_once(body, bldr),
+        Merge { ref builder, .. } => {
+            match builder.kind {
+                Ident(ref sym) => sym == bldr,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether `expr` refers to the symbol `sym`.
+fn uses_symbol(expr: &Expr<Type>, sym: &Symbol) -> bool {
+    let mut used = false;
+    expr.traverse(&mut |ref e| if let Ident(ref s) = e.kind {
+                           if s == sym {
+                               used = true;
+                           }
+                       });
+    used
+}
+
 /// Given an iterator, returns whether the iterator consumes every element of its data vector.
 fn consumes_all(iter: &Iter<Type>) -> bool {
     if let &Iter {
@@ -621,3 +749,2204 @@ fn replace_builder(lambda: &Expr<Type>,
         nested.clone()
     }
 }
+
+/// Fuses independent loops over the same iterators into a single loop whose builder is a struct of
+/// the original builders, so that the data is scanned once. The results are then unpacked with
+/// GetField.
+///
+/// For example,
+///
+/// let a = result(for(v, merger[i64,+], |b,i,e| merge(b, i * e)));
+/// let m = result(for(v, merger[i64,*], |b,i,e| merge(b, i + e)));
+/// {a, m}
+///
+/// becomes
+///
+/// let bs = for(v, {merger[i64,+], merger[i64,*]}, |bs,i,e| {merge(bs.$0, i * e),
+///                                                          merge(bs.$1, i + e)});
+/// let a = result(bs.$0);
+/// let m = result(bs.$1);
+/// {a, m}
+///
+/// Loops are only fused within a single evaluation scope, never across the branches of an If or
+/// into a Lambda, and a loop is never fused if it uses a symbol defined in that scope, since it
+/// could then depend on the result of one of the other loops. Loops which the vectorizer can
+/// vectorize on their own are left alone, since the fused loop would not be vectorizable.
+pub fn fuse_independent_loops(expr: &mut Expr<Type>) {
+    let mut sym_gen = SymbolGenerator::from_expression(expr);
+    fuse_loops_in_scope(expr, &mut sym_gen);
+}
+
+/// Fuses the independent loops in the scope rooted at `root`, and then in each scope nested within
+/// it.
+fn fuse_loops_in_scope(root: &mut Expr<Type>, sym_gen: &mut SymbolGenerator) {
+    let mut bindings: Vec<(Symbol, Expr<Type>)> = vec![];
+    loop {
+        let fused = {
+            let mut defined = vec![];
+            let mut loops = vec![];
+            independent_loops(root, &mut defined, &mut loops);
+            fusable_loop_group(&loops, &defined, sym_gen)
+        };
+        let (group, fused_loop) = match fused {
+            Some(f) => f,
+            None => break,
+        };
+        let name = sym_gen.new_symbol("fused");
+        let ident = Expr {
+            ty: fused_loop.ty.clone(),
+            kind: Ident(name.clone()),
+            annotations: Annotations::new(),
+        };
+        let mut index = 0;
+        replace_independent_loops(root, &group, &ident, &mut index);
+        bindings.push((name, fused_loop));
+    }
+
+    for (name, value) in bindings.into_iter().rev() {
+        let body = root.clone();
+        *root = Expr {
+            ty: body.ty.clone(),
+            kind: Let {
+                name: name,
+                value: Box::new(value),
+                body: Box::new(body),
+            },
+            annotations: Annotations::new(),
+        };
+    }
+
+    fuse_nested_scopes(root, sym_gen);
+}
+
+/// Calls `fuse_loops_in_scope` on each scope nested in the scope of `expr`.
+fn fuse_nested_scopes(expr: &mut Expr<Type>, sym_gen: &mut SymbolGenerator) {
+    match expr.kind {
+        Lambda { ref mut body, .. } => fuse_loops_in_scope(body, sym_gen),
+        If {
+            ref mut cond,
+            ref mut on_true,
+            ref mut on_false,
+        } => {
+            fuse_nested_scopes(cond, sym_gen);
+            fuse_loops_in_scope(on_true, sym_gen);
+            fuse_loops_in_scope(on_false, sym_gen);
+        }
+        _ => {
+            for c in expr.children_mut() {
+                fuse_nested_scopes(c, sym_gen);
+            }
+        }
+    }
+}
+
+/// Collects the loops in the scope of `expr` which `fuse_independent_loops` may fuse, in order,
+/// along with the symbols defined by Lets in that scope.
+fn independent_loops<'a>(expr: &'a Expr<Type>,
+                         defined: &mut Vec<Symbol>,
+                         loops: &mut Vec<&'a Expr<Type>>) {
+    if is_independent_loop(expr) {
+        loops.push(expr);
+        return;
+    }
+    match expr.kind {
+        Lambda { .. } => (),
+        If { ref cond, .. } => independent_loops(cond, defined, loops),
+        _ => {
+            if let Let { ref name, .. } = expr.kind {
+                defined.push(name.clone());
+            }
+            for c in expr.children() {
+                independent_loops(c, defined, loops);
+            }
+        }
+    }
+}
+
+/// Replaces the loops of `group`, given as indices into the loops collected by
+/// `independent_loops`, with the results of the corresponding fields of the fused loop `fused`.
+/// `index` counts the loops visited so far.
+fn replace_independent_loops(expr: &mut Expr<Type>,
+                             group: &Vec<usize>,
+                             fused: &Expr<Type>,
+                             index: &mut usize) {
+    if is_independent_loop(expr) {
+        if let Some(field) = group.iter().position(|i| *i == *index) {
+            let builder = super::exprs::getfield_expr(fused.clone(), field as u32);
+            if let Ok(result) = builder.and_then(super::exprs::result_expr) {
+                *expr = result;
+            }
+        }
+        *index += 1;
+        return;
+    }
+    match expr.kind {
+        Lambda { .. } => (),
+        If { ref mut cond, .. } => replace_independent_loops(cond, group, fused, index),
+        _ => {
+            for c in expr.children_mut() {
+                replace_independent_loops(c, group, fused, index);
+            }
+        }
+    }
+}
+
+/// Returns whether `expr` has the form `result(for(iters, newbuilder, |b,i,e| ...))`.
+fn is_independent_loop(expr: &Expr<Type>) -> bool {
+    if let Res { ref builder } = expr.kind {
+        if let For { ref builder, ref func, .. } = builder.kind {
+            if let NewBuilder(_) = builder.kind {
+                if let Lambda { ref params, .. } = func.kind {
+                    return params.len() == 3;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Finds the first group of at least two loops in `loops` over the same iterators which do not
+/// use any of the symbols in `defined` and cannot be vectorized on their own, and returns the
+/// indices of the loops in the group along with a single loop computing all of their builders.
+fn fusable_loop_group(loops: &Vec<&Expr<Type>>,
+                      defined: &[Symbol],
+                      sym_gen: &mut SymbolGenerator)
+                      -> Option<(Vec<usize>, Expr<Type>)> {
+    use super::vectorizer::is_vectorizable;
+
+    let fusable = loops.iter()
+        .map(|l| {
+            let vectorizable = match l.kind {
+                Res { ref builder } => is_vectorizable(builder),
+                _ => false,
+            };
+            !vectorizable && !free_symbols(l).iter().any(|s| defined.contains(&s.0))
+        })
+        .collect::<Vec<_>>();
+    for (i, first) in loops.iter().enumerate() {
+        let group = loops.iter()
+            .enumerate()
+            .skip(i)
+            .filter(|&(j, l)| fusable[i] && fusable[j] && same_loop_iters(first, l))
+            .map(|(j, _)| j)
+            .collect::<Vec<_>>();
+        if group.len() > 1 {
+            let fused = fused_loop(&group.iter().map(|j| loops[*j]).collect::<Vec<_>>(),
+                                   sym_gen);
+            if let Ok(fused) = fused {
+                return Some((group, fused));
+            }
+        }
+    }
+    None
+}
+
+/// Returns whether the loops taken by the results `a` and `b` iterate over the same iterators
+/// with the same annotations.
+fn same_loop_iters(a: &Expr<Type>, b: &Expr<Type>) -> bool {
+    if let (&Res { builder: ref a }, &Res { builder: ref b }) = (&a.kind, &b.kind) {
+        if let (&For { iters: ref a_iters, .. }, &For { iters: ref b_iters, .. }) = (&a.kind, &b.kind) {
+            return a_iters == b_iters && a.annotations == b.annotations;
+        }
+    }
+    false
+}
+
+/// Returns a loop over the iterators shared by the results `loops`, whose builder is a struct of
+/// their builders and whose function applies each of their functions to the corresponding field.
+fn fused_loop(loops: &Vec<&Expr<Type>>, sym_gen: &mut SymbolGenerator) -> WeldResult<Expr<Type>> {
+    let mut builders = vec![];
+    let mut functions = vec![];
+    let mut iters = vec![];
+    let mut annotations = Annotations::new();
+    for l in loops.iter() {
+        if let Res { ref builder } = l.kind {
+            if let For {
+                       iters: ref for_iters,
+                       ref builder,
+                       ref func,
+                   } = builder.kind {
+                if let Lambda { ref params, ref body } = func.kind {
+                    builders.push(builder.as_ref().clone());
+                    functions.push((params.clone(), body.as_ref().clone()));
+                }
+                iters = for_iters.clone();
+            }
+            annotations = builder.annotations.clone();
+        }
+    }
+    if functions.len() != loops.len() {
+        return weld_err!("Internal error: unexpected loop in fused_loop");
+    }
+
+    let builder = super::exprs::makestruct_expr(builders)?;
+    let bldr_param = Parameter {
+        name: sym_gen.new_symbol("bs"),
+        ty: builder.ty.clone(),
+    };
+    let bldr_ident = super::exprs::ident_expr(bldr_param.name.clone(), bldr_param.ty.clone())?;
+    let index_param = Parameter {
+        name: sym_gen.new_symbol("i"),
+        ty: functions[0].0[1].ty.clone(),
+    };
+    let elem_param = Parameter {
+        name: sym_gen.new_symbol("e"),
+        ty: functions[0].0[2].ty.clone(),
+    };
+
+    let mut bodies = vec![];
+    for (i, (params, mut body)) in functions.into_iter().enumerate() {
+        let field = super::exprs::getfield_expr(bldr_ident.clone(), i as u32)?;
+        body.substitute(&params[0].name, &field);
+        body.substitute(&params[1].name,
+                        &super::exprs::ident_expr(index_param.name.clone(), index_param.ty.clone())?);
+        body.substitute(&params[2].name,
+                        &super::exprs::ident_expr(elem_param.name.clone(), elem_param.ty.clone())?);
+        bodies.push(body);
+    }
+    let body = super::exprs::makestruct_expr(bodies)?;
+    let func = super::exprs::lambda_expr(vec![bldr_param, index_param, elem_param], body)?;
+    let mut fused = super::exprs::for_expr(iters, builder, func, false)?;
+    fused.annotations = annotations;
+    Ok(fused)
+}
+
+/// Upper bound on the number of elements or keys a statically inferred size hint will preallocate
+/// for a builder. Builders grow past their initial size when they need to, while each worker gets
+/// its own local dictionary, so large hints would only waste memory.
+const MAX_INFERRED_SIZE: i64 = 1 << 20;
+
+/// Annotates builders created directly in a For loop with the loop's iteration count when it is
+/// known at compile time, so that code generation can allocate them at the right size up front.
+///
+/// The iteration count is known when an iterator runs over a vector literal (e.g., a `map` over
+/// `[1,2,3]`) or when its start, end and stride are all literals. Inferred sizes are capped at
+/// `MAX_INFERRED_SIZE`, and annotations set explicitly by the user are never overwritten.
+pub fn infer_size(expr: &mut Expr<Type>) {
+    expr.transform(&mut |ref mut e| {
+        if let For {
+                   ref iters,
+                   ref mut builder,
+                   ..
+               } = e.kind {
+            if let NewBuilder(_) = builder.kind {
+                if let Some(len) = iters.iter().filter_map(|i| static_length(i)).next() {
+                    if let Builder(ref kind, ref mut annotations) = builder.ty {
+                        match *kind {
+                            Appender(_) | GroupMerger(_, _) => {
+                                if annotations.size().is_none() {
+                                    annotations.set_size(cmp::min(len, MAX_INFERRED_SIZE));
+                                }
+                            }
+                            DictMerger(_, _, _) => {
+                                if annotations.num_keys().is_none() &&
+                                   annotations.size().is_none() {
+                                    let num_keys = cmp::min(len, MAX_INFERRED_SIZE);
+                                    annotations.set_num_keys(num_keys);
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+        }
+        None
+    });
+}
+
+/// Returns the number of elements an iterator produces if it can be determined statically.
+fn static_length(iter: &Iter<Type>) -> Option<i64> {
+    match (&iter.start, &iter.end, &iter.stride) {
+        (&None, &None, &None) => {
+            if let MakeVector { ref elems } = iter.data.kind {
+                return Some(elems.len() as i64);
+            }
+        }
+        (&Some(ref start), &Some(ref end), &Some(ref stride)) => {
+            if let (&Literal(I64Literal(start)),
+                    &Literal(I64Literal(end)),
+                    &Literal(I64Literal(stride))) = (&start.kind, &end.kind, &stride.kind) {
+                // Matches the iteration count computed by the generated loop code.
+                if stride > 0 && end >= start {
+                    return Some((end - start) / stride);
+                }
+            }
+        }
+        _ => (),
+    }
+    None
+}
+
+/// Reports loops which always run and zip together vectors whose lengths provably differ, and
+/// marks loops whose iterators provably fit in their vectors with an internal `bounds_check`
+/// annotation, so that the generated code does not check their lengths at runtime. Loops which
+/// might not run, such as those in a branch of an `If`, keep the runtime check instead.
+///
+/// Lengths are known for vector literals, literal iterator ranges and slices of vectors of known
+/// length. Otherwise, the length of a vector is its `len`, which is propagated through Lets and
+/// through loops which merge exactly once per iteration into a new appender. Lengths which are
+/// not provably equal are never assumed to be, so such loops keep the runtime check.
+pub fn check_zip_lengths(expr: &mut Expr<Type>) -> WeldResult<()> {
+    let mut lengths = vec![];
+    match expr.kind {
+        Lambda { ref mut body, .. } => check_zip_lengths_expr(body, &mut lengths, true),
+        _ => check_zip_lengths_expr(expr, &mut lengths, true),
+    }
+}
+
+/// Helper for `check_zip_lengths`. `lengths` holds the lengths of vectors bound by enclosing Lets,
+/// and `always_runs` is whether `expr` is evaluated whenever the program runs.
+fn check_zip_lengths_expr(expr: &mut Expr<Type>,
+                          lengths: &mut Vec<(Symbol, Expr<Type>)>,
+                          always_runs: bool)
+                          -> WeldResult<()> {
+    if let Let {
+               ref name,
+               ref mut value,
+               ref mut body,
+           } = expr.kind {
+        try!(check_zip_lengths_expr(value, lengths, always_runs));
+        match vector_length(value, lengths) {
+            Some(length) => {
+                lengths.push((name.clone(), length));
+                try!(check_zip_lengths_expr(body, lengths, always_runs));
+                lengths.pop();
+            }
+            None => try!(check_zip_lengths_expr(body, lengths, always_runs)),
+        }
+        return Ok(());
+    }
+
+    let in_bounds = if let For { ref iters, .. } = expr.kind {
+        try!(iters_in_bounds(iters, lengths, always_runs))
+    } else {
+        false
+    };
+    if in_bounds && expr.annotations.bounds_check().is_none() {
+        expr.annotations.set_bounds_check(false);
+    }
+
+    match expr.kind {
+        If {
+            ref mut cond,
+            ref mut on_true,
+            ref mut on_false,
+        } => {
+            try!(check_zip_lengths_expr(cond, lengths, always_runs));
+            try!(check_zip_lengths_expr(on_true, lengths, false));
+            try!(check_zip_lengths_expr(on_false, lengths, false));
+        }
+        For {
+            ref mut iters,
+            ref mut builder,
+            ref mut func,
+        } => {
+            for iter in iters.iter_mut() {
+                try!(check_zip_lengths_expr(&mut iter.data, lengths, always_runs));
+                let bounds = iter.start.iter_mut().chain(iter.end.iter_mut());
+                for e in bounds.chain(iter.stride.iter_mut()) {
+                    try!(check_zip_lengths_expr(e, lengths, always_runs));
+                }
+            }
+            try!(check_zip_lengths_expr(builder, lengths, always_runs));
+            try!(check_zip_lengths_expr(func, lengths, false));
+        }
+        Lambda { ref mut body, .. } => try!(check_zip_lengths_expr(body, lengths, false)),
+        _ => {
+            for c in expr.children_mut() {
+                try!(check_zip_lengths_expr(c, lengths, always_runs));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the iterators of a loop provably run for the same number of iterations and
+/// stay within their vectors. If they provably run for different numbers of iterations, this is an
+/// error when the loop `always_runs`, and otherwise left to the runtime check.
+fn iters_in_bounds(iters: &Vec<Iter<Type>>,
+                   lengths: &Vec<(Symbol, Expr<Type>)>,
+                   always_runs: bool)
+                   -> WeldResult<bool> {
+    let counts = iters.iter().map(|i| iteration_count(i, lengths)).collect::<Vec<_>>();
+    let known = counts.iter().map(|c| c.as_ref().and_then(known_length)).collect::<Vec<_>>();
+    for (i, a) in known.iter().enumerate() {
+        for b in known.iter().skip(i + 1) {
+            if let (&Some(a), &Some(b)) = (a, b) {
+                if a != b && !always_runs {
+                    return Ok(false);
+                } else if a != b {
+                    return weld_err!("Mismatched zip sizes: zipped vectors have lengths {} and {}",
+                                     a,
+                                     b);
+                }
+            }
+        }
+    }
+    if counts.iter().any(|c| c.is_none() || *c != counts[0]) {
+        return Ok(false);
+    }
+    Ok(iters.iter()
+           .zip(counts.iter())
+           .all(|(iter, count)| iter_fits(iter, count.as_ref().unwrap(), lengths)))
+}
+
+/// Returns the number of iterations of an iterator as an expression, if it can be determined.
+fn iteration_count(iter: &Iter<Type>, lengths: &Vec<(Symbol, Expr<Type>)>) -> Option<Expr<Type>> {
+    if let Some(count) = static_length(iter) {
+        return i64_literal(count);
+    }
+    match (&iter.start, &iter.end, &iter.stride) {
+        (&None, &None, &None) => vector_length(&iter.data, lengths),
+        (&Some(ref start), &Some(ref end), &Some(ref stride)) => {
+            if let (&Literal(I64Literal(0)), &Literal(I64Literal(1))) = (&start.kind, &stride.kind) {
+                match end.kind {
+                    Length { ref data } => vector_length(data, lengths),
+                    Ident(_) => Some(end.as_ref().clone()),
+                    _ => None,
+                }
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether an iterator running for `count` iterations stays within its vector.
+fn iter_fits(iter: &Iter<Type>, count: &Expr<Type>, lengths: &Vec<(Symbol, Expr<Type>)>) -> bool {
+    match (&iter.start, &iter.stride) {
+        (&None, &None) => true,
+        (&Some(ref start), &Some(ref stride)) => {
+            let length = vector_length(&iter.data, lengths);
+            if let (&Literal(I64Literal(start)), &Literal(I64Literal(stride))) =
+                (&start.kind, &stride.kind) {
+                if let (Some(count), Some(length)) = (known_length(count),
+                                                      length.as_ref().and_then(known_length)) {
+                    // Matches the bounds check in the generated loop code.
+                    return count == 0 || start + (count - 1) * stride < length;
+                }
+                if start == 0 && stride == 1 {
+                    return length.as_ref() == Some(count);
+                }
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+/// Returns the length of the vector `expr` as an expression, if it can be determined.
+fn vector_length(expr: &Expr<Type>, lengths: &Vec<(Symbol, Expr<Type>)>) -> Option<Expr<Type>> {
+    match expr.kind {
+        Ident(ref sym) => {
+            match lengths.iter().rev().find(|l| l.0 == *sym) {
+                Some(l) => Some(l.1.clone()),
+                None => super::exprs::length_expr(expr.clone()).ok(),
+            }
+        }
+        MakeVector { ref elems } => i64_literal(elems.len() as i64),
+        Slice {
+            ref data,
+            ref index,
+            ref size,
+        } => {
+            // Slices are truncated to the end of the vector.
+            let length = vector_length(data, lengths).as_ref().and_then(known_length);
+            if let (Some(length), &Literal(I64Literal(index)), &Literal(I64Literal(size))) =
+                (length, &index.kind, &size.kind) {
+                if index >= 0 && index <= length && size >= 0 {
+                    return i64_literal(cmp::min(size, length - index));
+                }
+            }
+            None
+        }
+        Res { ref builder } => {
+            if let For {
+                       ref iters,
+                       ref builder,
+                       ref func,
+                   } = builder.kind {
+                if let NewBuilder(ref arg) = builder.kind {
+                    match builder.ty {
+                        Builder(Appender(_), _) => {
+                            if let Lambda { ref params, ref body } = func.kind {
+                                if iters.len() > 0 && merges_once(body, &params[0].name) {
+                                    return iteration_count(&iters[0], lengths);
+                                }
+                            }
+                        }
+                        Builder(VecMerger(_, _), _) => {
+                            if let Some(ref arg) = *arg {
+                                return vector_length(arg, lengths);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Returns the value of a length expression if it is a literal.
+fn known_length(length: &Expr<Type>) -> Option<i64> {
+    if let Literal(I64Literal(l)) = length.kind {
+        Some(l)
+    } else {
+        None
+    }
+}
+
+/// Returns an i64 literal expression.
+fn i64_literal(value: i64) -> Option<Expr<Type>> {
+    super::exprs::literal_expr(I64Literal(value)).ok()
+}
+
+/// Tiles a loop nest annotated with `tile_size` so that each tile of the inner loop's data is
+/// reused across every iteration of the outer loop before moving on to the next tile.
+///
+/// For example, with a tile size of 64:
+///
+/// @(tile_size:64) for(rows, vecmerger[f64,+](z), |b,i,r| for(x, b, |b2,j,e| merge(b2, ...)))
+///
+/// becomes
+///
+/// for(iter(x, 0L, len(x) + 63L, 64L), vecmerger[f64,+](z), |bt,t,_|
+///     for(rows, bt, |b,i,r| for(iter(x, t * 64L, min(t * 64L + 64L, len(x)), 1L), b,
+///         |b2,j,e| merge(b2, ...[j := t * 64L + j]))))
+///
+/// Caveats:
+///     - Only loops whose body is directly a loop into the same builder are tiled, and only when
+///     the inner loop's data are identifiers defined outside the outer loop.
+///     - Tiling reorders merges, so it is only applied to builders whose results do not depend on
+///     merge order (mergers, vecmergers and dictmergers).
+pub fn tile_loops(expr: &mut Expr<Type>) {
+    expr.transform(&mut |ref mut e| {
+        if let Some(tile_size) = *e.annotations.tile_size() {
+            if tile_size > 0 {
+                if let Ok(Some(tiled)) = tile_loop(e, tile_size as i64) {
+                    return Some(tiled);
+                }
+            }
+        }
+        None
+    });
+}
+
+/// Returns a tiled version of the loop nest `expr`, or `None` if it does not have a shape that
+/// `tile_loops` can handle.
+fn tile_loop(expr: &Expr<Type>, tile_size: i64) -> WeldResult<Option<Expr<Type>>> {
+    use super::exprs::*;
+
+    let (outer_iters, outer_bldr, outer_params, inner) = match expr.kind {
+        For {
+            ref iters,
+            ref builder,
+            ref func,
+        } => {
+            match func.kind {
+                Lambda {
+                    ref params,
+                    ref body,
+                } => (iters, builder, params, body),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    match outer_bldr.ty {
+        Builder(Merger(_, _), _) |
+        Builder(VecMerger(_, _), _) |
+        Builder(DictMerger(_, _, _), _) => (),
+        _ => return Ok(None),
+    }
+
+    let (inner_iters, inner_params, inner_body) = match inner.kind {
+        For {
+            ref iters,
+            ref builder,
+            ref func,
+        } => {
+            match (&builder.kind, &func.kind) {
+                (&Ident(ref sym),
+                 &Lambda {
+                      ref params,
+                      ref body,
+                  }) if *sym == outer_params[0].name => (iters, params, body),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    // The inner loop must be interchangeable with the outer one, so its data cannot depend on the
+    // outer loop's parameters.
+    for iter in inner_iters.iter() {
+        if iter.start.is_some() || iter.kind != IterKind::ScalarIter {
+            return Ok(None);
+        }
+        match iter.data.kind {
+            Ident(ref sym) if outer_params.iter().all(|p| p.name != *sym) => (),
+            _ => return Ok(None),
+        }
+    }
+
+    let mut sym_gen = SymbolGenerator::from_expression(expr);
+    let bldr_ty = outer_bldr.ty.clone();
+    let i64_ty = Scalar(ScalarKind::I64);
+
+    let tile_bldr_sym = sym_gen.new_symbol("bt");
+    let tile_idx_sym = sym_gen.new_symbol("t");
+    let tile_elem_sym = sym_gen.new_symbol("te");
+    let inner_idx_sym = sym_gen.new_symbol(&inner_params[1].name.name);
+
+    let tile_size_expr = literal_expr(LiteralKind::I64Literal(tile_size))?;
+    let tile_start = binop_expr(BinOpKind::Multiply,
+                                ident_expr(tile_idx_sym.clone(), i64_ty.clone())?,
+                                tile_size_expr.clone())?;
+
+    // Each inner iterator covers one tile of its data.
+    let mut tiled_iters = vec![];
+    for iter in inner_iters.iter() {
+        let tile_end = binop_expr(BinOpKind::Add, tile_start.clone(), tile_size_expr.clone())?;
+        let len = length_expr(*iter.data.clone())?;
+        let end = select_expr(binop_expr(BinOpKind::LessThan, tile_end.clone(), len.clone())?,
+                              tile_end,
+                              len)?;
+        tiled_iters.push(Iter {
+                             data: iter.data.clone(),
+                             start: Some(Box::new(tile_start.clone())),
+                             end: Some(Box::new(end)),
+                             stride: Some(Box::new(literal_expr(LiteralKind::I64Literal(1))?)),
+                             kind: IterKind::ScalarIter,
+                         });
+    }
+
+    // The inner loop's index is now relative to the start of the tile.
+    let mut new_inner_body = *inner_body.clone();
+    let absolute_idx = binop_expr(BinOpKind::Add,
+                                  tile_start.clone(),
+                                  ident_expr(inner_idx_sym.clone(), i64_ty.clone())?)?;
+    new_inner_body.substitute(&inner_params[1].name, &absolute_idx);
+    let mut new_inner_params = inner_params.clone();
+    new_inner_params[1].name = inner_idx_sym;
+
+    let new_inner = Expr {
+        ty: inner.ty.clone(),
+        kind: For {
+            iters: tiled_iters,
+            builder: Box::new(ident_expr(outer_params[0].name.clone(), outer_params[0].ty.clone())?),
+            func: Box::new(lambda_expr(new_inner_params, new_inner_body)?),
+        },
+        annotations: inner.annotations.clone(),
+    };
+
+    // Drop the tile size from the interchanged loop so that running the pass again is a no-op.
+    let mut outer_annotations = expr.annotations.clone();
+    outer_annotations.clear_tile_size();
+    let new_outer = Expr {
+        ty: expr.ty.clone(),
+        kind: For {
+            iters: outer_iters.clone(),
+            builder: Box::new(ident_expr(tile_bldr_sym.clone(), bldr_ty.clone())?),
+            func: Box::new(lambda_expr(outer_params.clone(), new_inner)?),
+        },
+        annotations: outer_annotations,
+    };
+
+    // The tile loop runs over the first inner vector with a stride of one tile, rounding up so the
+    // last partial tile is included.
+    let ref first_data = inner_iters[0].data;
+    let tile_elem_ty = match first_data.ty {
+        Vector(ref elem) => *elem.clone(),
+        _ => return Ok(None),
+    };
+    let tile_iter = Iter {
+        data: first_data.clone(),
+        start: Some(Box::new(literal_expr(LiteralKind::I64Literal(0))?)),
+        end: Some(Box::new(binop_expr(BinOpKind::Add,
+                                      length_expr(*first_data.clone())?,
+                                      literal_expr(LiteralKind::I64Literal(tile_size - 1))?)?)),
+        stride: Some(Box::new(tile_size_expr)),
+        kind: IterKind::ScalarIter,
+    };
+    let tile_params = vec![Parameter {
+                               ty: bldr_ty.clone(),
+                               name: tile_bldr_sym,
+                           },
+                           Parameter {
+                               ty: i64_ty,
+                               name: tile_idx_sym,
+                           },
+                           Parameter {
+                               ty: tile_elem_ty,
+                               name: tile_elem_sym,
+                           }];
+
+    Ok(Some(Expr {
+                ty: expr.ty.clone(),
+                kind: For {
+                    iters: vec![tile_iter],
+                    builder: outer_bldr.clone(),
+                    func: Box::new(lambda_expr(tile_params, new_outer)?),
+                },
+                annotations: Annotations::new(),
+            }))
+}
+
+/// Branch selectivities (in fractions of 10,000) between which a branch is considered
+/// unpredictable enough to be worth predicating.
+const UNPREDICTABLE_SELECTIVITY_MIN: i32 = 1000;
+const UNPREDICTABLE_SELECTIVITY_MAX: i32 = 9000;
+
+/// Predicates branches by converting If expressions into Selects, which evaluate both sides and
+/// avoid a branch misprediction.
+///
+/// An If is predicated when it is annotated with `@(predicate:true)`, when it appears in a loop
+/// annotated with `@(predicate:true)`, or when its `selectivity` annotation suggests that the
+/// branch is unpredictable. `@(predicate:false)` disables predication for a single If.
+///
+/// The following patterns are predicated:
+///
+/// if(cond, merge(b, e), b) => merge(b, select(cond, e, identity)) for mergers of scalars.
+/// if(cond, e1, e2) => select(cond, e1, e2) for scalar e1 and e2.
+///
+/// In both cases the branches must be safe to evaluate unconditionally.
+pub fn predicate(expr: &mut Expr<Type>) {
+    predicate_expr(expr, false);
+}
+
+fn predicate_expr(expr: &mut Expr<Type>, in_predicated_loop: bool) {
+    let in_predicated_loop = in_predicated_loop ||
+                             match expr.kind {
+                                 For { .. } => *expr.annotations.predicate() == Some(true),
+                                 _ => false,
+                             };
+
+    for c in expr.children_mut() {
+        predicate_expr(c, in_predicated_loop);
+    }
+
+    if let If { .. } = expr.kind {
+        if should_predicate(&expr.annotations, in_predicated_loop) {
+            if let Ok(Some(predicated)) = predicated_if(expr) {
+                *expr = predicated;
+            }
+        }
+    }
+}
+
+/// Returns whether an If with the given annotations should be predicated.
+fn should_predicate(annotations: &Annotations, default: bool) -> bool {
+    if let Some(predicate) = *annotations.predicate() {
+        return predicate;
+    }
+    if let Some(selectivity) = *annotations.selectivity() {
+        return selectivity >= UNPREDICTABLE_SELECTIVITY_MIN &&
+               selectivity <= UNPREDICTABLE_SELECTIVITY_MAX;
+    }
+    default
+}
+
+/// Returns a predicated version of an If expression, or `None` if it does not match a pattern we
+/// can predicate.
+fn predicated_if(expr: &Expr<Type>) -> WeldResult<Option<Expr<Type>>> {
+    use super::exprs::*;
+
+    if let If {
+               ref cond,
+               ref on_true,
+               ref on_false,
+           } = expr.kind {
+        // if(cond, merge(b, e), b) or if(cond, b, merge(b, e)).
+        let merge = match (&on_true.kind, &on_false.kind) {
+            (&Merge {
+                  ref builder,
+                  ref value,
+              },
+             &Ident(ref name)) => Some((builder, value, name, true)),
+            (&Ident(ref name),
+             &Merge {
+                  ref builder,
+                  ref value,
+              }) => Some((builder, value, name, false)),
+            _ => None,
+        };
+        if let Some((builder, value, name, merge_on_true)) = merge {
+            if let Ident(ref bldr_name) = builder.kind {
+                if bldr_name == name && is_speculatable(value) {
+                    if let Builder(Merger(ref elem_ty, op), _) = builder.ty {
+                        if let Scalar(kind) = **elem_ty {
+                            if let Some(identity) = merger_identity_expr(op, kind)? {
+                                let merged = if merge_on_true {
+                                    select_expr(*cond.clone(), *value.clone(), identity)?
+                                } else {
+                                    select_expr(*cond.clone(), identity, *value.clone())?
+                                };
+                                return Ok(Some(merge_expr(*builder.clone(), merged)?));
+                            }
+                        }
+                    }
+                }
+            }
+            return Ok(None);
+        }
+
+        // if(cond, e1, e2) on scalars.
+        if let Scalar(_) = expr.ty {
+            if is_speculatable(on_true) && is_speculatable(on_false) {
+                return Ok(Some(select_expr(*cond.clone(), *on_true.clone(), *on_false.clone())?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether an expression can be evaluated even when the program would not have evaluated
+/// it, i.e., it has no side effects and cannot fail at runtime.
+fn is_speculatable(expr: &Expr<Type>) -> bool {
+    let mut speculatable = true;
+    expr.traverse(&mut |ref e| match e.kind {
+        For { .. } | Merge { .. } | Res { .. } | NewBuilder(_) | CUDF { .. } | Lookup { .. } |
+        Slice { .. } | Apply { .. } | Lambda { .. } => speculatable = false,
+        BinOp { kind: BinOpKind::Divide, .. } |
+        BinOp { kind: BinOpKind::Modulo, .. } => {
+            // Integer division by zero traps.
+            match e.ty {
+                Scalar(ScalarKind::F32) | Scalar(ScalarKind::F64) => (),
+                _ => speculatable = false,
+            }
+        }
+        _ => (),
+    });
+    speculatable
+}
+
+/// Eliminates common subexpressions by binding identical pure subexpressions to a symbol with a
+/// Let and replacing each occurrence with that symbol.
+///
+/// Subexpressions are only shared within a single evaluation scope: a Lambda body, a branch of an
+/// If, or a Let body. This guarantees that every symbol a shared subexpression uses is defined
+/// where the Let is inserted, and that hoisting never evaluates an expression the program would
+/// not have evaluated. Expressions involving builders are never shared, since builders must be
+/// used linearly.
+pub fn common_subexpression_elimination(expr: &mut Expr<Type>) {
+    let mut sym_gen = SymbolGenerator::from_expression(expr);
+    cse_scope(expr, &mut sym_gen);
+}
+
+/// Eliminates common subexpressions in the scope rooted at `root`, and then in each scope nested
+/// within it.
+fn cse_scope(root: &mut Expr<Type>, sym_gen: &mut SymbolGenerator) {
+    let mut bindings: Vec<(Symbol, Expr<Type>)> = vec![];
+    loop {
+        let common = {
+            let mut candidates = vec![];
+            cse_candidates(root, &mut candidates);
+            for &(_, ref value) in bindings.iter() {
+                cse_candidates(value, &mut candidates);
+            }
+            find_common_subexpression(&candidates)
+        };
+        match common {
+            Some(common) => {
+                let name = sym_gen.new_symbol("cse");
+                let ident = Expr {
+                    ty: common.ty.clone(),
+                    kind: Ident(name.clone()),
+                    annotations: Annotations::new(),
+                };
+                cse_replace(root, &common, &ident);
+                for &mut (_, ref mut value) in bindings.iter_mut() {
+                    cse_replace(value, &common, &ident);
+                }
+                bindings.push((name, common));
+            }
+            None => break,
+        }
+    }
+
+    cse_nested_scopes(root, sym_gen);
+
+    // The value of a binding may use bindings found before or after it, so each binding goes
+    // inside the ones its value uses. Wrap the root first in a binding no other binding uses.
+    while !bindings.is_empty() {
+        let inner = bindings.iter()
+            .position(|&(ref name, _)| {
+                          !bindings.iter().any(|&(_, ref value)| symbol_usage_count(name, value) > 0)
+                      })
+            .unwrap();
+        let (name, value) = bindings.remove(inner);
+        let body = root.clone();
+        *root = Expr {
+            ty: body.ty.clone(),
+            kind: Let {
+                name: name,
+                value: Box::new(value),
+                body: Box::new(body),
+            },
+            annotations: Annotations::new(),
+        };
+    }
+}
+
+/// Calls `cse_scope` on each scope directly nested in the scope of `expr`.
+fn cse_nested_scopes(expr: &mut Expr<Type>, sym_gen: &mut SymbolGenerator) {
+    match expr.kind {
+        Lambda { ref mut body, .. } => cse_scope(body, sym_gen),
+        Let {
+            ref mut value,
+            ref mut body,
+            ..
+        } => {
+            cse_nested_scopes(value, sym_gen);
+            cse_scope(body, sym_gen);
+        }
+        If {
+            ref mut cond,
+            ref mut on_true,
+            ref mut on_false,
+        } => {
+            cse_nested_scopes(cond, sym_gen);
+            cse_scope(on_true, sym_gen);
+            cse_scope(on_false, sym_gen);
+        }
+        _ => {
+            for c in expr.children_mut() {
+                cse_nested_scopes(c, sym_gen);
+            }
+        }
+    }
+}
+
+/// Returns the children of `expr` which are evaluated in the same scope as `expr`.
+fn same_scope_children(expr: &Expr<Type>) -> Vec<&Expr<Type>> {
+    match expr.kind {
+        Lambda { .. } => vec![],
+        Let { ref value, .. } => vec![value.as_ref()],
+        If { ref cond, .. } => vec![cond.as_ref()],
+        _ => expr.children().collect(),
+    }
+}
+
+/// Collects the subexpressions in the scope of `expr` which may be shared, outermost first.
+fn cse_candidates<'a>(expr: &'a Expr<Type>, candidates: &mut Vec<&'a Expr<Type>>) {
+    if is_cse_candidate(expr) {
+        candidates.push(expr);
+    }
+    for c in same_scope_children(expr) {
+        cse_candidates(c, candidates);
+    }
+}
+
+/// Returns the first candidate which is equal to a later candidate, if any. Candidates are
+/// bucketed by their structural hash so that only candidates which may be equal are compared.
+fn find_common_subexpression(candidates: &Vec<&Expr<Type>>) -> Option<Expr<Type>> {
+    let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    let hashes: Vec<u64> = candidates.iter().map(|c| structural_hash(c)).collect();
+    for (i, hash) in hashes.iter().enumerate() {
+        buckets.entry(*hash).or_default().push(i);
+    }
+    for (i, a) in candidates.iter().enumerate() {
+        for &j in buckets[&hashes[i]].iter().filter(|&&j| j > i) {
+            if same_subexpression(a, candidates[j]) {
+                return Some((*a).clone());
+            }
+        }
+    }
+    None
+}
+
+/// Hashes the shape of `expr`: its types, expression kinds, operators and the symbols it uses
+/// from outside. Expressions for which `same_subexpression` holds have the same hash.
+fn structural_hash(expr: &Expr<Type>) -> u64 {
+    let free = free_symbols(expr);
+    let mut hasher = DefaultHasher::new();
+    expr.traverse(&mut |e| {
+        e.ty.hash(&mut hasher);
+        mem::discriminant(&e.kind).hash(&mut hasher);
+        match e.kind {
+            BinOp { kind, .. } => kind.hash(&mut hasher),
+            UnaryOp { kind, .. } => kind.hash(&mut hasher),
+            GetField { index, .. } => index.hash(&mut hasher),
+            Ident(ref name) if free.iter().any(|f| f.0 == *name) => name.hash(&mut hasher),
+            _ => (),
+        }
+    });
+    hasher.finish()
+}
+
+/// Replaces every occurrence of `common` in the scope of `expr` with `ident`.
+fn cse_replace(expr: &mut Expr<Type>, common: &Expr<Type>, ident: &Expr<Type>) {
+    if same_subexpression(expr, common) {
+        *expr = ident.clone();
+        return;
+    }
+    match expr.kind {
+        Lambda { .. } => (),
+        Let { ref mut value, .. } => cse_replace(value, common, ident),
+        If { ref mut cond, .. } => cse_replace(cond, common, ident),
+        _ => {
+            for c in expr.children_mut() {
+                cse_replace(c, common, ident);
+            }
+        }
+    }
+}
+
+/// Returns whether `expr` is a pure subexpression that is worth sharing.
+fn is_cse_candidate(expr: &Expr<Type>) -> bool {
+    match expr.kind {
+        Literal(_) | Ident(_) => return false,
+        GetField { expr: ref e, .. } => {
+            if let Ident(_) = e.kind {
+                return false;
+            }
+        }
+        _ => (),
+    }
+    let mut pure = true;
+    expr.traverse(&mut |ref e| {
+        match e.ty {
+            Builder(_, _) | Function(_, _) => pure = false,
+            _ => (),
+        }
+        if let CUDF { .. } = e.kind {
+            pure = false;
+        }
+    });
+    pure
+}
+
+/// Returns whether two expressions are equal, allowing the symbols they define to differ but
+/// requiring the symbols they use from outside to be identical.
+fn same_subexpression(a: &Expr<Type>, b: &Expr<Type>) -> bool {
+    if a.ty != b.ty || mem::discriminant(&a.kind) != mem::discriminant(&b.kind) {
+        return false;
+    }
+    let free_a = free_symbols(a);
+    if free_a != free_symbols(b) {
+        return false;
+    }
+    // Bind the free symbols so that `compare_ignoring_symbols` matches them up with each other.
+    let params = free_a.into_iter()
+        .map(|(name, ty)| Parameter { name: name, ty: ty })
+        .collect::<Vec<_>>();
+    let wrapped_a = super::exprs::lambda_expr(params.clone(), a.clone());
+    let wrapped_b = super::exprs::lambda_expr(params, b.clone());
+    match (wrapped_a, wrapped_b) {
+        (Ok(ref wa), Ok(ref wb)) => wa.compare_ignoring_symbols(wb).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Returns the symbols used but not defined in `expr`, in order of first use.
+fn free_symbols(expr: &Expr<Type>) -> Vec<(Symbol, Type)> {
+    let mut defined = vec![];
+    expr.traverse(&mut |ref e| match e.kind {
+        Let { ref name, .. } => defined.push(name.clone()),
+        Lambda { ref params, .. } => defined.extend(params.iter().map(|p| p.name.clone())),
+        _ => (),
+    });
+    let mut free: Vec<(Symbol, Type)> = vec![];
+    expr.traverse(&mut |ref e| if let Ident(ref name) = e.kind {
+        if !defined.contains(name) && !free.iter().any(|f| f.0 == *name) {
+            free.push((name.clone(), e.ty.clone()));
+        }
+    });
+    free
+}
+
+/// Folds operations whose operands are literals into literals, and removes branches and
+/// arithmetic that have no effect, such as `if(true, a, b)`, `x + 0` and `x * 1`.
+///
+/// Folded values are computed exactly as the generated LLVM code would compute them: integer
+/// arithmetic wraps around, and floating point arithmetic is IEEE 754 arithmetic in the width of
+/// the operands. Operations whose result is undefined in LLVM, such as integer division by zero or
+/// out of range float to integer casts, and operations producing infinite or NaN floats, are left
+/// to be evaluated at runtime.
+pub fn fold_constants(expr: &mut Expr<Type>) {
+    for c in expr.children_mut() {
+        fold_constants(c);
+    }
+    if let Some(folded) = folded_expr(expr) {
+        *expr = folded;
+    }
+}
+
+/// Returns a simplified version of `expr`, assuming its children are already simplified, or
+/// `None` if it cannot be simplified.
+fn folded_expr(expr: &Expr<Type>) -> Option<Expr<Type>> {
+    use super::exprs::literal_expr;
+
+    let literal = match expr.kind {
+        BinOp {
+            kind,
+            ref left,
+            ref right,
+        } => {
+            match (&left.kind, &right.kind) {
+                (&Literal(l), &Literal(r)) => fold_binop(kind, l, r),
+                _ => return simplify_binop(kind, left, right),
+            }
+        }
+        UnaryOp { kind, ref value } => {
+            match value.kind {
+                Literal(l) => fold_unaryop(kind, l),
+                _ => None,
+            }
+        }
+        // Negation is generated as a subtraction from zero.
+        Negate(ref value) => {
+            match value.kind {
+                Literal(I8Literal(l)) => Some(I8Literal(0i8.wrapping_sub(l))),
+                Literal(I32Literal(l)) => Some(I32Literal(0i32.wrapping_sub(l))),
+                Literal(I64Literal(l)) => Some(I64Literal(0i64.wrapping_sub(l))),
+                Literal(F32Literal(l)) => Some(F32Literal(0.0 - l)),
+                Literal(F64Literal(l)) => Some(F64Literal(0.0 - l)),
+                _ => None,
+            }
+        }
+        Cast {
+            kind,
+            ref child_expr,
+        } => {
+            match child_expr.kind {
+                Literal(l) => fold_cast(l, kind),
+                _ => None,
+            }
+        }
+        If {
+            ref cond,
+            ref on_true,
+            ref on_false,
+        } => {
+            return match cond.kind {
+                       Literal(BoolLiteral(true)) => Some(*on_true.clone()),
+                       Literal(BoolLiteral(false)) => Some(*on_false.clone()),
+                       _ => None,
+                   };
+        }
+        // Both sides of a Select are evaluated, so the dropped side must not be able to fail.
+        Select {
+            ref cond,
+            ref on_true,
+            ref on_false,
+        } => {
+            return match cond.kind {
+                       Literal(BoolLiteral(true)) if is_speculatable(on_false) => {
+                           Some(*on_true.clone())
+                       }
+                       Literal(BoolLiteral(false)) if is_speculatable(on_true) => {
+                           Some(*on_false.clone())
+                       }
+                       _ => None,
+                   };
+        }
+        GetField {
+            expr: ref struct_expr,
+            index,
+        } => {
+            // The other fields are dropped, so they must not be able to fail at runtime.
+            return match struct_expr.kind {
+                       MakeStruct { ref elems } => {
+                           let index = index as usize;
+                           let dropped = elems.iter().enumerate().filter(|&(i, _)| i != index);
+                           if dropped.map(|(_, e)| e).all(is_speculatable) {
+                               Some(elems[index].clone())
+                           } else {
+                               None
+                           }
+                       }
+                       _ => None,
+                   };
+        }
+        Length { ref data } => {
+            match data.kind {
+                MakeVector { ref elems } if elems.iter().all(is_speculatable) => {
+                    Some(I64Literal(elems.len() as i64))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    match literal {
+        Some(F32Literal(l)) if !l.is_finite() => None,
+        Some(F64Literal(l)) if !l.is_finite() => None,
+        Some(l) => literal_expr(l).ok(),
+        None => None,
+    }
+}
+
+/// Folds a binary operation on two literals of the same type.
+fn fold_binop(kind: BinOpKind, left: LiteralKind, right: LiteralKind) -> Option<LiteralKind> {
+    use std::i8;
+    use std::i32;
+    use std::i64;
+
+    if kind.is_comparison() {
+        return match (left, right) {
+                   (BoolLiteral(l), BoolLiteral(r)) => {
+                       match kind {
+                           BinOpKind::Equal => Some(l == r),
+                           BinOpKind::NotEqual => Some(l != r),
+                           _ => None,
+                       }
+                   }
+                   (I8Literal(l), I8Literal(r)) => fold_comparison(kind, l, r),
+                   (I32Literal(l), I32Literal(r)) => fold_comparison(kind, l, r),
+                   (I64Literal(l), I64Literal(r)) => fold_comparison(kind, l, r),
+                   (F32Literal(l), F32Literal(r)) => fold_comparison(kind, l, r),
+                   (F64Literal(l), F64Literal(r)) => fold_comparison(kind, l, r),
+                   _ => None,
+               }
+               .map(BoolLiteral);
+    }
+
+    match (left, right) {
+        (BoolLiteral(l), BoolLiteral(r)) => {
+            match kind {
+                BinOpKind::LogicalAnd | BinOpKind::BitwiseAnd => Some(BoolLiteral(l && r)),
+                BinOpKind::LogicalOr | BinOpKind::BitwiseOr => Some(BoolLiteral(l || r)),
+                BinOpKind::Xor => Some(BoolLiteral(l != r)),
+                _ => None,
+            }
+        }
+        (I8Literal(l), I8Literal(r)) => {
+            fold_int_binop(kind, l as i64, r as i64, i8::MIN as i64).map(|v| I8Literal(v as i8))
+        }
+        (I32Literal(l), I32Literal(r)) => {
+            fold_int_binop(kind, l as i64, r as i64, i32::MIN as i64).map(|v| I32Literal(v as i32))
+        }
+        (I64Literal(l), I64Literal(r)) => {
+            fold_int_binop(kind, l, r, i64::MIN).map(I64Literal)
+        }
+        (F32Literal(l), F32Literal(r)) => fold_float_binop(kind, l, r).map(F32Literal),
+        (F64Literal(l), F64Literal(r)) => fold_float_binop(kind, l, r).map(F64Literal),
+        _ => None,
+    }
+}
+
+/// Folds an arithmetic or bitwise operation on integers whose type has the minimum value `min`.
+/// The operands and the result are widened to i64; the result wraps around when truncated back to
+/// the original type, as LLVM's `add`, `sub` and `mul` do.
+fn fold_int_binop(kind: BinOpKind, left: i64, right: i64, min: i64) -> Option<i64> {
+    match kind {
+        BinOpKind::Add => Some(left.wrapping_add(right)),
+        BinOpKind::Subtract => Some(left.wrapping_sub(right)),
+        BinOpKind::Multiply => Some(left.wrapping_mul(right)),
+        // Division by zero and overflowing division are undefined, so they are left alone.
+        BinOpKind::Divide if right != 0 && !(left == min && right == -1) => Some(left / right),
+        BinOpKind::Modulo if right != 0 && !(left == min && right == -1) => Some(left % right),
+        BinOpKind::BitwiseAnd => Some(left & right),
+        BinOpKind::BitwiseOr => Some(left | right),
+        BinOpKind::Xor => Some(left ^ right),
+        _ => None,
+    }
+}
+
+/// Folds an arithmetic operation on floats.
+fn fold_float_binop<T>(kind: BinOpKind, left: T, right: T) -> Option<T>
+    where T: Add<Output = T> + Sub<Output = T> + Mul<Output = T> + Div<Output = T> +
+             Rem<Output = T>
+{
+    match kind {
+        BinOpKind::Add => Some(left + right),
+        BinOpKind::Subtract => Some(left - right),
+        BinOpKind::Multiply => Some(left * right),
+        BinOpKind::Divide => Some(left / right),
+        BinOpKind::Modulo => Some(left % right),
+        _ => None,
+    }
+}
+
+/// Folds a comparison. Float comparisons are ordered, so they are false if either side is NaN.
+fn fold_comparison<T: PartialOrd>(kind: BinOpKind, left: T, right: T) -> Option<bool> {
+    match kind {
+        BinOpKind::Equal => Some(left == right),
+        BinOpKind::NotEqual => Some(left < right || left > right),
+        BinOpKind::LessThan => Some(left < right),
+        BinOpKind::LessThanOrEqual => Some(left <= right),
+        BinOpKind::GreaterThan => Some(left > right),
+        BinOpKind::GreaterThanOrEqual => Some(left >= right),
+        _ => None,
+    }
+}
+
+/// Folds a unary operation on a float literal. `erf` is not part of Rust's standard library and
+/// is left to the runtime.
+fn fold_unaryop(kind: UnaryOpKind, value: LiteralKind) -> Option<LiteralKind> {
+    match (kind, value) {
+        (UnaryOpKind::Exp, F32Literal(v)) => Some(F32Literal(v.exp())),
+        (UnaryOpKind::Exp, F64Literal(v)) => Some(F64Literal(v.exp())),
+        (UnaryOpKind::Log, F32Literal(v)) => Some(F32Literal(v.ln())),
+        (UnaryOpKind::Log, F64Literal(v)) => Some(F64Literal(v.ln())),
+        (UnaryOpKind::Sqrt, F32Literal(v)) => Some(F32Literal(v.sqrt())),
+        (UnaryOpKind::Sqrt, F64Literal(v)) => Some(F64Literal(v.sqrt())),
+        _ => None,
+    }
+}
+
+/// Folds a cast of a literal, following the LLVM instructions chosen by `llvm::llvm_castop`:
+/// integers are sign extended or truncated, booleans are zero extended, and floats are truncated
+/// towards zero when converted to integers.
+fn fold_cast(value: LiteralKind, kind: ScalarKind) -> Option<LiteralKind> {
+    use std::i8;
+    use std::i32;
+    use std::i64;
+
+    match value {
+        BoolLiteral(v) => {
+            let int_value = if v { 1 } else { 0 };
+            match kind {
+                ScalarKind::Bool => Some(BoolLiteral(v)),
+                ScalarKind::I8 => Some(I8Literal(int_value as i8)),
+                ScalarKind::I32 => Some(I32Literal(int_value as i32)),
+                ScalarKind::I64 => Some(I64Literal(int_value)),
+                ScalarKind::F32 => Some(F32Literal(int_value as f32)),
+                ScalarKind::F64 => Some(F64Literal(int_value as f64)),
+            }
+        }
+        I8Literal(_) | I32Literal(_) | I64Literal(_) => {
+            let v = match value {
+                I8Literal(v) => v as i64,
+                I32Literal(v) => v as i64,
+                I64Literal(v) => v,
+                _ => return None,
+            };
+            match kind {
+                ScalarKind::Bool => Some(BoolLiteral(v & 1 != 0)),
+                ScalarKind::I8 => Some(I8Literal(v as i8)),
+                ScalarKind::I32 => Some(I32Literal(v as i32)),
+                ScalarKind::I64 => Some(I64Literal(v)),
+                ScalarKind::F32 => Some(F32Literal(v as f32)),
+                ScalarKind::F64 => Some(F64Literal(v as f64)),
+            }
+        }
+        F32Literal(_) | F64Literal(_) => {
+            let v = match value {
+                F32Literal(v) => v as f64,
+                F64Literal(v) => v,
+                _ => return None,
+            };
+            // Converting an out of range float to an integer is undefined.
+            let t = v.trunc();
+            let in_range = |min: f64, max: f64| t >= min && t <= max;
+            match kind {
+                ScalarKind::Bool if in_range(0.0, 1.0) => Some(BoolLiteral(t == 1.0)),
+                ScalarKind::I8 if in_range(i8::MIN as f64, i8::MAX as f64) => {
+                    Some(I8Literal(t as i8))
+                }
+                ScalarKind::I32 if in_range(i32::MIN as f64, i32::MAX as f64) => {
+                    Some(I32Literal(t as i32))
+                }
+                // i64::MAX rounds up to 2^63 as an f64, which is out of range.
+                ScalarKind::I64 if in_range(i64::MIN as f64, i64::MAX as f64) &&
+                                   t != i64::MAX as f64 => Some(I64Literal(t as i64)),
+                ScalarKind::F32 => {
+                    match value {
+                        F32Literal(v) => Some(F32Literal(v)),
+                        _ => Some(F32Literal(v as f32)),
+                    }
+                }
+                ScalarKind::F64 => Some(F64Literal(v)),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Simplifies a binary operation with a literal operand that leaves the other operand unchanged.
+fn simplify_binop(kind: BinOpKind,
+                  left: &Expr<Type>,
+                  right: &Expr<Type>)
+                  -> Option<Expr<Type>> {
+    if let Literal(r) = right.kind {
+        if is_right_identity(kind, r) {
+            return Some(left.clone());
+        }
+    }
+    if let Literal(l) = left.kind {
+        if is_left_identity(kind, l) {
+            return Some(right.clone());
+        }
+    }
+    None
+}
+
+/// Returns whether `x op value` is always `x`.
+fn is_right_identity(kind: BinOpKind, value: LiteralKind) -> bool {
+    let (is_zero, is_one, is_negative_zero) = match value {
+        BoolLiteral(_) => {
+            return match kind {
+                       BinOpKind::LogicalAnd | BinOpKind::BitwiseAnd => value == BoolLiteral(true),
+                       BinOpKind::LogicalOr | BinOpKind::BitwiseOr | BinOpKind::Xor => {
+                           value == BoolLiteral(false)
+                       }
+                       _ => false,
+                   }
+        }
+        I8Literal(v) => (v == 0, v == 1, false),
+        I32Literal(v) => (v == 0, v == 1, false),
+        I64Literal(v) => (v == 0, v == 1, false),
+        F32Literal(v) => (v == 0.0, v == 1.0, v == 0.0 && v.is_sign_negative()),
+        F64Literal(v) => (v == 0.0, v == 1.0, v == 0.0 && v.is_sign_negative()),
+    };
+    let is_float = match value {
+        F32Literal(_) | F64Literal(_) => true,
+        _ => false,
+    };
+    match kind {
+        // -0.0 + 0.0 is +0.0, so only -0.0 leaves every float unchanged when added.
+        BinOpKind::Add => is_zero && (!is_float || is_negative_zero),
+        BinOpKind::Subtract => is_zero && !is_negative_zero,
+        BinOpKind::Multiply | BinOpKind::Divide => is_one,
+        BinOpKind::BitwiseOr | BinOpKind::Xor => is_zero && !is_float,
+        _ => false,
+    }
+}
+
+/// Returns whether `value op x` is always `x`, which holds for the right identities of commutative
+/// operators.
+fn is_left_identity(kind: BinOpKind, value: LiteralKind) -> bool {
+    match kind {
+        BinOpKind::Add | BinOpKind::Multiply | BinOpKind::BitwiseOr | BinOpKind::Xor |
+        BinOpKind::LogicalAnd | BinOpKind::BitwiseAnd | BinOpKind::LogicalOr => {
+            is_right_identity(kind, value)
+        }
+        _ => false,
+    }
+}
+
+/// Hoists loop-invariant expressions out of the functions of For loops into Lets around the loops,
+/// so that they are evaluated once rather than once per iteration.
+///
+/// An expression is loop-invariant if it is pure and does not use the loop's parameters or any
+/// symbol defined inside the loop. Expressions evaluated on every iteration of a loop that is
+/// known to run at least once are hoisted even if they might fail, such as lookups; all other
+/// expressions, including those in a branch of an If or in an inner loop, are only hoisted if
+/// they are safe to evaluate unconditionally. A loop over a single vector symbol which might be
+/// empty is versioned on its length instead: `if(len(v) > 0L, <loop with the invariants hoisted>,
+/// <initial builder>)`, unless it is in the body of a loop that could be vectorized, which the If
+/// would prevent. Loops whose result is taken directly are hoisted out of as a whole, so that
+/// `result(for(...))` keeps the shape that the vectorizer and other transforms look for.
+pub fn hoist_loop_invariants(expr: &mut Expr<Type>) {
+    let mut sym_gen = SymbolGenerator::from_expression(expr);
+    hoist_loop_invariants_expr(expr, false, &mut sym_gen);
+}
+
+fn hoist_loop_invariants_expr(expr: &mut Expr<Type>,
+                              in_vectorizable_loop: bool,
+                              sym_gen: &mut SymbolGenerator) {
+    let is_loop_result = match expr.kind {
+        Res { ref builder } => {
+            match builder.kind {
+                For { .. } => true,
+                _ => false,
+            }
+        }
+        _ => false,
+    };
+
+    let bindings = if is_loop_result {
+        match expr.kind {
+            Res { ref mut builder } => {
+                hoist_loop_invariants_children(builder, in_vectorizable_loop, sym_gen);
+                hoist_from_loop(builder, sym_gen, false)
+            }
+            _ => vec![],
+        }
+    } else {
+        hoist_loop_invariants_children(expr, in_vectorizable_loop, sym_gen);
+        hoist_from_loop(expr, sym_gen, false)
+    };
+
+    if !in_vectorizable_loop {
+        version_loop(expr, is_loop_result, sym_gen);
+    }
+    wrap_in_lets(expr, bindings);
+}
+
+/// Hoists loop invariants in the children of `expr`, noting whether the function of a For loop
+/// is the body of a loop that could be vectorized.
+fn hoist_loop_invariants_children(expr: &mut Expr<Type>,
+                                  in_vectorizable_loop: bool,
+                                  sym_gen: &mut SymbolGenerator) {
+    use super::vectorizer::is_vectorizable;
+
+    let in_body = match expr.kind {
+        For { .. } => is_vectorizable(expr),
+        _ => in_vectorizable_loop,
+    };
+    for c in expr.children_mut() {
+        let in_loop = match c.kind {
+            Lambda { .. } => in_body,
+            _ => in_vectorizable_loop,
+        };
+        hoist_loop_invariants_expr(c, in_loop, sym_gen);
+    }
+}
+
+/// Versions the For loop `expr` (or the loop whose result `expr` takes, if `is_loop_result` is
+/// set) on its trip count if it has loop invariants that were not hoisted because they might
+/// fail and the loop might not run. The invariants are hoisted in a copy of the loop that runs
+/// when the loop is not empty; otherwise, the loop's initial builder is used directly. The initial
+/// builder is bound to a symbol shared by both branches unless it is a symbol or a new builder,
+/// which the vectorizer only recognizes in place; the argument of a new builder is bound instead.
+fn version_loop(expr: &mut Expr<Type>, is_loop_result: bool, sym_gen: &mut SymbolGenerator) {
+    let (cond, init) = {
+        let for_loop = match expr.kind {
+            Res { ref builder } if is_loop_result => builder.as_ref(),
+            _ => &*expr,
+        };
+        match loop_trip_guard(for_loop) {
+            Some(guard) => guard,
+            None => return,
+        }
+    };
+
+    let mut versioned = expr.clone();
+    let bindings = if is_loop_result {
+        match versioned.kind {
+            Res { ref mut builder } => hoist_from_loop(builder, sym_gen, true),
+            _ => vec![],
+        }
+    } else {
+        hoist_from_loop(&mut versioned, sym_gen, true)
+    };
+    if bindings.is_empty() {
+        return;
+    }
+
+    let mut init_bindings = vec![];
+    let init = {
+        let for_loop = match versioned.kind {
+            Res { ref mut builder } if is_loop_result => builder.as_mut(),
+            _ => &mut versioned,
+        };
+        match for_loop.kind {
+            For { ref mut builder, .. } => {
+                match builder.kind {
+                    NewBuilder(Some(ref mut arg)) => share_value(arg, &mut init_bindings, sym_gen),
+                    NewBuilder(None) => {}
+                    _ => share_value(builder, &mut init_bindings, sym_gen),
+                }
+                builder.as_ref().clone()
+            }
+            _ => init,
+        }
+    };
+    wrap_in_lets(&mut versioned, bindings);
+
+    let empty = if is_loop_result {
+        super::exprs::result_expr(init)
+    } else {
+        Ok(init)
+    };
+    if let Ok(mut guarded) = empty.and_then(|e| super::exprs::if_expr(cond, versioned, e)) {
+        wrap_in_lets(&mut guarded, init_bindings);
+        *expr = guarded;
+    }
+}
+
+/// Replaces `value` with a new symbol bound to it in `bindings`, unless it is a symbol or a
+/// literal, which can be copied.
+fn share_value(value: &mut Expr<Type>,
+               bindings: &mut Vec<(Symbol, Expr<Type>)>,
+               sym_gen: &mut SymbolGenerator) {
+    match value.kind {
+        Ident(_) | Literal(_) => return,
+        _ => {}
+    }
+    let name = sym_gen.new_symbol("licm");
+    if let Ok(ident) = super::exprs::ident_expr(name.clone(), value.ty.clone()) {
+        bindings.push((name, mem::replace(value, ident)));
+    }
+}
+
+/// Returns the condition under which the For loop `expr` runs at least once, along with its
+/// initial builder, if the loop iterates over a single vector symbol that might be empty.
+fn loop_trip_guard(expr: &Expr<Type>) -> Option<(Expr<Type>, Expr<Type>)> {
+    if let For {
+               ref iters,
+               ref builder,
+               ..
+           } = expr.kind {
+        if iters.len() != 1 || iters[0].kind != IterKind::ScalarIter ||
+           iters[0].start.is_some() {
+            return None;
+        }
+        if let Ident(_) = iters[0].data.kind {
+            let len = super::exprs::length_expr(iters[0].data.as_ref().clone()).ok()?;
+            let zero = super::exprs::literal_expr(I64Literal(0)).ok()?;
+            let cond = super::exprs::binop_expr(BinOpKind::GreaterThan, len, zero).ok()?;
+            return Some((cond, builder.as_ref().clone()));
+        }
+    }
+    None
+}
+
+/// Wraps `expr` in a Let for each of `bindings`, with the last binding outermost.
+fn wrap_in_lets(expr: &mut Expr<Type>, bindings: Vec<(Symbol, Expr<Type>)>) {
+    for (name, value) in bindings {
+        let body = expr.clone();
+        *expr = Expr {
+            ty: body.ty.clone(),
+            kind: Let {
+                name: name,
+                value: Box::new(value),
+                body: Box::new(body),
+            },
+            annotations: Annotations::new(),
+        };
+    }
+}
+
+/// Replaces the loop-invariant expressions in the function of the For loop `expr` with new
+/// symbols, and returns the bindings of those symbols. If `assume_nonempty` is set, the loop is
+/// taken to run at least once, so that invariants which might fail are hoisted too.
+fn hoist_from_loop(expr: &mut Expr<Type>,
+                   sym_gen: &mut SymbolGenerator,
+                   assume_nonempty: bool)
+                   -> Vec<(Symbol, Expr<Type>)> {
+    let mut bindings = vec![];
+    if let For {
+               ref iters,
+               ref mut func,
+               ..
+           } = expr.kind {
+        // Expressions that might fail are only hoisted if the loop would have evaluated them.
+        let conditional = !assume_nonempty && !iters.iter().any(is_nonempty_iter);
+        if let Lambda {
+                   ref params,
+                   ref mut body,
+               } = func.kind {
+            let mut loop_symbols: Vec<Symbol> = params.iter().map(|p| p.name.clone()).collect();
+            body.traverse(&mut |ref e| match e.kind {
+                Let { ref name, .. } => loop_symbols.push(name.clone()),
+                Lambda { ref params, .. } => {
+                    loop_symbols.extend(params.iter().map(|p| p.name.clone()))
+                }
+                _ => (),
+            });
+
+            loop {
+                let invariant = match find_loop_invariant(body, &loop_symbols, conditional) {
+                    Some(invariant) => invariant,
+                    None => break,
+                };
+                let name = sym_gen.new_symbol("licm");
+                let ident = Expr {
+                    ty: invariant.ty.clone(),
+                    kind: Ident(name.clone()),
+                    annotations: Annotations::new(),
+                };
+                body.transform(&mut |ref mut e| if same_subexpression(e, &invariant) {
+                                       Some(ident.clone())
+                                   } else {
+                                       None
+                                   });
+                bindings.push((name, invariant));
+            }
+
+            // Lets left behind by hoisting out of inner loops now just rename a hoisted symbol.
+            let hoisted: Vec<Symbol> = bindings.iter().map(|b| b.0.clone()).collect();
+            body.transform(&mut |ref mut e| {
+                if let Let {
+                           ref name,
+                           ref value,
+                           ref body,
+                       } = e.kind {
+                    if let Ident(ref sym) = value.kind {
+                        if hoisted.contains(sym) {
+                            let mut new_body = *body.clone();
+                            new_body.substitute(name, value);
+                            return Some(new_body);
+                        }
+                    }
+                }
+                None
+            });
+        }
+    }
+    bindings
+}
+
+/// Returns whether the iterator `iter` is known to produce at least one element.
+fn is_nonempty_iter(iter: &Iter<Type>) -> bool {
+    if iter.kind != IterKind::ScalarIter || iter.start.is_some() || iter.end.is_some() ||
+       iter.stride.is_some() {
+        return false;
+    }
+    match iter.data.kind {
+        MakeVector { ref elems } => !elems.is_empty(),
+        _ => false,
+    }
+}
+
+/// Returns the first loop-invariant expression in `expr`, outermost first. `conditional`
+/// indicates whether `expr` is only evaluated on some iterations of the loop.
+fn find_loop_invariant(expr: &Expr<Type>,
+                       loop_symbols: &Vec<Symbol>,
+                       conditional: bool)
+                       -> Option<Expr<Type>> {
+    if is_cse_candidate(expr) && (!conditional || is_speculatable(expr)) &&
+       free_symbols(expr).iter().all(|&(ref sym, _)| !loop_symbols.contains(sym)) {
+        return Some(expr.clone());
+    }
+    match expr.kind {
+        If {
+            ref cond,
+            ref on_true,
+            ref on_false,
+        } => {
+            find_loop_invariant(cond, loop_symbols, conditional)
+                .or_else(|| find_loop_invariant(on_true, loop_symbols, true))
+                .or_else(|| find_loop_invariant(on_false, loop_symbols, true))
+        }
+        Lambda { ref body, .. } => find_loop_invariant(body, loop_symbols, true),
+        _ => {
+            expr.children()
+                .filter_map(|c| find_loop_invariant(c, loop_symbols, conditional))
+                .next()
+        }
+    }
+}
+
+/// Removes struct fields that are never read from vectors built by appender loops.
+///
+/// This applies to the result of a loop which merges struct literals into a new appender, when
+/// the result is either bound with a Let or iterated over directly by another loop, and every
+/// use of it reads fields through GetField, either of an element from a Lookup or of a loop's
+/// element parameter, or takes its Length. The unused fields whose values cannot fail are removed
+/// from the merged MakeStructs, from the appender's element type and from the types of the vector
+/// and its elements, and the GetField indices of the remaining fields are shifted down. The
+/// transformed expression is only kept if it still passes type inference.
+pub fn eliminate_dead_fields(expr: &mut Expr<Type>) {
+    use super::type_inference::infer_types;
+
+    let mut projected = expr.clone();
+    if !eliminate_dead_fields_expr(&mut projected) {
+        return;
+    }
+    let mut partial = projected.to_partial();
+    if infer_types(&mut partial).is_ok() {
+        *expr = projected;
+    }
+}
+
+/// Removes dead fields in `expr` and its children, returning whether anything was removed.
+fn eliminate_dead_fields_expr(expr: &mut Expr<Type>) -> bool {
+    let mut changed = false;
+    for c in expr.children_mut() {
+        changed |= eliminate_dead_fields_expr(c);
+    }
+
+    match expr.kind {
+        Let {
+            ref name,
+            ref mut value,
+            ref mut body,
+        } => {
+            if let Some(field_tys) = appender_struct_fields(value) {
+                let mut used = vec![false; field_tys.len()];
+                if collect_field_uses(body, name, &mut used) {
+                    keep_failing_fields(value, &mut used);
+                    if let Some(new_index) = field_projection(&mut used) {
+                        let new_ty = project_appender_loop(value, &used);
+                        rewrite_field_uses(body, name, &new_ty, &new_index);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        For {
+            ref mut iters,
+            ref mut func,
+            ..
+        } if iters.len() == 1 => {
+            let mut projected = false;
+            if let Some(field_tys) = appender_struct_fields(&iters[0].data) {
+                if let Lambda {
+                           ref mut params,
+                           ref mut body,
+                       } = func.kind {
+                    let mut used = vec![false; field_tys.len()];
+                    if collect_field_uses(body, &params[2].name, &mut used) {
+                        keep_failing_fields(&iters[0].data, &mut used);
+                        if let Some(new_index) = field_projection(&mut used) {
+                            let new_ty = project_appender_loop(&mut iters[0].data, &used);
+                            let elem_ty = match new_ty {
+                                Vector(ref elem) => *elem.clone(),
+                                _ => unreachable!(),
+                            };
+                            rewrite_field_uses(body, &params[2].name, &elem_ty, &new_index);
+                            params[2].ty = elem_ty;
+                            projected = true;
+                        }
+                    }
+                }
+            }
+            if projected {
+                refresh_lambda_type(func);
+                changed = true;
+            }
+        }
+        _ => (),
+    }
+    changed
+}
+
+/// If `expr` is `result(for(..., appender[{...}], ...))` and every merge into the appender merges
+/// a struct literal, returns the types of the struct's fields. The appender must only flow through
+/// the expressions accepted by `merges_directly`, so that its merges can be told apart from those
+/// into other builders of the same type.
+fn appender_struct_fields(expr: &Expr<Type>) -> Option<Vec<Type>> {
+    if let Res { ref builder } = expr.kind {
+        if let For {
+                   builder: ref init_builder,
+                   ref func,
+                   ..
+               } = builder.kind {
+            if let (&NewBuilder(None), &Builder(Appender(ref elem_ty), _)) =
+                (&init_builder.kind, &init_builder.ty) {
+                if let (&Struct(ref field_tys), &Lambda { ref params, ref body }) =
+                    (elem_ty.as_ref(), &func.kind) {
+                    if merges_directly(body, &params[0].name) {
+                        let mut values = vec![];
+                        builder_merges(body, &params[0].name, &mut values);
+                        if values.iter().all(|v| if let MakeStruct { .. } = v.kind {
+                                                 true
+                                             } else {
+                                                 false
+                                             }) {
+                            return Some(field_tys.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Collects the values merged into the builder `bldr` in `expr`, a loop body accepted by
+/// `merges_directly`, including the merges of inner loops over `bldr`.
+fn builder_merges<'a>(expr: &'a Expr<Type>, bldr: &Symbol, values: &mut Vec<&'a Expr<Type>>) {
+    match expr.kind {
+        Merge {
+            ref builder,
+            ref value,
+        } => {
+            if let Ident(ref sym) = builder.kind {
+                if sym == bldr {
+                    values.push(value);
+                }
+            }
+        }
+        If {
+            ref on_true,
+            ref on_false,
+            ..
+        } => {
+            builder_merges(on_true, bldr, values);
+            builder_merges(on_false, bldr, values);
+        }
+        Let { ref body, .. } => builder_merges(body, bldr, values),
+        For { ref func, .. } => {
+            if let Lambda {
+                       ref params,
+                       ref body,
+                   } = func.kind {
+                builder_merges(body, &params[0].name, values);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Marks the fields of elements of `sym` that are read in `expr` in `used`. Returns false if `sym`
+/// is used in a way that might read any field.
+fn collect_field_uses(expr: &Expr<Type>, sym: &Symbol, used: &mut Vec<bool>) -> bool {
+    fn is_sym(expr: &Expr<Type>, sym: &Symbol) -> bool {
+        match expr.kind {
+            Ident(ref s) => s == sym,
+            _ => false,
+        }
+    }
+
+    match expr.kind {
+        Ident(ref s) if s == sym => return false,
+        Length { ref data } if is_sym(data, sym) => return true,
+        GetField {
+            expr: ref struct_expr,
+            index,
+        } => {
+            if is_sym(struct_expr, sym) {
+                used[index as usize] = true;
+                return true;
+            }
+            if let Lookup {
+                       ref data,
+                       index: ref lookup_index,
+                   } = struct_expr.kind {
+                if is_sym(data, sym) {
+                    used[index as usize] = true;
+                    return collect_field_uses(lookup_index, sym, used);
+                }
+            }
+        }
+        For {
+            ref iters,
+            ref builder,
+            ref func,
+        } if iters.len() == 1 && is_sym(&iters[0].data, sym) => {
+            if let Lambda {
+                       ref params,
+                       ref body,
+                   } = func.kind {
+                let ref iter = iters[0];
+                for bound in vec![&iter.start, &iter.end, &iter.stride] {
+                    if let Some(ref bound) = *bound {
+                        if !collect_field_uses(bound, sym, used) {
+                            return false;
+                        }
+                    }
+                }
+                return collect_field_uses(builder, sym, used) &&
+                       collect_field_uses(body, &params[2].name, used) &&
+                       collect_field_uses(body, sym, used);
+            }
+            return false;
+        }
+        _ => (),
+    }
+    expr.children().all(|c| collect_field_uses(c, sym, used))
+}
+
+/// Marks the fields of the struct literals merged by the appender loop `expr`, which must be
+/// accepted by `appender_struct_fields`, as used when their values might fail, so that removing
+/// them cannot hide an error.
+fn keep_failing_fields(expr: &Expr<Type>, used: &mut Vec<bool>) {
+    if let Res { ref builder } = expr.kind {
+        if let For { ref func, .. } = builder.kind {
+            if let Lambda {
+                       ref params,
+                       ref body,
+                   } = func.kind {
+                let mut values = vec![];
+                builder_merges(body, &params[0].name, &mut values);
+                for value in values {
+                    if let MakeStruct { ref elems } = value.kind {
+                        for (i, elem) in elems.iter().enumerate() {
+                            if !is_speculatable(elem) {
+                                used[i] = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the new index of each used field if some fields are unused, or `None` if no field can
+/// be removed. At least one field is always kept.
+fn field_projection(used: &mut Vec<bool>) -> Option<Vec<Option<u32>>> {
+    if used.iter().all(|u| *u) {
+        return None;
+    }
+    if used.iter().all(|u| !*u) {
+        used[0] = true;
+    }
+    let mut next = 0;
+    Some(used.iter()
+             .map(|u| if *u {
+                      next += 1;
+                      Some(next - 1)
+                  } else {
+                      None
+                  })
+             .collect())
+}
+
+/// Removes the unused fields from the appender loop `expr`, which must be accepted by
+/// `appender_struct_fields`, and returns its new type.
+fn project_appender_loop(expr: &mut Expr<Type>, used: &Vec<bool>) -> Type {
+    let mut new_elem_ty = None;
+    if let Res { ref mut builder } = expr.kind {
+        if let For {
+                   builder: ref mut init_builder,
+                   ref mut func,
+                   ..
+               } = builder.kind {
+            let new_bldr_ty = match init_builder.ty {
+                Builder(Appender(ref elem_ty), ref annotations) => {
+                    let elem_ty = match **elem_ty {
+                        Struct(ref field_tys) => Struct(project_fields(field_tys, used)),
+                        _ => unreachable!(),
+                    };
+                    new_elem_ty = Some(elem_ty.clone());
+                    Builder(Appender(Box::new(elem_ty)), annotations.clone())
+                }
+                _ => unreachable!(),
+            };
+            init_builder.ty = new_bldr_ty.clone();
+            project_loop_merges(func, &new_bldr_ty, used);
+        }
+        builder.ty = match builder.kind {
+            For { builder: ref init_builder, .. } => init_builder.ty.clone(),
+            _ => unreachable!(),
+        };
+    }
+    expr.ty = Vector(Box::new(new_elem_ty.unwrap()));
+    expr.ty.clone()
+}
+
+/// Gives the builder parameter of the loop function `func` the type `new_ty`, and removes the
+/// unused fields from the struct literals merged into it, following the builder as
+/// `builder_merges` does.
+fn project_loop_merges(func: &mut Expr<Type>, new_ty: &Type, used: &Vec<bool>) {
+    if let Lambda {
+               ref mut params,
+               ref mut body,
+           } = func.kind {
+        params[0].ty = new_ty.clone();
+        project_merges(body, new_ty, used);
+    }
+    refresh_lambda_type(func);
+}
+
+/// Helper for `project_loop_merges`. Updates the types of the expressions that the builder flows
+/// through in `expr`, a loop body accepted by `merges_directly`, and projects its merges.
+fn project_merges(expr: &mut Expr<Type>, new_ty: &Type, used: &Vec<bool>) {
+    match expr.kind {
+        Ident(_) => (),
+        Merge {
+            ref mut builder,
+            ref mut value,
+        } => {
+            builder.ty = new_ty.clone();
+            if let MakeStruct { ref mut elems } = value.kind {
+                let old_elems: Vec<_> = elems.drain(..).collect();
+                elems.extend(old_elems.into_iter()
+                                 .zip(used.iter())
+                                 .filter(|&(_, u)| *u)
+                                 .map(|(e, _)| e));
+            }
+            value.ty = match value.ty {
+                Struct(ref field_tys) => Struct(project_fields(field_tys, used)),
+                _ => unreachable!(),
+            };
+        }
+        If {
+            ref mut on_true,
+            ref mut on_false,
+            ..
+        } => {
+            project_merges(on_true, new_ty, used);
+            project_merges(on_false, new_ty, used);
+        }
+        Let { ref mut body, .. } => project_merges(body, new_ty, used),
+        For {
+            ref mut builder,
+            ref mut func,
+            ..
+        } => {
+            builder.ty = new_ty.clone();
+            project_loop_merges(func, new_ty, used);
+        }
+        _ => unreachable!(),
+    }
+    expr.ty = new_ty.clone();
+}
+
+fn project_fields(field_tys: &Vec<Type>, used: &Vec<bool>) -> Vec<Type> {
+    field_tys.iter().zip(used.iter()).filter(|&(_, u)| *u).map(|(t, _)| t.clone()).collect()
+}
+
+/// Updates the uses of `sym`, whose type is now `new_ty`, in `expr`, along with the uses of the
+/// elements of loops over `sym`. `new_index` maps old field indices to new ones.
+fn rewrite_field_uses(expr: &mut Expr<Type>,
+                      sym: &Symbol,
+                      new_ty: &Type,
+                      new_index: &Vec<Option<u32>>) {
+    let is_sym = |expr: &Expr<Type>| match expr.kind {
+        Ident(ref s) => s == sym,
+        _ => false,
+    };
+    let elem_ty = match *new_ty {
+        Vector(ref elem) => Some(*elem.clone()),
+        _ => None,
+    };
+
+    let projects_sym = match expr.kind {
+        GetField { expr: ref struct_expr, .. } => {
+            match struct_expr.kind {
+                Lookup { ref data, .. } => is_sym(data),
+                _ => is_sym(struct_expr),
+            }
+        }
+        _ => false,
+    };
+    if projects_sym {
+        if let GetField { ref mut index, .. } = expr.kind {
+            *index = new_index[*index as usize].unwrap();
+        }
+    }
+
+    // Loops over `sym` now have elements of the new element type.
+    let mut elem_param = None;
+    if let For {
+               ref iters,
+               ref mut func,
+               ..
+           } = expr.kind {
+        if iters.len() == 1 && is_sym(&iters[0].data) {
+            if let Lambda { ref mut params, .. } = func.kind {
+                if let Some(ref elem_ty) = elem_ty {
+                    params[2].ty = elem_ty.clone();
+                    elem_param = Some((params[2].name.clone(), elem_ty.clone()));
+                }
+            }
+        }
+    }
+
+    for c in expr.children_mut() {
+        rewrite_field_uses(c, sym, new_ty, new_index);
+        if let Some((ref param, ref param_ty)) = elem_param {
+            rewrite_field_uses(c, param, param_ty, new_index);
+        }
+    }
+
+    let ty = match expr.kind {
+        Ident(_) if is_sym(expr) => Some(new_ty.clone()),
+        Lookup { ref data, .. } if is_sym(data) => elem_ty,
+        _ => None,
+    };
+    if let Some(ty) = ty {
+        expr.ty = ty;
+    }
+    refresh_lambda_type(expr);
+}
+
+/// Recomputes the type of `expr` from its parameters and body if it is a Lambda.
+fn refresh_lambda_type(expr: &mut Expr<Type>) {
+    let ty = match expr.kind {
+        Lambda {
+            ref params,
+            ref body,
+        } => Some(Function(params.iter().map(|p| p.ty.clone()).collect(), Box::new(body.ty.clone()))),
+        _ => None,
+    };
+    if let Some(ty) = ty {
+        expr.ty = ty;
+    }
+}
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 
 use super::ast::ExprKind::*;
+use super::ast::LiteralKind;
 use super::ast::LiteralKind::*;
+use super::ast::ScalarKind;
 use super::ast::ScalarKind::*;
 use super::ast::IterKind;
 use super::ast::Symbol;
@@ -91,10 +93,42 @@ fn infer_up(expr: &mut PartialExpr, env: &mut TypeMap) -> WeldResult<bool> {
     Ok(changed)
 }
 
+/// Returns the scalar kind of a literal.
+fn literal_kind(lit: &LiteralKind) -> ScalarKind {
+    match *lit {
+        BoolLiteral(_) => Bool,
+        I8Literal(_) => I8,
+        I32Literal(_) => I32,
+        I64Literal(_) => I64,
+        F32Literal(_) => F32,
+        F64Literal(_) => F64,
+    }
+}
+
+/// Returns the type of the value merged by each lane of a vectorized merge value, which is a SIMD
+/// value or a struct of them, or `None` if the merge value is not vectorized.
+fn lane_type(ty: &PartialType) -> Option<PartialType> {
+    match *ty {
+        Simd(kind) => Some(Scalar(kind)),
+        Struct(ref fields) if !fields.is_empty() => {
+            let lane_fields: Vec<_> = fields.iter().filter_map(lane_type).collect();
+            if lane_fields.len() == fields.len() {
+                Some(Struct(lane_fields))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
 /// Infer the type of expr or its children locally based on what is known about some of them.
 /// Return true if any new expression's type was inferred, or an error if types are inconsistent.
 fn infer_locally(expr: &mut PartialExpr, env: &mut TypeMap) -> WeldResult<bool> {
     match expr.kind {
+        // The vectorizer gives literals in vectorized loops a SIMD type of the literal's kind.
+        Literal(ref lit) if expr.ty == Simd(literal_kind(lit)) => Ok(false),
+
         Literal(I32Literal(_)) => push_complete_type(&mut expr.ty, Scalar(I32), "I32Literal"),
 
         Literal(I64Literal(_)) => push_complete_type(&mut expr.ty, Scalar(I64), "I64Literal"),
@@ -140,12 +174,18 @@ fn infer_locally(expr: &mut PartialExpr, env: &mut TypeMap) -> WeldResult<bool>
             match value.ty {
                 Scalar(F32) => push_complete_type(&mut expr.ty, Scalar(F32), "UnaryOp"),
                 Scalar(F64) => push_complete_type(&mut expr.ty, Scalar(F64), "UnaryOp"),
+                Simd(F32) => push_complete_type(&mut expr.ty, Simd(F32), "UnaryOp"),
+                Simd(F64) => push_complete_type(&mut expr.ty, Simd(F64), "UnaryOp"),
                 Unknown => push_type(&mut expr.ty, &value.ty, "UnaryOp"),
                 _ => return weld_err!("Internal error: {} called on non-scalar or non-float", op),
             }
         }
 
-        Cast { kind, .. } => Ok(try!(push_complete_type(&mut expr.ty, Scalar(kind), "Cast"))),
+        Cast { kind, ref child_expr } => {
+            // Casts in vectorized loops convert every lane of a SIMD value.
+            let ty = if let Simd(_) = child_expr.ty { Simd(kind) } else { Scalar(kind) };
+            Ok(try!(push_complete_type(&mut expr.ty, ty, "Cast")))
+        }
 
         ToVec { ref mut child_expr } => {
             let mut changed = false;
@@ -364,13 +404,10 @@ fn infer_locally(expr: &mut PartialExpr, env: &mut TypeMap) -> WeldResult<bool>
             let mut changed = false;
             match builder.ty {
                 Builder(ref mut b, _) => {
-                    if let Simd(kind) = value.ty {
-                        let mut simd_mty = Simd(kind);
-                        changed |= try!(sync_types(&mut simd_mty, &mut value.ty, "Merge"));
+                    if let Some(mut lane_ty) = lane_type(&value.ty) {
+                        // Vectorized loops merge one value per SIMD lane.
                         let mty = b.merge_type_mut();
-                        if let Simd(kind) = simd_mty {
-                            *mty = Scalar(kind);
-                        }
+                        changed |= try!(sync_types(mty, &mut lane_ty, "Merge"));
                     } else {
                         let mty = b.merge_type_mut();
                         changed |= try!(sync_types(mty, &mut value.ty, "Merge"));
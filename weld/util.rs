@@ -51,6 +51,12 @@ impl SymbolGenerator {
         SymbolGenerator { id_map: id_map }
     }
 
+    /// Record an existing symbol, so that new symbols with its name are given larger IDs.
+    pub fn add_symbol(&mut self, symbol: &Symbol) {
+        let id = self.id_map.entry(symbol.name.clone()).or_insert(0);
+        *id = max(*id, symbol.id);
+    }
+
     pub fn new_symbol(&mut self, name: &str) -> Symbol {
         let id = self.id_map.entry(name.to_owned()).or_insert(-1);
         *id += 1;
@@ -14,21 +14,34 @@ use std::collections::HashSet;
 
 use super::exprs;
 
-/// Vectorizes a type.
+/// Vectorizes a type. Structs are vectorized field by field.
 fn vectorized_type(ty: &Type) -> Type {
-    if let Scalar(kind) = *ty {
-        Simd(kind)
-    } else {
-        ty.clone()
+    match *ty {
+        Scalar(kind) => Simd(kind),
+        Struct(ref field_tys) => Struct(field_tys.iter().map(vectorized_type).collect()),
+        _ => ty.clone(),
+    }
+}
+
+/// Returns `true` if this is a scalar or a struct whose fields are (possibly nested) structs of
+/// scalars, which are the types whose values can be computed one per SIMD lane.
+fn vectorizable_type(ty: &Type) -> bool {
+    match *ty {
+        Scalar(_) => true,
+        Struct(ref field_tys) => field_tys.iter().all(vectorizable_type),
+        _ => false,
     }
 }
 
 /// Returns `true` if this is a set of iterators we can vectorize, `false` otherwise.
 /// 
-/// We can vectorize an iterator if all of its iterators consume the entire collection.
+/// We can vectorize a set of iterators if each iterator is a scalar iterator over a vector of
+/// scalars, either over the entire vector or over a strided range of it.
 fn vectorizable_iters(iters: &Vec<Iter<Type>>) -> bool {
     for ref iter in iters {
-        if iter.start.is_some() || iter.end.is_some() || iter.stride.is_some() {
+        // Strided iterators are loaded with a gather, so any start, end and stride will do.
+        if iter.start.is_some() != iter.end.is_some() ||
+           iter.start.is_some() != iter.stride.is_some() {
             return false;
         }
         if let Vector(ref elem_ty) = iter.data.ty {
@@ -79,6 +92,15 @@ fn vectorize_expr(e: &mut Expr<Type>, broadcast_idens: &HashSet<Symbol>) -> Weld
         Select { .. } => {
             e.ty = vectorized_type(&e.ty);
         }
+        MakeStruct { .. } => {
+            e.ty = vectorized_type(&e.ty);
+        }
+        Cast { .. } => {
+            e.ty = vectorized_type(&e.ty);
+        }
+        UnaryOp { .. } => {
+            e.ty = vectorized_type(&e.ty);
+        }
         // Predication for a value merged into a merger. This pattern checks for if(cond, merge(b, e), b).
         If { ref cond, ref on_true, ref on_false } => {
             if let Merge { ref builder, ref value } = on_true.kind {
@@ -88,34 +110,9 @@ fn vectorize_expr(e: &mut Expr<Type>, broadcast_idens: &HashSet<Symbol>) -> Weld
                             if let Builder(ref bk, _) = builder.ty {
                                 if let BuilderKind::Merger(ref ty, ref op) = *bk {
                                     if let Scalar(ref sk) = *ty.as_ref() {
-                                        let identity = match *op {
-                                            BinOpKind::Add => {
-                                                match *sk {
-                                                    ScalarKind::I8 => exprs::literal_expr(LiteralKind::I8Literal(0))?,
-                                                    ScalarKind::I32 => exprs::literal_expr(LiteralKind::I32Literal(0))?,
-                                                    ScalarKind::I64 => exprs::literal_expr(LiteralKind::I64Literal(0))?,
-                                                    ScalarKind::F32 => exprs::literal_expr(LiteralKind::F32Literal(0.0))?,
-                                                    ScalarKind::F64 => exprs::literal_expr(LiteralKind::F64Literal(0.0))?,
-                                                    _ => {
-                                                        return weld_err!("Predication not supported");
-                                                    }
-                                                }
-                                            }
-                                            BinOpKind::Multiply => {
-                                                match *sk {
-                                                    ScalarKind::I8 => exprs::literal_expr(LiteralKind::I8Literal(1))?,
-                                                    ScalarKind::I32 => exprs::literal_expr(LiteralKind::I32Literal(1))?,
-                                                    ScalarKind::I64 => exprs::literal_expr(LiteralKind::I64Literal(1))?,
-                                                    ScalarKind::F32 => exprs::literal_expr(LiteralKind::F32Literal(1.0))?,
-                                                    ScalarKind::F64 => exprs::literal_expr(LiteralKind::F64Literal(1.0))?,
-                                                    _ => {
-                                                        return weld_err!("Predication not supported");
-                                                    }
-                                                }
-                                            }
-                                            _ => {
-                                                return weld_err!("Merger type not vectorizable.");
-                                            }
+                                        let identity = match exprs::merger_identity_expr(*op, *sk)? {
+                                            Some(identity) => identity,
+                                            None => return weld_err!("Predication not supported"),
                                         };
                                         // Change if(cond, merge(b, e), b) => 
                                         // merge(b, select(cond, e, identity).
@@ -150,6 +147,9 @@ fn vectorize_expr(e: &mut Expr<Type>, broadcast_idens: &HashSet<Symbol>) -> Weld
 /// expressions which appear in a function body are vectorizable expressions (see
 /// `docs/vectorization.md` for details) 
 fn vectorizable(for_loop: &Expr<Type>) -> WeldResult<HashSet<Symbol>> {
+    if *for_loop.annotations.vectorize() == Some(false) {
+        return weld_err!("Vectorization disabled by annotation");
+    }
     if let For { ref iters, builder: ref init_builder, ref func } = for_loop.kind {
         // Check if the iterators are consumed.
         if vectorizable_iters(&iters) {
@@ -161,6 +161,19 @@ fn vectorizable(for_loop: &Expr<Type>) -> WeldResult<HashSet<Symbol>> {
                         BuilderKind::Merger(ref ty, _) => {
                             if let Scalar(_) = **ty {} else { return weld_err!("Unsupported builder"); }
                         }
+                        // Elements, keys and values are computed in SIMD lanes and merged one lane
+                        // at a time.
+                        BuilderKind::Appender(ref ty) => {
+                            if !vectorizable_type(ty) { return weld_err!("Unsupported builder"); }
+                        }
+                        BuilderKind::DictMerger(ref kt, ref vt, _) => {
+                            if !vectorizable_type(kt) || !vectorizable_type(vt) {
+                                return weld_err!("Unsupported builder");
+                            }
+                        }
+                        BuilderKind::VecMerger(ref ty, _) => {
+                            if !vectorizable_type(ty) { return weld_err!("Unsupported builder"); }
+                        }
                         _ => {
                             return weld_err!("Unsupported builder");
                         }
@@ -187,21 +200,24 @@ fn vectorizable(for_loop: &Expr<Type>) -> WeldResult<HashSet<Symbol>> {
                                 }
                             },
                             BinOp{ .. } => {},
+                            Select{ .. } => {},
+                            Cast{ .. } => {},
+                            UnaryOp{ .. } => {},
+                            MakeStruct{ .. } => {
+                                if !vectorizable_type(&f.ty) {
+                                    passed = false;
+                                }
+                            },
                             Let{ ref name, .. } => {
                                 defined_in_loop.insert(name.clone()); 
                             },
-                            // GetField is allowed if the only fields we fetch are the ones from
-                            // the argument (in case the input was Zipped). At this point, it is
-                            // already guaranteed that each input vector is a scalar.
+                            // GetField is allowed on structs of scalars, such as the argument (in
+                            // case the input was Zipped) or structs built in the loop, since they
+                            // are vectorized field by field.
                             GetField { ref expr, .. } => {
-                                let mut getfield_passed = false;
-                                let ref elem_param = params[2];
-                                if let Ident(ref name) = expr.kind {
-                                    if elem_param.name == *name {
-                                       getfield_passed = true; 
-                                    }
+                                if !vectorizable_type(&expr.ty) {
+                                    passed = false;
                                 }
-                                passed = getfield_passed;
                             }
                             Merge{ .. } => {},
                             If { ref on_true, ref on_false, .. } => {
@@ -221,7 +237,7 @@ fn vectorizable(for_loop: &Expr<Type>) -> WeldResult<HashSet<Symbol>> {
                                         }
                                     }
                                 }
-                                passed = can_predicate;
+                                passed = passed && can_predicate;
                             }
                             _ => {
                                 passed = false;
@@ -280,14 +296,231 @@ fn vectorizable(for_loop: &Expr<Type>) -> WeldResult<HashSet<Symbol>> {
     return weld_err!("Unsupported pattern");
 }
 
+/// Returns whether the loop `for_loop` can be vectorized on its own, possibly after splitting out
+/// its inner loops.
+pub fn is_vectorizable(for_loop: &Expr<Type>) -> bool {
+    if vectorizable(for_loop).is_ok() {
+        return true;
+    }
+    let mut sym_gen = SymbolGenerator::from_expression(for_loop);
+    match split_inner_loops(for_loop, &mut sym_gen) {
+        Ok(Some(_)) => true,
+        _ => false,
+    }
+}
+
+/// Collects the inner loops in a loop body whose results can be computed before the loop body
+/// runs. Loops under an `If` are skipped, since they may only be safe to evaluate when its
+/// condition holds.
+fn inner_loops<'a>(expr: &'a Expr<Type>, loops: &mut Vec<&'a Expr<Type>>) {
+    match expr.kind {
+        Res { .. } => loops.push(expr),
+        If { .. } => {}
+        _ => {
+            for child in expr.children() {
+                inner_loops(child, loops);
+            }
+        }
+    }
+}
+
+/// Replaces the inner loops found by `inner_loops` with the given expressions, in order.
+fn replace_inner_loops(expr: &mut Expr<Type>, replacements: &mut Vec<Expr<Type>>) {
+    match expr.kind {
+        Res { .. } => *expr = replacements.remove(0),
+        If { .. } => {}
+        _ => {
+            for child in expr.children_mut() {
+                replace_inner_loops(child, replacements);
+            }
+        }
+    }
+}
+
+/// Splits the inner loops out of a loop that is not vectorizable because of them, so that the
+/// loop can be vectorized while its inner loops stay scalar.
+///
+/// Each inner loop which produces a scalar and depends only on the element, and not on the
+/// builder, the index or values computed in the loop body, is moved into a scalar loop over the
+/// same iterators which appends its result for every element. The original loop then zips over
+/// these results instead of computing them:
+///
+/// ```text
+/// for(v, b0, |b,i,e| merge(b, e * result(for(w, merger[i32,+], |b2,i2,x| merge(b2, x * e)))))
+/// ```
+///
+/// becomes
+///
+/// ```text
+/// let a = v;
+/// let t = result(@(vectorize:false) for(a, appender[i32], |b1,i1,e1|
+///     merge(b1, result(for(w, merger[i32,+], |b2,i2,x| merge(b2, x * e1))))));
+/// for(zip(a, t), b0, |b,i,e2| let e = e2.$0; merge(b, e * e2.$1))
+/// ```
+///
+/// Returns `None` if the loop can't be split or would still not be vectorizable after splitting.
+fn split_inner_loops(for_loop: &Expr<Type>,
+                     sym_gen: &mut SymbolGenerator)
+                     -> WeldResult<Option<Expr<Type>>> {
+    if *for_loop.annotations.vectorize() == Some(false) {
+        return Ok(None);
+    }
+    if let For { ref iters, builder: ref init_builder, ref func } = for_loop.kind {
+        if !vectorizable_iters(iters) {
+            return Ok(None);
+        }
+        if let NewBuilder(_) = init_builder.kind {} else {
+            return Ok(None);
+        }
+        if let Lambda { ref params, ref body } = func.kind {
+            let mut loops = vec![];
+            inner_loops(body, &mut loops);
+            if loops.is_empty() {
+                return Ok(None);
+            }
+
+            // Symbols an inner loop may not use, since they are only known inside the loop body.
+            let mut loop_symbols = vec![params[0].name.clone(), params[1].name.clone()];
+            body.traverse(&mut |e| if let Let { ref name, .. } = e.kind {
+                loop_symbols.push(name.clone());
+            });
+            for inner in loops.iter() {
+                if let Scalar(_) = inner.ty {} else {
+                    return Ok(None);
+                }
+                let mut independent = true;
+                inner.traverse(&mut |e| if let Ident(ref name) = e.kind {
+                    if loop_symbols.contains(name) {
+                        independent = false;
+                    }
+                });
+                if !independent {
+                    return Ok(None);
+                }
+            }
+
+            let data_names = iters.iter().map(|_| sym_gen.new_symbol("a")).collect::<Vec<_>>();
+            let mut scalar_iters = vec![];
+            for (iter, name) in iters.iter().zip(&data_names) {
+                scalar_iters.push(Iter {
+                    data: Box::new(exprs::ident_expr(name.clone(), iter.data.ty.clone())?),
+                    start: iter.start.clone(),
+                    end: iter.end.clone(),
+                    stride: iter.stride.clone(),
+                    kind: IterKind::ScalarIter,
+                });
+            }
+
+            // Compute each inner loop in its own scalar loop, which appends the loop's result for
+            // each element.
+            let ref elem_param = params[2];
+            let mut results = vec![];
+            for inner in loops.iter() {
+                let builder = exprs::newbuilder_expr(BuilderKind::Appender(Box::new(inner.ty.clone())),
+                                                     None)?;
+                let elem = Parameter {
+                    name: sym_gen.new_symbol(&elem_param.name.name),
+                    ty: elem_param.ty.clone(),
+                };
+                let bldr = Parameter {
+                    name: sym_gen.new_symbol("b"),
+                    ty: builder.ty.clone(),
+                };
+                let index = Parameter {
+                    name: sym_gen.new_symbol("i"),
+                    ty: Scalar(ScalarKind::I64),
+                };
+                let mut value = (*inner).clone();
+                value.substitute(&elem_param.name,
+                                 &exprs::ident_expr(elem.name.clone(), elem.ty.clone())?);
+                let merge = exprs::merge_expr(exprs::ident_expr(bldr.name.clone(), bldr.ty.clone())?,
+                                              value)?;
+                let lambda = exprs::lambda_expr(vec![bldr, index, elem], merge)?;
+                let mut prelude = exprs::for_expr(scalar_iters.clone(), builder, lambda, false)?;
+                prelude.annotations.set_vectorize(false);
+                results.push((sym_gen.new_symbol("t"), exprs::result_expr(prelude)?));
+            }
+
+            // Zip the results of the inner loops with the original iterators, and rebuild the
+            // original element from the zipped one.
+            let mut zipped_iters = scalar_iters.clone();
+            for &(ref name, ref result) in results.iter() {
+                zipped_iters.push(Iter {
+                    data: Box::new(exprs::ident_expr(name.clone(), result.ty.clone())?),
+                    start: None,
+                    end: None,
+                    stride: None,
+                    kind: IterKind::ScalarIter,
+                });
+            }
+            let zipped_elem_tys = zipped_iters.iter()
+                .map(|iter| match iter.data.ty {
+                    Vector(ref elem_ty) => *elem_ty.clone(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            let zipped_elem = Parameter {
+                name: sym_gen.new_symbol(&elem_param.name.name),
+                ty: Struct(zipped_elem_tys),
+            };
+            let zipped_ident = exprs::ident_expr(zipped_elem.name.clone(), zipped_elem.ty.clone())?;
+
+            let mut replacements = vec![];
+            for i in 0..results.len() {
+                replacements.push(exprs::getfield_expr(zipped_ident.clone(), (iters.len() + i) as u32)?);
+            }
+            let mut new_body = body.as_ref().clone();
+            replace_inner_loops(&mut new_body, &mut replacements);
+
+            let elem_value = if iters.len() == 1 {
+                exprs::getfield_expr(zipped_ident.clone(), 0)?
+            } else {
+                let mut fields = vec![];
+                for i in 0..iters.len() {
+                    fields.push(exprs::getfield_expr(zipped_ident.clone(), i as u32)?);
+                }
+                exprs::makestruct_expr(fields)?
+            };
+            let new_body = exprs::let_expr(elem_param.name.clone(), elem_value, new_body)?;
+            let new_params = vec![params[0].clone(), params[1].clone(), zipped_elem];
+            let new_func = exprs::lambda_expr(new_params, new_body)?;
+            let new_loop = exprs::for_expr(zipped_iters, *init_builder.clone(), new_func, false)?;
+            if vectorizable(&new_loop).is_err() {
+                return Ok(None);
+            }
+
+            let mut prev_expr = exprs::result_expr(new_loop)?;
+            for (name, result) in results.into_iter().rev() {
+                prev_expr = exprs::let_expr(name, result, prev_expr)?;
+            }
+            for (iter, name) in iters.iter().zip(data_names).rev() {
+                prev_expr = exprs::let_expr(name, *iter.data.clone(), prev_expr)?;
+            }
+            return Ok(Some(prev_expr));
+        }
+    }
+    Ok(None)
+}
+
 /// Vectorize an expression.
 pub fn vectorize(expr: &mut Expr<Type>) {
     let mut vectorized = false;
+    // Symbols are generated for the whole expression, since the lets added around one loop may
+    // enclose other loops that are vectorized later.
+    let mut sym_gen = SymbolGenerator::from_expression(expr);
     expr.transform_and_continue_res(&mut |ref mut expr| {
         //  The Res is a stricter-than-necessary check, but prevents us from having to check nested
         //  loops for now.
         if let Res { builder: ref for_loop } = expr.kind {
-            let ref broadcast_idens = vectorizable(for_loop)?;
+            // Loops that can't be vectorized may still produce vectorizable loops, e.g. when
+            // post-processing the result of an aggregation.
+            let ref broadcast_idens = match vectorizable(for_loop) {
+                Ok(idens) => idens,
+                Err(_) => {
+                    // Loops with inner loops are split so the outer loop can be vectorized.
+                    return Ok((split_inner_loops(for_loop, &mut sym_gen)?, true));
+                }
+            };
             if let For { ref iters, builder: ref init_builder, ref func } = for_loop.kind {
                 if let NewBuilder(_) = init_builder.kind {
                     if let Lambda { ref params, ref body } = func.kind {
@@ -314,8 +547,6 @@ pub fn vectorize(expr: &mut Expr<Type>) {
                         // Pull out the iter into a let statement. This lets us repeat the
                         // iter via an identifier in the vectorized loop. Here, we just
                         // create the identifiers which refer to the data items.
-                        let mut sym_gen = SymbolGenerator::from_expression(expr);
-
                         let data_names = iters.iter().map(|_| {
                             sym_gen.new_symbol("a")
                         })
@@ -0,0 +1,470 @@
+//! Checks of the invariants that every optimization pass must preserve in a typed expression or a
+//! SIR program.
+//!
+//! These checks are run after each pass when `weld.compile.verifyPasses` is set, so that a buggy
+//! transform is reported by name instead of surfacing as an LLVM error or a wrong answer.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use super::ast::*;
+use super::ast::ExprKind::*;
+use super::ast::Type::*;
+use super::error::*;
+use super::sir::*;
+use super::type_inference;
+
+/// Checks that `expr` is well typed, that every symbol it uses is defined and no symbol it defines
+/// shadows another, and that every builder in it is used linearly.
+pub fn verify(expr: &Expr<Type>) -> WeldResult<()> {
+    try!(verify_types(expr));
+    try!(verify_symbols(expr, &mut vec![]));
+    try!(verify_builders(expr));
+    Ok(())
+}
+
+/// Checks that type inference accepts the types `expr` is annotated with.
+fn verify_types(expr: &Expr<Type>) -> WeldResult<()> {
+    let mut partial = expr.to_partial();
+    if let Err(e) = type_inference::infer_types(&mut partial) {
+        return weld_err!("type check failed: {}", e);
+    }
+    Ok(())
+}
+
+/// Checks that every symbol used in `expr` is in `scope`, and that no symbol is defined again
+/// while it is in scope, which would break transforms that substitute symbols.
+fn verify_symbols(expr: &Expr<Type>, scope: &mut Vec<Symbol>) -> WeldResult<()> {
+    let new_symbols = match expr.kind {
+        Ident(ref sym) => {
+            if !scope.contains(sym) {
+                return weld_err!("symbol {} is used but not defined", sym);
+            }
+            vec![]
+        }
+        Let { ref name, ref value, ref body } => {
+            try!(verify_symbols(value, scope));
+            try!(define_symbol(name, scope));
+            let result = verify_symbols(body, scope);
+            scope.pop();
+            return result;
+        }
+        Lambda { ref params, .. } => params.iter().map(|p| p.name.clone()).collect(),
+        _ => vec![],
+    };
+
+    for sym in new_symbols.iter() {
+        try!(define_symbol(sym, scope));
+    }
+    for c in expr.children() {
+        try!(verify_symbols(c, scope));
+    }
+    for _ in new_symbols.iter() {
+        scope.pop();
+    }
+    Ok(())
+}
+
+/// Adds `sym` to `scope`, or returns an error if it is already in scope.
+fn define_symbol(sym: &Symbol, scope: &mut Vec<Symbol>) -> WeldResult<()> {
+    if scope.contains(sym) {
+        return weld_err!("symbol {} is defined again while in scope", sym);
+    }
+    scope.push(sym.clone());
+    Ok(())
+}
+
+/// Checks that every builder bound by a Let or passed to a Lambda is used at most once on any
+/// path through its scope. The fields of a struct of builders are separate builders.
+fn verify_builders(expr: &Expr<Type>) -> WeldResult<()> {
+    match expr.kind {
+        Let { ref name, ref value, ref body } => {
+            if contains_builder(&value.ty) {
+                try!(verify_linear(name, body));
+            }
+        }
+        Lambda { ref params, ref body } => {
+            for p in params.iter().filter(|p| contains_builder(&p.ty)) {
+                try!(verify_linear(&p.name, body));
+            }
+        }
+        _ => (),
+    }
+    for c in expr.children() {
+        try!(verify_builders(c));
+    }
+    Ok(())
+}
+
+/// Returns whether a value of type `ty` is or contains a builder.
+fn contains_builder(ty: &Type) -> bool {
+    match *ty {
+        Builder(_, _) => true,
+        Struct(ref fields) => fields.iter().any(contains_builder),
+        _ => false,
+    }
+}
+
+/// Checks that the builder `sym` is used at most once on any path through `expr`.
+fn verify_linear(sym: &Symbol, expr: &Expr<Type>) -> WeldResult<()> {
+    let uses = builder_uses(sym, expr);
+    let max_field = uses.fields.values().cloned().max().unwrap_or(0);
+    if uses.whole + max_field > 1 {
+        return weld_err!("builder {} is used more than once", sym);
+    }
+    Ok(())
+}
+
+/// The number of times a builder is used on a path through an expression, both as a whole and
+/// for each field if it is a struct of builders.
+#[derive(Default)]
+struct BuilderUses {
+    whole: usize,
+    fields: HashMap<u32, usize>,
+}
+
+impl BuilderUses {
+    /// Adds the uses in `other`, which happen on the same path.
+    fn add(&mut self, other: BuilderUses) {
+        self.whole += other.whole;
+        for (field, count) in other.fields {
+            *self.fields.entry(field).or_insert(0) += count;
+        }
+    }
+
+    /// Merges the uses in `other`, which happen on an alternative path.
+    fn max(&mut self, other: BuilderUses) {
+        self.whole = cmp::max(self.whole, other.whole);
+        for (field, count) in other.fields {
+            let entry = self.fields.entry(field).or_insert(0);
+            *entry = cmp::max(*entry, count);
+        }
+    }
+}
+
+/// Counts the uses of the builder `sym` on the path through `expr` that uses it the most. A
+/// builder used inside a function which does not define it may be used any number of times.
+fn builder_uses(sym: &Symbol, expr: &Expr<Type>) -> BuilderUses {
+    let mut uses = BuilderUses::default();
+    match expr.kind {
+        Ident(ref s) if s == sym => uses.whole = 1,
+        GetField { expr: ref e, index } => {
+            match e.kind {
+                Ident(ref s) if s == sym => {
+                    uses.fields.insert(index, 1);
+                }
+                _ => uses.add(builder_uses(sym, e)),
+            }
+        }
+        If { ref cond, ref on_true, ref on_false } => {
+            uses.add(builder_uses(sym, cond));
+            let mut branches = builder_uses(sym, on_true);
+            branches.max(builder_uses(sym, on_false));
+            uses.add(branches);
+        }
+        Let { ref name, ref value, ref body } => {
+            uses.add(builder_uses(sym, value));
+            if name != sym {
+                uses.add(builder_uses(sym, body));
+            }
+        }
+        Lambda { ref params, ref body } => {
+            if !params.iter().any(|p| p.name == *sym) {
+                let inner = builder_uses(sym, body);
+                if inner.whole > 0 || inner.fields.values().any(|c| *c > 0) {
+                    uses.whole = 2;
+                }
+            }
+        }
+        _ => {
+            for c in expr.children() {
+                uses.add(builder_uses(sym, c));
+            }
+        }
+    }
+    uses
+}
+
+/// Checks that every block in `prog` ends in a terminator, that every symbol a function uses is
+/// one of its parameters or locals and is defined on every path to its use, and that the types of
+/// the symbols each statement uses and assigns are consistent.
+pub fn verify_sir(prog: &SirProgram) -> WeldResult<()> {
+    // Loop bodies define their builder, index and element arguments on entry.
+    let mut loop_args = HashMap::new();
+    for func in prog.funcs.iter() {
+        for block in func.blocks.iter() {
+            if let Terminator::ParallelFor(ref pf) = block.terminator {
+                loop_args.insert(pf.body,
+                                 vec![pf.builder_arg.clone(), pf.idx_arg.clone(), pf.data_arg.clone()]);
+            }
+        }
+    }
+    for func in prog.funcs.iter() {
+        let mut entry: HashSet<Symbol> = func.params.keys().cloned().collect();
+        if let Some(args) = loop_args.get(&func.id) {
+            entry.extend(args.iter().cloned());
+        }
+        if let Err(e) = verify_sir_function(prog, func, entry) {
+            return weld_err!("F{}: {}", func.id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Checks a single SIR function whose symbols in `entry` are defined when it starts.
+fn verify_sir_function(prog: &SirProgram, func: &SirFunction, entry: HashSet<Symbol>) -> WeldResult<()> {
+    for sym in func.params.keys() {
+        if func.locals.contains_key(sym) {
+            return weld_err!("symbol {} is both a parameter and a local", sym);
+        }
+    }
+    for (i, block) in func.blocks.iter().enumerate() {
+        if block.id != i {
+            return weld_err!("block B{} is stored at index {}", block.id, i);
+        }
+        match block.terminator {
+            Terminator::Crash => return weld_err!("block B{} has no terminator", block.id),
+            Terminator::Branch { on_true, on_false, .. } => {
+                if on_true >= func.blocks.len() || on_false >= func.blocks.len() {
+                    return weld_err!("block B{} branches to a missing block", block.id);
+                }
+            }
+            Terminator::JumpBlock(target) if target >= func.blocks.len() => {
+                return weld_err!("block B{} jumps to a missing block", block.id);
+            }
+            _ => (),
+        }
+        if block.terminator.callees().iter().any(|f| *f >= prog.funcs.len()) {
+            return weld_err!("block B{} calls a missing function", block.id);
+        }
+    }
+
+    // Find the symbols defined on every path to the start of each block.
+    let mut defined_in: Vec<Option<HashSet<Symbol>>> = vec![None; func.blocks.len()];
+    if !func.blocks.is_empty() {
+        defined_in[0] = Some(entry);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in func.blocks.iter() {
+            let mut defined = match defined_in[block.id] {
+                Some(ref defined) => defined.clone(),
+                None => continue,
+            };
+            defined.extend(block.statements.iter().filter_map(|s| s.output()).cloned());
+            let successors = match block.terminator {
+                Terminator::Branch { on_true, on_false, .. } => vec![on_true, on_false],
+                Terminator::JumpBlock(target) => vec![target],
+                _ => vec![],
+            };
+            for succ in successors {
+                let next = match defined_in[succ] {
+                    Some(ref prev) => prev.intersection(&defined).cloned().collect(),
+                    None => defined.clone(),
+                };
+                if defined_in[succ].as_ref() != Some(&next) {
+                    defined_in[succ] = Some(next);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for block in func.blocks.iter() {
+        let mut defined = match defined_in[block.id] {
+            Some(ref defined) => defined.clone(),
+            None => continue,
+        };
+        for statement in block.statements.iter() {
+            for sym in statement.children() {
+                if !defined.contains(sym) {
+                    return weld_err!("symbol {} is used before it is defined in `{}`", sym, statement);
+                }
+            }
+            if let Some(sym) = statement.output() {
+                if !func.locals.contains_key(sym) {
+                    return weld_err!("symbol {} is assigned but is not a local", sym);
+                }
+                defined.insert(sym.clone());
+            }
+            try!(verify_sir_statement(func, statement));
+        }
+        for sym in block.terminator.children() {
+            if !defined.contains(sym) {
+                return weld_err!("symbol {} is used before it is defined in `{}`", sym, block.terminator);
+            }
+        }
+        // Functions called by the terminator take their parameters from this function.
+        for callee in block.terminator.callees() {
+            for sym in prog.funcs[callee].params.keys() {
+                if !defined.contains(sym) {
+                    return weld_err!("symbol {} is passed to F{} before it is defined", sym, callee);
+                }
+            }
+        }
+        try!(verify_sir_terminator(prog, func, &block.terminator));
+    }
+    Ok(())
+}
+
+/// Returns the type of `sym` in `func`.
+fn sir_symbol_type<'a>(func: &'a SirFunction, sym: &Symbol) -> WeldResult<&'a Type> {
+    match func.locals.get(sym).or(func.params.get(sym)) {
+        Some(ty) => Ok(ty),
+        None => weld_err!("symbol {} is not a parameter or local", sym),
+    }
+}
+
+/// Checks that the types of the symbols `statement` uses and assigns are consistent.
+fn verify_sir_statement(func: &SirFunction, statement: &Statement) -> WeldResult<()> {
+    use super::sir::Statement::*;
+    let ty = |sym: &Symbol| sir_symbol_type(func, sym);
+    let bool_like = |t: &Type| *t == Scalar(ScalarKind::Bool) || *t == Simd(ScalarKind::Bool);
+    let consistent = match *statement {
+        BinOp { ref output, op, ty: ref op_ty, ref left, ref right } => {
+            let out_ty = try!(ty(output));
+            try!(ty(left)) == op_ty && try!(ty(right)) == op_ty &&
+            (if op.is_comparison() { bool_like(out_ty) } else { out_ty == op_ty })
+        }
+        UnaryOp { ref output, ref child, .. } |
+        Negate { ref output, ref child } |
+        Slice { ref output, ref child, .. } => try!(ty(output)) == try!(ty(child)),
+        Assign { ref output, ref value } => same_type(try!(ty(output)), try!(ty(value))),
+        Broadcast { ref output, ref child } => {
+            match (try!(ty(output)), try!(ty(child))) {
+                (&Simd(k1), &Scalar(k2)) => k1 == k2,
+                _ => false,
+            }
+        }
+        Cast { ref output, ref new_ty, .. } => try!(ty(output)) == new_ty,
+        Lookup { ref output, ref child, ref index } => {
+            match *try!(ty(child)) {
+                Vector(ref elem_ty) => **elem_ty == *try!(ty(output)),
+                Dict(ref key_ty, ref value_ty) => {
+                    **key_ty == *try!(ty(index)) && **value_ty == *try!(ty(output))
+                }
+                _ => false,
+            }
+        }
+        KeyExists { ref output, .. } => bool_like(try!(ty(output))),
+        Length { ref output, .. } => *try!(ty(output)) == Scalar(ScalarKind::I64),
+        Select { ref output, ref cond, ref on_true, ref on_false } => {
+            let out_ty = try!(ty(output));
+            bool_like(try!(ty(cond))) && try!(ty(on_true)) == out_ty && try!(ty(on_false)) == out_ty
+        }
+        ToVec { ref child, .. } => {
+            if let Dict(_, _) = *try!(ty(child)) { true } else { false }
+        }
+        AssignLiteral { ref output, ref value } => {
+            let kind = match *value {
+                LiteralKind::BoolLiteral(_) => ScalarKind::Bool,
+                LiteralKind::I8Literal(_) => ScalarKind::I8,
+                LiteralKind::I32Literal(_) => ScalarKind::I32,
+                LiteralKind::I64Literal(_) => ScalarKind::I64,
+                LiteralKind::F32Literal(_) => ScalarKind::F32,
+                LiteralKind::F64Literal(_) => ScalarKind::F64,
+            };
+            let out_ty = try!(ty(output));
+            *out_ty == Scalar(kind) || *out_ty == Simd(kind)
+        }
+        Merge { ref builder, ref value } => {
+            try!(ty(value));
+            if let Builder(_, _) = *try!(ty(builder)) { true } else { false }
+        }
+        Res { ref output, ref builder } => {
+            try!(ty(output));
+            if let Builder(_, _) = *try!(ty(builder)) { true } else { false }
+        }
+        NewBuilder { ref output, ref arg, ty: ref builder_ty } => {
+            if let Some(ref a) = *arg {
+                try!(ty(a));
+            }
+            try!(ty(output)) == builder_ty
+        }
+        MakeStruct { ref output, ref elems } => {
+            let mut field_tys = vec![];
+            for &(ref sym, ref elem_ty) in elems.iter() {
+                if try!(ty(sym)) != elem_ty {
+                    return weld_err!("inconsistent types in `{}`", statement);
+                }
+                field_tys.push(elem_ty.clone());
+            }
+            *try!(ty(output)) == Struct(field_tys)
+        }
+        MakeVector { ref output, ref elems, ref elem_ty } => {
+            for sym in elems.iter() {
+                if try!(ty(sym)) != elem_ty {
+                    return weld_err!("inconsistent types in `{}`", statement);
+                }
+            }
+            *try!(ty(output)) == Vector(Box::new(elem_ty.clone()))
+        }
+        GetField { ref output, ref value, index } => {
+            match *try!(ty(value)) {
+                Struct(ref fields) => fields.get(index as usize) == Some(try!(ty(output))),
+                _ => false,
+            }
+        }
+        CUDF { ref output, ref args, .. } => {
+            for sym in args.iter() {
+                try!(ty(sym));
+            }
+            try!(ty(output));
+            true
+        }
+    };
+    if !consistent {
+        return weld_err!("inconsistent types in `{}`", statement);
+    }
+    Ok(())
+}
+
+/// Returns whether `a` and `b` are the same type, ignoring the annotations of builder types.
+fn same_type(a: &Type, b: &Type) -> bool {
+    match (a, b) {
+        (&Builder(ref k1, _), &Builder(ref k2, _)) => k1 == k2,
+        (&Struct(ref f1), &Struct(ref f2)) => {
+            f1.len() == f2.len() && f1.iter().zip(f2.iter()).all(|(a, b)| same_type(a, b))
+        }
+        _ => a == b,
+    }
+}
+
+/// Checks that the types of the symbols `terminator` uses are consistent.
+fn verify_sir_terminator(prog: &SirProgram,
+                         func: &SirFunction,
+                         terminator: &Terminator)
+                         -> WeldResult<()> {
+    let consistent = match *terminator {
+        Terminator::Branch { ref cond, .. } => {
+            *try!(sir_symbol_type(func, cond)) == Scalar(ScalarKind::Bool)
+        }
+        Terminator::ProgramReturn(ref sym) => {
+            // The program's type is the type of the function it was generated from.
+            match prog.ret_ty {
+                Function(_, ref ret_ty) => try!(sir_symbol_type(func, sym)) == ret_ty.as_ref(),
+                _ => false,
+            }
+        }
+        Terminator::ParallelFor(ref pf) => {
+            let mut consistent = true;
+            for iter in pf.data.iter() {
+                if let Vector(_) = *try!(sir_symbol_type(func, &iter.data)) {} else {
+                    consistent = false;
+                }
+                for sym in iter.start.iter().chain(iter.end.iter()).chain(iter.stride.iter()) {
+                    consistent &= *try!(sir_symbol_type(func, sym)) == Scalar(ScalarKind::I64);
+                }
+            }
+            let body = &prog.funcs[pf.body];
+            consistent && same_type(try!(sir_symbol_type(func, &pf.builder)),
+                                    try!(sir_symbol_type(body, &pf.builder_arg)))
+        }
+        _ => true,
+    };
+    if !consistent {
+        return weld_err!("inconsistent types in `{}`", terminator);
+    }
+    Ok(())
+}